@@ -0,0 +1,33 @@
+// Core dumps. A process with core dumps enabled that dies of a fault writes
+// /core.<pid>:
+//   CoreHeader
+//   nregions times: CoreRegion, then its len bytes of memory
+// Regions are runs of mapped user pages, in address order.
+
+use crate::ptrace::Regs;
+
+pub const CORE_MAGIC: u32 = 0x4552_4f43; // "CORE"
+
+// prctl options (Linux values). Core dumps are enabled per process with
+// PR_SET_DUMPABLE and inherited by fork; the default for the first process
+// is the kernel's `coredump` command line option.
+pub const PR_GET_DUMPABLE: usize = 3;
+pub const PR_SET_DUMPABLE: usize = 4;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CoreHeader {
+    pub magic: u32,
+    pub pid: u32,
+    pub trap_num: u32,
+    pub nregions: u32,
+    pub fault_addr: u64, // Page faults: the faulting address (CR2)
+    pub regs: Regs,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CoreRegion {
+    pub start: u64,
+    pub len: u64,
+}
@@ -0,0 +1,16 @@
+// /dev/fb, the framebuffer (fs::FB_MAJOR): width x height pixels of 32
+// bits, 0x00RRGGBB, rows stride bytes apart. write() at an offset draws
+// and what it covers is shown at once; read() returns what is there.
+
+// ioctl(fd, FBIOGET_VSCREENINFO, &mut FbInfo): Linux's number, with a
+// smaller struct.
+pub const FBIOGET_VSCREENINFO: usize = 0x4600;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32, // Bytes per row
+    pub bpp: u32,    // Bits per pixel
+}
@@ -0,0 +1,69 @@
+// File system types exchanged through syscalls or read from directories.
+
+// Stat::typ
+pub const T_DIR: u16 = 1;
+pub const T_FILE: u16 = 2;
+pub const T_DEV: u16 = 3;
+
+// Device major numbers, for mknod.
+pub const CONSOLE_MAJOR: u16 = 1;
+pub const FB_MAJOR: u16 = 2;
+
+// Filled in by fstat.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub dev: u32,   // Device holding the file
+    pub ino: u32,   // Inode number
+    pub typ: u16,   // T_DIR, T_FILE or T_DEV
+    pub nlink: u16, // Links to the file
+    pub size: u64,  // Size in bytes
+}
+
+const _: () = assert!(core::mem::size_of::<Stat>() == 24);
+const _: () = assert!(core::mem::offset_of!(Stat, size) == 16);
+
+// Filled in by statfs. Counts are of bsize-byte blocks.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StatFs {
+    pub bsize: u32,
+    pub blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+}
+
+// ext2 directory entry header, as returned by getdents() on a directory
+// opened with O_DIRECTORY. The name (name_len bytes) follows it; rec_len is
+// the distance to the next entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub file_type: u8,
+}
+
+const _: () = assert!(core::mem::size_of::<DirEntry>() == 8);
+
+// Longest name in a directory (name_len is a byte), and longest path a
+// syscall accepts, including the NUL.
+pub const NAME_MAX: usize = 255;
+pub const PATH_MAX: usize = 1024;
+
+// open() flags (Linux values).
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x040;
+pub const O_TRUNC: i32 = 0x200;
+pub const O_APPEND: i32 = 0x400;
+pub const O_DIRECTORY: i32 = 0x10000; // Open a directory, for getdents only
+
+// lseek() whence values: the offset is from the start, from the current
+// offset, or from the end of the file.
+pub const SEEK_SET: i32 = 0;
+pub const SEEK_CUR: i32 = 1;
+pub const SEEK_END: i32 = 2;
@@ -0,0 +1,14 @@
+#![no_std]
+
+// Definitions shared by the kernel and ulib, so the two sides of the
+// syscall interface cannot drift apart.
+
+pub mod coredump;
+pub mod fb;
+pub mod fs;
+pub mod mman;
+pub mod net;
+pub mod ptrace;
+pub mod signal;
+pub mod syscall;
+pub mod tty;
@@ -0,0 +1,14 @@
+// mmap() arguments (Linux values).
+
+// prot: what the pages may be used for. PROT_NONE reserves the range only.
+pub const PROT_NONE: u32 = 0x0;
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+// flags: exactly one of MAP_SHARED and MAP_PRIVATE. A MAP_SHARED mapping
+// is the same memory in children forked later, and its writes to a file
+// reach it when unmapped; MAP_PRIVATE ones never do.
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_ANONYMOUS: u32 = 0x20; // Zero-filled, no file; fd is ignored
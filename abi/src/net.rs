@@ -0,0 +1,71 @@
+// Sockets, IPv4 only. socket(AF_INET, SOCK_DGRAM, 0) makes a UDP one, used
+// with bind, sendto and recvfrom, or connect()ed to a peer and then read
+// and written a datagram at a time; socket(AF_INET, SOCK_STREAM, 0) a TCP
+// one, which connect()s, or bind()s, listen()s and accept()s, and is then
+// read and written. Either is an fd like a pipe's, which dup, fork and
+// close treat alike. Addresses are a SockAddrIn, laid out as Linux's
+// struct sockaddr_in.
+//
+// socket(AF_INET, SOCK_DGRAM, IPPROTO_ICMP) makes a ping socket, as on
+// Linux: what is sent on it is an ICMP echo request, header and all, whose
+// identifier and checksum the kernel fills in; what is received, the
+// replies to it, ICMP header and all.
+
+pub const AF_INET: u16 = 2;
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+pub const IPPROTO_ICMP: usize = 1;
+
+// ICMP message types, for ping sockets
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO: u8 = 8;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SockAddrIn {
+    pub family: u16, // AF_INET
+    pub port: u16,   // Network byte order
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub const fn new(addr: [u8; 4], port: u16) -> Self {
+        Self {
+            family: AF_INET,
+            port: port.to_be(),
+            addr,
+            zero: [0; 8],
+        }
+    }
+
+    // The port in host byte order
+    pub const fn port(&self) -> u16 {
+        u16::from_be(self.port)
+    }
+}
+
+// recvfrom flags: fail with EAGAIN rather than wait for a datagram. Taken
+// by UDP sockets only.
+pub const MSG_DONTWAIT: usize = 0x40;
+
+// ioctl on a socket: the interface's configuration, as DHCP gave it, into
+// a NetConf. The first of Linux's SIOCDEVPRIVATE numbers.
+pub const SIOCGNETCONF: usize = 0x89F0;
+
+// Addresses are 0.0.0.0 while there are none.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetConf {
+    pub up: u32, // 1 if there is an interface
+    pub addr: [u8; 4],
+    pub mask: [u8; 4],
+    pub gateway: [u8; 4],
+    pub dns: [u8; 4],
+    pub mac: [u8; 6],
+    pub pad: [u8; 2],
+}
+
+// Port 0 in bind or an unbound socket's first sendto picks a free one from
+// here up.
+pub const EPHEMERAL_PORT: u16 = 49152;
@@ -0,0 +1,62 @@
+// ptrace requests (Linux values). Only the calling process's children can be
+// traced.
+
+// Trace the caller; it stops when it next execs.
+pub const PTRACE_TRACEME: usize = 0;
+// Resume a stopped tracee and wait until it stops again.
+pub const PTRACE_CONT: usize = 7;
+// Kill a stopped tracee.
+pub const PTRACE_KILL: usize = 8;
+// Resume a stopped tracee for one instruction, then wait for it to stop.
+pub const PTRACE_SINGLESTEP: usize = 9;
+// Copy a stopped tracee's Regs to addr.
+pub const PTRACE_GETREGS: usize = 12;
+// Trace the child (stopping it) and wait until it is stopped.
+pub const PTRACE_ATTACH: usize = 16;
+// Stop tracing and resume a stopped tracee. Clears its breakpoints.
+pub const PTRACE_DETACH: usize = 17;
+// Set or clear a hardware breakpoint of a stopped tracee, as described by
+// the Breakpoint at addr. Not in Linux, which uses PTRACE_POKEUSER.
+pub const PTRACE_SETBP: usize = 0x4280;
+
+// User registers of a stopped tracee.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Regs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+// Breakpoint kinds. A tracee stops with rip at a BP_EXEC address before
+// executing it, and after the instruction that writes a BP_WRITE range.
+pub const BP_CLEAR: u16 = 0;
+pub const BP_EXEC: u16 = 1;
+pub const BP_WRITE: u16 = 2;
+
+// Number of hardware breakpoints (x86 DR0-DR3).
+pub const NBREAKPOINT: usize = 4;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Breakpoint {
+    pub addr: u64,
+    pub slot: u32, // 0..NBREAKPOINT
+    pub kind: u16,
+    pub len: u16, // BP_WRITE: 1, 2, 4 or 8 bytes at an aligned addr
+}
@@ -0,0 +1,43 @@
+// Signals (Linux numbers). Each process has a handler for every signal, a
+// mask of blocked signals and a set of pending ones; a pending signal that
+// is not blocked is delivered when the process next returns to user mode.
+
+pub const SIGINT: usize = 2;
+pub const SIGKILL: usize = 9; // Cannot be caught, ignored or blocked
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17; // Sent to the parent when a child exits
+pub const NSIG: usize = 32; // Signals are 1..NSIG
+
+// SigAction handlers other than a function address.
+pub const SIG_DFL: usize = 0; // Default: SIGCHLD is ignored, the rest terminate
+pub const SIG_IGN: usize = 1;
+
+// sigaction(sig, act, oldact): act, unless null, replaces the action for sig;
+// oldact, unless null, receives the previous one.
+//
+// A handler runs as handler(sig) on the user stack, with mask and sig itself
+// blocked. It returns to restorer, which must make the sigreturn syscall:
+// that puts back the registers and mask saved below its stack frame.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SigAction {
+    pub handler: usize,
+    pub mask: u32,  // Blocked while the handler runs, besides sig
+    pub flags: u32, // None defined yet; must be 0
+    pub restorer: usize,
+}
+
+// sigprocmask(how, set) returns the old mask.
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// Bit for sig in a mask.
+pub const fn sigbit(sig: usize) -> u32 {
+    1 << sig
+}
@@ -0,0 +1,244 @@
+// Syscall numbers. Linux numbering where a Linux equivalent exists;
+// tinyos-specific calls start at 512.
+
+pub const SYS_READ: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_STAT: usize = 4;
+pub const SYS_FSTAT: usize = 5;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_SBRK: usize = 12;
+pub const SYS_SIGACTION: usize = 13;
+pub const SYS_SIGPROCMASK: usize = 14;
+pub const SYS_SIGRETURN: usize = 15;
+pub const SYS_IOCTL: usize = 16;
+pub const SYS_PIPE: usize = 22;
+pub const SYS_DUP: usize = 32;
+pub const SYS_DUP2: usize = 33;
+pub const SYS_SOCKET: usize = 41;
+pub const SYS_CONNECT: usize = 42;
+pub const SYS_ACCEPT: usize = 43;
+pub const SYS_SENDTO: usize = 44;
+pub const SYS_RECVFROM: usize = 45;
+pub const SYS_BIND: usize = 49;
+pub const SYS_LISTEN: usize = 50;
+pub const SYS_GETSOCKNAME: usize = 51;
+pub const SYS_GETPEERNAME: usize = 52;
+pub const SYS_FORK: usize = 57;
+pub const SYS_EXEC: usize = 59;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_WAIT: usize = 61;
+pub const SYS_KILL: usize = 62;
+pub const SYS_FSYNC: usize = 74;
+pub const SYS_FTRUNCATE: usize = 77;
+pub const SYS_CHDIR: usize = 80;
+pub const SYS_MKDIR: usize = 83;
+pub const SYS_UNLINK: usize = 87;
+pub const SYS_GETRUSAGE: usize = 98;
+pub const SYS_SETPGID: usize = 109;
+pub const SYS_GETPGID: usize = 121;
+pub const SYS_MKNOD: usize = 133;
+pub const SYS_PTRACE: usize = 101;
+pub const SYS_STATFS: usize = 137;
+pub const SYS_PRCTL: usize = 157;
+pub const SYS_SYNC: usize = 162;
+pub const SYS_GETDENTS: usize = 217;
+pub const SYS_IRQSTAT: usize = 512;
+pub const SYS_SYSINFO: usize = 513;
+pub const SYS_UPTIME: usize = 514;
+pub const SYS_SLEEP: usize = 515;
+pub const SYS_WAITPID: usize = 516;
+pub const SYS_NICE: usize = 517;
+pub const SYS_CLONE: usize = 518;
+
+// Every syscall above. The kernel checks at compile time that each one has
+// a handler.
+pub const SYSCALLS: &[usize] = &[
+    SYS_READ,
+    SYS_WRITE,
+    SYS_OPEN,
+    SYS_CLOSE,
+    SYS_STAT,
+    SYS_FSTAT,
+    SYS_LSEEK,
+    SYS_MMAP,
+    SYS_MUNMAP,
+    SYS_SBRK,
+    SYS_SIGACTION,
+    SYS_SIGPROCMASK,
+    SYS_SIGRETURN,
+    SYS_IOCTL,
+    SYS_PIPE,
+    SYS_DUP,
+    SYS_DUP2,
+    SYS_SOCKET,
+    SYS_CONNECT,
+    SYS_ACCEPT,
+    SYS_SENDTO,
+    SYS_RECVFROM,
+    SYS_BIND,
+    SYS_LISTEN,
+    SYS_GETSOCKNAME,
+    SYS_GETPEERNAME,
+    SYS_FORK,
+    SYS_EXEC,
+    SYS_EXIT,
+    SYS_WAIT,
+    SYS_KILL,
+    SYS_FSYNC,
+    SYS_FTRUNCATE,
+    SYS_CHDIR,
+    SYS_MKDIR,
+    SYS_UNLINK,
+    SYS_GETRUSAGE,
+    SYS_SETPGID,
+    SYS_GETPGID,
+    SYS_MKNOD,
+    SYS_PTRACE,
+    SYS_STATFS,
+    SYS_PRCTL,
+    SYS_SYNC,
+    SYS_GETDENTS,
+    SYS_IRQSTAT,
+    SYS_SYSINFO,
+    SYS_UPTIME,
+    SYS_SLEEP,
+    SYS_WAITPID,
+    SYS_NICE,
+    SYS_CLONE,
+];
+
+// Returned (negated) for a number the kernel has no handler for, so callers
+// can tell it apart from an ordinary failure (-1).
+pub const ENOSYS: isize = 38;
+
+// Returned (negated) by a write on a nonblocking fd that cannot take any
+// bytes yet.
+pub const EAGAIN: isize = 11;
+
+// Returned (negated) by open: O_DIRECTORY on something that is not a
+// directory, or a directory opened without O_DIRECTORY.
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+
+// Returned (negated) by a blocking call cut short by a signal.
+pub const EINTR: isize = 4;
+
+// Returned (negated) by open for writing, or unlink, of the file holding
+// the journal, /.log.
+pub const EPERM: isize = 1;
+
+// Returned (negated) for a bad argument: an unknown lseek whence or an
+// offset outside the file, a signal that cannot be caught, or fsync on
+// something other than a file.
+pub const EINVAL: isize = 22;
+// Returned (negated) by file system calls when the disk failed a read or
+// write.
+pub const EIO: isize = 5;
+// Returned (negated) by lseek on a pipe or a device other than /dev/fb,
+// which have no offset.
+pub const ESPIPE: isize = 29;
+
+// Returned (negated) by mmap: no room for the mapping, a file that cannot
+// be mapped, or one not open for the access asked for.
+pub const ENOMEM: isize = 12;
+pub const EACCES: isize = 13;
+pub const ENODEV: isize = 19;
+
+// Returned (negated) by setpgid and getpgid for no such process, or one
+// that is neither the caller nor its child.
+pub const ESRCH: isize = 3;
+
+// Returned (negated) by ioctl on an fd that is not a terminal, or for a
+// request it does not know.
+pub const ENOTTY: isize = 25;
+
+// Returned (negated) by open of a device node with no device behind it,
+// such as a console past tty::NTTY, or /dev/fb without a GPU, and by file
+// system calls on a disk or partition that is gone.
+pub const ENXIO: isize = 6;
+
+// Returned (negated) by open when every in-memory inode is taken by the
+// caller's own open files, so waiting for one to come free would not end.
+pub const ENFILE: isize = 23;
+
+// Returned (negated) for a path with a component longer than NAME_MAX, or
+// longer than PATH_MAX in all.
+pub const ENAMETOOLONG: isize = 36;
+
+// Returned (negated) by socket for a family or type other than AF_INET
+// and SOCK_DGRAM or SOCK_STREAM, by the other socket calls on an fd that
+// is not one, and by listen on a UDP socket.
+pub const EAFNOSUPPORT: isize = 97;
+pub const EPROTONOSUPPORT: isize = 93;
+pub const ENOTSOCK: isize = 88;
+pub const EOPNOTSUPP: isize = 95;
+
+// Returned (negated) by bind to a port another socket has.
+pub const EADDRINUSE: isize = 98;
+
+// Returned (negated) by sendto: no network interface, no address for it
+// from DHCP, no answer to ARP for the next hop, or a datagram too big for
+// one frame.
+pub const ENETDOWN: isize = 100;
+pub const ENETUNREACH: isize = 101;
+pub const EHOSTUNREACH: isize = 113;
+pub const EMSGSIZE: isize = 90;
+
+// Returned (negated) by TCP sockets: connect to a port nobody listens on,
+// a connection reset by its peer or given up on for want of answers, use
+// of one not connected or already, and writes after close.
+pub const ECONNREFUSED: isize = 111;
+pub const ECONNRESET: isize = 104;
+pub const ETIMEDOUT: isize = 110;
+pub const ENOTCONN: isize = 107;
+pub const EISCONN: isize = 106;
+pub const EPIPE: isize = 32;
+
+// Returned (negated) by write, or sendto without an address, on a UDP
+// socket that is not connected.
+pub const EDESTADDRREQ: isize = 89;
+
+// Kernel counters returned by sysinfo.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SysInfo {
+    pub breads: u64,                          // bread calls
+    pub dcache_hits: u64,                     // dirlookup answered from the name cache
+    pub dcache_misses: u64,                   // dirlookup that scanned the directory
+    pub fpu_restores: u64,                    // FPU state loaded on first use (#NM)
+    pub fpu_saves: u64,                       // FPU state saved at a context switch
+    pub free_pages: u64,                      // Physical pages the kernel has not handed out
+    pub bcache_hits: u64,                     // bread answered from the buffer cache
+    pub bcache_misses: u64,                   // bread that went to the disk
+    pub blocks_read: u64,                     // Blocks read from disk, read-ahead included
+    pub blocks_written: u64,                  // Blocks sent to disk, write-back included
+    pub readaheads: u64,                      // Blocks read ahead
+    pub dirty_waits: u64,                     // bget waiting for flushd to clean a buffer
+    pub inodes_free: u64,                     // In-memory inode slots nothing references
+    pub disk_queue: [u32; SYSINFO_NDISK],     // Requests in flight on virtio0 and on
+    pub disk_queue_max: [u32; SYSINFO_NDISK], // The most there have been at once
+}
+
+// Virtio disks sysinfo reports on
+pub const SYSINFO_NDISK: usize = 4;
+
+// getrusage(who, buf): CPU time of the caller, or of all its children that
+// have been waited for.
+pub const RUSAGE_SELF: isize = 0;
+pub const RUSAGE_CHILDREN: isize = -1;
+
+// CPU time in timer ticks, charged to the process a tick interrupts.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rusage {
+    pub utime: u64, // Ticks spent in user mode
+    pub stime: u64, // Ticks spent in the kernel
+}
+
+// nice(inc): the range of nice values. Higher is lower priority.
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
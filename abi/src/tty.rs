@@ -0,0 +1,29 @@
+// Virtual consoles: minors 0..NTTY of fs::CONSOLE_MAJOR, /dev/tty0 on.
+// Alt-N (ESC then the digit N) switches the serial line to ttyN.
+pub const NTTY: usize = 4;
+
+// ioctl(fd, request, arg) requests for a console (Linux numbers).
+
+// Terminal settings: arg points to a Termios.
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+
+// Job control: arg points to an i32, the foreground process group, which
+// Ctrl-C sends SIGINT to. 0 means none.
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+
+// Terminal settings. Only the local modes of a Linux termios are kept.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Termios {
+    pub lflag: u32,
+}
+
+// Termios.lflag bits (Linux values), all set by default.
+pub const ISIG: u32 = 0o1; // Ctrl-C interrupts the foreground process group
+pub const ICANON: u32 = 0o2; // Line editing; read returns a line at a time
+pub const ECHO: u32 = 0o10; // Echo input
+
+// Without ICANON, read returns as soon as any byte is there, and bytes
+// come as typed: no line editing, Ctrl-D or CR to NL.
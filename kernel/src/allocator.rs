@@ -2,20 +2,33 @@ use crate::PG_SIZE;
 
 use crate::spinlock::Spinlock;
 
+// Pages of physical memory, which the allocator hands out.
+const NPAGES: usize = crate::util::PHYS_MEM / PG_SIZE;
+
 pub struct Allocator {
     pub freelist: *const Run,
+    pub nfree: usize, // Pages on freelist
+    // References to each page handed out: page tables mapping it shared (see
+    // mmap.rs) each hold one. At most NPROC.
+    refs: [u8; NPAGES],
 }
 
 pub struct Run {
     pub next: *const Run,
 }
 
-pub static ALLOCATOR: Spinlock<Allocator> = Spinlock::new(Allocator::new(), "ALLOCATOR");
+pub static ALLOCATOR: Spinlock<Allocator> = Spinlock::ranked(
+    Allocator::new(),
+    "ALLOCATOR",
+    crate::lockorder::RANK_ALLOCATOR,
+);
 
 impl Allocator {
     pub const fn new() -> Self {
         Self {
             freelist: core::ptr::null(),
+            nfree: 0,
+            refs: [0; NPAGES],
         }
     }
 
@@ -28,10 +41,18 @@ impl Allocator {
         }
     }
 
+    // Drop a reference to the page at addr, freeing it with the last.
     pub fn kfree(&mut self, addr: usize) {
+        let refs = &mut self.refs[page(addr)];
+        if *refs > 1 {
+            *refs -= 1;
+            return;
+        }
+        *refs = 0;
         let run: &mut Run = unsafe { &mut *(addr as *mut Run) };
         run.next = self.freelist;
         self.freelist = run;
+        self.nfree += 1;
     }
 
     pub fn kalloc(&mut self) -> *mut u8 {
@@ -41,11 +62,22 @@ impl Allocator {
         }
         unsafe {
             self.freelist = (*run).next;
+            self.nfree -= 1;
+            self.refs[page(run as usize)] = 1;
             // Zero out run
-            crate::util::stosq(run as *mut u64, 0, PG_SIZE / 8);
+            crate::util::fast_zero(run as *mut u8, PG_SIZE);
         }
         run as *mut u8
     }
+
+    // Take another reference to a page from kalloc.
+    pub fn incref(&mut self, addr: usize) {
+        self.refs[page(addr)] += 1;
+    }
+}
+
+fn page(addr: usize) -> usize {
+    crate::util::v2p(addr) / PG_SIZE
 }
 
 fn pgroundup(sz: usize) -> usize {
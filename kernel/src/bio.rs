@@ -1,75 +1,346 @@
 use crate::fs::BSIZE;
+use crate::part;
+use crate::ramdisk;
 use crate::spinlock::Spinlock;
+use crate::util::PG_SIZE;
 use crate::virtio;
+use abi::syscall::{EIO, ENXIO};
+use core::fmt;
+use core::mem::size_of;
+use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 
-pub const NBUF: usize = 30;
+// Buffers the cache starts with: the journal may keep up to LOGSIZE of them
+// pinned. It grows on demand, a page of buffers at a time, up to
+// NBUF_DEFAULT or the nbuf= on the kernel command line.
+pub const NBUF_MIN: usize = crate::journal::LOGSIZE + 10;
+const NBUF_DEFAULT: usize = 1024;
+const NBUF_MAX: usize = 8192;
+const PER_PAGE: usize = PG_SIZE / size_of::<Buf>();
+const MAXPAGES: usize = NBUF_MAX.div_ceil(PER_PAGE);
 
+// Block devices, the numbers in Buf::dev and Stat::dev. Selected as the root
+// with root= on the kernel command line. The virtio disks are numbered
+// from DEV_VIRTIO0 on: virtioN is DEV_VIRTIO0 + N. Partitions are numbered
+// from their disk's (see part).
+pub const DEV_RAMDISK: u32 = 1;
+pub const DEV_VIRTIO0: u32 = 2;
+
+const VIRTIO_NAMES: [&str; virtio::NDISK] = ["virtio0", "virtio1", "virtio2", "virtio3"];
+
+// The virtio disk dev is, if it is one.
+pub fn virtio_disk(dev: u32) -> Option<usize> {
+    let disk = dev.checked_sub(DEV_VIRTIO0)? as usize;
+    (disk < virtio::NDISK).then_some(disk)
+}
+
+// Every whole disk there may be.
+pub fn disks() -> impl Iterator<Item = u32> {
+    core::iter::once(DEV_RAMDISK).chain((0..virtio::NDISK as u32).map(|d| DEV_VIRTIO0 + d))
+}
+
+fn disk_name(dev: u32) -> &'static str {
+    match (dev, virtio_disk(dev)) {
+        (DEV_RAMDISK, _) => "ramdisk",
+        (_, Some(disk)) => VIRTIO_NAMES[disk],
+        _ => "?",
+    }
+}
+
+// The name of a device, as root= takes it: virtio0, or virtio0p1 for a
+// partition.
 #[derive(Clone, Copy)]
+pub struct DevName(u32);
+
+impl fmt::Display for DevName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(disk_name(part::whole(self.0)))?;
+        match part::number(self.0) {
+            0 => Ok(()),
+            n => write!(f, "p{}", n),
+        }
+    }
+}
+
+pub fn dev_name(dev: u32) -> DevName {
+    DevName(dev)
+}
+
+pub fn dev_by_name(name: &str) -> Option<u32> {
+    let by_disk = |name: &str| disks().find(|&dev| disk_name(dev) == name);
+    if let Some((disk, n)) = name.rsplit_once('p') {
+        if let (Some(dev), Ok(n)) = (by_disk(disk), n.parse::<u32>()) {
+            return (1..=part::MAXPART).contains(&n).then_some(dev | n << 8);
+        }
+    }
+    by_disk(name)
+}
+
+// Whether there is a device behind dev.
+pub fn dev_present(dev: u32) -> bool {
+    if part::number(dev) != 0 {
+        return part::find(dev).is_some();
+    }
+    match (dev, virtio_disk(dev)) {
+        (DEV_RAMDISK, _) => ramdisk::present(),
+        (_, Some(disk)) => virtio::present(disk),
+        _ => false,
+    }
+}
+
+// Read buf.len() / 512 sectors from sector on the whole disk dev, around
+// the cache. Err(-ENXIO) if there is no such disk, Err(-EIO) if the read
+// fails; the same for the writes below.
+pub fn read_sectors(dev: u32, sector: u64, buf: &mut [u8]) -> Result<(), isize> {
+    match (dev, virtio_disk(dev)) {
+        (DEV_RAMDISK, _) => ramdisk::read_block(sector, buf),
+        (_, Some(disk)) => virtio::read_block(disk, sector, buf),
+        _ => return Err(-ENXIO),
+    }
+    .map_err(|()| -EIO)
+}
+
+fn write_sectors(dev: u32, sector: u64, buf: &[u8]) -> Result<(), isize> {
+    match (dev, virtio_disk(dev)) {
+        (DEV_RAMDISK, _) => ramdisk::write_block(sector, buf),
+        (_, Some(disk)) => virtio::write_block(disk, sector, buf),
+        _ => return Err(-ENXIO),
+    }
+    .map_err(|()| -EIO)
+}
+
+// Both drivers use 512 byte sectors, but we use 1024 byte blocks, so
+// we need to specify `blockno * 2` as sector number. Note that the buffer
+// size can be larger than 512 bytes.
+fn read_block(dev: u32, blockno: u32, buf: &mut [u8]) -> Result<(), isize> {
+    let (dev, sector) =
+        part::map(dev, blockno as u64 * 2, buf.len() as u64 / 512).map_err(|()| -ENXIO)?;
+    read_sectors(dev, sector, buf)
+}
+
+fn write_block(dev: u32, blockno: u32, buf: &[u8]) -> Result<(), isize> {
+    let (dev, sector) =
+        part::map(dev, blockno as u64 * 2, buf.len() as u64 / 512).map_err(|()| -ENXIO)?;
+    write_sectors(dev, sector, buf)
+}
+
+// Number of bread calls, reported by sysinfo.
+pub static BREADS: AtomicU64 = AtomicU64::new(0);
+// And of those answered from the cache or not, blocks read from and written
+// to disk, and read-aheads started.
+pub static HITS: AtomicU64 = AtomicU64::new(0);
+pub static MISSES: AtomicU64 = AtomicU64::new(0);
+pub static BLOCKS_READ: AtomicU64 = AtomicU64::new(0);
+pub static BLOCKS_WRITTEN: AtomicU64 = AtomicU64::new(0);
+pub static READAHEADS: AtomicU64 = AtomicU64::new(0);
+// Times bget had to wait for flushd to clean a buffer, as every one no one
+// held was dirty.
+pub static DIRTY_WAITS: AtomicU64 = AtomicU64::new(0);
+
 pub struct Buf {
     pub valid: bool, // Has data been read from disk?
-    pub disk: bool,  // Does content match disk?
+    pub dirty: bool, // Changed in the cache only, until flush writes it back?
     pub dev: u32,
     pub blockno: u32,
     pub refcnt: u32,
     pub prev: usize, // LRU cache list
     pub next: usize,
+    // 0, or where a read-ahead or write-back of data is at
+    // (virtio::IO_PENDING..). It holds a reference until it is settled.
+    io: AtomicU8,
     pub data: [u8; BSIZE],
 }
 
-impl Buf {
-    pub const fn new() -> Self {
-        Self {
-            valid: false,
-            disk: false,
-            dev: 0,
-            blockno: 0,
-            refcnt: 0,
-            prev: 0,
-            next: 0,
-            data: [0; BSIZE],
-        }
+// The buffers, PER_PAGE to a page from kalloc, numbered in the order they
+// were added. A buffer stays where it is, so its number can be kept.
+pub struct Bufs {
+    pages: [*mut Buf; MAXPAGES],
+    n: usize,
+}
+
+impl Index<usize> for Bufs {
+    type Output = Buf;
+
+    fn index(&self, i: usize) -> &Buf {
+        assert!(i < self.n, "bio: no buffer {}", i);
+        unsafe { &*self.pages[i / PER_PAGE].add(i % PER_PAGE) }
+    }
+}
+
+impl IndexMut<usize> for Bufs {
+    fn index_mut(&mut self, i: usize) -> &mut Buf {
+        assert!(i < self.n, "bio: no buffer {}", i);
+        unsafe { &mut *self.pages[i / PER_PAGE].add(i % PER_PAGE) }
     }
 }
 
-// Buffer cache
+// Buffer cache. The buffers are on a circular list through prev and next,
+// most recently used first: brelse moves a buffer to the front when its
+// last reference goes, and bget recycles from the back.
 pub struct Bcache {
-    pub bufs: [Buf; NBUF],
-    pub head: usize, // Index of head of LRU list
+    pub bufs: Bufs,
+    head: usize,   // Most recently used; bufs[head].prev is the least
+    max: usize,    // Buffers the cache may grow to
+    ndirty: usize, // Buffers with dirty set
 }
 
-pub static BCACHE: Spinlock<Bcache> = Spinlock::new(
+pub static BCACHE: Spinlock<Bcache> = Spinlock::ranked(
     Bcache {
-        bufs: [Buf::new(); NBUF],
+        bufs: Bufs {
+            pages: [core::ptr::null_mut(); MAXPAGES],
+            n: 0,
+        },
         head: 0,
+        max: NBUF_DEFAULT,
+        ndirty: 0,
     },
     "BCACHE",
+    crate::lockorder::RANK_BCACHE,
 );
 
-pub fn binit() {
-    let mut bcache = BCACHE.lock();
+impl Bcache {
+    // Set or clear buffer i's dirty flag, keeping ndirty. Once more than
+    // 1/DIRTY_SHARE of the buffers are dirty, flushd is woken to write them
+    // back, so that bget keeps finding clean ones to recycle.
+    fn set_dirty(&mut self, i: usize, dirty: bool) {
+        if self.bufs[i].dirty == dirty {
+            return;
+        }
+        self.bufs[i].dirty = dirty;
+        if dirty {
+            self.ndirty += 1;
+            if self.ndirty * DIRTY_SHARE > self.bufs.n {
+                wake_flushd();
+            }
+        } else {
+            self.ndirty -= 1;
+        }
+    }
 
-    // Create linked list of buffers
-    // Head -> buf[0] -> buf[1] ... -> Head
-    // For simplicity, let's just use indices.
-    // prev/next are indices in bufs array.
-    // 0 is a dummy head? Or just circular list.
-    // Let's use 0 as LRU head (dummy).
+    // Link buffer i in at the back, as the least recently used.
+    fn push_back(&mut self, i: usize) {
+        if self.bufs.n == 1 {
+            self.bufs[i].prev = i;
+            self.bufs[i].next = i;
+            self.head = i;
+            return;
+        }
+        let head = self.head;
+        let tail = self.bufs[head].prev;
+        self.bufs[i].prev = tail;
+        self.bufs[i].next = head;
+        self.bufs[tail].next = i;
+        self.bufs[head].prev = i;
+    }
 
-    // Initialize list to all free
-    // head.next = &bufs[0]
-    // bufs[0].next = &bufs[1] ...
+    // Move buffer i to the front, as the most recently used.
+    fn touch(&mut self, i: usize) {
+        if self.head == i {
+            return;
+        }
+        let (prev, next) = (self.bufs[i].prev, self.bufs[i].next);
+        self.bufs[prev].next = next;
+        self.bufs[next].prev = prev;
+        self.push_back(i);
+        self.head = i;
+    }
 
-    let n = NBUF;
-    for i in 0..n {
-        bcache.bufs[i].next = (i + 1) % n;
-        bcache.bufs[i].prev = (i + n - 1) % n;
+    // Take in a finished read-ahead or write-back of buffer i, dropping its
+    // reference. A read is into an invalid buffer, a write from a valid one;
+    // one that failed leaves the buffer invalid, or dirty to be tried again.
+    fn settle(&mut self, i: usize) {
+        let ok = match self.bufs[i].io.load(Ordering::Acquire) {
+            virtio::IO_OK => true,
+            virtio::IO_FAILED => false,
+            _ => return,
+        };
+        self.bufs[i].io.store(0, Ordering::Relaxed);
+        if !self.bufs[i].valid {
+            self.bufs[i].valid = ok;
+        } else if !ok {
+            self.set_dirty(i, true);
+        }
+        self.bufs[i].refcnt -= 1;
+    }
+
+    // The buffer cached block blockno of dev is in, if any.
+    fn lookup(&self, dev: u32, blockno: u32) -> Option<usize> {
+        let mut i = self.head;
+        for _ in 0..self.bufs.n {
+            if self.bufs[i].dev == dev && self.bufs[i].blockno == blockno {
+                return Some(i);
+            }
+            i = self.bufs[i].next;
+        }
+        None
+    }
+
+    // A buffer to put a block in: the least recently used clean one no one
+    // holds, or a fresh one if that would drop a cached block and the cache
+    // may still grow. Dirty buffers wait for flush.
+    fn victim(&mut self) -> Option<usize> {
+        let mut victim = None;
+        let mut i = self.bufs[self.head].prev;
+        for _ in 0..self.bufs.n {
+            self.settle(i);
+            if self.bufs[i].refcnt == 0 && !self.bufs[i].dirty {
+                victim = Some(i);
+                break;
+            }
+            i = self.bufs[i].prev;
+        }
+        if victim.is_none_or(|i| self.bufs[i].dev != 0) && self.grow() {
+            victim = Some(self.bufs[self.head].prev);
+        }
+        victim
+    }
+
+    // Add a page of buffers at the back. False if the cache is as big as
+    // it may get, or there is no memory.
+    fn grow(&mut self) -> bool {
+        let page = self.bufs.n / PER_PAGE;
+        if self.bufs.n >= self.max || page == MAXPAGES {
+            return false;
+        }
+        let p = crate::allocator::ALLOCATOR.lock().kalloc() as *mut Buf;
+        if p.is_null() {
+            return false;
+        }
+        // All zeros: empty buffers no one holds
+        unsafe { crate::util::stosq(p as *mut u64, 0, PG_SIZE / 8) };
+        self.bufs.pages[page] = p;
+        for k in 0..PER_PAGE {
+            self.bufs.n += 1;
+            self.push_back(page * PER_PAGE + k);
+        }
+        true
     }
-    bcache.head = 0;
 }
 
-// Read a block into buffer
-pub fn bread(dev: u32, blockno: u32) -> usize {
+pub fn binit() {
+    let mut bcache = BCACHE.lock();
+    bcache.max = match crate::cmdline::get("nbuf").map(str::parse::<usize>) {
+        Some(Ok(n)) => n.clamp(NBUF_MIN, NBUF_MAX),
+        Some(Err(_)) => {
+            crate::warn!("bio: bad nbuf=, using {}", NBUF_DEFAULT);
+            NBUF_DEFAULT
+        }
+        None => NBUF_DEFAULT,
+    };
+    while bcache.bufs.n < NBUF_MIN {
+        if !bcache.grow() {
+            panic!("binit: out of memory");
+        }
+    }
+    crate::info!("bio: {} buffers, up to {}", bcache.bufs.n, bcache.max);
+}
+
+// Read a block into buffer.
+// Returns Err(-ENXIO) if the device is missing or Err(-EIO) if the read fails,
+// with the buffer released, so callers never see a buffer filled with garbage.
+pub fn bread(dev: u32, blockno: u32) -> Result<usize, isize> {
     // crate::uart_println!("DEBUG: bread dev={} blockno={}", dev, blockno);
+    BREADS.fetch_add(1, Ordering::Relaxed);
     let b = bget(dev, blockno);
     let mut do_read = false;
     {
@@ -79,60 +350,299 @@ pub fn bread(dev: u32, blockno: u32) -> usize {
         }
     }
 
-    if do_read {
+    if !do_read {
+        HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        BLOCKS_READ.fetch_add(1, Ordering::Relaxed);
         let mut buf_data = [0u8; BSIZE];
-        // virtio block driver uses 512 byte sectors, but we use 1024 byte blocks, so
-        // we need to specify `blockno * 2` as sector number. Note that the buffer
-        // size can be larger than 512 bytes.
-        virtio::read_block(blockno as u64 * 2, &mut buf_data);
+        if let Err(e) = read_block(dev, blockno, &mut buf_data) {
+            crate::error!("bread: failed to read dev={} blockno={}", dev, blockno);
+            brelse(b);
+            return Err(e);
+        }
 
         let mut cache = BCACHE.lock();
         cache.bufs[b].data.copy_from_slice(&buf_data);
         cache.bufs[b].valid = true;
     }
 
-    b
+    Ok(b)
 }
 
-pub fn bwrite(b: usize) {
-    let mut cache = BCACHE.lock();
+pub fn bwrite(b: usize) -> Result<(), isize> {
+    let cache = BCACHE.lock();
+    let dev = cache.bufs[b].dev;
     let blockno = cache.bufs[b].blockno;
     let data = cache.bufs[b].data;
     drop(cache);
 
-    virtio::write_block(blockno as u64 * 2, &data);
+    BLOCKS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = write_block(dev, blockno, &data) {
+        crate::error!("bwrite: failed to write dev={} blockno={}", dev, blockno);
+        return Err(e);
+    }
 
     let mut cache = BCACHE.lock();
     cache.bufs[b].valid = true; // Up to date
+    Ok(())
 }
 
 pub fn brelse(b: usize) {
     let mut cache = BCACHE.lock();
     cache.bufs[b].refcnt -= 1;
+    if cache.bufs[b].refcnt == 0 {
+        cache.touch(b);
+        // Let bget callers waiting for a free buffer retry.
+        crate::proc::wakeup(core::ptr::addr_of!(BCACHE) as usize);
+    }
+}
+
+// Keep buffer b cached, holding an extra reference, until bunpin. Used by the
+// journal for modified blocks that are not yet written to disk.
+pub fn bpin(b: usize) {
+    BCACHE.lock().bufs[b].refcnt += 1;
+}
+
+pub fn bunpin(b: usize) {
+    brelse(b);
 }
 
 pub fn bget(dev: u32, blockno: u32) -> usize {
     // crate::uart_println!("DEBUG: bget enter dev={} blockno={}", dev, blockno);
     let mut cache = BCACHE.lock();
 
-    // 1. Look for block
-    for i in 0..NBUF {
-        if cache.bufs[i].dev == dev && cache.bufs[i].blockno == blockno {
+    loop {
+        // 1. Look for block, most recently used first. If a read-ahead
+        // into it is under way, wait for it and look again.
+        if let Some(i) = cache.lookup(dev, blockno) {
+            if cache.bufs[i].io.load(Ordering::Acquire) == virtio::IO_PENDING {
+                crate::proc::sleep(core::ptr::addr_of!(BCACHE) as usize, Some(cache));
+                cache = BCACHE.lock();
+                continue;
+            }
+            cache.settle(i);
             cache.bufs[i].refcnt += 1;
             return i;
         }
-    }
 
-    // 2. Alloc new
-    for i in 0..NBUF {
-        if cache.bufs[i].refcnt == 0 {
+        // 2. Alloc new
+        if let Some(i) = cache.victim() {
             cache.bufs[i].dev = dev;
             cache.bufs[i].blockno = blockno;
             cache.bufs[i].valid = false;
             cache.bufs[i].refcnt = 1;
             return i;
         }
+
+        // 3. All buffers are in use or dirty. flushd writes the dirty ones
+        // back, and the others are only held for the duration of a single
+        // operation, so wait for flush or brelse instead of failing. Only
+        // with no flushd to wait for are the dirty ones written back here.
+        let dirty = (0..cache.bufs.n).any(|i| cache.bufs[i].dirty && cache.bufs[i].refcnt == 0);
+        if dirty && !FLUSHD_RUNNING.load(Ordering::Relaxed) {
+            drop(cache);
+            let flushed = flush(None);
+            cache = BCACHE.lock();
+            if flushed.is_ok() {
+                continue;
+            }
+        } else if dirty {
+            DIRTY_WAITS.fetch_add(1, Ordering::Relaxed);
+            wake_flushd();
+        }
+        if crate::proc::myproc().is_none() {
+            panic!("bget: no buffers");
+        }
+        crate::proc::sleep(core::ptr::addr_of!(BCACHE) as usize, Some(cache));
+        cache = BCACHE.lock();
     }
+}
+
+// Start reading or writing buffer buf in the background, if its device is
+// a virtio disk with room in its queue and there is a process to wait for
+// it. Its io is IO_PENDING until it is done.
+unsafe fn start_async(buf: *mut Buf, write: bool) -> Result<(), ()> {
+    let (dev, blockno) = ((*buf).dev, (*buf).blockno);
+    let (whole, sector) = part::map(dev, blockno as u64 * 2, BSIZE as u64 / 512)?;
+    let disk = virtio_disk(whole).ok_or(())?;
+    crate::proc::myproc().ok_or(())?;
+    let data = &mut *core::ptr::addr_of_mut!((*buf).data);
+    let chan = core::ptr::addr_of!(BCACHE) as usize;
+    virtio::block_io_async(disk, sector, data, write, &(*buf).io, chan)
+}
 
-    panic!("bget: no buffers");
+// Start reading block blockno of dev into the cache, if it is not there,
+// and return without waiting for it; bget waits if the block is wanted
+// before it is in. Only virtio disks read in the background, and only
+// while their queue has room: otherwise this does nothing.
+pub fn readahead(dev: u32, blockno: u32) {
+    let whole = part::whole(dev);
+    if crate::proc::myproc().is_none() || virtio_disk(whole).is_none() {
+        return;
+    }
+    let mut cache = BCACHE.lock();
+    if cache.lookup(dev, blockno).is_some() {
+        return;
+    }
+    let Some(i) = cache.victim() else {
+        return;
+    };
+    // The read holds a reference until it is settled.
+    cache.bufs[i].dev = dev;
+    cache.bufs[i].blockno = blockno;
+    cache.bufs[i].valid = false;
+    cache.bufs[i].refcnt = 1;
+    cache.bufs[i]
+        .io
+        .store(virtio::IO_PENDING, Ordering::Relaxed);
+    cache.touch(i);
+    // Buffers are never freed or moved, so data and io stay put.
+    let buf = &mut cache.bufs[i] as *mut Buf;
+    drop(cache);
+
+    if unsafe { start_async(buf, false) }.is_ok() {
+        READAHEADS.fetch_add(1, Ordering::Relaxed);
+        BLOCKS_READ.fetch_add(1, Ordering::Relaxed);
+    } else {
+        let mut cache = BCACHE.lock();
+        cache.bufs[i].io.store(0, Ordering::Relaxed);
+        cache.bufs[i].dev = 0;
+        cache.bufs[i].refcnt = 0;
+        crate::proc::wakeup(core::ptr::addr_of!(BCACHE) as usize);
+    }
+}
+
+// Mark buffer b changed in the cache, for flush to write back later in
+// place of a bwrite now. The caller still brelse()s it as usual.
+pub fn bdirty(b: usize) {
+    BCACHE.lock().set_dirty(b, true);
+}
+
+// Dirty buffers flush has in flight at once
+const FLUSH_BATCH: usize = 32;
+
+// Write the dirty buffers of dev, or of every device, back to disk and wait
+// for them: on a virtio disk up to FLUSH_BATCH at once, on others one by
+// one. Err if any write failed; the buffer stays dirty, to be tried again.
+// A buffer may be written while its holder changes it: the holder marks it
+// dirty again after, so the change is written by the next flush.
+pub fn flush(dev: Option<u32>) -> Result<(), isize> {
+    let chan = core::ptr::addr_of!(BCACHE) as usize;
+    let mut result = Ok(());
+    let mut next = 0;
+    loop {
+        // Each buffer in the batch gets a reference and IO_PENDING, so bget
+        // holds off handing it out until it is settled.
+        let mut batch = [0u16; FLUSH_BATCH];
+        let mut bufs = [core::ptr::null_mut::<Buf>(); FLUSH_BATCH];
+        let mut n = 0;
+        let mut cache = BCACHE.lock();
+        while next < cache.bufs.n && n < FLUSH_BATCH {
+            let buf = &cache.bufs[next];
+            if buf.dirty && dev.is_none_or(|d| buf.dev == d) && buf.io.load(Ordering::Relaxed) == 0
+            {
+                cache.set_dirty(next, false);
+                let buf = &mut cache.bufs[next];
+                buf.refcnt += 1;
+                buf.io.store(virtio::IO_PENDING, Ordering::Relaxed);
+                batch[n] = next as u16;
+                bufs[n] = buf as *mut Buf;
+                n += 1;
+            }
+            next += 1;
+        }
+        drop(cache);
+        if n == 0 {
+            break;
+        }
+
+        BLOCKS_WRITTEN.fetch_add(n as u64, Ordering::Relaxed);
+        for &buf in &bufs[..n] {
+            if unsafe { start_async(buf, true) }.is_ok() {
+                continue;
+            }
+            let (dev, blockno) = unsafe { ((*buf).dev, (*buf).blockno) };
+            let ok = write_block(dev, blockno, unsafe { &(*buf).data }).is_ok();
+            if !ok {
+                crate::error!("flush: failed to write dev={} blockno={}", dev, blockno);
+            }
+            let done = if ok { virtio::IO_OK } else { virtio::IO_FAILED };
+            unsafe { (*buf).io.store(done, Ordering::Release) };
+        }
+
+        let mut cache = BCACHE.lock();
+        let pending = |cache: &Bcache| {
+            batch[..n]
+                .iter()
+                .any(|&i| cache.bufs[i as usize].io.load(Ordering::Acquire) == virtio::IO_PENDING)
+        };
+        while pending(&cache) {
+            crate::proc::sleep(chan, Some(cache));
+            cache = BCACHE.lock();
+        }
+        for &i in &batch[..n] {
+            if cache.bufs[i as usize].io.load(Ordering::Relaxed) == virtio::IO_FAILED {
+                result = Err(-EIO);
+            }
+            cache.settle(i as usize);
+        }
+        crate::proc::wakeup(chan);
+    }
+
+    // Writes started by another flush, flushd's say, must be done too.
+    let mut cache = BCACHE.lock();
+    let busy = |cache: &Bcache| {
+        (0..cache.bufs.n).any(|i| {
+            let buf = &cache.bufs[i];
+            dev.is_none_or(|d| buf.dev == d) && buf.io.load(Ordering::Acquire) == virtio::IO_PENDING
+        })
+    };
+    while busy(&cache) {
+        crate::proc::sleep(chan, Some(cache));
+        cache = BCACHE.lock();
+    }
+    result
+}
+
+// Timer ticks between write-backs
+const FLUSH_TICKS: u64 = 500;
+// flushd is woken early once more than 1/DIRTY_SHARE of the buffers are dirty
+const DIRTY_SHARE: usize = 4;
+
+// Set while flushd runs, so bget can wait for it; and when it is wanted
+// before its next FLUSH_TICKS are up.
+static FLUSHD_RUNNING: AtomicBool = AtomicBool::new(false);
+static FLUSH_WANTED: AtomicBool = AtomicBool::new(false);
+
+// Have flushd write the dirty buffers back now. It sleeps on the timer, so
+// at worst this waits for the next tick.
+fn wake_flushd() {
+    FLUSH_WANTED.store(true, Ordering::Relaxed);
+    crate::proc::wakeup(core::ptr::addr_of!(crate::trap::TICKS) as usize);
+}
+
+// The write-back daemon, a kernel thread. Every FLUSH_TICKS, or sooner when
+// too many buffers are dirty or bget is waiting for a clean one, it writes
+// the dirty buffers back and what the journal has committed to its home
+// locations (see journal::checkpoint). The flush comes first: checkpoint
+// waits for the operations in progress, and one may be waiting in bget.
+pub extern "C" fn flushd() -> ! {
+    FLUSHD_RUNNING.store(true, Ordering::Relaxed);
+    loop {
+        {
+            let mut ticks = crate::trap::TICKS.lock();
+            let start = *ticks;
+            while *ticks - start < FLUSH_TICKS && !FLUSH_WANTED.load(Ordering::Relaxed) {
+                crate::proc::sleep(
+                    core::ptr::addr_of!(crate::trap::TICKS) as usize,
+                    Some(ticks),
+                );
+                ticks = crate::trap::TICKS.lock();
+            }
+        }
+        FLUSH_WANTED.store(false, Ordering::Relaxed);
+        let _ = flush(None);
+        crate::journal::checkpoint();
+    }
 }
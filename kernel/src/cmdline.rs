@@ -0,0 +1,78 @@
+// Kernel command line, e.g. "init=/sh loglevel=debug root=virtio0".
+// Passed by the boot loader (QEMU: -append) through the multiboot info.
+
+const MULTIBOOT_BOOTLOADER_MAGIC: u64 = 0x2BADB002;
+const MULTIBOOT_INFO_CMDLINE: u32 = 1 << 2;
+pub const MULTIBOOT_INFO_MODS: u32 = 1 << 3;
+const CMDLINE_MAX: usize = 256;
+
+// Start of the multiboot information structure.
+#[repr(C)]
+pub struct MultibootInfo {
+    pub flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,        // Physical address of a NUL-terminated string
+    pub mods_count: u32, // Boot modules (QEMU: -initrd)
+    pub mods_addr: u32,  // Physical address of [MultibootModule; mods_count]
+}
+
+#[repr(C)]
+pub struct MultibootModule {
+    pub mod_start: u32, // Physical range [mod_start, mod_end)
+    pub mod_end: u32,
+    cmdline: u32,
+    pad: u32,
+}
+
+// The multiboot info, if the kernel was loaded by a multiboot loader.
+pub fn multiboot_info(mb_magic: u64, mb_info: u64) -> Option<&'static MultibootInfo> {
+    if mb_magic != MULTIBOOT_BOOTLOADER_MAGIC || mb_info == 0 {
+        return None;
+    }
+    Some(unsafe { &*(crate::util::p2v(mb_info as usize) as *const MultibootInfo) })
+}
+
+static mut CMDLINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static mut CMDLINE_LEN: usize = 0;
+
+// Copy the command line out of the multiboot info. Must run before the
+// allocator hands out the memory it lives in.
+pub fn init(mb_magic: u64, mb_info: u64) {
+    let Some(info) = multiboot_info(mb_magic, mb_info) else {
+        return;
+    };
+    if info.flags & MULTIBOOT_INFO_CMDLINE == 0 || info.cmdline == 0 {
+        return;
+    }
+    let src = crate::util::p2v(info.cmdline as usize) as *const u8;
+    unsafe {
+        let mut n = 0;
+        while n < CMDLINE_MAX && *src.add(n) != 0 {
+            CMDLINE[n] = *src.add(n);
+            n += 1;
+        }
+        // Keep only valid UTF-8, so as_str never fails.
+        let bytes = core::slice::from_raw_parts(&raw const CMDLINE as *const u8, n);
+        CMDLINE_LEN = match core::str::from_utf8(bytes) {
+            Ok(_) => n,
+            Err(e) => e.valid_up_to(),
+        };
+    }
+}
+
+pub fn as_str() -> &'static str {
+    unsafe {
+        let bytes = core::slice::from_raw_parts(&raw const CMDLINE as *const u8, CMDLINE_LEN);
+        core::str::from_utf8_unchecked(bytes)
+    }
+}
+
+// Value of "key=value". A bare "key" has the empty value.
+pub fn get(key: &str) -> Option<&'static str> {
+    as_str().split_ascii_whitespace().find_map(|opt| {
+        let (k, v) = opt.split_once('=').unwrap_or((opt, ""));
+        (k == key).then_some(v)
+    })
+}
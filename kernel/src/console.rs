@@ -1,37 +1,131 @@
 #![allow(static_mut_refs)]
+// Virtual consoles: NTTY of them share the console line, the serial port
+// or hvc0 (see virtio_console), each with its own input buffer, terminal
+// settings and foreground process group. Input goes to the active one,
+// picked with Alt-N (ESC N), and so does its output; the others keep what
+// is written to them in a scrollback buffer, which is played back when
+// switched to.
+
 use crate::spinlock::Spinlock;
 use crate::uart::uart_putc;
+use abi::tty::{Termios, ECHO, ICANON, ISIG};
+
+pub use abi::tty::NTTY;
 
 pub const INPUT_BUF_SIZE: usize = 128;
+pub const SCROLLBACK: usize = 2048;
 
 pub struct Console {
     pub buf: [u8; INPUT_BUF_SIZE],
-    pub r: usize, // Read index
-    pub w: usize, // Write index
-    pub e: usize, // Edit index
+    pub r: usize,          // Read index
+    pub w: usize,          // Write index
+    pub e: usize,          // Edit index
+    pub fg: usize,         // Foreground process group, sent SIGINT by Ctrl-C; 0 if none
+    pub lflag: u32,        // abi::tty local modes: ECHO, ICANON, ISIG
+    out: [u8; SCROLLBACK], // The last bytes written or echoed
+    out_w: usize,          // Bytes written or echoed in all
+}
+
+pub struct Consoles {
+    pub tty: [Console; NTTY],
+    pub active: usize, // The tty on the console line
 }
 
-pub static CONSOLE: Spinlock<Console> = Spinlock::new(
-    Console {
-        buf: [0; INPUT_BUF_SIZE],
-        r: 0,
-        w: 0,
-        e: 0,
+pub static CONSOLE: Spinlock<Consoles> = Spinlock::ranked(
+    Consoles {
+        tty: [const { Console::new() }; NTTY],
+        active: 0,
     },
     "CONSOLE",
+    crate::lockorder::RANK_CONSOLE,
 );
 
-// Write to console (wraps uart_putc)
-pub fn consolewrite(src: u64, n: usize) -> usize {
-    let buf = unsafe { core::slice::from_raw_parts(src as *const u8, n) };
-    for &b in buf {
-        uart_putc(b);
+impl Console {
+    const fn new() -> Self {
+        Self {
+            buf: [0; INPUT_BUF_SIZE],
+            r: 0,
+            w: 0,
+            e: 0,
+            fg: 0,
+            lflag: ECHO | ICANON | ISIG,
+            out: [0; SCROLLBACK],
+            out_w: 0,
+        }
+    }
+
+    fn record(&mut self, c: u8) {
+        self.out[self.out_w % SCROLLBACK] = c;
+        self.out_w = self.out_w.wrapping_add(1);
+    }
+
+    // Echo c on the active console
+    fn echo(&mut self, c: u8) {
+        self.record(c);
+        put(&[c]);
+    }
+
+    fn backspace(&mut self) {
+        self.echo(ASCII_BS);
+        self.echo(b' ');
+        self.echo(ASCII_BS);
+    }
+}
+
+// Send bytes on the console line: hvc0 if it is the console, else the
+// serial port.
+pub fn put(bytes: &[u8]) {
+    if crate::virtio_console::write(bytes).is_err() {
+        for &b in bytes {
+            uart_putc(b);
+        }
+    }
+}
+
+// Put tty n on the console line, with what it showed last.
+fn switch(cons: &mut Consoles, n: usize) {
+    if cons.active == n {
+        return;
+    }
+    cons.active = n;
+    put(b"\n[tty");
+    put(&[b'0' + n as u8, b']', b'\n']);
+    // The scrollback ring, oldest first
+    let con = &cons.tty[n];
+    let start = con.out_w.saturating_sub(SCROLLBACK) % SCROLLBACK;
+    let end = con.out_w % SCROLLBACK;
+    if con.out_w >= SCROLLBACK {
+        put(&con.out[start..]);
+    }
+    put(&con.out[..end]);
+}
+
+// Write to tty minor: to the console line if it is the active one.
+pub fn consolewrite(minor: usize, src: u64, n: usize) -> usize {
+    // Copied out first, so that no fault is taken holding the lock
+    let mut chunk = [0u8; 256];
+    let mut done = 0;
+    while done < n {
+        let m = (n - done).min(chunk.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping((src as *const u8).add(done), chunk.as_mut_ptr(), m)
+        };
+        let mut guard = CONSOLE.lock();
+        let shown = guard.active == minor;
+        let con = &mut guard.tty[minor];
+        for &b in &chunk[..m] {
+            con.record(b);
+        }
+        if shown {
+            put(&chunk[..m]);
+        }
+        done += m;
     }
     n
 }
 
-// Read from console
-pub fn consoleread(dst: u64, n: usize) -> usize {
+// Read from tty minor
+pub fn consoleread(minor: usize, dst: u64, n: usize) -> usize {
     let mut guard = CONSOLE.lock();
     let mut target = dst as *mut u8;
     let mut c: u8;
@@ -39,26 +133,28 @@ pub fn consoleread(dst: u64, n: usize) -> usize {
 
     while count < n {
         // Wait for input
-        while guard.r == guard.w {
-            if unsafe { crate::proc::killed(&*crate::proc::mycpu().process.unwrap()) } {
+        while guard.tty[minor].r == guard.tty[minor].w {
+            if unsafe { crate::proc::killed(&*crate::proc::myproc().unwrap()) } {
                 return 0; // -1?
             }
             crate::proc::sleep(
-                unsafe { core::ptr::addr_of!(guard.r) as usize },
+                core::ptr::addr_of!(guard.tty[minor].r) as usize,
                 Some(guard),
             );
             guard = CONSOLE.lock();
         }
 
-        c = guard.buf[guard.r % INPUT_BUF_SIZE];
-        guard.r = guard.r.wrapping_add(1);
+        let con = &mut guard.tty[minor];
+        c = con.buf[con.r % INPUT_BUF_SIZE];
+        con.r = con.r.wrapping_add(1);
+        let canon = con.lflag & ICANON != 0;
 
-        if c == 4 {
+        if canon && c == 4 {
             // Ctrl-D (EOF)
             if count > 0 {
                 // Save it for next time? typical Unix: return what we have.
                 // But here we consumed it.
-                guard.r -= 1; // Put back? No.
+                con.r -= 1; // Put back? No.
             }
             // EOF
             return count;
@@ -70,64 +166,183 @@ pub fn consoleread(dst: u64, n: usize) -> usize {
         }
         count += 1;
 
-        if c == b'\n' {
+        // A line at a time, or in raw mode whatever has been typed
+        if (canon && c == b'\n') || (!canon && con.r == con.w) {
             break;
         }
     }
     count
 }
 
-// Called by UART trap handler on character input
-pub fn consoleintr(c: fn() -> Option<u8>) {
+// Called on character input, by the UART trap handler and by
+// virtio_console::poll
+pub fn consoleintr(getc: fn() -> Option<u8>) {
     let mut guard = CONSOLE.lock();
-    loop {
-        let c_in = c();
-        if let Some(cc) = c_in {
-            // crate::debug!("consoleintr: got {}", cc);
-        }
-        if c_in.is_none() {
-            break;
+    let mut dump = false;
+    let mut interrupt = 0;
+    let mut next = None;
+    while let Some(c) = next.take().or_else(getc) {
+        // Alt-N: the terminal sends ESC and the digit together. An ESC
+        // followed by anything else is input like any other byte.
+        if c == ASCII_ESC {
+            match getc() {
+                Some(d) if d >= b'0' && ((d - b'0') as usize) < NTTY => {
+                    switch(&mut guard, (d - b'0') as usize);
+                    continue;
+                }
+                other => next = other,
+            }
         }
-        let c = c_in.unwrap();
+
+        let active = guard.active;
+        let con = &mut guard.tty[active];
+        let canon = con.lflag & ICANON != 0;
+        let echo = con.lflag & ECHO != 0;
 
         match c {
+            // C-P: list processes, once the console is let go. Kept in raw
+            // mode too, to debug a hang.
+            16 => dump = true,
+            // C-C: drop the line being edited and interrupt the foreground
+            3 if con.lflag & ISIG != 0 => {
+                while con.e != con.w && con.buf[con.e.wrapping_sub(1) % INPUT_BUF_SIZE] != b'\n' {
+                    con.e = con.e.wrapping_sub(1);
+                }
+                if echo {
+                    con.echo(b'^');
+                    con.echo(b'C');
+                    con.echo(b'\n');
+                }
+                interrupt = con.fg;
+            }
             // C-U
-            21 => {
-                while guard.e != guard.w
-                    && guard.buf[guard.e.wrapping_sub(1) % INPUT_BUF_SIZE] != b'\n'
-                {
-                    guard.e = guard.e.wrapping_sub(1);
-                    backspace();
+            21 if canon => {
+                while con.e != con.w && con.buf[con.e.wrapping_sub(1) % INPUT_BUF_SIZE] != b'\n' {
+                    con.e = con.e.wrapping_sub(1);
+                    if echo {
+                        con.backspace();
+                    }
                 }
             }
             // C-H or Backspace
-            8 | 127 => {
-                if guard.e != guard.w {
-                    guard.e = guard.e.wrapping_sub(1);
-                    backspace();
+            8 | 127 if canon => {
+                if con.e != con.w {
+                    con.e = con.e.wrapping_sub(1);
+                    if echo {
+                        con.backspace();
+                    }
                 }
             }
             _ => {
-                if c != 0 && (guard.e.wrapping_sub(guard.r) < INPUT_BUF_SIZE) {
-                    let val = if c == b'\r' { b'\n' } else { c };
-                    let idx = guard.e % INPUT_BUF_SIZE;
-                    guard.buf[idx] = val;
-                    guard.e = guard.e.wrapping_add(1);
-                    uart_putc(val);
-                    if val == b'\n' || val == 4 || guard.e == guard.r.wrapping_add(INPUT_BUF_SIZE) {
-                        guard.w = guard.e;
-                        crate::proc::wakeup(unsafe { core::ptr::addr_of!(guard.r) as usize });
+                if c != 0 && (con.e.wrapping_sub(con.r) < INPUT_BUF_SIZE) {
+                    let val = if canon && c == b'\r' { b'\n' } else { c };
+                    let idx = con.e % INPUT_BUF_SIZE;
+                    con.buf[idx] = val;
+                    con.e = con.e.wrapping_add(1);
+                    if echo {
+                        con.echo(val);
+                    }
+                    // In raw mode every byte is ready to read at once
+                    if !canon
+                        || val == b'\n'
+                        || val == 4
+                        || con.e == con.r.wrapping_add(INPUT_BUF_SIZE)
+                    {
+                        con.w = con.e;
+                        crate::proc::wakeup(core::ptr::addr_of!(con.r) as usize);
                     }
                 }
             }
         }
     }
+    drop(guard);
+    if interrupt != 0 {
+        crate::proc::kill_group(interrupt, abi::signal::SIGINT);
+    }
+    if dump {
+        crate::proc::procdump();
+    }
 }
 
-const ASCII_BS: u8 = 8;
-
-fn backspace() {
-    uart_putc(ASCII_BS);
-    uart_putc(b' ');
-    uart_putc(ASCII_BS);
+// ioctl on tty minor: see abi::tty.
+pub fn ioctl(minor: usize, request: usize, arg: u64) -> isize {
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
+    match request {
+        abi::tty::TIOCGPGRP => {
+            let pgid = CONSOLE.lock().tty[minor].fg as i32;
+            let mut allocator = crate::allocator::ALLOCATOR.lock();
+            if !crate::vm::copyout(
+                pgdir,
+                &mut allocator,
+                arg,
+                &pgid as *const i32 as *const u8,
+                4,
+            ) {
+                return -1;
+            }
+            0
+        }
+        abi::tty::TIOCSPGRP => {
+            let mut pgid = 0i32;
+            let mut allocator = crate::allocator::ALLOCATOR.lock();
+            if !crate::vm::copyin(
+                pgdir,
+                &mut allocator,
+                &mut pgid as *mut i32 as *mut u8,
+                arg,
+                4,
+            ) {
+                return -1;
+            }
+            drop(allocator);
+            if pgid < 0 {
+                return -abi::syscall::EINVAL;
+            }
+            CONSOLE.lock().tty[minor].fg = pgid as usize;
+            0
+        }
+        abi::tty::TCGETS => {
+            let t = Termios {
+                lflag: CONSOLE.lock().tty[minor].lflag,
+            };
+            let mut allocator = crate::allocator::ALLOCATOR.lock();
+            if !crate::vm::copyout(
+                pgdir,
+                &mut allocator,
+                arg,
+                &t as *const Termios as *const u8,
+                core::mem::size_of::<Termios>(),
+            ) {
+                return -1;
+            }
+            0
+        }
+        abi::tty::TCSETS => {
+            let mut t = Termios::default();
+            let mut allocator = crate::allocator::ALLOCATOR.lock();
+            if !crate::vm::copyin(
+                pgdir,
+                &mut allocator,
+                &mut t as *mut Termios as *mut u8,
+                arg,
+                core::mem::size_of::<Termios>(),
+            ) {
+                return -1;
+            }
+            drop(allocator);
+            let mut guard = CONSOLE.lock();
+            let con = &mut guard.tty[minor];
+            con.lflag = t.lflag & (ECHO | ICANON | ISIG);
+            // Leaving line mode: what was being edited is ready to read
+            if con.lflag & ICANON == 0 && con.e != con.w {
+                con.w = con.e;
+                crate::proc::wakeup(core::ptr::addr_of!(con.r) as usize);
+            }
+            0
+        }
+        _ => -abi::syscall::ENOTTY,
+    }
 }
+
+const ASCII_BS: u8 = 8;
+const ASCII_ESC: u8 = 0x1b;
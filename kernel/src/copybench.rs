@@ -0,0 +1,127 @@
+// Boot-time benchmark of util::fast_copy against plain byte and qword loops.
+// Run with "copybench" on the kernel command line (make bench-copy).
+
+use crate::util::{fast_copy, fast_zero, has_erms, rdtsc, PG_SIZE};
+
+const NPAGES: usize = 32;
+const ROUNDS: usize = 8;
+
+// Volatile accesses keep the compiler from turning the loops into memcpy.
+unsafe fn byte_copy(dst: *mut u8, src: *const u8, len: usize) {
+    for i in 0..len {
+        unsafe { dst.add(i).write_volatile(src.add(i).read_volatile()) };
+    }
+}
+
+unsafe fn qword_copy(dst: *mut u8, src: *const u8, len: usize) {
+    let (dst, src) = (dst as *mut u64, src as *const u64);
+    for i in 0..len / 8 {
+        unsafe { dst.add(i).write_volatile(src.add(i).read_volatile()) };
+    }
+}
+
+fn pattern(page: usize, i: usize) -> u8 {
+    (page * 31 + i * 7 + (i >> 8)) as u8
+}
+
+// Cycles taken to copy every source page to its destination ROUNDS times.
+fn time(
+    src: &[*mut u8; NPAGES],
+    dst: &[*mut u8; NPAGES],
+    copy: unsafe fn(*mut u8, *const u8, usize),
+) -> u64 {
+    let start = unsafe { rdtsc() };
+    for _ in 0..ROUNDS {
+        for p in 0..NPAGES {
+            unsafe { copy(dst[p], src[p], PG_SIZE) };
+        }
+    }
+    unsafe { rdtsc() - start }
+}
+
+fn check(dst: &[*mut u8; NPAGES], zero: bool) -> bool {
+    (0..NPAGES).all(|p| {
+        let page = unsafe { core::slice::from_raw_parts(dst[p], PG_SIZE) };
+        page.iter()
+            .enumerate()
+            .all(|(i, &b)| b == if zero { 0 } else { pattern(p, i) })
+    })
+}
+
+pub fn run() {
+    let mut src = [core::ptr::null_mut(); NPAGES];
+    let mut dst = [core::ptr::null_mut(); NPAGES];
+    {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        for (s, d) in src.iter_mut().zip(dst.iter_mut()) {
+            *s = allocator.kalloc();
+            *d = allocator.kalloc();
+            assert!(!s.is_null() && !d.is_null(), "copybench: out of memory");
+        }
+    }
+    for (p, &s) in src.iter().enumerate() {
+        for i in 0..PG_SIZE {
+            unsafe { *s.add(i) = pattern(p, i) };
+        }
+    }
+
+    let mut ok = true;
+    let mut cycles = [0u64; 3];
+    let copies: [unsafe fn(*mut u8, *const u8, usize); 3] = [byte_copy, qword_copy, fast_copy];
+    for (c, copy) in copies.iter().enumerate() {
+        for &d in dst.iter() {
+            unsafe { fast_zero(d, PG_SIZE) };
+        }
+        if !check(&dst, true) {
+            crate::error!("copybench: fast_zero left data behind");
+            ok = false;
+        }
+        cycles[c] = time(&src, &dst, *copy).max(1);
+        if !check(&dst, false) {
+            crate::error!("copybench: copy {} produced wrong data", c);
+            ok = false;
+        }
+    }
+    // An unaligned length exercises the byte tail of the qword fallback.
+    unsafe { fast_copy(dst[0].add(1), src[1].add(3), PG_SIZE - 13) };
+    let tail = unsafe { core::slice::from_raw_parts(dst[0].add(1), PG_SIZE - 13) };
+    if !tail
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| b == pattern(1, i + 3))
+    {
+        crate::error!("copybench: unaligned copy produced wrong data");
+        ok = false;
+    }
+
+    let [bytes, qwords, fast] = cycles;
+    crate::info!(
+        "copybench: {} pages x {}: byte {} qword {} fast {} cycles (erms={})",
+        NPAGES,
+        ROUNDS,
+        bytes,
+        qwords,
+        fast,
+        has_erms()
+    );
+    crate::info!(
+        "copybench: fast_copy is {}.{}x the byte loop, {}.{}x the qword loop",
+        bytes / fast,
+        bytes * 10 / fast % 10,
+        qwords / fast,
+        qwords * 10 / fast % 10
+    );
+    if fast >= bytes {
+        crate::error!("copybench: fast_copy is no faster than the byte loop");
+        ok = false;
+    }
+
+    {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        for p in 0..NPAGES {
+            allocator.kfree(src[p] as usize);
+            allocator.kfree(dst[p] as usize);
+        }
+    }
+    crate::info!("copybench: {}", if ok { "ok" } else { "FAILED" });
+}
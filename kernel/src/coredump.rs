@@ -0,0 +1,114 @@
+// Core dumps: a process with `dumpable` set that dies of a fault writes its
+// registers and every mapped user page to /core.<pid>, in the format of
+// abi::coredump, for post-mortem inspection.
+
+use crate::fs::Inode;
+use crate::proc::Process;
+use crate::trap::TrapFrame;
+use crate::util::{p2v, PG_SIZE};
+use abi::coredump::*;
+
+// Write the core file of the current process p, which faulted at addr.
+pub fn dump(p: &Process, tf: &TrapFrame, addr: u64) {
+    let mut buf = [0u8; 32];
+    let path = core_path(&mut buf, p.pid);
+    crate::journal::begin_op();
+    let ip = crate::fs::create(path);
+    crate::journal::end_op();
+    let r = ip.and_then(|ip| {
+        let r = write_core(ip, p, tf, addr);
+        crate::fs::iput(ip);
+        r
+    });
+    match r {
+        Ok(()) => crate::info!("core dumped to {}", path),
+        Err(e) => crate::info!("core dump to {} failed: {}", path, e),
+    }
+}
+
+// "/core.<pid>"
+fn core_path(buf: &mut [u8; 32], pid: usize) -> &str {
+    let prefix = b"/core.";
+    buf[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 20];
+    let mut n = 0;
+    let mut v = pid;
+    loop {
+        digits[n] = b'0' + (v % 10) as u8;
+        n += 1;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    for i in 0..n {
+        buf[prefix.len() + i] = digits[n - 1 - i];
+    }
+    core::str::from_utf8(&buf[..prefix.len() + n]).unwrap()
+}
+
+fn write_core(ip: &Inode, p: &Process, tf: &TrapFrame, addr: u64) -> Result<(), isize> {
+    // A core file left by an earlier boot is overwritten: free its blocks
+    // first, so that none of it shows through where this one is shorter.
+    crate::journal::begin_op();
+    let r = crate::fs::itrunc(ip, 0);
+    crate::journal::end_op();
+    r?;
+
+    let mut header = CoreHeader {
+        magic: CORE_MAGIC,
+        pid: p.pid as u32,
+        trap_num: tf.trap_num as u32,
+        nregions: 0,
+        fault_addr: addr,
+        regs: crate::ptrace::regs(tf),
+    };
+    let mut off = core::mem::size_of::<CoreHeader>() as u32;
+    // The region being written, and the offset of its CoreRegion.
+    let mut region: Option<(CoreRegion, u32)> = None;
+    let mut ok = true;
+    crate::vm::for_each_user_page(p.pgdir, &mut |va, pte| {
+        match region {
+            Some((ref mut r, _)) if r.start + r.len == va => r.len += PG_SIZE as u64,
+            _ => {
+                if let Some((r, at)) = region {
+                    ok &= put(ip, at, &r).is_ok();
+                }
+                header.nregions += 1;
+                region = Some((
+                    CoreRegion {
+                        start: va,
+                        len: PG_SIZE as u64,
+                    },
+                    off,
+                ));
+                off += core::mem::size_of::<CoreRegion>() as u32;
+            }
+        }
+        let page = p2v(pte.addr() as usize) as *const u8;
+        ok &= write_all(ip, page, off, PG_SIZE as u32).is_ok();
+        off += PG_SIZE as u32;
+        ok
+    });
+    if let Some((r, at)) = region {
+        ok &= put(ip, at, &r).is_ok();
+    }
+    if !ok {
+        return Err(-1);
+    }
+    put(ip, 0, &header)
+}
+
+fn put<T>(ip: &Inode, off: u32, val: &T) -> Result<(), isize> {
+    let n = core::mem::size_of::<T>() as u32;
+    write_all(ip, val as *const T as *const u8, off, n)
+}
+
+fn write_all(ip: &Inode, src: *const u8, off: u32, n: u32) -> Result<(), isize> {
+    let src = unsafe { core::slice::from_raw_parts(src, n as usize) };
+    if crate::fs::writei_logged(ip, src, off)? == n {
+        Ok(())
+    } else {
+        Err(-1)
+    }
+}
@@ -0,0 +1,335 @@
+// Intel 8254x (e1000) and 82574 (e1000e) network interface, QEMU's default
+// NICs, as an alternative to virtio-net under net/. Its registers are in
+// memory BAR 0; frames go through rings of legacy descriptors, NRX for
+// receiving and NTX for sending, with a page of buffer each.
+//
+// Received frames are taken by net::poll on every timer tick like
+// virtio-net's, and also as soon as they come: the device interrupts on
+// its INTx line, and intr() passes them up the stack. Sending does not
+// wait: a descriptor is only checked to be done with when it comes round
+// again.
+
+use crate::net::eth::FRAME_MAX;
+use crate::pci::PciDevice;
+use crate::spinlock::Spinlock;
+use crate::util::v2p;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+pub const DEVICE_IDS: [u16; 2] = [E1000_82540EM, E1000E_82574L];
+const E1000_82540EM: u16 = 0x100E;
+const E1000E_82574L: u16 = 0x10D3;
+
+// Registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014; // EEPROM read
+const ICR: usize = 0x00C0; // Interrupt cause, cleared by reading it
+const IMS: usize = 0x00D0; // Interrupt mask set
+const IMC: usize = 0x00D8; // Interrupt mask clear
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const MTA: usize = 0x5200; // Multicast table, 128 words
+const RAL: usize = 0x5400; // Receive address 0
+const RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5; // Auto-speed detection
+const CTRL_SLU: u32 = 1 << 6; // Set link up
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1; // Link up
+const RAH_AV: u32 = 1 << 31; // Address valid
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15; // Accept broadcast
+const RCTL_SECRC: u32 = 1 << 26; // Strip the CRC; buffer size 0 is 2048 bytes
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3; // Pad short frames
+const TCTL_CT: u32 = 0x0F << 4; // Collision threshold
+const TCTL_COLD: u32 = 0x40 << 12; // Collision distance, full duplex
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+// Interrupt causes
+const ICR_LSC: u32 = 1 << 2; // Link status change
+const ICR_RXDMT0: u32 = 1 << 4; // Receive ring running low
+const ICR_RXO: u32 = 1 << 6; // Receive overrun
+const ICR_RXT0: u32 = 1 << 7; // Frame received
+
+// Descriptor status and command bits
+const DESC_DD: u8 = 1 << 0; // Done
+const DESC_EOP: u8 = 1 << 1; // Status: the end of a frame
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1; // Insert the CRC
+const CMD_RS: u8 = 1 << 3; // Report status (DD)
+
+// Ring lengths must be multiples of 8: 128 bytes of descriptors.
+const NRX: usize = 16;
+const NTX: usize = 8;
+const RX_BUF: usize = 2048;
+
+#[repr(C)]
+struct RxDesc {
+    addr: u64,
+    len: u16,
+    csum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+struct TxDesc {
+    addr: u64,
+    len: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+struct Rx {
+    regs: usize,
+    ring: *mut RxDesc,
+    bufs: [usize; NRX],
+    next: usize, // The next descriptor the device fills
+}
+
+struct Tx {
+    regs: usize,
+    ring: *mut TxDesc,
+    bufs: [usize; NTX],
+    tail: usize, // The next descriptor to fill
+}
+
+static RX: Spinlock<Option<Rx>> = Spinlock::ranked(None, "NIC_RX", crate::lockorder::RANK_NIC_RX);
+static TX: Spinlock<Option<Tx>> = Spinlock::ranked(None, "NIC_TX", crate::lockorder::RANK_NIC_TX);
+
+// The IRQ of the device, or NO_IRQ before init or if it has none.
+const NO_IRQ: u32 = u32::MAX;
+static IRQ: AtomicU32 = AtomicU32::new(NO_IRQ);
+
+fn read(regs: usize, reg: usize) -> u32 {
+    unsafe { read_volatile((regs + reg) as *const u32) }
+}
+
+fn write(regs: usize, reg: usize, v: u32) {
+    unsafe { write_volatile((regs + reg) as *mut u32, v) }
+}
+
+// Word n of the EEPROM, or None if the read does not finish. The 82574
+// puts the address and done bit elsewhere in EERD.
+fn eeprom(regs: usize, device_id: u16, n: u16) -> Option<u16> {
+    let (shift, done) = match device_id {
+        E1000E_82574L => (2, 1 << 1),
+        _ => (8, 1 << 4),
+    };
+    write(regs, EERD, (n as u32) << shift | 1);
+    for _ in 0..100000 {
+        let v = read(regs, EERD);
+        if v & done != 0 {
+            return Some((v >> 16) as u16);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+// The MAC address in the first three words of the EEPROM, or else what the
+// device loaded from it into receive address 0.
+fn mac(regs: usize, device_id: u16) -> [u8; 6] {
+    let words = [0, 1, 2].map(|n| eeprom(regs, device_id, n));
+    if let [Some(a), Some(b), Some(c)] = words {
+        let [a, b, c] = [a, b, c].map(u16::to_le_bytes);
+        return [a[0], a[1], b[0], b[1], c[0], c[1]];
+    }
+    let (lo, hi) = (read(regs, RAL), read(regs, RAH));
+    let [a, b, c, d] = lo.to_le_bytes();
+    let [e, f, _, _] = hi.to_le_bytes();
+    [a, b, c, d, e, f]
+}
+
+// Set up the device and its rings. Returns its MAC address.
+pub fn init(dev: &PciDevice) -> Option<[u8; 6]> {
+    let regs = dev.map_bar(0, 0)?;
+
+    write(regs, IMC, u32::MAX);
+    write(regs, CTRL, read(regs, CTRL) | CTRL_RST);
+    while read(regs, CTRL) & CTRL_RST != 0 {
+        core::hint::spin_loop();
+    }
+    write(regs, IMC, u32::MAX);
+    read(regs, ICR);
+    write(regs, CTRL, read(regs, CTRL) | CTRL_SLU | CTRL_ASDE);
+
+    let mac = mac(regs, dev.device_id);
+    let [a, b, c, d, e, f] = mac;
+    write(regs, RAL, u32::from_le_bytes([a, b, c, d]));
+    write(regs, RAH, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+    for i in 0..128 {
+        write(regs, MTA + 4 * i, 0);
+    }
+
+    // A page for each ring, and one for each buffer
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    let rings = [allocator.kalloc(), allocator.kalloc()];
+    let rx_bufs: [*mut u8; NRX] = core::array::from_fn(|_| allocator.kalloc());
+    let tx_bufs: [*mut u8; NTX] = core::array::from_fn(|_| allocator.kalloc());
+    let pages = rings.iter().chain(&rx_bufs).chain(&tx_bufs);
+    if pages.clone().any(|p| p.is_null()) {
+        for &p in pages.filter(|p| !p.is_null()) {
+            allocator.kfree(p as usize);
+        }
+        crate::error!("e1000: out of memory");
+        return None;
+    }
+    drop(allocator);
+
+    let rx_ring = rings[0] as *mut RxDesc;
+    for (i, &buf) in rx_bufs.iter().enumerate() {
+        unsafe {
+            rx_ring.add(i).write(RxDesc {
+                addr: v2p(buf as usize) as u64,
+                len: 0,
+                csum: 0,
+                status: 0,
+                errors: 0,
+                special: 0,
+            })
+        };
+    }
+    let tx_ring = rings[1] as *mut TxDesc;
+    for (i, &buf) in tx_bufs.iter().enumerate() {
+        unsafe {
+            tx_ring.add(i).write(TxDesc {
+                addr: v2p(buf as usize) as u64,
+                len: 0,
+                cso: 0,
+                cmd: 0,
+                status: DESC_DD, // Free
+                css: 0,
+                special: 0,
+            })
+        };
+    }
+
+    let rx_pa = v2p(rx_ring as usize) as u64;
+    write(regs, RDBAL, rx_pa as u32);
+    write(regs, RDBAH, (rx_pa >> 32) as u32);
+    write(regs, RDLEN, (NRX * size_of::<RxDesc>()) as u32);
+    write(regs, RDH, 0);
+    // The device fills up to the tail, so one descriptor is always spare.
+    write(regs, RDT, NRX as u32 - 1);
+    write(regs, RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+    let tx_pa = v2p(tx_ring as usize) as u64;
+    write(regs, TDBAL, tx_pa as u32);
+    write(regs, TDBAH, (tx_pa >> 32) as u32);
+    write(regs, TDLEN, (NTX * size_of::<TxDesc>()) as u32);
+    write(regs, TDH, 0);
+    write(regs, TDT, 0);
+    write(regs, TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    write(regs, TIPG, TIPG_DEFAULT);
+
+    *RX.lock() = Some(Rx {
+        regs,
+        ring: rx_ring,
+        bufs: rx_bufs.map(|p| p as usize),
+        next: 0,
+    });
+    *TX.lock() = Some(Tx {
+        regs,
+        ring: tx_ring,
+        bufs: tx_bufs.map(|p| p as usize),
+        tail: 0,
+    });
+
+    // 0 and 0xFF: the firmware gave it no line.
+    if !matches!(dev.irq_line, 0 | 0xFF) {
+        IRQ.store(dev.irq_line as u32, Ordering::Relaxed);
+        unsafe { crate::ioapic::enable_level(dev.irq_line as u32, 0) };
+        write(regs, IMS, ICR_RXT0 | ICR_RXO | ICR_RXDMT0 | ICR_LSC);
+    }
+    Some(mac)
+}
+
+pub fn irq() -> Option<u32> {
+    Some(IRQ.load(Ordering::Relaxed)).filter(|&irq| irq != NO_IRQ)
+}
+
+// The device's interrupt: take in what it has received.
+pub fn intr() {
+    let Some(regs) = RX.lock().as_ref().map(|rx| rx.regs) else {
+        return;
+    };
+    let cause = read(regs, ICR);
+    if cause & ICR_LSC != 0 {
+        let up = read(regs, STATUS) & STATUS_LU != 0;
+        crate::info!("e1000: link {}", if up { "up" } else { "down" });
+    }
+    if cause & (ICR_RXT0 | ICR_RXO | ICR_RXDMT0) != 0 {
+        crate::net::input();
+    }
+}
+
+// Send a frame built by fill, which gets room for FRAME_MAX bytes and
+// returns the length of the frame.
+pub fn transmit(fill: impl FnOnce(&mut [u8]) -> usize) -> Result<(), ()> {
+    let mut guard = TX.lock();
+    let tx = guard.as_mut().ok_or(())?;
+    let i = tx.tail;
+    let desc = unsafe { &mut *tx.ring.add(i) };
+    // Still being sent from the last time round the ring
+    while unsafe { read_volatile(&desc.status) } & DESC_DD == 0 {
+        core::hint::spin_loop();
+    }
+    fence(Ordering::SeqCst);
+    let buf = unsafe { core::slice::from_raw_parts_mut(tx.bufs[i] as *mut u8, FRAME_MAX) };
+    let len = fill(buf);
+    desc.len = len as u16;
+    desc.cmd = CMD_EOP | CMD_IFCS | CMD_RS;
+    unsafe { write_volatile(&mut desc.status, 0) };
+    tx.tail = (i + 1) % NTX;
+    fence(Ordering::SeqCst);
+    write(tx.regs, TDT, tx.tail as u32);
+    Ok(())
+}
+
+// Pass each frame received since the last call to f, and give its buffer
+// back to the device.
+pub fn receive(mut f: impl FnMut(&[u8])) {
+    let mut guard = RX.lock();
+    let Some(rx) = guard.as_mut() else {
+        return;
+    };
+    loop {
+        let i = rx.next;
+        let desc = unsafe { &mut *rx.ring.add(i) };
+        let status = unsafe { read_volatile(&desc.status) };
+        if status & DESC_DD == 0 {
+            break;
+        }
+        fence(Ordering::SeqCst);
+        // Frames that span buffers (they can't, below RX_BUF) or have
+        // errors are dropped.
+        let len = (desc.len as usize).min(RX_BUF);
+        if status & DESC_EOP != 0 && desc.errors == 0 {
+            f(unsafe { core::slice::from_raw_parts(rx.bufs[i] as *const u8, len) });
+        }
+        unsafe { write_volatile(&mut desc.status, 0) };
+        rx.next = (i + 1) % NRX;
+        fence(Ordering::SeqCst);
+        write(rx.regs, RDT, i as u32);
+    }
+}
@@ -11,6 +11,7 @@ pub const EM_X86_64: u16 = 62; // AMD x86-64
 
 // Program Header Type
 pub const PT_LOAD: u32 = 1;
+pub const PT_GNU_STACK: u32 = 0x6474e551; // memsz: requested stack size (ld -z stack-size)
 
 // Program Header Flags
 pub const PF_X: u32 = 1; // Executable
@@ -49,3 +50,35 @@ pub struct ProgramHeader {
     pub memsz: u64,
     pub align: u64,
 }
+
+// Section Header Type
+pub const SHT_SYMTAB: u32 = 2;
+
+// Symbol Type (low 4 bits of Symbol::info)
+pub const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SectionHeader {
+    pub name: u32,
+    pub type_: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32, // SHT_SYMTAB: index of the string table section
+    pub info: u32,
+    pub addralign: u64,
+    pub entsize: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Symbol {
+    pub name: u32, // Offset in the string table
+    pub info: u8,
+    pub other: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
@@ -1,11 +1,108 @@
-use crate::elf::{ElfHeader, ProgramHeader, ELF_MAGIC, PT_LOAD};
+use crate::elf::{ElfHeader, ProgramHeader, ELF_MAGIC, PT_GNU_STACK, PT_LOAD};
 use crate::fs::{self};
 use crate::trap::TrapFrame;
 
 use crate::util::{p2v, PG_SIZE};
 use crate::vm::{self, PageTableEntry};
 
-pub fn exec(path: &str, argv: &[&str]) -> isize {
+// Total size of the argument strings (including NULs) passed to exec.
+pub const ARG_MAX: usize = PG_SIZE;
+
+// Initial user stack size, unless the ELF asks for another one with a
+// PT_GNU_STACK segment (linker flag -z stack-size=N), up to USTACK_MAX.
+pub const USTACK_SIZE: usize = 8 * PG_SIZE;
+pub const USTACK_MAX: usize = 256 * PG_SIZE;
+
+// The user stack ends here, away from the heap growing up from the loaded
+// segments. Mm.ustack records where it starts.
+pub const USTACK_TOP: u64 = 0x8000_0000;
+
+// Argument strings copied out of the caller's address space into a kernel page.
+// They must not be read from user memory once exec starts building the new
+// address space, so sys_exec stages them here first.
+pub struct ExecArgs {
+    buf: *mut u8, // NUL-terminated strings, packed back to back
+    len: usize,
+    argc: usize,
+}
+
+impl ExecArgs {
+    pub fn new() -> Option<Self> {
+        let buf = crate::allocator::ALLOCATOR.lock().kalloc();
+        if buf.is_null() {
+            return None;
+        }
+        Some(Self {
+            buf,
+            len: 0,
+            argc: 0,
+        })
+    }
+
+    // Append a copy of the NUL-terminated string at va in pgdir.
+    // Fails if va is not mapped or the strings no longer fit in ARG_MAX.
+    pub fn push_user(&mut self, pgdir: *mut PageTable, va: u64) -> Result<(), ()> {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        let mut n = 0;
+        loop {
+            if self.len + n >= ARG_MAX {
+                return Err(());
+            }
+            let dst = unsafe { self.buf.add(self.len + n) };
+            if !vm::copyin(pgdir, &mut allocator, dst, va + n as u64, 1) {
+                return Err(());
+            }
+            n += 1;
+            if unsafe { *dst } == 0 {
+                break;
+            }
+        }
+        self.len += n;
+        self.argc += 1;
+        Ok(())
+    }
+
+    // Append a copy of a kernel string.
+    pub fn push(&mut self, arg: &str) -> Result<(), ()> {
+        let n = arg.len() + 1;
+        if self.len + n > ARG_MAX {
+            return Err(());
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(arg.as_ptr(), self.buf.add(self.len), arg.len());
+            *self.buf.add(self.len + arg.len()) = 0;
+        }
+        self.len += n;
+        self.argc += 1;
+        Ok(())
+    }
+
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    // Iterate over the arguments, each including its NUL terminator.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let mut off = 0;
+        (0..self.argc).map(move |_| {
+            let rest = unsafe { core::slice::from_raw_parts(self.buf.add(off), self.len - off) };
+            let n = rest.iter().position(|&b| b == 0).unwrap() + 1;
+            off += n;
+            &rest[..n]
+        })
+    }
+}
+
+impl Drop for ExecArgs {
+    fn drop(&mut self) {
+        crate::allocator::ALLOCATOR.lock().kfree(self.buf as usize);
+    }
+}
+
+// Replace the current process image with the ELF at path.
+// path and argv must live in kernel memory: the old user address space is
+// replaced at the end of exec, and the new one is built in a separate page table.
+pub fn exec(path: &str, argv: &ExecArgs) -> isize {
     // 1. Open file
     let ip = match fs::namei(path) {
         Some(ip) => {
@@ -17,7 +114,14 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
             return -1;
         }
     };
+    let ret = load(ip, argv);
+    crate::journal::begin_op();
+    fs::iput(ip);
+    crate::journal::end_op();
+    ret
+}
 
+fn load(ip: &fs::Inode, argv: &ExecArgs) -> isize {
     // 2. Read ELF Header
     let mut elf = ElfHeader {
         magic: 0,
@@ -43,7 +147,7 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
         0,
         core::mem::size_of::<ElfHeader>() as u32,
     );
-    if sz != core::mem::size_of::<ElfHeader>() as u32 || elf.magic != ELF_MAGIC {
+    if sz != Ok(core::mem::size_of::<ElfHeader>() as u32) || elf.magic != ELF_MAGIC {
         crate::debug!("exec: bad elf header");
         return -1;
     }
@@ -51,17 +155,19 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
     // 3. Create new page table
     crate::debug!("exec: loaded elf, entry=0x{:x}", elf.entry);
 
-    let pgdir = {
+    let image = {
         let mut allocator = crate::allocator::ALLOCATOR.lock();
-        match vm::uvm_create(&mut allocator) {
-            Some(p) => p,
+        match crate::mm::alloc(&mut allocator) {
+            Some(mm) => NewImage(mm),
             None => return -1,
         }
     };
+    let pgdir = image.0.pgdir;
 
     // 4. Load segments
     let mut off = elf.phoff;
     let mut max_vaddr = 0;
+    let mut stack_size = USTACK_SIZE as u64;
 
     for _ in 0..elf.phnum {
         let mut ph = ProgramHeader {
@@ -79,23 +185,27 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
             &mut ph as *mut ProgramHeader as *mut u8,
             off as u32,
             core::mem::size_of::<ProgramHeader>() as u32,
-        ) != core::mem::size_of::<ProgramHeader>() as u32
+        ) != Ok(core::mem::size_of::<ProgramHeader>() as u32)
         {
-            // TODO: Free pgdir
             return -1;
         }
         off += core::mem::size_of::<ProgramHeader>() as u64;
 
+        if ph.type_ == PT_GNU_STACK && ph.memsz > 0 {
+            if ph.memsz > USTACK_MAX as u64 {
+                crate::debug!("exec: stack size {} too large", ph.memsz);
+                return -1;
+            }
+            stack_size = (ph.memsz + PG_SIZE as u64 - 1) & !(PG_SIZE as u64 - 1);
+        }
         if ph.type_ != PT_LOAD {
             continue;
         }
         if ph.memsz < ph.filesz {
-            // TODO: Free pgdir
             return -1;
         }
         if ph.vaddr + ph.memsz < ph.vaddr {
             // Overflow
-            // TODO: Free pgdir
             return -1;
         }
 
@@ -134,6 +244,7 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
                     PG_SIZE as u64,
                     PageTableEntry::WRITABLE | PageTableEntry::USER,
                 ) {
+                    allocator.kfree(mem as usize);
                     return -1;
                 }
                 a += PG_SIZE as u64;
@@ -164,7 +275,7 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
                 (kva as *mut u8).wrapping_add(page_offset as usize),
                 current_off as u32,
                 n as u32,
-            ) != n as u32
+            ) != Ok(n as u32)
             {
                 return -1;
             }
@@ -179,84 +290,83 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
     }
     crate::debug!("exec: segments loaded");
 
-    // Allocate stack next to loaded segments (+ guard page)
+    // The heap starts after the loaded segments; the stack sits below
+    // USTACK_TOP, with an unmapped guard page under it.
     let sz = (max_vaddr + PG_SIZE as u64 - 1) & !(PG_SIZE as u64 - 1); // Round up
-    let stack_base = sz + PG_SIZE as u64; // Guard page
-    let stack_top = stack_base + 2 * PG_SIZE as u64;
+    let stack_top = USTACK_TOP;
+    let stack_base = stack_top - stack_size;
+    if sz + PG_SIZE as u64 > stack_base {
+        crate::debug!("exec: segments overlap the stack");
+        return -1;
+    }
 
     // Map stack
     {
         let mut allocator = crate::allocator::ALLOCATOR.lock();
-        let mem = allocator.kalloc();
-        if mem.is_null() {
-            return -1;
-        }
-        vm::map_pages(
-            pgdir,
-            &mut allocator,
-            stack_base,
-            crate::util::v2p(mem as usize) as u64,
-            PG_SIZE as u64,
-            PageTableEntry::WRITABLE | PageTableEntry::USER,
-        );
-        let mem2 = allocator.kalloc();
-        if mem2.is_null() {
-            return -1;
+        let mut a = stack_base;
+        while a < stack_top {
+            let mem = allocator.kalloc();
+            if mem.is_null() {
+                return -1;
+            }
+            if !vm::map_pages(
+                pgdir,
+                &mut allocator,
+                a,
+                crate::util::v2p(mem as usize) as u64,
+                PG_SIZE as u64,
+                PageTableEntry::WRITABLE | PageTableEntry::USER,
+            ) {
+                allocator.kfree(mem as usize);
+                return -1;
+            }
+            a += PG_SIZE as u64;
         }
-        vm::map_pages(
-            pgdir,
-            &mut allocator,
-            stack_base + PG_SIZE as u64,
-            crate::util::v2p(mem2 as usize) as u64,
-            PG_SIZE as u64,
-            PageTableEntry::WRITABLE | PageTableEntry::USER,
-        );
     }
     crate::debug!("exec: stack allocated at {:x}-{:x}", stack_base, stack_top);
 
     // 5. Push arguments to stack
+    // Strings go at the top, then the argv pointer array below them.
     let mut sp = stack_top;
-    let mut ustack = [0u64; 16]; // Max 16 args + null
-
-    // Push strings
-    for (i, arg) in argv.iter().enumerate() {
-        sp -= (arg.len() + 1) as u64;
-        sp -= sp % 16;
+    for arg in argv.iter() {
+        sp -= arg.len() as u64;
+        sp &= !15;
 
         let mut allocator = crate::allocator::ALLOCATOR.lock();
         if !copyout(pgdir, &mut allocator, sp, arg.as_ptr(), arg.len()) {
             return -1;
         }
-        // Write null terminator
-        let zero = 0u8;
-        if !copyout(
-            pgdir,
-            &mut allocator,
-            sp + arg.len() as u64,
-            &zero as *const u8,
-            1,
-        ) {
-            return -1;
-        }
-        ustack[i] = sp;
     }
-    ustack[argv.len()] = 0; // Null terminator for argv array
 
-    // Align stack
-    sp = sp & !15;
-
-    // Push argv array
-    sp -= ((argv.len() + 1) * 8) as u64; // argc pointers + null ptr
+    // Push argv array (argc pointers + null ptr)
+    sp -= ((argv.argc() + 1) * 8) as u64;
+    sp &= !15;
     let argv_base = sp;
 
     {
         let mut allocator = crate::allocator::ALLOCATOR.lock();
+        // Walk the strings again to recompute where each one was placed.
+        let mut str_sp = stack_top;
+        for (i, arg) in argv.iter().enumerate() {
+            str_sp -= arg.len() as u64;
+            str_sp &= !15;
+            if !copyout(
+                pgdir,
+                &mut allocator,
+                argv_base + (i * 8) as u64,
+                &str_sp as *const u64 as *const u8,
+                8,
+            ) {
+                return -1;
+            }
+        }
+        let null = 0u64;
         if !copyout(
             pgdir,
             &mut allocator,
-            sp,
-            ustack.as_ptr() as *const u8,
-            (argv.len() + 1) * 8,
+            argv_base + (argv.argc() * 8) as u64,
+            &null as *const u64 as *const u8,
+            8,
         ) {
             return -1;
         }
@@ -265,13 +375,29 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
     // 6. Commit Process Changes
     unsafe {
         #[allow(static_mut_refs)]
-        let p = &mut *crate::proc::mycpu().process.unwrap();
-
-        // Save old pgdir to free later
-        let old_pgdir = p.pgdir;
-
+        let p = &mut *crate::proc::myproc().unwrap();
+
+        // Let go of the old address space once off it. Other threads
+        // sharing it carry on in the old image.
+        let mm: *mut Mm = &mut *image.0;
+        core::mem::forget(image);
+        let old_mm = p.mm.replace(mm);
+        (*mm).sz = sz as usize;
+        (*mm).heap_start = sz as usize;
+        (*mm).ustack = stack_base as usize;
         p.pgdir = pgdir;
-        p.sz = stack_top as usize;
+        p.exe = (ip.dev, ip.inum);
+        // A process that called PTRACE_TRACEME stops at its new entry point.
+        if p.traced {
+            p.stop_pending = true;
+        }
+        // Breakpoints refer to addresses in the old image.
+        p.debugregs = crate::ptrace::DebugRegs::new();
+        crate::ptrace::load_debugregs(p);
+        crate::signal::exec_reset(p);
+        // The new image starts with clean FPU/SSE registers, loaded on first use.
+        p.fpu.reset();
+        crate::fpu::disable();
         p.state = crate::proc::ProcessState::RUNNING; // Redundant but clear
 
         // Update TrapFrame
@@ -281,7 +407,7 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
         tf.rsp = sp; // Stack Pointer at argv array
 
         // System V ABI: rdi=argc, rsi=argv
-        tf.rdi = argv.len() as u64;
+        tf.rdi = argv.argc() as u64;
         tf.rsi = argv_base;
 
         // Fake return address
@@ -292,8 +418,9 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
         // Switch to new page table
         vm::switch(pgdir);
 
-        // TODO: Free old pgdir and memory.
-        // vm::free_vm(old_pgdir);
+        if let Some(old_mm) = old_mm {
+            crate::mm::put(&mut *old_mm);
+        }
     }
     crate::debug!("exec: process committed");
 
@@ -301,8 +428,19 @@ pub fn exec(path: &str, argv: &[&str]) -> isize {
 }
 
 use crate::allocator::Allocator;
+use crate::mm::Mm;
 use crate::vm::PageTable;
 
+// The address space exec is building, freed with everything mapped in it if
+// exec fails before switching the process over.
+struct NewImage(&'static mut Mm);
+
+impl Drop for NewImage {
+    fn drop(&mut self) {
+        crate::mm::put(self.0);
+    }
+}
+
 fn copyout(
     pgdir: *mut PageTable,
     allocator: &mut Allocator,
@@ -329,7 +467,7 @@ fn copyout(
         let n = core::cmp::min(PG_SIZE - (va as usize - va0), len);
         unsafe {
             let dst = dst_ptr.add(va as usize - va0);
-            core::ptr::copy_nonoverlapping(buf, dst, n);
+            crate::util::fast_copy(dst, buf, n);
         }
 
         len -= n;
@@ -1,6 +1,8 @@
 use crate::fs::Inode;
 use crate::pipe::PipeData;
-use crate::spinlock::Spinlock;
+use crate::proc::{Users, NPROC};
+use crate::spinlock::{Spinlock, SpinlockGuard};
+use abi::fs::Stat;
 
 pub const NFILE: usize = 100; // Open files per system
 
@@ -10,6 +12,8 @@ pub enum FileType {
     Pipe,
     Inode,
     Device,
+    Dir,    // Directory opened with O_DIRECTORY: getdents only
+    Socket, // net::udp or net::tcp
 }
 
 #[derive(Clone, Copy)]
@@ -18,10 +22,13 @@ pub struct File {
     pub refcnt: usize,
     pub readable: bool,
     pub writable: bool,
+    pub append: bool, // O_APPEND: each write goes at the end of file
     pub pipe: Option<*mut Spinlock<PipeData>>,
     pub ip: Option<&'static Inode>,
     pub off: u32,
     pub major: u16, // For devices
+    pub minor: u16,
+    pub sock: Option<crate::net::Socket>,
 }
 
 impl File {
@@ -31,10 +38,13 @@ impl File {
             refcnt: 0,
             readable: false,
             writable: false,
+            append: false,
             pipe: None,
             ip: None,
             off: 0,
             major: 0,
+            minor: 0,
+            sock: None,
         }
     }
 }
@@ -43,11 +53,12 @@ pub struct FileTable {
     pub files: [File; NFILE],
 }
 
-pub static FTABLE: Spinlock<FileTable> = Spinlock::new(
+pub static FTABLE: Spinlock<FileTable> = Spinlock::ranked(
     FileTable {
         files: [File::new(); NFILE],
     },
     "FTABLE",
+    crate::lockorder::RANK_FTABLE,
 );
 
 pub fn filealloc() -> Option<&'static mut File> {
@@ -84,26 +95,247 @@ pub fn fileclose(f: &mut File) {
         return;
     }
 
-    if f.f_type == FileType::Inode || f.f_type == FileType::Device {
-        if let Some(ip) = f.ip {
-            crate::fs::iput(ip);
-        }
-    }
-
     if f.f_type == FileType::Pipe {
         if let Some(pi) = f.pipe {
             crate::pipe::pipeclose(pi, f.writable);
         }
     }
 
+    let (f_type, ip, sock) = (f.f_type, f.ip, f.sock.take());
     f.f_type = FileType::None;
     f.ip = None;
     drop(ft);
+
+    // iput may free the inode, which sleeps and writes through the journal,
+    // so it runs in an operation and without the file table lock.
+    if matches!(f_type, FileType::Inode | FileType::Device | FileType::Dir) {
+        if let Some(ip) = ip {
+            crate::journal::begin_op();
+            crate::fs::iput(ip);
+            crate::journal::end_op();
+        }
+    }
+    if let Some(sock) = sock {
+        sock.close();
+    }
+}
+
+// A process's open files, indexed by fd, and its working directory. Threads
+// made by clone share them. The table lock covers the slots, not the files:
+// a thread closing an fd another is reading from leaves the reader with a
+// file it no longer holds a reference to, as with any other use-after-close.
+pub struct Files {
+    pub cwd: (u32, u32), // (dev, inum) of the working directory; inum 0 is the root
+    ofile: Spinlock<[Option<*mut File>; crate::proc::NFILE]>,
+    users: Users,
+}
+
+// Every process has one Files at most, so NPROC of them are enough.
+static mut FILES: [Files; NPROC] = [const { Files::new() }; NPROC];
+
+impl Files {
+    const fn new() -> Self {
+        Self {
+            cwd: (0, 0),
+            ofile: Spinlock::ranked(
+                [None; crate::proc::NFILE],
+                "files",
+                crate::lockorder::RANK_FILES,
+            ),
+            users: Users::new(),
+        }
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<'_, [Option<*mut File>; crate::proc::NFILE]> {
+        self.ofile.lock()
+    }
+
+    // Put f in the lowest free fd, or None if all are in use.
+    pub fn fdalloc(&self, f: *mut File) -> Option<usize> {
+        let mut ofile = self.lock();
+        let fd = ofile.iter().position(|slot| slot.is_none())?;
+        ofile[fd] = Some(f);
+        Some(fd)
+    }
+
+    pub fn get(&self, fd: usize) -> Option<*mut File> {
+        self.lock().get(fd).copied().flatten()
+    }
+
+    // Empty fd, returning what it held for the caller to close.
+    pub fn take(&self, fd: usize) -> Option<*mut File> {
+        self.lock().get_mut(fd)?.take()
+    }
+
+    // How many inode references the open files hold, each file counted once
+    // however many fds it is behind.
+    pub fn inode_refs(&self) -> u32 {
+        let ofile = self.lock();
+        let mut n = 0;
+        for (fd, f) in ofile.iter().enumerate() {
+            let Some(f) = *f else { continue };
+            if !ofile[..fd].contains(&Some(f)) && unsafe { (*f).ip.is_some() } {
+                n += 1;
+            }
+        }
+        n
+    }
+
+    // Another process, made by clone, uses these files.
+    pub fn share(&self) {
+        self.users.get();
+    }
+
+    // Start out as a copy of other, for fork.
+    pub fn copy_from(&mut self, other: &Files) {
+        self.cwd = other.cwd;
+        let open = *other.lock();
+        for f in open.iter().flatten() {
+            filedup(unsafe { &mut **f });
+        }
+        *self.lock() = open;
+    }
+}
+
+// An empty Files, or None if all are in use.
+#[allow(static_mut_refs)]
+pub fn files_alloc() -> Option<&'static mut Files> {
+    unsafe { FILES.iter_mut().find(|f| f.users.claim()) }
 }
 
-pub fn filestat(_f: &File, _addr: u64) -> isize {
-    // TODO: Implement
-    -1
+// Let go of files. The last user closes every open file.
+pub fn files_put(files: &mut Files) {
+    if !files.users.put() {
+        return;
+    }
+    let open = core::mem::replace(&mut *files.lock(), [None; crate::proc::NFILE]);
+    for f in open.into_iter().flatten() {
+        fileclose(unsafe { &mut *f });
+    }
+    files.cwd = (0, 0);
+    files.users.freed();
+}
+
+// Copy the Stat of an inode-backed file to user address addr.
+pub fn filestat(f: &File, addr: u64) -> isize {
+    let ip = match (f.f_type, f.ip) {
+        (FileType::Inode | FileType::Device | FileType::Dir, Some(ip)) => ip,
+        _ => return -1,
+    };
+    inodestat(ip, addr)
+}
+
+// Copy the Stat of inode ip to user address addr.
+pub fn inodestat(ip: &crate::fs::Inode, addr: u64) -> isize {
+    let st = match ip.ilock() {
+        Ok(guard) => crate::fs::stati(ip, &guard),
+        Err(e) => return e,
+    };
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyout(
+        pgdir,
+        &mut allocator,
+        addr,
+        &st as *const Stat as *const u8,
+        core::mem::size_of::<Stat>(),
+    ) {
+        return -1;
+    }
+    0
+}
+
+// Copy the entries of a directory fd to user address addr, resuming at f.off.
+pub fn filegetdents(f: &mut File, addr: u64, n: usize) -> isize {
+    let ip = match (f.f_type, f.ip) {
+        (FileType::Dir, Some(ip)) => ip,
+        _ => return -abi::syscall::ENOTDIR,
+    };
+    match crate::fs::getdents(ip, &mut f.off, addr, n) {
+        Ok(n) => n as isize,
+        Err(e) => e,
+    }
+}
+
+// Set the size of a regular file open for writing.
+pub fn filetruncate(f: &mut File, len: usize) -> isize {
+    let ip = match (f.f_type, f.ip) {
+        (FileType::Inode, Some(ip)) if f.writable => ip,
+        _ => return -1,
+    };
+    if len > u32::MAX as usize {
+        return -1;
+    }
+    crate::journal::begin_op();
+    let r = crate::fs::itrunc(ip, len as u32);
+    crate::journal::end_op();
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// Write the journal's last commit home and the dirty buffers of f's device
+// to disk, for fsync.
+pub fn filesync(f: &File) -> isize {
+    let ip = match (f.f_type, f.ip) {
+        (FileType::Inode, Some(ip)) => ip,
+        _ => return -abi::syscall::EINVAL,
+    };
+    crate::journal::checkpoint();
+    match crate::bio::flush(Some(ip.dev)) {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// Whether there is a device behind node (major, minor).
+pub fn devpresent(major: u16, minor: u16) -> bool {
+    match major {
+        abi::fs::CONSOLE_MAJOR => (minor as usize) < crate::console::NTTY,
+        abi::fs::FB_MAJOR => minor == 0 && crate::virtio_gpu::present(),
+        _ => true,
+    }
+}
+
+// Move the offset of f to off from whence (an abi::fs SEEK_ value) and
+// return it. It may go past the end of file; a write there leaves a hole.
+// Of the devices, only the framebuffer has an offset.
+pub fn filelseek(f: &mut File, off: i64, whence: i32) -> isize {
+    let end = match (f.f_type, f.ip) {
+        (FileType::Inode, Some(ip)) => match ip.ilock() {
+            Ok(di) => di.i_size as i64,
+            Err(e) => return e,
+        },
+        (FileType::Device, _) if f.major == abi::fs::FB_MAJOR => crate::virtio_gpu::FB_SIZE as i64,
+        _ => return -abi::syscall::ESPIPE,
+    };
+    let base = match whence {
+        abi::fs::SEEK_SET => 0,
+        abi::fs::SEEK_CUR => f.off as i64,
+        abi::fs::SEEK_END => end,
+        _ => return -abi::syscall::EINVAL,
+    };
+    match base.checked_add(off) {
+        Some(new) if (0..=u32::MAX as i64).contains(&new) => {
+            f.off = new as u32;
+            new as isize
+        }
+        _ => -abi::syscall::EINVAL,
+    }
+}
+
+// ioctl(request, arg) on f: only the consoles, the framebuffer and sockets
+// take any.
+pub fn fileioctl(f: &File, request: usize, arg: u64) -> isize {
+    match (f.f_type, f.major) {
+        (FileType::Device, abi::fs::CONSOLE_MAJOR) => {
+            crate::console::ioctl(f.minor as usize, request, arg)
+        }
+        (FileType::Device, abi::fs::FB_MAJOR) => crate::virtio_gpu::ioctl(request, arg),
+        (FileType::Socket, _) => crate::net::ioctl(request, arg),
+        _ => -abi::syscall::ENOTTY,
+    }
 }
 
 pub fn fileread(f: &mut File, addr: u64, n: usize) -> isize {
@@ -119,12 +351,23 @@ pub fn fileread(f: &mut File, addr: u64, n: usize) -> isize {
             -1
         }
         FileType::Device => {
-            if f.major == 1 {
-                // Console
-                return crate::console::consoleread(addr, n) as isize;
+            if f.major == abi::fs::CONSOLE_MAJOR {
+                return crate::console::consoleread(f.minor as usize, addr, n) as isize;
+            }
+            if f.major == abi::fs::FB_MAJOR {
+                let r = crate::virtio_gpu::read(f.off, addr, n);
+                if r > 0 {
+                    f.off += r as u32;
+                }
+                return r;
             }
             -1
         }
+        FileType::Socket => match f.sock {
+            Some(crate::net::Socket::Tcp(s)) => crate::net::tcp::recv(s, addr, n),
+            Some(crate::net::Socket::Udp(s)) => crate::net::udp::read(s, addr, n),
+            None => -1,
+        },
         FileType::Inode => {
             if let Some(ip) = f.ip {
                 // We need to implement writei/readi that takes user address?
@@ -137,11 +380,13 @@ pub fn fileread(f: &mut File, addr: u64, n: usize) -> isize {
                 // Wait, user pages are accessible if we are in kernel and they are mapped.
                 // But typically we use `copyout`/`copyin`.
 
-                let res = crate::fs::readi(ip, addr as *mut u8, f.off, n as u32);
-                if res > 0 {
-                    f.off += res;
+                match crate::fs::readi(ip, addr as *mut u8, f.off, n as u32) {
+                    Ok(res) => {
+                        f.off += res;
+                        res as isize
+                    }
+                    Err(e) => e,
                 }
-                res as isize
             } else {
                 -1
             }
@@ -163,20 +408,39 @@ pub fn filewrite(f: &mut File, addr: u64, n: usize) -> isize {
             -1
         }
         FileType::Device => {
-            if f.major == 1 {
-                // Console
-                return crate::console::consolewrite(addr, n) as isize;
+            if f.major == abi::fs::CONSOLE_MAJOR {
+                return crate::console::consolewrite(f.minor as usize, addr, n) as isize;
+            }
+            if f.major == abi::fs::FB_MAJOR {
+                let r = crate::virtio_gpu::write(f.off, addr, n);
+                if r > 0 {
+                    f.off += r as u32;
+                }
+                return r;
             }
             -1
         }
+        FileType::Socket => match f.sock {
+            Some(crate::net::Socket::Tcp(s)) => crate::net::tcp::send(s, addr, n),
+            Some(crate::net::Socket::Udp(s)) => crate::net::udp::write(s, addr, n),
+            None => -1,
+        },
         FileType::Inode => {
             if let Some(ip) = f.ip {
-                // TODO include Transaction?
-                let res = crate::fs::writei(ip, addr as *const u8, f.off, n as u32);
-                if res > 0 {
-                    f.off += res;
+                if f.append {
+                    match ip.ilock() {
+                        Ok(di) => f.off = di.i_size,
+                        Err(e) => return e,
+                    }
+                }
+                let src = unsafe { core::slice::from_raw_parts(addr as *const u8, n) };
+                match crate::fs::writei_logged(ip, src, f.off) {
+                    Ok(res) => {
+                        f.off += res;
+                        res as isize
+                    }
+                    Err(e) => e,
                 }
-                res as isize
             } else {
                 -1
             }
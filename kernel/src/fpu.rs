@@ -0,0 +1,175 @@
+// Per-process x87/SSE/AVX register state.
+//
+// The kernel is built soft-float and never touches these registers, so only
+// user code changes them. Switching is lazy: the scheduler sets CR0.TS before
+// running a process, and its state is only loaded when it first uses the FPU,
+// which traps with #NM. The state is saved when the process gives up the CPU
+// only if it was loaded, so integer-only processes cost nothing. XSAVE is
+// used when the CPU has it (with AVX enabled if present), FXSAVE otherwise.
+
+use crate::trap::TrapFrame;
+use crate::util::{lcr0, lcr4, rcr0, rcr4};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const CR0_MP: u64 = 1 << 1; // Monitor coprocessor: WAIT honours TS
+const CR0_EM: u64 = 1 << 2; // Emulate x87: makes every FPU/SSE instruction #UD
+const CR0_TS: u64 = 1 << 3; // Task switched: the next FPU/SSE instruction raises #NM
+const CR4_OSFXSR: u64 = 1 << 9; // FXSAVE/FXRSTOR and SSE enabled
+const CR4_OSXMMEXCPT: u64 = 1 << 10; // SIMD exceptions raise #XM
+const CR4_OSXSAVE: u64 = 1 << 18; // XSAVE and XCR0 enabled
+
+const CPUID1_XSAVE: u32 = 1 << 26; // ECX
+const CPUID1_AVX: u32 = 1 << 28; // ECX
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const FCW_DEFAULT: u16 = 0x37f; // All x87 exceptions masked
+const MXCSR_DEFAULT: u32 = 0x1f80; // All SSE exceptions masked
+
+// Legacy area (512), XSAVE header (64) and the AVX upper halves (256).
+const FPU_STATE_SIZE: usize = 1024;
+
+// State components saved by XSAVE, or 0 when FXSAVE is used. Same on all CPUs.
+static XFEATURES: AtomicU64 = AtomicU64::new(0);
+
+// Lazy switching statistics, reported by sysinfo.
+pub static FPU_RESTORES: AtomicU64 = AtomicU64::new(0); // #NM traps
+pub static FPU_SAVES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+    // Reset state with all exceptions masked. An all-zero XSAVE header
+    // makes XRSTOR load the init state, taking only MXCSR from here.
+    pub const fn new() -> Self {
+        let mut b = [0u8; FPU_STATE_SIZE];
+        let fcw = FCW_DEFAULT.to_le_bytes();
+        let mxcsr = MXCSR_DEFAULT.to_le_bytes();
+        b[0] = fcw[0];
+        b[1] = fcw[1];
+        b[24] = mxcsr[0];
+        b[25] = mxcsr[1];
+        b[26] = mxcsr[2];
+        b[27] = mxcsr[3];
+        Self(b)
+    }
+
+    // Same as new(), in place: the state is too big for a 4K kernel stack.
+    pub fn reset(&mut self) {
+        self.0.fill(0);
+        self.0[0..2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        self.0[24..28].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+    }
+
+    // Make state that user code may have written (by sigreturn) safe to
+    // restore: reserved MXCSR bits and a bad XSAVE header would make the
+    // restore fault in the kernel.
+    pub fn sanitize(&mut self) {
+        let mxcsr = u32::from_le_bytes(self.0[24..28].try_into().unwrap()) & 0xffff;
+        self.0[24..28].copy_from_slice(&mxcsr.to_le_bytes());
+        let x = XFEATURES.load(Ordering::Relaxed);
+        let bv = u64::from_le_bytes(self.0[512..520].try_into().unwrap()) & x;
+        self.0[512..520].copy_from_slice(&bv.to_le_bytes());
+        self.0[520..576].fill(0); // XCOMP_BV (standard format) and reserved
+    }
+}
+
+// Components to enable in XCR0, or 0 if the CPU lacks XSAVE.
+fn xfeatures() -> u64 {
+    let ecx = __cpuid(1).ecx;
+    if ecx & CPUID1_XSAVE == 0 {
+        return 0;
+    }
+    let mut want = XCR0_X87 | XCR0_SSE;
+    if ecx & CPUID1_AVX != 0 {
+        want |= XCR0_AVX;
+    }
+    want & __cpuid_count(0xd, 0).eax as u64
+}
+
+// Enable SSE (and AVX) on this CPU. Called by every CPU at boot.
+pub fn init() {
+    let x = xfeatures();
+    unsafe {
+        lcr0((rcr0() & !CR0_EM) | CR0_MP);
+        let mut cr4 = rcr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if x != 0 {
+            cr4 |= CR4_OSXSAVE;
+        }
+        lcr4(cr4);
+        if x != 0 {
+            core::arch::asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") x as u32,
+                in("edx") (x >> 32) as u32,
+            );
+        }
+        core::arch::asm!("fninit");
+    }
+    XFEATURES.store(x, Ordering::Relaxed);
+}
+
+// Make the next FPU instruction on this CPU trap, so that the running
+// process's state is loaded on demand.
+pub fn disable() {
+    unsafe { lcr0(rcr0() | CR0_TS) };
+}
+
+// If the FPU registers were loaded since the last disable(), they belong to
+// the current process: save them into its st.
+pub fn save_if_used(st: &mut FpuState) {
+    if unsafe { rcr0() } & CR0_TS == 0 {
+        save(st);
+        FPU_SAVES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// #NM: the current process used the FPU for the first time since it was
+// switched in. Load its state and let it continue.
+pub fn nm_trap(tf: &TrapFrame) {
+    let p = match crate::proc::myproc() {
+        Some(p) if tf.cs & 3 == 3 => unsafe { &*p },
+        _ => panic!("FPU used in the kernel (rip={:x})", tf.rip),
+    };
+    unsafe { core::arch::asm!("clts") };
+    restore(&p.fpu);
+    FPU_RESTORES.fetch_add(1, Ordering::Relaxed);
+}
+
+fn save(st: &mut FpuState) {
+    let x = XFEATURES.load(Ordering::Relaxed);
+    unsafe {
+        if x != 0 {
+            core::arch::asm!(
+                "xsave64 [{}]",
+                in(reg) st.0.as_mut_ptr(),
+                in("eax") x as u32,
+                in("edx") (x >> 32) as u32,
+            );
+        } else {
+            core::arch::asm!("fxsave64 [{}]", in(reg) st.0.as_mut_ptr());
+        }
+    }
+}
+
+fn restore(st: &FpuState) {
+    let x = XFEATURES.load(Ordering::Relaxed);
+    unsafe {
+        if x != 0 {
+            core::arch::asm!(
+                "xrstor64 [{}]",
+                in(reg) st.0.as_ptr(),
+                in("eax") x as u32,
+                in("edx") (x >> 32) as u32,
+            );
+        } else {
+            core::arch::asm!("fxrstor64 [{}]", in(reg) st.0.as_ptr());
+        }
+    }
+}
@@ -1,7 +1,12 @@
 // Ext2 Filesystem Implementation
 
+use crate::journal::LOGSIZE;
+use crate::lockorder::{RANK_DCACHE, RANK_DIRLOCK, RANK_GDT, RANK_ICACHE, RANK_INODE, RANK_SB};
 use crate::sleeplock::{SleepLockGuard, SleepLockSafe};
 use crate::spinlock::Spinlock;
+use abi::fs::{Stat, StatFs, NAME_MAX, T_DEV, T_DIR, T_FILE};
+use abi::syscall::{EINTR, ENFILE, EPERM};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 // Constants
 pub const BSIZE: usize = 1024;
@@ -12,6 +17,9 @@ pub const EXT2_IND_BLOCK: usize = 12;
 pub const EXT2_DIND_BLOCK: usize = 13;
 pub const EXT2_TIND_BLOCK: usize = 14;
 pub const EXT2_N_BLOCKS: usize = 15;
+pub const NINDIRECT: usize = BSIZE / 4; // Block numbers in an indirect block
+pub const MAXFILE: usize = EXT2_NDIR_BLOCKS + NINDIRECT + NINDIRECT * NINDIRECT; // Blocks bmap can address
+pub const MAXPATH: usize = 128; // Maximum path length (including NUL)
 
 // Superblock
 #[repr(C)]
@@ -87,7 +95,12 @@ pub struct Inode {
     pub dev: u32,
     pub inum: u32,
     pub refcnt: u32,
+    pub valid: AtomicBool, // Has the disk inode been read into `lock`?
     pub lock: SleepLockSafe<DiskInode>,
+    // Read-ahead (see readi): the block a sequential read would start in,
+    // and the block read-ahead has been started up to.
+    ra_next: AtomicU32,
+    ra_end: AtomicU32,
 }
 
 impl Inode {
@@ -96,22 +109,18 @@ impl Inode {
             dev: 0,
             inum: 0,
             refcnt: 0,
-            lock: SleepLockSafe::new(unsafe { core::mem::zeroed() }),
+            valid: AtomicBool::new(false),
+            lock: SleepLockSafe::ranked(unsafe { core::mem::zeroed() }, "INODE", RANK_INODE),
+            ra_next: AtomicU32::new(0),
+            ra_end: AtomicU32::new(0),
         }
     }
 }
 
-// Directory Entry
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct DirEntry {
-    pub inode: u32,
-    pub rec_len: u16,
-    pub name_len: u8,
-    pub file_type: u8,
-}
+pub use abi::fs::DirEntry;
 
-static SB: Spinlock<SuperBlock> = Spinlock::new(
+// Lock order (see lockorder.rs): inode sleep-lock < SB < GDT < BCACHE < ICACHE.
+static SB: Spinlock<SuperBlock> = Spinlock::ranked(
     SuperBlock {
         s_inodes_count: 0,
         s_blocks_count: 0,
@@ -140,9 +149,10 @@ static SB: Spinlock<SuperBlock> = Spinlock::new(
         s_def_resgid: 0,
     },
     "SB",
+    RANK_SB,
 );
 
-static GDT: Spinlock<[GroupDesc; 32]> = Spinlock::new(
+static GDT: Spinlock<[GroupDesc; 32]> = Spinlock::ranked(
     [GroupDesc {
         bg_block_bitmap: 0,
         bg_inode_bitmap: 0,
@@ -154,10 +164,22 @@ static GDT: Spinlock<[GroupDesc; 32]> = Spinlock::new(
         bg_reserved: [0; 3],
     }; 32],
     "GDT",
+    RANK_GDT,
 );
 
-pub fn fsinit(dev: u32) {
-    let b = crate::bio::bread(dev, 1);
+// Device holding the root filesystem, set by fsinit; 0 until one is mounted.
+static ROOTDEV: AtomicU32 = AtomicU32::new(0);
+
+pub fn rootdev() -> u32 {
+    ROOTDEV.load(Ordering::Relaxed)
+}
+
+// Read and check the superblock and group descriptors of dev.
+fn read_super(dev: u32) -> Result<(SuperBlock, [GroupDesc; 32]), isize> {
+    let name = crate::bio::dev_name(dev);
+    let b = crate::bio::bread(dev, 1).inspect_err(|_| {
+        crate::error!("fsinit: {}: cannot read the superblock", name);
+    })?;
     let sb: SuperBlock;
     {
         let cache = crate::bio::BCACHE.lock();
@@ -168,111 +190,490 @@ pub fn fsinit(dev: u32) {
     crate::bio::brelse(b);
 
     if sb.s_magic != EXT2_MAGIC {
-        unsafe {
-            let ptr = &sb as *const SuperBlock as *const u8;
-            crate::uart_println!(
-                "DEBUG: SB bytes: {:x} {:x} {:x} {:x}",
-                *ptr,
-                *ptr.add(1),
-                *ptr.add(2),
-                *ptr.add(3)
-            );
-        }
-        panic!("invalid ext2 filesystem magic: {:x}", sb.s_magic);
+        crate::error!(
+            "fsinit: {}: no ext2 filesystem (magic {:#x}, want {:#x})",
+            name,
+            sb.s_magic,
+            EXT2_MAGIC
+        );
+        return Err(-1);
     }
-
-    *SB.lock() = sb;
-
-    if sb.s_first_data_block != 1 && sb.s_log_block_size == 0 {
-        panic!("unexpected first data block for 1k blocks");
+    if sb.s_log_block_size != 0
+        || sb.s_first_data_block != 1
+        || sb.s_blocks_per_group == 0
+        || sb.s_inodes_per_group == 0
+    {
+        crate::error!(
+            "fsinit: {}: unsupported ext2 layout (block size {}, first data block {})",
+            name,
+            1024u64 << sb.s_log_block_size.min(16),
+            sb.s_first_data_block
+        );
+        return Err(-1);
     }
 
     let gdt_block = sb.s_first_data_block + 1;
-    let b_gdt = crate::bio::bread(dev, gdt_block);
+    let b_gdt = crate::bio::bread(dev, gdt_block).inspect_err(|_| {
+        crate::error!("fsinit: {}: cannot read the group descriptors", name);
+    })?;
+    let mut gdt = [GroupDesc::default(); 32];
     {
         let cache = crate::bio::BCACHE.lock();
         let buf = &cache.bufs[b_gdt];
         let ptr = buf.data.as_ptr() as *const GroupDesc;
-        let mut guard = GDT.lock();
-        for i in 0..32 {
-            guard[i] = unsafe { core::ptr::read_unaligned(ptr.add(i)) };
+        for (i, gd) in gdt.iter_mut().enumerate() {
+            *gd = unsafe { core::ptr::read_unaligned(ptr.add(i)) };
         }
     }
     crate::bio::brelse(b_gdt);
+    Ok((sb, gdt))
+}
+
+// Inode number of /.log on the root device while the journal lives in its
+// blocks, else 0. Freeing or overwriting those blocks would corrupt
+// recovery, so unlink and sys_open refuse the file (see is_log).
+static LOG_INUM: AtomicU32 = AtomicU32::new(0);
+
+// Whether ip is the file holding the journal.
+pub fn is_log(ip: &Inode) -> bool {
+    let inum = LOG_INUM.load(Ordering::Relaxed);
+    inum != 0 && ip.inum == inum && ip.dev == rootdev()
+}
+
+// The inode number of /.log and the blocks of it that hold the journal, if
+// the file exists and has all of them allocated.
+fn log_area(dev: u32) -> Option<(u32, [u32; LOGSIZE + 1])> {
+    let root = iget(dev, ROOT_INO).ok()?;
+    let inum = dirlookup(root, ".log");
+    iput(root);
+    let ip = iget(dev, inum?).ok()?;
+    let area = ip.ilock().ok().and_then(|di| {
+        let mut area = [0; LOGSIZE + 1];
+        for (bn, a) in area.iter_mut().enumerate() {
+            *a = bmap(&di, bn as u32, dev).ok().filter(|&b| b != 0)?;
+        }
+        Some((ip.inum, area))
+    });
+    iput(ip);
+    area
+}
+
+// Mount the filesystem on dev as the root. Fails, saying why, if dev cannot
+// be read or does not hold an ext2 filesystem this kernel can use.
+pub fn fsinit(dev: u32) -> Result<(), isize> {
+    let name = crate::bio::dev_name(dev);
+    let (sb, gdt) = read_super(dev)?;
+    // SB and GDT rank below BCACHE, so copy them in after releasing it.
+    // Reading inodes needs them.
+    *SB.lock() = sb;
+    *GDT.lock() = gdt;
+
+    // Install what a crash left in the log before using anything else, then
+    // read the superblock again, which recovery may have rewritten.
+    let (mut sb, mut gdt) = match log_area(dev) {
+        Some((inum, area)) => {
+            if crate::journal::init(dev, &area).is_err() {
+                crate::error!("fsinit: {}: cannot recover the journal", name);
+                return Err(-1);
+            }
+            LOG_INUM.store(inum, Ordering::Relaxed);
+            read_super(dev)?
+        }
+        None => {
+            crate::warn!("fsinit: {}: no /.log, writes are not crash-safe", name);
+            (sb, gdt)
+        }
+    };
+    let gdt_block = sb.s_first_data_block + 1;
+
+    // The bitmaps are authoritative: recount the free blocks and inodes from
+    // them, in case the counts on disk were left stale.
+    let counted = count_free(dev, Bitmap::Block, &mut sb, &mut gdt).and_then(|blocks_ok| {
+        count_free(dev, Bitmap::Inode, &mut sb, &mut gdt).map(|inodes_ok| blocks_ok && inodes_ok)
+    });
+    let Ok(counts_ok) = counted else {
+        crate::error!("fsinit: {}: cannot read the bitmaps", name);
+        return Err(-1);
+    };
+    *SB.lock() = sb;
+    *GDT.lock() = gdt;
+    if !counts_ok {
+        let groups = (sb.s_blocks_count - sb.s_first_data_block).div_ceil(sb.s_blocks_per_group);
+        crate::journal::begin_op();
+        let r = write_struct(dev, 1, 0, &sb).and_then(|_| {
+            (0..core::cmp::min(groups, 32) as usize).try_for_each(|g| {
+                let off = g * core::mem::size_of::<GroupDesc>();
+                write_struct(dev, gdt_block, off, &gdt[g])
+            })
+        });
+        crate::journal::end_op();
+        if r.is_err() {
+            crate::error!("fsinit: cannot write back the free counts");
+        }
+    }
+    crate::info!(
+        "fsinit: {} of {} blocks free, {} of {} inodes free",
+        sb.s_free_blocks_count,
+        sb.s_blocks_count,
+        sb.s_free_inodes_count,
+        sb.s_inodes_count
+    );
+    ROOTDEV.store(dev, Ordering::Relaxed);
+    Ok(())
+}
+
+// Block and inode allocation. Each group has a bitmap of its blocks and one
+// of its inodes; the free counts are kept in both the group descriptor and
+// the superblock, and written back to disk on every change.
+#[derive(Clone, Copy, PartialEq)]
+enum Bitmap {
+    Block,
+    Inode,
+}
+
+// Per kind, an index below which every bit is known to be set, so that
+// allocation need not rescan the full start of the bitmaps. Set by fsinit,
+// raised by allocation and lowered by bitmap_free.
+static FREE_HINT: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+// Bits per group and bits in all of the bitmaps of `kind`.
+fn bitmap_geometry(sb: &SuperBlock, kind: Bitmap) -> (u32, u32) {
+    match kind {
+        Bitmap::Block => (
+            sb.s_blocks_per_group,
+            sb.s_blocks_count - sb.s_first_data_block,
+        ),
+        Bitmap::Inode => (sb.s_inodes_per_group, sb.s_inodes_count),
+    }
+}
+
+// Count the clear bits in the bitmaps of `kind`, which are what allocation
+// goes by, and store them as the free counts of each group and of the
+// superblock. Sets the free hint to the first clear bit. Returns whether the
+// counts read from disk were already right.
+fn count_free(
+    dev: u32,
+    kind: Bitmap,
+    sb: &mut SuperBlock,
+    gdt: &mut [GroupDesc; 32],
+) -> Result<bool, isize> {
+    let (per_group, total) = bitmap_geometry(sb, kind);
+    let ngroups = core::cmp::min(total.div_ceil(per_group), 32);
+    let mut first_free = total;
+    let mut sum = 0;
+    let mut ok = true;
+    for g in 0..ngroups {
+        let gd = &mut gdt[g as usize];
+        let nbits = core::cmp::min(per_group, total - g * per_group) as usize;
+        let bitmap = match kind {
+            Bitmap::Block => gd.bg_block_bitmap,
+            Bitmap::Inode => gd.bg_inode_bitmap,
+        };
+        let b = crate::bio::bread(dev, bitmap)?;
+        let (free, first) = {
+            let cache = crate::bio::BCACHE.lock();
+            let data = &cache.bufs[b].data;
+            let clear = |i: &usize| data[i / 8] & (1 << (i % 8)) == 0;
+            ((0..nbits).filter(clear).count(), (0..nbits).find(clear))
+        };
+        crate::bio::brelse(b);
+        if let Some(i) = first {
+            first_free = first_free.min(g * per_group + i as u32);
+        }
+        let count = match kind {
+            Bitmap::Block => &mut gd.bg_free_blocks_count,
+            Bitmap::Inode => &mut gd.bg_free_inodes_count,
+        };
+        ok &= *count as usize == free;
+        *count = free as u16;
+        sum += free as u32;
+    }
+    let count = match kind {
+        Bitmap::Block => &mut sb.s_free_blocks_count,
+        Bitmap::Inode => &mut sb.s_free_inodes_count,
+    };
+    if *count != sum {
+        crate::warn!(
+            "fsinit: superblock has {} free {}, bitmaps have {}",
+            *count,
+            if kind == Bitmap::Block {
+                "blocks"
+            } else {
+                "inodes"
+            },
+            sum
+        );
+        ok = false;
+    }
+    *count = sum;
+    FREE_HINT[kind as usize].store(first_free, Ordering::Relaxed);
+    Ok(ok)
+}
+
+// Find a clear bit in the bitmaps of `kind`, set it and return its index
+// over all groups.
+fn bitmap_alloc(dev: u32, kind: Bitmap) -> Result<u32, isize> {
+    let (per_group, total) = bitmap_geometry(&SB.lock(), kind);
+    let ngroups = core::cmp::min(total.div_ceil(per_group), 32);
+    let hint = FREE_HINT[kind as usize].load(Ordering::Relaxed);
+    for g in hint / per_group..ngroups {
+        let bitmap = {
+            let gdt = GDT.lock();
+            let gd = &gdt[g as usize];
+            match kind {
+                Bitmap::Block if gd.bg_free_blocks_count > 0 => gd.bg_block_bitmap,
+                Bitmap::Inode if gd.bg_free_inodes_count > 0 => gd.bg_inode_bitmap,
+                _ => continue,
+            }
+        };
+        // The last group may be short.
+        let nbits = core::cmp::min(per_group, total - g * per_group) as usize;
+        let b = crate::bio::bread(dev, bitmap)?;
+        // Test and set under BCACHE, so two allocators cannot take the same bit.
+        let from = if g == hint / per_group {
+            (hint % per_group) as usize
+        } else {
+            0
+        };
+        let bit = {
+            let mut cache = crate::bio::BCACHE.lock();
+            let data = &mut cache.bufs[b].data;
+            let bit = (from..nbits).find(|&i| data[i / 8] & (1 << (i % 8)) == 0);
+            if let Some(i) = bit {
+                data[i / 8] |= 1 << (i % 8);
+            }
+            bit
+        };
+        let Some(i) = bit else {
+            crate::bio::brelse(b);
+            continue;
+        };
+        let r = crate::journal::log_write(b);
+        crate::bio::brelse(b);
+        r?;
+        update_free(dev, g as usize, kind, -1)?;
+        let i = g * per_group + i as u32;
+        // Unless a free below i (or another allocation) moved it meanwhile.
+        let _ = FREE_HINT[kind as usize].compare_exchange(
+            hint,
+            i + 1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        return Ok(i);
+    }
+    Err(-1)
+}
+
+// Clear bit i (over all groups) of the bitmaps of `kind`.
+fn bitmap_free(dev: u32, kind: Bitmap, i: u32) -> Result<(), isize> {
+    let per_group = {
+        let sb = SB.lock();
+        match kind {
+            Bitmap::Block => sb.s_blocks_per_group,
+            Bitmap::Inode => sb.s_inodes_per_group,
+        }
+    };
+    let (g, bit) = ((i / per_group) as usize, (i % per_group) as usize);
+    if g >= 32 {
+        return Err(-1);
+    }
+    let bitmap = {
+        let gdt = GDT.lock();
+        match kind {
+            Bitmap::Block => gdt[g].bg_block_bitmap,
+            Bitmap::Inode => gdt[g].bg_inode_bitmap,
+        }
+    };
+    let b = crate::bio::bread(dev, bitmap)?;
+    let was_set = {
+        let mut cache = crate::bio::BCACHE.lock();
+        let data = &mut cache.bufs[b].data;
+        let was_set = data[bit / 8] & (1 << (bit % 8)) != 0;
+        data[bit / 8] &= !(1 << (bit % 8));
+        was_set
+    };
+    if !was_set {
+        crate::bio::brelse(b);
+        crate::error!("bitmap_free: bit {} of group {} is already free", bit, g);
+        return Err(-1);
+    }
+    let r = crate::journal::log_write(b);
+    crate::bio::brelse(b);
+    r?;
+    FREE_HINT[kind as usize].fetch_min(i, Ordering::Relaxed);
+    update_free(dev, g, kind, 1)
+}
+
+// Add delta to the free count of `kind` in group g and the superblock, and
+// write both back.
+fn update_free(dev: u32, g: usize, kind: Bitmap, delta: i32) -> Result<(), isize> {
+    let (sb, gd) = {
+        let mut sb = SB.lock();
+        let mut gdt = GDT.lock();
+        let gd = &mut gdt[g];
+        match kind {
+            Bitmap::Block => {
+                sb.s_free_blocks_count = sb.s_free_blocks_count.wrapping_add_signed(delta);
+                gd.bg_free_blocks_count = gd.bg_free_blocks_count.wrapping_add_signed(delta as i16);
+            }
+            Bitmap::Inode => {
+                sb.s_free_inodes_count = sb.s_free_inodes_count.wrapping_add_signed(delta);
+                gd.bg_free_inodes_count = gd.bg_free_inodes_count.wrapping_add_signed(delta as i16);
+            }
+        }
+        (*sb, *gd)
+    };
+    // With 1K blocks the superblock is all of block 1.
+    write_struct(dev, 1, 0, &sb)?;
+    let gdt_block = sb.s_first_data_block + 1;
+    write_struct(dev, gdt_block, g * core::mem::size_of::<GroupDesc>(), &gd)
+}
+
+// Write val at byte offset off of a block.
+fn write_struct<T: Copy>(dev: u32, block: u32, off: usize, val: &T) -> Result<(), isize> {
+    let b = crate::bio::bread(dev, block)?;
+    {
+        let mut cache = crate::bio::BCACHE.lock();
+        let ptr = unsafe { cache.bufs[b].data.as_mut_ptr().add(off) } as *mut T;
+        unsafe { core::ptr::write_unaligned(ptr, *val) };
+    }
+    let r = crate::journal::log_write(b);
+    crate::bio::brelse(b);
+    r
+}
+
+// Allocate a zeroed data block.
+fn balloc(dev: u32) -> Result<u32, isize> {
+    let first = SB.lock().s_first_data_block;
+    let blockno = first + bitmap_alloc(dev, Bitmap::Block)?;
+    let b = crate::bio::bget(dev, blockno);
+    {
+        let mut cache = crate::bio::BCACHE.lock();
+        cache.bufs[b].data.fill(0);
+        cache.bufs[b].valid = true;
+    }
+    let r = crate::journal::log_write(b);
+    crate::bio::brelse(b);
+    r.map(|_| blockno)
+}
+
+// Free a data block.
+fn bfree(dev: u32, blockno: u32) -> Result<(), isize> {
+    let first = SB.lock().s_first_data_block;
+    if blockno < first {
+        return Err(-1);
+    }
+    bitmap_free(dev, Bitmap::Block, blockno - first)
+}
+
+// Allocate an inode with the given mode and one link, and write it out.
+// Returns it referenced but unlocked.
+fn ialloc(dev: u32, mode: u16) -> Result<&'static Inode, isize> {
+    let inum = bitmap_alloc(dev, Bitmap::Inode)? + 1;
+    let ip = match iget(dev, inum) {
+        Ok(ip) => ip,
+        Err(e) => {
+            bitmap_free(dev, Bitmap::Inode, inum - 1)?;
+            return Err(e);
+        }
+    };
+    let r = ip.ilock().and_then(|mut di| {
+        *di = unsafe { core::mem::zeroed() };
+        di.i_mode = mode;
+        di.i_links_count = 1;
+        iupdate(ip, &di)
+    });
+    if let Err(e) = r {
+        iput(ip);
+        return Err(e);
+    }
+    Ok(ip)
 }
 
-const NINODE: usize = 10;
+// Every open file holds its inode, the device files of the consoles' shells
+// included, so there are enough slots for those and then some.
+const NINODE: usize = 50;
 struct ICache {
     inodes: [Inode; NINODE],
 }
 
-static ICACHE: Spinlock<ICache> = Spinlock::new(
+static ICACHE: Spinlock<ICache> = Spinlock::ranked(
     ICache {
-        inodes: [
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-            Inode::new(),
-        ],
+        inodes: [const { Inode::new() }; NINODE],
     },
     "ICACHE",
+    RANK_ICACHE,
 );
 
-pub fn iget(dev: u32, inum: u32) -> &'static Inode {
+// How many in-memory inode slots nothing references, for sysinfo.
+pub fn inodes_free() -> usize {
+    let cache = ICACHE.lock();
+    cache.inodes.iter().filter(|ip| ip.refcnt == 0).count()
+}
+
+// Get a reference to the in-memory inode, without locking or reading it.
+// If every slot is referenced, wait for an iput: inodes are only held by open
+// files and in-progress lookups, so the shortage is temporary. Err(-EINTR)
+// if the caller is killed meanwhile, or Err(-ENFILE) if its own open files
+// hold every reference, since then no iput would come.
+pub fn iget(dev: u32, inum: u32) -> Result<&'static Inode, isize> {
     let mut guard = ICACHE.lock();
-    let cache = &mut *guard;
 
-    let mut empty: Option<usize> = None;
-    for (i, ip) in cache.inodes.iter_mut().enumerate() {
-        if ip.refcnt > 0 && ip.dev == dev && ip.inum == inum {
-            ip.refcnt += 1;
-            return unsafe { &*(ip as *const Inode) };
+    loop {
+        let cache = &mut *guard;
+        let mut empty: Option<usize> = None;
+        for (i, ip) in cache.inodes.iter_mut().enumerate() {
+            if ip.refcnt > 0 && ip.dev == dev && ip.inum == inum {
+                ip.refcnt += 1;
+                return Ok(unsafe { &*(ip as *const Inode) });
+            }
+            if empty.is_none() && ip.refcnt == 0 {
+                empty = Some(i);
+            }
         }
-        if empty.is_none() && ip.refcnt == 0 {
-            empty = Some(i);
+
+        if let Some(idx) = empty {
+            let ip = &mut cache.inodes[idx];
+            ip.dev = dev;
+            ip.inum = inum;
+            ip.refcnt = 1;
+            ip.valid.store(false, Ordering::Release);
+            ip.ra_next.store(0, Ordering::Relaxed);
+            ip.ra_end.store(0, Ordering::Relaxed);
+            return Ok(unsafe { &*(ip as *const Inode) });
         }
-    }
 
-    if let Some(idx) = empty {
-        let ip = &mut cache.inodes[idx];
-        ip.dev = dev;
-        ip.inum = inum;
-        ip.refcnt = 1;
-        return unsafe { &*(ip as *const Inode) };
+        let Some(p) = crate::proc::myproc() else {
+            panic!("iget: no inodes");
+        };
+        // The file table lock ranks below ICACHE, so count without it.
+        let held: u32 = cache.inodes.iter().map(|ip| ip.refcnt).sum();
+        drop(guard);
+        let mine = unsafe { (*p).files }.map_or(0, |files| unsafe { (*files).inode_refs() });
+        if unsafe { crate::proc::killed(&*p) } {
+            return Err(-EINTR);
+        }
+        if mine >= held {
+            return Err(-ENFILE);
+        }
+        guard = ICACHE.lock();
+        if guard.inodes.iter().all(|ip| ip.refcnt > 0) {
+            crate::proc::sleep(core::ptr::addr_of!(ICACHE) as usize, Some(guard));
+            guard = ICACHE.lock();
+        }
     }
-    panic!("iget: no inodes");
 }
 
 impl Inode {
-    pub fn ilock(&self) -> SleepLockGuard<DiskInode> {
+    // Lock the inode, reading it from disk if necessary.
+    // Returns bread's error if the on-disk inode cannot be read.
+    pub fn ilock(&self) -> Result<SleepLockGuard<'_, DiskInode>, isize> {
         let mut guard = self.lock.lock();
 
-        if guard.i_mode == 0 {
-            let (block, byte_offset) = {
-                let sb = SB.lock();
-                let inodes_per_group = sb.s_inodes_per_group;
-                let group = (self.inum - 1) / inodes_per_group;
-                let index = (self.inum - 1) % inodes_per_group;
-
-                let gdt = GDT.lock();
-                let inode_table_block = gdt[group as usize].bg_inode_table;
-
-                let inode_size = 128;
-
-                let offset_in_table = index * inode_size;
-                let block_offset = offset_in_table / BSIZE as u32;
-                let byte_offset = offset_in_table % BSIZE as u32;
-
-                (inode_table_block + block_offset, byte_offset)
-            };
-
-            let b = crate::bio::bread(self.dev, block);
+        if !self.valid.load(Ordering::Acquire) {
+            let (block, byte_offset) = inode_pos(self.inum);
+            let b = crate::bio::bread(self.dev, block)?;
             {
                 let cache = crate::bio::BCACHE.lock();
                 let buf = &cache.bufs[b];
@@ -281,23 +682,139 @@ impl Inode {
                 *guard = unsafe { core::ptr::read_unaligned(ptr) };
             }
             crate::bio::brelse(b);
+            self.valid.store(true, Ordering::Release);
+        }
+        Ok(guard)
+    }
+}
+
+// Block and byte offset of inode inum in its group's inode table.
+fn inode_pos(inum: u32) -> (u32, u32) {
+    let sb = SB.lock();
+    let inodes_per_group = sb.s_inodes_per_group;
+    let group = (inum - 1) / inodes_per_group;
+    let index = (inum - 1) % inodes_per_group;
+
+    let gdt = GDT.lock();
+    let inode_table_block = gdt[group as usize].bg_inode_table;
+
+    let inode_size = 128;
+
+    let offset_in_table = index * inode_size;
+    let block_offset = offset_in_table / BSIZE as u32;
+    let byte_offset = offset_in_table % BSIZE as u32;
+
+    (inode_table_block + block_offset, byte_offset)
+}
+
+// Write a locked inode back to the inode table.
+pub fn iupdate(ip: &Inode, di: &DiskInode) -> Result<(), isize> {
+    let (block, byte_offset) = inode_pos(ip.inum);
+    let b = crate::bio::bread(ip.dev, block)?;
+    {
+        let mut cache = crate::bio::BCACHE.lock();
+        let buf = &mut cache.bufs[b];
+        let ptr = unsafe { buf.data.as_mut_ptr().add(byte_offset as usize) } as *mut DiskInode;
+        unsafe { core::ptr::write_unaligned(ptr, *di) };
+    }
+    let r = crate::journal::log_write(b);
+    crate::bio::brelse(b);
+    r
+}
+
+// Drop a reference to an in-memory inode. Once the last reference is gone
+// the cached disk inode is forgotten and the slot can be recycled by iget,
+// so nothing of this inode can be seen through the slot's next inum.
+// Dropping the last reference to an inode with no links left frees it on
+// disk, so callers that may do that must be inside a journal operation.
+pub fn iput(ip: &Inode) {
+    // Nobody can take a new reference meanwhile: no directory names it.
+    let last = ICACHE
+        .lock()
+        .inodes
+        .iter()
+        .any(|i| core::ptr::eq(i, ip) && i.refcnt == 1);
+    if last && ip.ilock().is_ok_and(|di| di.i_links_count == 0) && ifree(ip).is_err() {
+        crate::error!("iput: cannot free inode {}", ip.inum);
+    }
+
+    let _guard = ICACHE.lock();
+    let ip = ip as *const Inode as *mut Inode;
+    unsafe {
+        if (*ip).refcnt < 1 {
+            panic!("iput: refcnt");
         }
-        guard
+        (*ip).refcnt -= 1;
+        if (*ip).refcnt == 0 {
+            // No references, so nobody holds or waits for the sleep-lock.
+            (*ip).valid.store(false, Ordering::Release);
+            *(*ip).lock.get_mut() = core::mem::zeroed();
+            (*ip).dev = 0;
+            (*ip).inum = 0;
+            crate::proc::wakeup(core::ptr::addr_of!(ICACHE) as usize);
+        }
+    }
+}
+
+// Free the blocks and then the inode itself of an unlinked inode.
+fn ifree(ip: &Inode) -> Result<(), isize> {
+    itrunc(ip, 0)?;
+    {
+        let mut di = ip.ilock()?;
+        di.i_mode = 0;
+        di.i_dtime = 1; // There is no clock; nonzero marks it deleted for fsck
+        iupdate(ip, &di)?;
     }
+    bitmap_free(ip.dev, Bitmap::Inode, ip.inum - 1)
 }
 
-pub fn iput(_ip: &Inode) {}
 pub fn iinit() {}
 
-// Read data from inode.
-pub fn readi(ip: &Inode, dst: *mut u8, off: u32, n: u32) -> u32 {
-    let guard = ip.ilock();
+// Stat of a locked inode.
+pub fn stati(ip: &Inode, di: &DiskInode) -> Stat {
+    let typ = match di.i_mode & 0xF000 {
+        0x4000 => T_DIR,
+        0x2000 => T_DEV,
+        _ => T_FILE,
+    };
+    Stat {
+        dev: ip.dev,
+        ino: ip.inum,
+        typ,
+        nlink: di.i_links_count,
+        size: di.i_size as u64,
+    }
+}
+
+// Block and inode counts of the root filesystem.
+pub fn statfs() -> StatFs {
+    let sb = SB.lock();
+    StatFs {
+        bsize: BSIZE as u32,
+        blocks: sb.s_blocks_count,
+        free_blocks: sb.s_free_blocks_count,
+        inodes: sb.s_inodes_count,
+        free_inodes: sb.s_free_inodes_count,
+    }
+}
+
+// Blocks read ahead of a sequential reader
+const READAHEAD: u32 = 16;
+
+// Read data from inode. Unallocated blocks below i_size (holes) read as zeros.
+// Returns the number of bytes read, or the device's error.
+//
+// A read that starts where the last one ended is taken to be sequential,
+// and the READAHEAD blocks after it are read into the cache in the
+// background (see bio::readahead).
+pub fn readi(ip: &Inode, dst: *mut u8, off: u32, n: u32) -> Result<u32, isize> {
+    let guard = ip.ilock()?;
     let mut tot = 0;
     let mut offset = off;
     let mut m = n;
 
     if off > guard.i_size {
-        return 0;
+        return Ok(0);
     }
     if off + n > guard.i_size {
         m = guard.i_size - off;
@@ -306,55 +823,70 @@ pub fn readi(ip: &Inode, dst: *mut u8, off: u32, n: u32) -> u32 {
     let mut dst_ptr = dst;
 
     while m > 0 {
-        let b = bmap(&guard, offset / BSIZE as u32, ip.dev);
-        if b == 0 {
-            break;
-        }
-        let buf_idx = crate::bio::bread(ip.dev, b);
+        let b = bmap(&guard, offset / BSIZE as u32, ip.dev)?;
         let start = (offset % BSIZE as u32) as usize;
         let len = core::cmp::min(m as usize, BSIZE - start);
 
-        unsafe {
-            let cache = crate::bio::BCACHE.lock();
-            let src = cache.bufs[buf_idx].data.as_ptr().add(start);
-            core::ptr::copy_nonoverlapping(src, dst_ptr, len);
+        if b == 0 {
+            unsafe { core::ptr::write_bytes(dst_ptr, 0, len) };
+        } else {
+            let buf_idx = crate::bio::bread(ip.dev, b)?;
+            unsafe {
+                let cache = crate::bio::BCACHE.lock();
+                let src = cache.bufs[buf_idx].data.as_ptr().add(start);
+                core::ptr::copy_nonoverlapping(src, dst_ptr, len);
+            }
+            crate::bio::brelse(buf_idx);
         }
-        crate::bio::brelse(buf_idx);
 
         tot += len as u32;
         offset += len as u32;
         m -= len as u32;
         dst_ptr = unsafe { dst_ptr.add(len) };
     }
-    tot
+
+    let bsize = BSIZE as u32;
+    let sequential = off / bsize == ip.ra_next.load(Ordering::Relaxed);
+    ip.ra_next.store(offset / bsize, Ordering::Relaxed);
+    if !sequential {
+        ip.ra_end.store(0, Ordering::Relaxed);
+    } else if tot > 0 {
+        let next = (offset - 1) / bsize + 1; // After the last block read
+        let end = (next + READAHEAD).min(guard.i_size.div_ceil(bsize));
+        for bn in ip.ra_end.load(Ordering::Relaxed).max(next)..end {
+            match bmap(&guard, bn, ip.dev) {
+                Ok(0) => {}
+                Ok(b) => crate::bio::readahead(ip.dev, b),
+                Err(_) => break,
+            }
+        }
+        ip.ra_end.store(end, Ordering::Relaxed);
+    }
+    Ok(tot)
 }
 
-pub fn writei(ip: &Inode, src: *const u8, off: u32, n: u32) -> u32 {
-    let mut guard = ip.ilock();
+// Write data to inode, allocating blocks as needed.
+// Returns the number of bytes written, which is short if the disk is full or
+// the file would exceed the largest size bmap can address.
+pub fn writei(ip: &Inode, src: *const u8, off: u32, n: u32) -> Result<u32, isize> {
+    if off as usize + n as usize > MAXFILE * BSIZE {
+        return Err(-1);
+    }
+    let mut guard = ip.ilock()?;
     let mut tot = 0;
     let mut offset = off;
     let mut m = n;
-
-    // TODO: support max file size check (NDIR + IND * ...)
+    let blocks = guard.i_blocks;
 
     let mut src_ptr = src;
 
     while m > 0 {
-        let b = bmap(&guard, offset / BSIZE as u32, ip.dev); // TODO: bmap alloc if 0?? bmap doesn't alloc yet.
-        // For now, bmap returns 0 if not allocated. We need bmap to allocate.
-        // Simplification: Assume file has blocks or fail.
-        // But write usually extends.
+        let b = match bmap_alloc(&mut guard, offset / BSIZE as u32, ip.dev) {
+            Ok(b) => b,
+            Err(_) => break,
+        };
 
-        if b == 0 {
-            // Need to allocate block
-            // Missing balloc implementation.
-            // For this task (console support), writei to file is secondary.
-            // But if we want to write to file, we need balloc.
-            // Let's implement basic writing to existing blocks first, or panic if extending.
-            break;
-        }
-
-        let buf_idx = crate::bio::bread(ip.dev, b);
+        let buf_idx = crate::bio::bread(ip.dev, b)?;
         let start = (offset % BSIZE as u32) as usize;
         let len = core::cmp::min(m as usize, BSIZE - start);
 
@@ -363,8 +895,11 @@ pub fn writei(ip: &Inode, src: *const u8, off: u32, n: u32) -> u32 {
             let dst = cache.bufs[buf_idx].data.as_mut_ptr().add(start);
             core::ptr::copy_nonoverlapping(src_ptr, dst, len);
         }
-        crate::bio::bwrite(buf_idx); // Write back immediately for now
+        let r = crate::journal::log_write(buf_idx);
         crate::bio::brelse(buf_idx);
+        if r.is_err() {
+            break;
+        }
 
         tot += len as u32;
         offset += len as u32;
@@ -372,54 +907,425 @@ pub fn writei(ip: &Inode, src: *const u8, off: u32, n: u32) -> u32 {
         src_ptr = unsafe { src_ptr.add(len) };
     }
 
-    if offset > guard.i_size {
-        guard.i_size = offset;
-        // iupdate ?? We need to persist size change to disk inode.
-        // ip.iupdate();
-        // Since we don't have iupdate yet, we might lose size update on crash, but it should be in memory.
-        // We really need iupdate to write DiskInode back.
-        // But for now, let's just leave it in memory.
+    if offset > guard.i_size || guard.i_blocks != blocks {
+        guard.i_size = guard.i_size.max(offset);
+        iupdate(ip, &guard)?;
     }
 
-    tot
+    Ok(tot)
+}
+
+// Most bytes one writei_logged transaction writes: up to four data blocks if
+// unaligned, leaving room in MAXOPBLOCKS for three indirect blocks (the
+// double indirect one and two under it), two bitmap blocks, the superblock,
+// the group descriptors and the inode.
+const MAXWRITE: u32 = 3 * BSIZE as u32;
+
+// writei, in as many journal transactions as it takes. Returns the bytes
+// written, short if an error stopped it.
+pub fn writei_logged(ip: &Inode, src: &[u8], off: u32) -> Result<u32, isize> {
+    let n = src.len() as u32;
+    let mut tot = 0;
+    while tot < n {
+        let len = core::cmp::min(n - tot, MAXWRITE);
+        crate::journal::begin_op();
+        let r = writei(ip, src[tot as usize..].as_ptr(), off + tot, len);
+        crate::journal::end_op();
+        let m = match r {
+            Ok(m) => m,
+            Err(e) if tot == 0 => return Err(e),
+            Err(_) => break,
+        };
+        tot += m;
+        if m < len {
+            break;
+        }
+    }
+    Ok(tot)
+}
+
+// Set the size of the file to `size` bytes. Blocks wholly past the new end
+// are freed, and the indirect block with them once none of its entries is
+// left; growing the file just leaves a hole. The rest of the last block is
+// zeroed, so that the file reads zeros there if it grows again.
+pub fn itrunc(ip: &Inode, size: u32) -> Result<(), isize> {
+    let mut di = ip.ilock()?;
+    if size < di.i_size {
+        let sectors = (BSIZE / 512) as u32;
+        let keep = size.div_ceil(BSIZE as u32) as usize;
+        for bn in keep..EXT2_NDIR_BLOCKS {
+            if di.i_block[bn] != 0 {
+                bfree(ip.dev, di.i_block[bn])?;
+                di.i_block[bn] = 0;
+                di.i_blocks -= sectors;
+            }
+        }
+        // Data blocks before each indirect tree, and its depth.
+        let trees = [
+            (EXT2_IND_BLOCK, EXT2_NDIR_BLOCKS, 1),
+            (EXT2_DIND_BLOCK, EXT2_NDIR_BLOCKS + NINDIRECT, 2),
+        ];
+        for (slot, start, depth) in trees {
+            let from = keep.saturating_sub(start);
+            if di.i_block[slot] == 0 || from >= NINDIRECT.pow(depth) {
+                continue;
+            }
+            di.i_blocks -= free_indirect(ip.dev, di.i_block[slot], depth, from)?;
+            if from == 0 {
+                di.i_block[slot] = 0;
+            }
+        }
+        let tail = size as usize % BSIZE;
+        let b = bmap(&di, size / BSIZE as u32, ip.dev)?;
+        if tail != 0 && b != 0 {
+            let buf = crate::bio::bread(ip.dev, b)?;
+            crate::bio::BCACHE.lock().bufs[buf].data[tail..].fill(0);
+            let r = crate::journal::log_write(buf);
+            crate::bio::brelse(buf);
+            r?;
+        }
+    }
+    di.i_size = size;
+    iupdate(ip, &di)
+}
+
+// Free the data blocks from number `from` on in the tree under indirect
+// block addr, which has `depth` levels (1: addr lists data blocks), with the
+// indirect blocks left empty. addr itself is freed if `from` is 0, and
+// otherwise updated. Returns the sectors freed, for i_blocks.
+fn free_indirect(dev: u32, addr: u32, depth: u32, from: usize) -> Result<u32, isize> {
+    let sectors = (BSIZE / 512) as u32;
+    let per = NINDIRECT.pow(depth - 1); // Data blocks under each entry
+    let ind = crate::bio::bread(dev, addr)?;
+    let mut freed = 0;
+    let mut r = Ok(());
+    for i in from / per..NINDIRECT {
+        let sub = if i == from / per { from % per } else { 0 };
+        let entry = {
+            let cache = crate::bio::BCACHE.lock();
+            let ptr = cache.bufs[ind].data.as_ptr() as *const u32;
+            unsafe { core::ptr::read(ptr.add(i)) }
+        };
+        if entry == 0 {
+            continue;
+        }
+        r = if depth == 1 {
+            bfree(dev, entry).map(|_| sectors)
+        } else {
+            free_indirect(dev, entry, depth - 1, sub)
+        }
+        .map(|n| freed += n);
+        if r.is_err() {
+            break;
+        }
+        if sub == 0 {
+            let mut cache = crate::bio::BCACHE.lock();
+            let ptr = cache.bufs[ind].data.as_mut_ptr() as *mut u32;
+            unsafe { core::ptr::write(ptr.add(i), 0) };
+        }
+    }
+    if from > 0 {
+        r = r.and_then(|_| crate::journal::log_write(ind));
+    }
+    crate::bio::brelse(ind);
+    r?;
+    if from == 0 {
+        bfree(dev, addr)?;
+        freed += sectors;
+    }
+    Ok(freed)
+}
+
+// Like bmap, but allocate the block (and the indirect block) if missing.
+fn bmap_alloc(di: &mut DiskInode, bn: u32, dev: u32) -> Result<u32, isize> {
+    let sectors = (BSIZE / 512) as u32; // i_blocks counts 512-byte sectors
+    let mut bn = bn as usize;
+    if bn < EXT2_NDIR_BLOCKS {
+        if di.i_block[bn] == 0 {
+            di.i_block[bn] = balloc(dev)?;
+            di.i_blocks += sectors;
+        }
+        return Ok(di.i_block[bn]);
+    }
+
+    let (slot, depth) = indirect_slot(&mut bn).ok_or(-1isize)?;
+    if di.i_block[slot] == 0 {
+        di.i_block[slot] = balloc(dev)?;
+        di.i_blocks += sectors;
+    }
+    let mut addr = di.i_block[slot];
+    for level in (0..depth).rev() {
+        let per = NINDIRECT.pow(level);
+        let (next, allocated) = entry_alloc(dev, addr, bn / per)?;
+        if allocated {
+            di.i_blocks += sectors;
+        }
+        addr = next;
+        bn %= per;
+    }
+    Ok(addr)
+}
+
+// For a block number past the direct blocks, the i_block slot of the
+// indirect tree that maps it and the depth of that tree. bn is made
+// relative to the start of the tree.
+fn indirect_slot(bn: &mut usize) -> Option<(usize, u32)> {
+    *bn -= EXT2_NDIR_BLOCKS;
+    if *bn < NINDIRECT {
+        return Some((EXT2_IND_BLOCK, 1));
+    }
+    *bn -= NINDIRECT;
+    if *bn < NINDIRECT * NINDIRECT {
+        return Some((EXT2_DIND_BLOCK, 2));
+    }
+    None
+}
+
+// Entry i of indirect block addr.
+fn entry(dev: u32, addr: u32, i: usize) -> Result<u32, isize> {
+    let ind = crate::bio::bread(dev, addr)?;
+    let entry = {
+        let cache = crate::bio::BCACHE.lock();
+        let ptr = cache.bufs[ind].data.as_ptr() as *const u32;
+        unsafe { core::ptr::read(ptr.add(i)) }
+    };
+    crate::bio::brelse(ind);
+    Ok(entry)
+}
+
+// Entry i of indirect block addr, allocating a zeroed block for it if it is
+// empty. Also returns whether it was allocated.
+fn entry_alloc(dev: u32, addr: u32, i: usize) -> Result<(u32, bool), isize> {
+    let ind = crate::bio::bread(dev, addr)?;
+    let cur = {
+        let cache = crate::bio::BCACHE.lock();
+        let ptr = cache.bufs[ind].data.as_ptr() as *const u32;
+        unsafe { core::ptr::read(ptr.add(i)) }
+    };
+    if cur != 0 {
+        crate::bio::brelse(ind);
+        return Ok((cur, false));
+    }
+    let r = balloc(dev).and_then(|new| {
+        {
+            let mut cache = crate::bio::BCACHE.lock();
+            let ptr = cache.bufs[ind].data.as_mut_ptr() as *mut u32;
+            unsafe { core::ptr::write(ptr.add(i), new) };
+        }
+        crate::journal::log_write(ind).map(|_| (new, true))
+    });
+    crate::bio::brelse(ind);
+    r
 }
 
 // Return the disk block address of the nth block in inode.
 // Returns 0 if no block allocated.
 // Supports Direct blocks (0-11) and Singly Indirect (12).
-fn bmap(ip: &DiskInode, bn: u32, dev: u32) -> u32 {
-    let mut bn = bn;
-    if bn < EXT2_NDIR_BLOCKS as u32 {
-        return ip.i_block[bn as usize];
+fn bmap(ip: &DiskInode, bn: u32, dev: u32) -> Result<u32, isize> {
+    let mut bn = bn as usize;
+    if bn < EXT2_NDIR_BLOCKS {
+        return Ok(ip.i_block[bn]);
     }
 
-    // Simplified Indirect support (Singular only for now)
-    bn -= EXT2_NDIR_BLOCKS as u32;
-    if bn < (BSIZE / 4) as u32 {
-        let addr = ip.i_block[EXT2_IND_BLOCK];
+    let Some((slot, depth)) = indirect_slot(&mut bn) else {
+        return Ok(0);
+    };
+    let mut addr = ip.i_block[slot];
+    for level in (0..depth).rev() {
         if addr == 0 {
-            return 0;
-        }
-        // Read indirect block
-        let buf_idx = crate::bio::bread(dev, addr);
-        let blk_addr: u32;
-        {
-            let cache = crate::bio::BCACHE.lock();
-            let buf = &cache.bufs[buf_idx];
-            let ptr = buf.data.as_ptr() as *const u32;
-            blk_addr = unsafe { core::ptr::read(ptr.add(bn as usize)) };
+            return Ok(0); // A hole
         }
-        crate::bio::brelse(buf_idx);
-        return blk_addr;
+        let per = NINDIRECT.pow(level);
+        addr = entry(dev, addr, bn / per)?;
+        bn %= per;
+    }
+    Ok(addr)
+}
+
+// Name cache: (dev, directory inum, name) -> inum for recent lookups, so hot
+// paths skip scanning directory blocks. Only successful lookups are cached.
+const NDCACHE: usize = 32;
+const DCACHE_NAMELEN: usize = 32; // Longer names are not cached
+
+#[derive(Clone, Copy)]
+struct DcacheEntry {
+    dev: u32,
+    parent: u32,
+    inum: u32, // 0 if the entry is unused
+    name_len: usize,
+    name: [u8; DCACHE_NAMELEN],
+}
+
+struct Dcache {
+    entries: [DcacheEntry; NDCACHE],
+    next: usize, // Next entry to replace (round robin)
+    gen: u64,    // Bumped by each dcache_invalidate
+}
+
+static DCACHE: Spinlock<Dcache> = Spinlock::ranked(
+    Dcache {
+        entries: [DcacheEntry {
+            dev: 0,
+            parent: 0,
+            inum: 0,
+            name_len: 0,
+            name: [0; DCACHE_NAMELEN],
+        }; NDCACHE],
+        next: 0,
+        gen: 0,
+    },
+    "DCACHE",
+    RANK_DCACHE,
+);
+
+// Lookup statistics, reported by sysinfo.
+pub static DCACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static DCACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+impl DcacheEntry {
+    fn matches(&self, dev: u32, parent: u32, name: &str) -> bool {
+        self.inum != 0
+            && self.dev == dev
+            && self.parent == parent
+            && &self.name[..self.name_len] == name.as_bytes()
+    }
+}
+
+fn dcache_lookup(dev: u32, parent: u32, name: &str) -> Option<u32> {
+    let cache = DCACHE.lock();
+    let inum = cache
+        .entries
+        .iter()
+        .find(|e| e.matches(dev, parent, name))
+        .map(|e| e.inum);
+    if inum.is_some() {
+        DCACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        DCACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+    inum
+}
+
+fn dcache_gen() -> u64 {
+    DCACHE.lock().gen
+}
+
+// Cache what a directory scan found, unless an entry has been invalidated
+// since the scan began (gen from dcache_gen): it may be this one, removed
+// after the scan saw it, and caching it would bring it back.
+fn dcache_insert(dev: u32, parent: u32, name: &str, inum: u32, gen: u64) {
+    if name.len() > DCACHE_NAMELEN {
+        return;
+    }
+    let mut cache = DCACHE.lock();
+    if cache.gen != gen || cache.entries.iter().any(|e| e.matches(dev, parent, name)) {
+        return;
     }
+    let i = cache.next;
+    cache.next = (i + 1) % NDCACHE;
+    let e = &mut cache.entries[i];
+    e.dev = dev;
+    e.parent = parent;
+    e.inum = inum;
+    e.name_len = name.len();
+    e.name[..name.len()].copy_from_slice(name.as_bytes());
+}
 
-    0
+// Forget a directory entry. Must be called by anything that removes, renames
+// or replaces an entry (unlink, rename, create over an existing name).
+pub fn dcache_invalidate(dev: u32, parent: u32, name: &str) {
+    let mut cache = DCACHE.lock();
+    cache.gen += 1;
+    for e in cache.entries.iter_mut() {
+        if e.matches(dev, parent, name) {
+            e.inum = 0;
+        }
+    }
 }
 
 // Directory Lookup
 // Returns Inode number.
 pub fn dirlookup(dir: &Inode, name: &str) -> Option<u32> {
-    let guard = dir.ilock();
+    if let Some(inum) = dcache_lookup(dir.dev, dir.inum, name) {
+        return Some(inum);
+    }
+    let gen = dcache_gen();
+    let inum = dirscan(dir, name)?;
+    dcache_insert(dir.dev, dir.inum, name, inum, gen);
+    Some(inum)
+}
+
+// Copy the live entries of directory ip, from byte offset *off on, to user
+// address dst: each a DirEntry with rec_len set to its packed size, then the
+// name. Stops before the first entry that does not fit in n bytes and leaves
+// *off at it, so a listing reads each block about once however many calls
+// it takes. Returns the bytes copied, 0 at the end of the directory, or Err
+// if not even one entry fits.
+//
+// Entries only ever split or merge within a block, so walking the block
+// from its start to the first entry at or after *off resumes correctly even
+// if the directory changed between calls: removed entries are skipped and
+// nothing is returned twice.
+pub fn getdents(ip: &Inode, off: &mut u32, dst: u64, n: usize) -> Result<usize, isize> {
+    let size = ip.ilock()?.i_size;
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
+    let mut block = [0u8; BSIZE];
+    let mut ent = [0u8; 264]; // Header and the longest name, padded
+    let mut copied = 0;
+
+    while *off < size {
+        let base = *off & !(BSIZE as u32 - 1);
+        if readi(ip, block.as_mut_ptr(), base, BSIZE as u32)? != BSIZE as u32 {
+            return Err(-1);
+        }
+        let start = (*off - base) as usize;
+        let mut pos = 0;
+        while pos < BSIZE {
+            let de =
+                unsafe { core::ptr::read_unaligned(block.as_ptr().add(pos) as *const DirEntry) };
+            let rec_len = de.rec_len as usize;
+            if rec_len == 0 || pos + rec_len > BSIZE {
+                return Err(-1); // Corrupt directory
+            }
+            if de.inode != 0 && pos >= start {
+                let len = dirent_size(de.name_len as usize);
+                if copied + len > n {
+                    return if copied == 0 { Err(-1) } else { Ok(copied) };
+                }
+                let hdr = core::mem::size_of::<DirEntry>();
+                let name = &block[pos + hdr..pos + hdr + de.name_len as usize];
+                ent[..len].fill(0);
+                put_dirent(
+                    &mut ent,
+                    DirEntry {
+                        rec_len: len as u16,
+                        ..de
+                    },
+                    None,
+                );
+                ent[hdr..hdr + name.len()].copy_from_slice(name);
+                let mut allocator = crate::allocator::ALLOCATOR.lock();
+                if !crate::vm::copyout(
+                    pgdir,
+                    &mut allocator,
+                    dst + copied as u64,
+                    ent.as_ptr(),
+                    len,
+                ) {
+                    return Err(-1);
+                }
+                copied += len;
+            }
+            pos += rec_len;
+            *off = (*off).max(base + pos as u32);
+        }
+    }
+    Ok(copied)
+}
+
+// Search the directory blocks for name.
+fn dirscan(dir: &Inode, name: &str) -> Option<u32> {
+    let guard = dir.ilock().ok()?;
     if (guard.i_mode & 0xF000) != 0x4000 {
         return None; // Not a directory
     }
@@ -429,7 +1335,7 @@ pub fn dirlookup(dir: &Inode, name: &str) -> Option<u32> {
 
     drop(guard); // Unlock to use readi
     loop {
-        let n = readi(dir, buf.as_mut_ptr(), off, BSIZE as u32);
+        let n = readi(dir, buf.as_mut_ptr(), off, BSIZE as u32).ok()?;
         if n == 0 {
             break;
         }
@@ -460,19 +1366,392 @@ pub fn dirlookup(dir: &Inode, name: &str) -> Option<u32> {
     None
 }
 
+// Whether a component of path is too long to be a directory entry. Such a
+// name can neither be found nor created, and is never cut short to one that
+// could.
+pub fn name_too_long(path: &str) -> bool {
+    path.split('/').any(|name| name.len() > NAME_MAX)
+}
+
+// Look up path, from the root if it starts with '/', else from the current
+// process's working directory. Returns the inode referenced but unlocked.
 pub fn namei(path: &str) -> Option<&'static Inode> {
-    let mut ip = iget(1, ROOT_INO);
+    let dev = rootdev();
+    let cwd = crate::proc::myproc()
+        .and_then(|p| unsafe { (*p).files })
+        .map(|files| unsafe { (*files).cwd })
+        .filter(|&(_, inum)| inum != 0 && !path.starts_with('/'));
+    let mut ip = match cwd {
+        Some((dev, inum)) => iget(dev, inum).ok()?,
+        None => iget(dev, ROOT_INO).ok()?,
+    };
 
     for name in path.split('/') {
         if name.is_empty() {
             continue;
         }
-        match dirlookup(ip, name) {
-            Some(inum) => {
-                ip = iget(1, inum);
+        // Release the directory before taking the next inode, so a lookup
+        // never needs two free slots in ICACHE.
+        let inum = dirlookup(ip, name);
+        let dev = ip.dev;
+        iput(ip);
+        ip = iget(dev, inum?).ok()?;
+    }
+    Some(ip)
+}
+
+// Look up the directory holding the last element of path, and return it
+// with that element's name, which is empty for "/".
+pub fn nameiparent(path: &str) -> Option<(&'static Inode, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some(split) => split,
+        None => (".", path),
+    };
+    Some((namei(parent)?, name))
+}
+
+// Serializes directory updates, so the read-modify-write of a directory
+// block in dirlink cannot race another, and a name checked absent by create
+// stays absent until it is linked.
+static DIRLOCK: SleepLockSafe<()> = SleepLockSafe::ranked((), "DIRLOCK", RANK_DIRLOCK);
+
+// Size of a directory entry with an n-byte name: header plus the name,
+// padded to 4 bytes.
+fn dirent_size(n: usize) -> usize {
+    (core::mem::size_of::<DirEntry>() + n + 3) & !3
+}
+
+// Add the entry name -> inum to directory dp: into the first entry with
+// enough slack, else in a new block at the end. Call with DIRLOCK held.
+fn dirlink(dp: &Inode, name: &str, inum: u32) -> Result<(), isize> {
+    if name.is_empty() || name.len() > NAME_MAX {
+        return Err(-1);
+    }
+    let need = dirent_size(name.len());
+    let size = dp.ilock()?.i_size;
+    let mut buf = [0u8; BSIZE];
+
+    let mut off = 0;
+    while off < size {
+        if readi(dp, buf.as_mut_ptr(), off, BSIZE as u32)? != BSIZE as u32 {
+            return Err(-1);
+        }
+        let mut pos = 0;
+        while pos < BSIZE {
+            let de = unsafe { core::ptr::read_unaligned(buf.as_ptr().add(pos) as *const DirEntry) };
+            let rec_len = de.rec_len as usize;
+            if rec_len == 0 || pos + rec_len > BSIZE {
+                return Err(-1); // Corrupt directory
+            }
+            let used = if de.inode == 0 {
+                0
+            } else {
+                dirent_size(de.name_len as usize)
+            };
+            if rec_len - used >= need {
+                if used > 0 {
+                    let shrunk = DirEntry {
+                        rec_len: used as u16,
+                        ..de
+                    };
+                    put_dirent(&mut buf[pos..], shrunk, None);
+                }
+                let new = DirEntry {
+                    inode: inum,
+                    rec_len: (rec_len - used) as u16,
+                    name_len: name.len() as u8,
+                    file_type: 0, // Revision 0 has no file types
+                };
+                put_dirent(&mut buf[pos + used..], new, Some(name));
+                let n = writei(dp, buf.as_ptr(), off, BSIZE as u32)?;
+                return if n == BSIZE as u32 { Ok(()) } else { Err(-1) };
+            }
+            pos += rec_len;
+        }
+        off += BSIZE as u32;
+    }
+
+    buf.fill(0);
+    let new = DirEntry {
+        inode: inum,
+        rec_len: BSIZE as u16,
+        name_len: name.len() as u8,
+        file_type: 0,
+    };
+    put_dirent(&mut buf, new, Some(name));
+    let n = writei(dp, buf.as_ptr(), size, BSIZE as u32)?;
+    if n == BSIZE as u32 {
+        Ok(())
+    } else {
+        Err(-1)
+    }
+}
+
+fn put_dirent(buf: &mut [u8], de: DirEntry, name: Option<&str>) {
+    unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut DirEntry, de) };
+    if let Some(name) = name {
+        let start = core::mem::size_of::<DirEntry>();
+        buf[start..start + name.len()].copy_from_slice(name.as_bytes());
+    }
+}
+
+// Remove the entry for name from directory dp and return its inode number.
+// The space goes to the previous entry in the block, or if it is the first,
+// the entry stays as an empty one.
+fn dirunlink(dp: &Inode, name: &str) -> Result<u32, isize> {
+    let size = dp.ilock()?.i_size;
+    let mut buf = [0u8; BSIZE];
+    let mut off = 0;
+    while off < size {
+        if readi(dp, buf.as_mut_ptr(), off, BSIZE as u32)? != BSIZE as u32 {
+            return Err(-1);
+        }
+        let mut pos = 0;
+        let mut prev: Option<(usize, DirEntry)> = None;
+        while pos < BSIZE {
+            let de = unsafe { core::ptr::read_unaligned(buf.as_ptr().add(pos) as *const DirEntry) };
+            let rec_len = de.rec_len as usize;
+            if rec_len == 0 || pos + rec_len > BSIZE {
+                return Err(-1); // Corrupt directory
+            }
+            let start = pos + core::mem::size_of::<DirEntry>();
+            let found = &buf[start..start + de.name_len as usize];
+            if de.inode != 0 && found == name.as_bytes() {
+                match prev {
+                    Some((at, p)) => {
+                        let merged = DirEntry {
+                            rec_len: p.rec_len + de.rec_len,
+                            ..p
+                        };
+                        put_dirent(&mut buf[at..], merged, None);
+                    }
+                    None => put_dirent(&mut buf[pos..], DirEntry { inode: 0, ..de }, None),
+                }
+                let n = writei(dp, buf.as_ptr(), off, BSIZE as u32)?;
+                return if n == BSIZE as u32 {
+                    Ok(de.inode)
+                } else {
+                    Err(-1)
+                };
             }
-            None => return None,
+            prev = Some((pos, de));
+            pos += rec_len;
         }
+        off += BSIZE as u32;
     }
-    Some(ip)
+    Err(-1)
+}
+
+// Remove the name path of a file. The file itself is freed by the last iput
+// once it has no links left, so it stays usable while open. Directories
+// cannot be removed this way, nor can the journal's /.log.
+pub fn unlink(path: &str) -> Result<(), isize> {
+    let (dp, name) = nameiparent(path).ok_or(-1isize)?;
+    if name.is_empty() || name == "." || name == ".." {
+        iput(dp);
+        return Err(-1);
+    }
+    let _dirlock = DIRLOCK.lock();
+    let r = dirlookup(dp, name).ok_or(-1).and_then(|inum| {
+        let ip = iget(dp.dev, inum)?;
+        if is_log(ip) {
+            iput(ip);
+            return Err(-EPERM);
+        }
+        let r = ip.ilock().and_then(|mut di| {
+            if di.i_mode & 0xF000 == 0x4000 {
+                return Err(-1);
+            }
+            dirunlink(dp, name)?;
+            dcache_invalidate(dp.dev, dp.inum, name);
+            di.i_links_count -= 1;
+            iupdate(ip, &di)
+        });
+        iput(ip);
+        r
+    });
+    iput(dp);
+    r
+}
+
+// Regular file, rw-r--r--.
+const S_IFREG_644: u16 = 0x8000 | 0o644;
+
+// Create a regular file at path, or return the file already there.
+// Returns it referenced but unlocked.
+pub fn create(path: &str) -> Result<&'static Inode, isize> {
+    let (dp, name) = nameiparent(path).ok_or(-1isize)?;
+    if name.is_empty() {
+        iput(dp);
+        return Err(-1);
+    }
+    let _dirlock = DIRLOCK.lock();
+
+    let ip = match dirlookup(dp, name) {
+        Some(inum) => {
+            let ip = match iget(dp.dev, inum) {
+                Ok(ip) => ip,
+                Err(e) => {
+                    iput(dp);
+                    return Err(e);
+                }
+            };
+            let is_file = ip.ilock().map(|di| di.i_mode & 0xF000 == 0x8000);
+            if is_file != Ok(true) {
+                iput(ip);
+                iput(dp);
+                return Err(-1);
+            }
+            Ok(ip)
+        }
+        None => ialloc(dp.dev, S_IFREG_644).and_then(|ip| match dirlink(dp, name, ip.inum) {
+            Ok(()) => Ok(ip),
+            Err(e) => {
+                crate::error!("create: cannot link {}", path);
+                idiscard(ip);
+                Err(e)
+            }
+        }),
+    };
+    iput(dp);
+    ip
+}
+
+// Directory, rwxr-xr-x.
+const S_IFDIR_755: u16 = 0x4000 | 0o755;
+
+// Create an empty directory at path, holding "." and "..". Fails if
+// anything is already there.
+pub fn mkdir(path: &str) -> Result<(), isize> {
+    let (dp, name) = nameiparent(path).ok_or(-1isize)?;
+    if name.is_empty() || name == "." || name == ".." {
+        iput(dp);
+        return Err(-1);
+    }
+    let _dirlock = DIRLOCK.lock();
+    let r = mkdir_in(dp, name);
+    iput(dp);
+    r
+}
+
+fn mkdir_in(dp: &Inode, name: &str) -> Result<(), isize> {
+    let is_dir = dp.ilock()?.i_mode & 0xF000 == 0x4000;
+    if !is_dir || dirlookup(dp, name).is_some() {
+        return Err(-1);
+    }
+    let ip = ialloc(dp.dev, S_IFDIR_755)?;
+    // The first block: "." and then ".." taking the rest of it.
+    let mut buf = [0u8; BSIZE];
+    let dot_len = dirent_size(1);
+    let dot = DirEntry {
+        inode: ip.inum,
+        rec_len: dot_len as u16,
+        name_len: 1,
+        file_type: 0,
+    };
+    let dotdot = DirEntry {
+        inode: dp.inum,
+        rec_len: (BSIZE - dot_len) as u16,
+        name_len: 2,
+        file_type: 0,
+    };
+    put_dirent(&mut buf, dot, Some("."));
+    put_dirent(&mut buf[dot_len..], dotdot, Some(".."));
+    let r = writei(ip, buf.as_ptr(), 0, BSIZE as u32)
+        .and_then(|n| if n == BSIZE as u32 { Ok(()) } else { Err(-1) })
+        .and_then(|()| dirlink(dp, name, ip.inum));
+    if let Err(e) = r {
+        idiscard(ip);
+        return Err(e);
+    }
+
+    // Its own "." and the parent's ".." in it are links too.
+    let r = ip
+        .ilock()
+        .and_then(|mut di| {
+            di.i_links_count = 2;
+            iupdate(ip, &di)
+        })
+        .and_then(|()| {
+            let mut di = dp.ilock()?;
+            di.i_links_count += 1;
+            iupdate(dp, &di)
+        })
+        .and_then(|()| update_dirs(ip.dev, ip.inum, 1));
+    iput(ip);
+    r
+}
+
+// Character device, rw-rw-rw-.
+const S_IFCHR_666: u16 = 0x2000 | 0o666;
+
+// Create a character device node at path for device (major, minor). Fails
+// if anything is already there.
+pub fn mknod(path: &str, major: u8, minor: u8) -> Result<(), isize> {
+    let (dp, name) = nameiparent(path).ok_or(-1isize)?;
+    if name.is_empty() || name == "." || name == ".." {
+        iput(dp);
+        return Err(-1);
+    }
+    let _dirlock = DIRLOCK.lock();
+    let r = mknod_in(dp, name, major, minor);
+    iput(dp);
+    r
+}
+
+fn mknod_in(dp: &Inode, name: &str, major: u8, minor: u8) -> Result<(), isize> {
+    let is_dir = dp.ilock()?.i_mode & 0xF000 == 0x4000;
+    if !is_dir || dirlookup(dp, name).is_some() {
+        return Err(-1);
+    }
+    let ip = ialloc(dp.dev, S_IFCHR_666)?;
+    let r = ip
+        .ilock()
+        .and_then(|mut di| {
+            di.i_block[0] = (major as u32) << 8 | minor as u32;
+            iupdate(ip, &di)
+        })
+        .and_then(|()| dirlink(dp, name, ip.inum));
+    if let Err(e) = r {
+        idiscard(ip);
+        return Err(e);
+    }
+    iput(ip);
+    Ok(())
+}
+
+// The (major, minor) of a device inode. Like Linux, ext2 keeps the old
+// 8-bit encoding in the first block slot, or the new one in the second.
+pub fn devnum(di: &DiskInode) -> (u32, u32) {
+    let old = di.i_block[0];
+    if old != 0 {
+        return (old >> 8 & 0xff, old & 0xff);
+    }
+    let new = di.i_block[1];
+    (new >> 8 & 0xfff, new & 0xff | new >> 12 & 0xfff00)
+}
+
+// Drop the one link of an inode from ialloc that could not be linked into a
+// directory, so that iput frees it.
+fn idiscard(ip: &Inode) {
+    if let Ok(mut di) = ip.ilock() {
+        di.i_links_count = 0;
+        let _ = iupdate(ip, &di);
+    }
+    iput(ip);
+}
+
+// Add delta to the directory count of the group holding inode inum.
+fn update_dirs(dev: u32, inum: u32, delta: i16) -> Result<(), isize> {
+    let (g, gd, gdt_block) = {
+        let sb = SB.lock();
+        let mut gdt = GDT.lock();
+        let g = ((inum - 1) / sb.s_inodes_per_group) as usize;
+        let gd = gdt.get_mut(g).ok_or(-1isize)?;
+        gd.bg_used_dirs_count = gd.bg_used_dirs_count.wrapping_add_signed(delta);
+        (g, *gd, sb.s_first_data_block + 1)
+    };
+    write_struct(dev, gdt_block, g * core::mem::size_of::<GroupDesc>(), &gd)
 }
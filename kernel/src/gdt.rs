@@ -36,10 +36,6 @@ pub fn init(cpuid: usize) {
     }
 }
 
-pub fn tss_addr(cpuid: usize) -> u64 {
-    unsafe { core::ptr::addr_of!(TSS[cpuid]) as u64 }
-}
-
 unsafe fn load_tr(selector: u16) {
     unsafe {
         core::arch::asm!("ltr {0:x}", in(reg) selector, options(nostack, preserves_flags));
@@ -209,7 +205,8 @@ unsafe fn reload_segments(code_selector: u16, data_selector: u16) {
             "mov ds, {2:e}",
             "mov es, {2:e}",
             "mov fs, {2:e}",
-            "mov gs, {2:e}",
+            // Not gs: that would clear its base, the per-CPU pointer set
+            // by proc::init_gs.
             "mov ss, {2:e}",
             in(reg) code_selector as u64,
             out(reg) _,
@@ -1,22 +1,62 @@
 use crate::allocator::Allocator;
-use crate::proc::mycpu;
+use crate::mm::Mm;
+use crate::proc::myproc;
+use crate::util::PG_SIZE;
 use crate::vm;
 
-pub fn growproc(n: isize) -> Result<(), ()> {
-    let cpu = mycpu();
-    let p = unsafe { &mut *cpu.process.unwrap() };
-    let sz = p.sz;
+// Grow or shrink the heap by n bytes. Returns where it ended before.
+pub fn growproc(n: isize) -> Result<usize, ()> {
+    let mm = unsafe { (*myproc().unwrap()).mm() };
+    let _guard = mm.lock();
+    let sz = mm.sz;
 
     if n > 0 {
+        // Keep a guard page between the heap and the mappings and stack
+        // above it.
+        let limit = crate::mmap::lowest(mm) as usize;
+        if mm.ustack != 0
+            && sz
+                .checked_add(n as usize + PG_SIZE)
+                .is_none_or(|end| end > limit)
+        {
+            return Err(());
+        }
         // Lazy allocation (= demand paging): just increment sz.
         // Physical memory will be allocated in page fault handler.
-        p.sz += n as usize;
+        mm.sz += n as usize;
     } else if n < 0 {
-        let new_sz = (sz as isize + n) as usize;
-        let new_sz = vm::uvm_dealloc(p.pgdir, &mut crate::allocator::ALLOCATOR.lock(), sz, new_sz);
-        p.sz = new_sz;
+        // The heap only gives back what it was given.
+        let new_sz = sz
+            .checked_sub(n.unsigned_abs())
+            .filter(|&s| s >= mm.heap_start)
+            .ok_or(())?;
+        let (lo, hi) = (vm::pgroundup(new_sz as u64), vm::pgroundup(sz as u64));
+        vm::uvm_unmap(mm.pgdir, &mut crate::allocator::ALLOCATOR.lock(), lo, hi);
+        vm::flush_unmapped(mm.pgdir, lo, hi);
+        mm.sz = new_sz;
     }
 
-    vm::switch(p.pgdir);
-    Ok(())
+    Ok(sz)
+}
+
+// Extend mm's stack down to the page holding addr, a fault below it. The
+// stack grows on demand up to exec::USTACK_MAX, as long as a guard page is
+// left between it and the heap. Returns whether addr is now mapped.
+pub fn grow_stack(mm: &mut Mm, addr: u64) -> bool {
+    use crate::exec::{USTACK_MAX, USTACK_TOP};
+    let base = vm::pgrounddown(addr);
+    let heap_end = (mm.sz as u64).next_multiple_of(PG_SIZE as u64);
+    if mm.ustack == 0
+        || addr >= mm.ustack as u64
+        || base < USTACK_TOP - USTACK_MAX as u64
+        || base < heap_end + PG_SIZE as u64
+    {
+        return false;
+    }
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if vm::uvm_alloc(mm.pgdir, &mut allocator, base as usize, mm.ustack).is_none() {
+        return false;
+    }
+    mm.ustack = base as usize;
+    true
 }
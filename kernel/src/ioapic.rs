@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::util::{IOAPIC_ADDR, T_IRQ0};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 const REG_ID: u32 = 0x00;
 const REG_VER: u32 = 0x01;
@@ -8,6 +9,29 @@ const REG_TABLE: u32 = 0x10;
 
 const IOREGSEL: usize = 0x00;
 const IOWIN: usize = 0x10;
+const IOEOI: usize = 0x40; // EOI register (version >= 0x20)
+
+// Redirection table entry bits
+const INT_DISABLED: u32 = 0x00010000; // Interrupt disabled
+const INT_LEVEL: u32 = 0x00008000; // Level-triggered (vs edge-)
+
+pub const MAX_IRQS: usize = 24;
+
+// Trigger mode of each IRQ, so the trap handler knows which EOI to issue.
+static LEVEL_TRIGGERED: [AtomicBool; MAX_IRQS] = [const { AtomicBool::new(false) }; MAX_IRQS];
+
+// Highest IRQ with a redirection entry, from the version register.
+static MAXINTR: AtomicU32 = AtomicU32::new(0);
+
+// Register of irq's redirection entry, low half; the high half follows.
+// None, saying so, if the IOAPIC has no entry for it.
+fn entry(irq: u32) -> Option<u32> {
+    if irq > MAXINTR.load(Ordering::Relaxed) || irq as usize >= MAX_IRQS {
+        crate::error!("ioapic: no IRQ {}", irq);
+        return None;
+    }
+    Some(REG_TABLE + 2 * irq)
+}
 
 pub fn init() {
     let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
@@ -17,26 +41,165 @@ pub fn init() {
     let ver = unsafe { read(ioapic_addr, REG_VER) };
     let maxintr = (ver >> 16) & 0xFF;
     crate::info!("IOAPIC max entries: {}", maxintr);
+    MAXINTR.store(maxintr, Ordering::Relaxed);
 
     // Mark all interrupts edge-triggered, active high, disabled,
     // and not routed to any CPUs.
     for i in 0..=maxintr {
         unsafe {
-            write(ioapic_addr, REG_TABLE + 2 * i, 0x10000 | T_IRQ0 + i);
+            write(ioapic_addr, REG_TABLE + 2 * i, INT_DISABLED | T_IRQ0 + i);
             write(ioapic_addr, REG_TABLE + 2 * i + 1, 0);
         }
     }
 }
 
+// Enable an edge-triggered IRQ (ISA devices: UART, ...).
 pub unsafe fn enable(irq: u32, cpu_id: u32) {
+    route(irq, cpu_id, 0);
+}
+
+// Enable a level-triggered IRQ (PCI INTx lines, which QEMU reports as active high).
+pub unsafe fn enable_level(irq: u32, cpu_id: u32) {
+    route(irq, cpu_id, INT_LEVEL);
+}
+
+unsafe fn route(irq: u32, cpu_id: u32, flags: u32) {
+    let Some(reg) = entry(irq) else {
+        return;
+    };
     let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    LEVEL_TRIGGERED[irq as usize].store(flags & INT_LEVEL != 0, Ordering::Relaxed);
     // For now assuming CPU 0 or broadcast.
     // Write low 32 bits: vector = T_IRQ0 + irq, Mask = 0 (enabled).
-    write(ioapic_addr, REG_TABLE + 2 * irq, T_IRQ0 + irq);
+    write(ioapic_addr, reg, flags | T_IRQ0 + irq);
 
     // Write high 32 bits: destination APIC ID.
     // cpu_id << 24.
-    write(ioapic_addr, REG_TABLE + 2 * irq + 1, cpu_id << 24);
+    write(ioapic_addr, reg + 1, cpu_id << 24);
+}
+
+// Mask an IRQ, keeping the rest of its redirection entry so that
+// `unmask` restores the same routing and trigger mode.
+pub unsafe fn disable(irq: u32) {
+    let Some(reg) = entry(irq) else {
+        return;
+    };
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    let low = read(ioapic_addr, reg);
+    write(ioapic_addr, reg, low | INT_DISABLED);
+}
+
+// Unmask an IRQ previously masked by `disable`.
+pub unsafe fn unmask(irq: u32) {
+    let Some(reg) = entry(irq) else {
+        return;
+    };
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    let low = read(ioapic_addr, reg);
+    write(ioapic_addr, reg, low & !INT_DISABLED);
+}
+
+// Reroute an IRQ to another CPU. The IRQ is masked while the entry is
+// rewritten so it is never delivered with a half-updated destination.
+pub unsafe fn set_dest(irq: u32, cpu_id: u32) {
+    let Some(reg) = entry(irq) else {
+        return;
+    };
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    let low = read(ioapic_addr, reg);
+    write(ioapic_addr, reg, low | INT_DISABLED);
+    write(ioapic_addr, reg + 1, cpu_id << 24);
+    write(ioapic_addr, reg, low);
+}
+
+// Mask the first disk's IRQ, read from the disk and see its count stay put,
+// then unmask it, read again and see the count move, for make
+// test-irqmask. The boot CPU polls for the reads, so they finish masked too.
+pub fn mask_check() {
+    let Some(irq) = crate::virtio::irqs().next() else {
+        crate::warn!("irqmask: no disk");
+        return;
+    };
+    let count = || {
+        let counts = crate::trap::IRQ_COUNTS.iter();
+        counts
+            .map(|c| c[irq as usize].load(Ordering::Relaxed))
+            .sum::<u64>()
+    };
+    // Read a sector, and give its interrupt a few ticks to come in.
+    let read_and_wait = |before: u64| {
+        let mut buf = [0u8; 512];
+        let _ = crate::bio::read_sectors(crate::bio::DEV_VIRTIO0, 0, &mut buf);
+        let start = crate::trap::ticks();
+        while count() == before && crate::trap::ticks() - start < 10 {
+            core::hint::spin_loop();
+        }
+        count() - before
+    };
+    unsafe { disable(irq) };
+    let masked = read_and_wait(count());
+    unsafe { unmask(irq) };
+    let unmasked = read_and_wait(count());
+    crate::warn!(
+        "irqmask: IRQ {} counted {} masked, {} unmasked",
+        irq,
+        masked,
+        unmasked
+    );
+}
+
+// Route `irqs` round-robin over the started CPUs.
+pub fn balance(irqs: &[u32]) {
+    let mut cpus = [0u32; crate::proc::NCPU];
+    let mut ncpu = 0;
+    unsafe {
+        #[allow(static_mut_refs)]
+        for cpu in crate::proc::CPUS.iter() {
+            if core::ptr::read_volatile(&cpu.started) {
+                cpus[ncpu] = cpu.lapicid;
+                ncpu += 1;
+            }
+        }
+    }
+    if ncpu == 0 {
+        return;
+    }
+    for (i, &irq) in irqs.iter().enumerate() {
+        let cpu = cpus[i % ncpu];
+        unsafe { set_dest(irq, cpu) };
+        crate::info!("IRQ {} -> CPU {}", irq, cpu);
+    }
+}
+
+// Destination APIC ID of an IRQ.
+pub fn dest(irq: u32) -> u32 {
+    let Some(reg) = entry(irq) else {
+        return 0;
+    };
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    unsafe { read(ioapic_addr, reg + 1) >> 24 }
+}
+
+pub fn is_level(irq: u32) -> bool {
+    (irq as usize) < MAX_IRQS && LEVEL_TRIGGERED[irq as usize].load(Ordering::Relaxed)
+}
+
+// Does this IOAPIC have the EOI register needed for directed EOI?
+pub fn has_eoi_register() -> bool {
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    unsafe { read(ioapic_addr, REG_VER) & 0xFF >= 0x20 }
+}
+
+// Clear the Remote IRR bit of a level-triggered IRQ. Needed when the LAPIC
+// suppresses EOI broadcast; otherwise the IRQ is never delivered again.
+pub fn eoi(irq: u32) {
+    if !has_eoi_register() {
+        return;
+    }
+    let ioapic_addr = crate::util::io2v(IOAPIC_ADDR);
+    unsafe {
+        core::ptr::write_volatile((ioapic_addr + IOEOI) as *mut u32, T_IRQ0 + irq);
+    }
 }
 
 unsafe fn read(base: usize, reg: u32) -> u32 {
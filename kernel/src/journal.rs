@@ -0,0 +1,292 @@
+// Write-ahead log of file system blocks, as in xv6.
+//
+// A system call that changes the file system brackets its changes with
+// begin_op() and end_op(), and writes each modified buffer with log_write()
+// instead of bwrite(). log_write() only records the block number and pins
+// the buffer in the cache. When the last outstanding operation ends, the
+// blocks are committed together: copied to the log, made durable by writing
+// the log header with their count and home locations, and left dirty in
+// the cache for their home locations. The checkpoint writes them home and
+// clears the header: from flushd, on fsync, or at the latest when the next
+// operation begins, as the log is then about to be reused. If the system
+// crashes in between, fsinit() finds a non-empty header and installs the
+// logged blocks again, so each group of operations is all or nothing.
+//
+// ext2 has no log area, so the log lives in the blocks of an ordinary file,
+// /.log, written by `make fs`: the first holds the header, the next LOGSIZE
+// hold logged blocks. fsinit() records its inode, and unlink and open for
+// writing refuse it with EPERM, so nothing frees or overwrites those blocks
+// behind the log. Without it, log_write() falls back to bdirty(), and
+// the blocks go home whenever flushd or fsync writes them.
+
+use crate::fs::BSIZE;
+use crate::lockorder::RANK_LOG;
+use crate::spinlock::{Spinlock, SpinlockGuard};
+
+// Most blocks one operation may write. Callers split larger writes.
+pub const MAXOPBLOCKS: usize = 12;
+// Blocks the log holds, so up to three operations can run at once.
+pub const LOGSIZE: usize = 3 * MAXOPBLOCKS;
+
+const LOG_MAGIC: u32 = 0x4c4f_4721; // "!GOL"
+
+// First block of the log. n is only nonzero while a commit is being
+// installed; block[i] is the home of log block i + 1.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LogHeader {
+    magic: u32,
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<LogHeader>() <= BSIZE);
+
+struct Log {
+    enabled: bool,
+    dev: u32,
+    area: [u32; LOGSIZE + 1], // Header block, then the log blocks
+    outstanding: usize,       // Operations between begin_op and end_op
+    committing: bool,         // Or checkpointing
+    n: usize,                 // Blocks logged by the current operations
+    block: [u32; LOGSIZE],
+    checkpoint: bool, // A commit is in the header, not yet checkpointed
+}
+
+static LOG: Spinlock<Log> = Spinlock::ranked(
+    Log {
+        enabled: false,
+        dev: 0,
+        area: [0; LOGSIZE + 1],
+        outstanding: 0,
+        committing: false,
+        n: 0,
+        block: [0; LOGSIZE],
+        checkpoint: false,
+    },
+    "LOG",
+    RANK_LOG,
+);
+
+fn chan() -> usize {
+    core::ptr::addr_of!(LOG) as usize
+}
+
+// Start logging to `area` (the header block and LOGSIZE log blocks) on dev,
+// after installing whatever a crash left committed there.
+pub fn init(dev: u32, area: &[u32; LOGSIZE + 1]) -> Result<(), isize> {
+    let head = read_head(dev, area[0])?;
+    if head.magic == LOG_MAGIC && head.n as usize <= LOGSIZE {
+        if head.n > 0 {
+            recover(dev, area, &head.block[..head.n as usize])?;
+            crate::info!("journal: recovered {} blocks", head.n);
+        }
+    } else {
+        crate::info!("journal: formatting the log");
+    }
+    write_head(dev, area[0], &[])?;
+    let mut log = LOG.lock();
+    log.dev = dev;
+    log.area = *area;
+    log.enabled = true;
+    Ok(())
+}
+
+// Called at the start of each file system operation. Waits while a commit is
+// in progress, or while the log might not have room for this operation.
+// The first operation after a commit checkpoints it first.
+pub fn begin_op() {
+    let mut log = LOG.lock();
+    loop {
+        if !log.enabled {
+            return;
+        }
+        if log.committing || log.n + (log.outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+            crate::proc::sleep(chan(), Some(log));
+            log = LOG.lock();
+        } else if log.outstanding == 0 && log.checkpoint {
+            log = checkpoint_locked(log);
+        } else {
+            log.outstanding += 1;
+            return;
+        }
+    }
+}
+
+// Called at the end of each file system operation. Commits if this was the
+// last one outstanding.
+pub fn end_op() {
+    let mut log = LOG.lock();
+    if !log.enabled {
+        return;
+    }
+    if log.outstanding == 0 || log.committing {
+        panic!("end_op: no operation in progress");
+    }
+    log.outstanding -= 1;
+    if log.outstanding > 0 {
+        // begin_op may be waiting for log space.
+        crate::proc::wakeup(chan());
+        return;
+    }
+    log.committing = true;
+    let (dev, area, n, block) = (log.dev, log.area, log.n, log.block);
+    drop(log);
+
+    // No operation is running, so nothing changes the logged blocks or the
+    // log while it is written without the lock.
+    if n > 0 && commit(dev, &area, &block[..n]).is_err() {
+        crate::error!("journal: commit of {} blocks failed", n);
+    }
+
+    let mut log = LOG.lock();
+    log.n = 0;
+    log.committing = false;
+    log.checkpoint |= n > 0;
+    crate::proc::wakeup(chan());
+}
+
+// Write the blocks of the last commit to their home locations, unless
+// flushd already has, and clear the header so the log can be reused.
+// Waits for the operations in progress to end.
+pub fn checkpoint() {
+    let mut log = LOG.lock();
+    while log.checkpoint && (log.committing || log.outstanding > 0) {
+        crate::proc::sleep(chan(), Some(log));
+        log = LOG.lock();
+    }
+    if log.checkpoint {
+        drop(checkpoint_locked(log));
+    }
+}
+
+// checkpoint, with the log locked and no operation in progress. Until it
+// is done, operations wait as for a commit: none may change a block
+// before it is home.
+fn checkpoint_locked(mut log: SpinlockGuard<'static, Log>) -> SpinlockGuard<'static, Log> {
+    log.committing = true;
+    let (dev, head) = (log.dev, log.area[0]);
+    drop(log);
+
+    if crate::bio::flush(Some(dev))
+        .and_then(|_| write_head(dev, head, &[]))
+        .is_err()
+    {
+        crate::error!("journal: checkpoint failed");
+    }
+
+    let mut log = LOG.lock();
+    log.checkpoint = false;
+    log.committing = false;
+    crate::proc::wakeup(chan());
+    log
+}
+
+// Record that buffer b (from bread) was modified, to be written at commit.
+// Replaces bwrite: the buffer stays pinned in the cache until then. The
+// caller still brelse()s it as usual.
+pub fn log_write(b: usize) -> Result<(), isize> {
+    let (dev, blockno) = {
+        let cache = crate::bio::BCACHE.lock();
+        (cache.bufs[b].dev, cache.bufs[b].blockno)
+    };
+    let mut log = LOG.lock();
+    if !log.enabled || dev != log.dev {
+        drop(log);
+        crate::bio::bdirty(b);
+        return Ok(());
+    }
+    if log.outstanding == 0 {
+        panic!("log_write: outside of an operation");
+    }
+    let n = log.n;
+    if log.block[..n].contains(&blockno) {
+        return Ok(()); // Absorbed: already logged and pinned
+    }
+    if n == LOGSIZE {
+        panic!("log_write: transaction too big");
+    }
+    log.block[n] = blockno;
+    log.n += 1;
+    crate::bio::bpin(b);
+    Ok(())
+}
+
+fn commit(dev: u32, area: &[u32; LOGSIZE + 1], blocks: &[u32]) -> Result<(), isize> {
+    // Copy the modified blocks from the cache to the log, and write them
+    // all at once. No other buffer of dev is dirty: the last commit was
+    // checkpointed before these operations began.
+    for (i, &blockno) in blocks.iter().enumerate() {
+        copy_block(dev, blockno, area[i + 1])?;
+    }
+    crate::bio::flush(Some(dev))?;
+    // The commit point: from here on the operations survive a crash.
+    write_head(dev, area[0], blocks)?;
+    if crate::cmdline::get("journalcrash").is_some() {
+        panic!("journal: crashing after the commit point (journalcrash)");
+    }
+    // Leave the blocks for the checkpoint to write home, and unpin them.
+    for &blockno in blocks {
+        let b = crate::bio::bget(dev, blockno);
+        crate::bio::bdirty(b);
+        crate::bio::bunpin(b);
+        crate::bio::brelse(b);
+    }
+    Ok(())
+}
+
+// Copy the blocks a crash left in the log to their home locations.
+fn recover(dev: u32, area: &[u32; LOGSIZE + 1], blocks: &[u32]) -> Result<(), isize> {
+    for (i, &blockno) in blocks.iter().enumerate() {
+        copy_block(dev, area[i + 1], blockno)?;
+    }
+    crate::bio::flush(Some(dev))
+}
+
+// Copy the contents of block `from`, as cached, to block `to`, leaving it
+// dirty for bio::flush.
+fn copy_block(dev: u32, from: u32, to: u32) -> Result<(), isize> {
+    let src = crate::bio::bread(dev, from)?;
+    let dst = crate::bio::bget(dev, to);
+    {
+        let mut cache = crate::bio::BCACHE.lock();
+        let data = cache.bufs[src].data;
+        cache.bufs[dst].data = data;
+        cache.bufs[dst].valid = true;
+    }
+    crate::bio::bdirty(dst);
+    crate::bio::brelse(dst);
+    crate::bio::brelse(src);
+    Ok(())
+}
+
+fn read_head(dev: u32, blockno: u32) -> Result<LogHeader, isize> {
+    let b = crate::bio::bread(dev, blockno)?;
+    let head = {
+        let cache = crate::bio::BCACHE.lock();
+        let ptr = cache.bufs[b].data.as_ptr() as *const LogHeader;
+        unsafe { core::ptr::read_unaligned(ptr) }
+    };
+    crate::bio::brelse(b);
+    Ok(head)
+}
+
+fn write_head(dev: u32, blockno: u32, blocks: &[u32]) -> Result<(), isize> {
+    let mut head = LogHeader {
+        magic: LOG_MAGIC,
+        n: blocks.len() as u32,
+        block: [0; LOGSIZE],
+    };
+    head.block[..blocks.len()].copy_from_slice(blocks);
+    let b = crate::bio::bget(dev, blockno);
+    {
+        let mut cache = crate::bio::BCACHE.lock();
+        let buf = &mut cache.bufs[b];
+        buf.data.fill(0);
+        unsafe { core::ptr::write_unaligned(buf.data.as_mut_ptr() as *mut LogHeader, head) };
+        buf.valid = true;
+    }
+    let r = crate::bio::bwrite(b);
+    crate::bio::brelse(b);
+    r
+}
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use crate::util::{IRQ_ERROR, IRQ_TIMER, LAPIC_ADDR, T_IRQ0};
+use crate::util::{IRQ_ERROR, IRQ_SPURIOUS, IRQ_TIMER, LAPIC_ADDR, T_IRQ0};
 
 // Local APIC registers
 pub const ID: u32 = 0x0020; // ID
@@ -30,12 +30,22 @@ pub const ICR_BCAST: u32 = 0x00080000;
 
 pub const MASKED: u32 = 0x10000;
 
+const SVR_ENABLE: u32 = 0x100;
+const SVR_SUPPRESS_EOI_BROADCAST: u32 = 0x1000;
+const VER_SUPPRESS_EOI_BROADCAST: u32 = 1 << 24;
+
 pub fn init() {
     let lapic = crate::util::io2v(LAPIC_ADDR);
 
     unsafe {
         // Enable local APIC; set spurious interrupt vector.
-        write(lapic, SVR, 0x100 | (T_IRQ0 + 255));
+        // If both sides support it, level-triggered IRQs get a directed EOI
+        // (see eoi_irq) instead of an EOI broadcast to every IOAPIC.
+        let mut svr = SVR_ENABLE | (T_IRQ0 + IRQ_SPURIOUS);
+        if read(lapic, VER) & VER_SUPPRESS_EOI_BROADCAST != 0 && crate::ioapic::has_eoi_register() {
+            svr |= SVR_SUPPRESS_EOI_BROADCAST;
+        }
+        write(lapic, SVR, svr);
 
         // The timer repeatedly counts down at bus frequency
         // from lapic[TICR] and then issues an interrupt.
@@ -84,6 +94,38 @@ pub fn eoi() {
     }
 }
 
+// Read and clear the Error Status Register.
+pub fn esr() -> u32 {
+    let lapic = crate::util::io2v(LAPIC_ADDR);
+    unsafe {
+        // The ESR is updated by a write before reading.
+        write(lapic, ESR, 0);
+        let esr = read(lapic, ESR);
+        write(lapic, ESR, 0);
+        esr
+    }
+}
+
+// Acknowledge a device IRQ. Edge-triggered IRQs only need the LAPIC EOI;
+// level-triggered ones must also clear Remote IRR in the IOAPIC, or they
+// either stay blocked (directed EOI) or keep re-firing.
+pub fn eoi_irq(irq: u32) {
+    eoi();
+    if crate::ioapic::is_level(irq) && unsafe { read_reg(SVR) } & SVR_SUPPRESS_EOI_BROADCAST != 0 {
+        crate::ioapic::eoi(irq);
+    }
+}
+
+// Send interrupt vector to the CPU with LAPIC ID lapicid.
+pub fn send_ipi(lapicid: u32, vector: u32) {
+    let lapic = crate::util::io2v(LAPIC_ADDR);
+    unsafe {
+        write(lapic, ICRHI, lapicid << 24);
+        write(lapic, ICRLO, vector); // Fixed delivery, physical destination
+        while read(lapic, ICRLO) & ICR_DELIVS != 0 {}
+    }
+}
+
 unsafe fn write(lapic: usize, reg: u32, val: u32) {
     unsafe {
         core::ptr::write_volatile((lapic + reg as usize) as *mut u32, val);
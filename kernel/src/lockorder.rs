@@ -0,0 +1,172 @@
+// Global lock ordering.
+//
+// A lock may only be acquired while every ranked lock already held has a
+// lower rank, so two paths can never wait on each other's locks. Spinlocks
+// are tracked per CPU; sleep-locks are tracked per process, since they stay
+// held across sleep() and may be released on another CPU.
+// Rank 0 means unranked: such locks are not checked.
+//
+// Order, outermost first:
+//   DIRLOCK                       (sleep-lock around directory updates)
+//   mm sleep-lock                 (address space layout; faults page in files)
+//   inode sleep-lock
+//   files                         (a process's fd table)
+//   FTABLE < CONSOLE < pipe       (may sleep, so the process locks come later)
+//   LOG                           (journal state; log_write runs under inode locks)
+//   SB < GDT < BCACHE < ICACHE    (ICACHE is a leaf, so iget() can be called
+//                                  with an inode or buffer held)
+//   DCACHE                        (leaf)
+//   SLEEPLOCK                     (the spinlock inside each sleep-lock)
+//   NET < NIC_RX                  (network stack state; frames received are
+//                                  copied to pages from ALLOCATOR)
+//   ALLOCATOR                     (page faults take it under the above)
+//   VIRTIO_BLK_DRIVER, GPU,       (virtio::init and virtio_gpu::init run with
+//   HVC_RX                         ALLOCATOR held; never two at once)
+//   NIC_TX                        (replies go out while receiving)
+//   TICKS                         (the timer interrupt wakes sleepers under it)
+//   WAIT_LOCK                     (parent links; wait sleeps under it)
+//   process lock                  (sleep/wakeup under any of the above; one
+//                                  at a time)
+//   HVC_TX, UART_TX               (logging may happen anywhere; one or the
+//                                  other, never both)
+// Several inode sleep-locks may be held at once; callers order them
+// parent before child.
+//
+// Checks only run in debug builds (PROFILE=debug).
+
+pub const RANK_NONE: u8 = 0;
+pub const RANK_DIRLOCK: u8 = 5;
+pub const RANK_MM: u8 = 8;
+pub const RANK_INODE: u8 = 10;
+pub const RANK_FILES: u8 = 11;
+pub const RANK_FTABLE: u8 = 12;
+pub const RANK_CONSOLE: u8 = 14;
+pub const RANK_PIPE: u8 = 16;
+pub const RANK_LOG: u8 = 18;
+pub const RANK_SB: u8 = 20;
+pub const RANK_GDT: u8 = 30;
+pub const RANK_BCACHE: u8 = 40;
+pub const RANK_ICACHE: u8 = 50;
+pub const RANK_DCACHE: u8 = 52;
+pub const RANK_SLEEPLOCK: u8 = 55;
+pub const RANK_NET: u8 = 56;
+pub const RANK_NIC_RX: u8 = 57;
+pub const RANK_ALLOCATOR: u8 = 60;
+pub const RANK_VIRTIO: u8 = 65;
+pub const RANK_NIC_TX: u8 = 66;
+pub const RANK_TICKS: u8 = 70;
+pub const RANK_WAIT: u8 = 75;
+pub const RANK_PROC: u8 = 80;
+pub const RANK_HVC_TX: u8 = 85;
+pub const RANK_UART_TX: u8 = 90;
+
+const MAXHELD: usize = 16;
+
+// Stack of ranked locks held by a CPU or a process.
+#[derive(Clone, Copy)]
+pub struct Held {
+    locks: [usize; MAXHELD],
+    ranks: [u8; MAXHELD],
+    names: [&'static str; MAXHELD],
+    n: usize,
+}
+
+impl Held {
+    pub const fn new() -> Self {
+        Self {
+            locks: [0; MAXHELD],
+            ranks: [0; MAXHELD],
+            names: [""; MAXHELD],
+            n: 0,
+        }
+    }
+
+    // Panic if any held lock ranks above `rank` (or at it, unless `allow_equal`).
+    fn check(&self, rank: u8, name: &'static str, allow_equal: bool) {
+        for i in 0..self.n {
+            if self.ranks[i] > rank || (self.ranks[i] == rank && !allow_equal) {
+                panic!(
+                    "lock order: acquiring {} (rank {}) while holding {} (rank {})",
+                    name, rank, self.names[i], self.ranks[i]
+                );
+            }
+        }
+    }
+
+    fn push(&mut self, lock: usize, rank: u8, name: &'static str) {
+        if self.n == MAXHELD {
+            panic!("lock order: too many locks held");
+        }
+        self.locks[self.n] = lock;
+        self.ranks[self.n] = rank;
+        self.names[self.n] = name;
+        self.n += 1;
+    }
+
+    // Guards need not be dropped in LIFO order, so remove by identity.
+    fn pop(&mut self, lock: usize) {
+        if let Some(i) = (0..self.n).rev().find(|&i| self.locks[i] == lock) {
+            for j in i..self.n - 1 {
+                self.locks[j] = self.locks[j + 1];
+                self.ranks[j] = self.ranks[j + 1];
+                self.names[j] = self.names[j + 1];
+            }
+            self.n -= 1;
+        }
+    }
+}
+
+fn current_proc_held() -> Option<&'static mut Held> {
+    crate::proc::myproc().map(|p| unsafe { &mut (*p).held })
+}
+
+// Called with interrupts disabled, before spinning on the lock.
+pub fn acquire_spin(lock: usize, rank: u8, name: &'static str) {
+    if cfg!(debug_assertions) && rank != RANK_NONE {
+        let cpu = crate::proc::mycpu();
+        cpu.held.check(rank, name, false);
+        if let Some(held) = current_proc_held() {
+            held.check(rank, name, false);
+        }
+        cpu.held.push(lock, rank, name);
+    }
+}
+
+pub fn release_spin(lock: usize, rank: u8) {
+    if cfg!(debug_assertions) && rank != RANK_NONE {
+        crate::proc::mycpu().held.pop(lock);
+    }
+}
+
+// Called before waiting for the sleep-lock.
+pub fn acquire_sleep(lock: usize, rank: u8, name: &'static str) {
+    if cfg!(debug_assertions) && rank != RANK_NONE {
+        crate::spinlock::push_cli();
+        crate::proc::mycpu().held.check(rank, name, false);
+        if let Some(held) = current_proc_held() {
+            held.check(rank, name, true);
+            held.push(lock, rank, name);
+        }
+        crate::spinlock::pop_cli();
+    }
+}
+
+pub fn release_sleep(lock: usize, rank: u8) {
+    if cfg!(debug_assertions) && rank != RANK_NONE {
+        crate::spinlock::push_cli();
+        if let Some(held) = current_proc_held() {
+            held.pop(lock);
+        }
+        crate::spinlock::pop_cli();
+    }
+}
+
+// Deliberately take BCACHE then FTABLE, which must panic naming both locks.
+// Booted by `make test-lockorder`.
+#[cfg(feature = "lockorder-selftest")]
+pub fn selftest() {
+    crate::info!("lockorder: taking BCACHE then FTABLE, expecting a panic");
+    let _bcache = crate::bio::BCACHE.lock();
+    let _ftable = crate::file::FTABLE.lock();
+    panic!("lockorder: out-of-order acquisition was not detected");
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
 #[derive(PartialEq, PartialOrd, Copy, Clone)]
 pub enum LogLevel {
     Error = 1,
@@ -21,7 +23,7 @@ impl LogLevel {
 }
 
 // Default to Info if not set
-pub const CURRENT_LOG_LEVEL: LogLevel = {
+pub const DEFAULT_LOG_LEVEL: LogLevel = {
     if let Some(level) = option_env!("LOG_LEVEL") {
         LogLevel::from_str(level)
     } else {
@@ -29,10 +31,28 @@ pub const CURRENT_LOG_LEVEL: LogLevel = {
     }
 };
 
+// Current level; starts at the build-time default and can be changed at boot
+// (loglevel= on the kernel command line).
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LOG_LEVEL as u8);
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        4 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => ({
-        if $crate::log::CURRENT_LOG_LEVEL >= $crate::log::LogLevel::Error {
+        if $crate::log::level() >= $crate::log::LogLevel::Error {
             $crate::uart_println!("\x1b[31m[ERROR]\x1b[0m {}", format_args!($($arg)*));
         }
     });
@@ -41,7 +61,7 @@ macro_rules! error {
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => ({
-        if $crate::log::CURRENT_LOG_LEVEL >= $crate::log::LogLevel::Warn {
+        if $crate::log::level() >= $crate::log::LogLevel::Warn {
             $crate::uart_println!("\x1b[33m[WARN]\x1b[0m {}", format_args!($($arg)*));
         }
     });
@@ -50,7 +70,7 @@ macro_rules! warn {
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => ({
-        if $crate::log::CURRENT_LOG_LEVEL >= $crate::log::LogLevel::Info {
+        if $crate::log::level() >= $crate::log::LogLevel::Info {
             $crate::uart_println!("\x1b[34m[INFO]\x1b[0m {}", format_args!($($arg)*));
         }
     });
@@ -59,7 +79,7 @@ macro_rules! info {
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => ({
-        if $crate::log::CURRENT_LOG_LEVEL >= $crate::log::LogLevel::Debug {
+        if $crate::log::level() >= $crate::log::LogLevel::Debug {
             $crate::uart_println!("\x1b[32m[DEBUG]\x1b[0m {}", format_args!($($arg)*));
         }
     });
@@ -68,7 +88,7 @@ macro_rules! debug {
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)*) => ({
-        if $crate::log::CURRENT_LOG_LEVEL >= $crate::log::LogLevel::Trace {
+        if $crate::log::level() >= $crate::log::LogLevel::Trace {
             $crate::uart_println!("\x1b[90m[TRACE]\x1b[0m {}", format_args!($($arg)*));
         }
     });
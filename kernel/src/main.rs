@@ -5,26 +5,46 @@
 
 mod allocator;
 mod bio;
+mod cmdline;
 mod console;
+mod copybench;
+mod coredump;
+mod e1000;
 mod elf;
 mod exec;
 pub mod file;
+mod fpu;
 pub mod fs;
 mod gdt;
 pub mod growproc;
 mod ioapic;
+mod journal;
 mod lapic;
+mod lockorder;
 mod log;
+mod mm;
+mod mmap;
+mod net;
+mod part;
 mod pci;
 mod pipe;
 mod proc;
+mod ptrace;
+mod ramdisk;
+mod signal;
 mod sleeplock;
 mod spinlock;
+mod symtab;
 mod syscall;
 mod trap;
 mod uart;
 mod util;
 mod virtio;
+mod virtio_console;
+mod virtio_gpu;
+mod virtio_mmio;
+mod virtio_net;
+mod virtio_pci;
 mod vm;
 
 use allocator::Allocator;
@@ -43,12 +63,24 @@ fn kernel_range() -> (usize, usize) {
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn kmain() -> ! {
+pub extern "C" fn kmain(mb_magic: u64, mb_info: u64) -> ! {
+    proc::init_gs(0);
+    cmdline::init(mb_magic, mb_info);
+    ramdisk::init(mb_magic, mb_info);
+    if let Some(level) = cmdline::get("loglevel") {
+        log::set_level(log::LogLevel::from_str(level));
+    }
+
     crate::info!("Hello from tinyos!");
+    crate::info!("Command line: {}", cmdline::as_str());
+    util::detect_cpu();
+    fpu::init();
+    crate::info!("SSE enabled");
 
+    // The boot loader placed the ramdisk image after the kernel; keep it.
     crate::allocator::ALLOCATOR
         .lock()
-        .init(kernel_range().1, p2v(PHYS_MEM));
+        .init(kernel_range().1.max(ramdisk::end()), p2v(PHYS_MEM));
 
     {
         let mut allocator = crate::allocator::ALLOCATOR.lock();
@@ -63,6 +95,7 @@ pub extern "C" fn kmain() -> ! {
     crate::info!("CPUs initialized");
 
     lapic::init();
+    proc::mycpu().started = true;
     crate::info!("LAPIC initialized");
 
     ioapic::init();
@@ -78,47 +111,120 @@ pub extern "C" fn kmain() -> ! {
         ioapic::enable(IRQ_UART, 0);
     }
 
-    syscall::init(0);
+    // console=hvc0 moves the console off the serial line from here on.
+    if let Some(dev) = pci::scan_pci(virtio_console::DEVICE_ID)
+        .or_else(|| pci::scan_pci(virtio_console::TRANSITIONAL_DEVICE_ID))
+    {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        virtio_console::init(&dev, &mut allocator);
+    }
+
+    syscall::init();
     crate::info!("Syscalls initialized");
 
     bio::binit();
     crate::info!("Buffer cache initialized");
 
+    if cmdline::get("copybench").is_some() {
+        copybench::run();
+    }
+    if cmdline::get("spurious").is_some() {
+        trap::spurious_check();
+    }
+
+    #[cfg(feature = "lockorder-selftest")]
+    lockorder::selftest();
+
     {
         let mut allocator = crate::allocator::ALLOCATOR.lock();
         proc::init_process(&mut allocator);
+        if cmdline::get("noflushd").is_none() {
+            proc::spawn_kthread(&mut allocator, "flushd", bio::flushd);
+        }
     }
     crate::info!("Init process initialized");
 
-    let device = pci::scan_pci(virtio::VIRTIO_LEGACY_DEVICE_ID);
-    if let Some(dev) = device {
-        crate::info!("Device found, initializing virtio (legacy)...");
-        // Initialize Virtio
-        unsafe {
+    // The disks: virtio0 and on, in slot order, or one on virtio-mmio.
+    let ids = [
+        virtio::VIRTIO_LEGACY_DEVICE_ID,
+        virtio::VIRTIO_MODERN_DEVICE_ID,
+    ];
+    let mut ndisk = 0;
+    for dev in pci::scan_pci_all(&ids).take(virtio::NDISK) {
+        crate::info!("Device found, initializing virtio{}...", ndisk);
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        unsafe { virtio::init(ndisk, &dev, &mut allocator) };
+        ndisk += 1;
+    }
+    if ndisk == 0 {
+        if let Some(transport) = virtio_mmio::find(virtio_mmio::DEVICE_BLK) {
             let mut allocator = crate::allocator::ALLOCATOR.lock();
-            virtio::init(&dev, &mut allocator);
+            unsafe { virtio::init_mmio(0, transport, &mut allocator) };
         }
-
-        // Enable Virtio IRQ (11) on CPU 0
-        unsafe {
-            ioapic::enable(IRQ_VIRTIO, 0);
+    }
+    if virtio::irqs().next().is_some() {
+        // Enable the disks' IRQs (11 for slot 3 on PCI) on CPU 0. A PCI
+        // INTx line is level-triggered, and so is virtio-mmio's.
+        for irq in virtio::irqs() {
+            unsafe { ioapic::enable_level(irq, 0) };
         }
 
         // Enable Interrupts
         unsafe { core::arch::asm!("sti") };
+    }
 
-        // Initialize Filesystem
-        fs::fsinit(1);
-        crate::info!("Filesystem initialized");
+    if let Some(dev) = pci::scan_pci(virtio_gpu::DEVICE_ID) {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        virtio_gpu::init(&dev, &mut allocator);
+    }
+    net::init();
+
+    for dev in bio::disks().filter(|&dev| bio::dev_present(dev)) {
+        part::scan(dev);
+    }
+
+    // Mount root= if given. Otherwise try virtio0 if present, then the
+    // ramdisk, and use the first that holds a valid filesystem: the disk
+    // itself, or if it has partitions each of them in turn.
+    let mounted = match cmdline::get("root") {
+        Some(name) => bio::dev_by_name(name)
+            .filter(|&dev| bio::dev_present(dev))
+            .map(mount_root)
+            .unwrap_or_else(|| panic!("root={}: no such device", name)),
+        None => [bio::DEV_VIRTIO0, bio::DEV_RAMDISK]
+            .into_iter()
+            .filter(|&dev| bio::dev_present(dev))
+            .flat_map(|dev| {
+                let whole = part::of(dev).next().is_none().then_some(dev);
+                part::of(dev).chain(whole)
+            })
+            .any(mount_root),
+    };
+    if !mounted {
+        crate::error!("No valid filesystem found; continuing without a root filesystem");
     }
 
     // Enable interrupts
     unsafe {
         core::arch::asm!("sti");
     }
+    if cmdline::get("irqmask").is_some() {
+        ioapic::mask_check();
+    }
 
     start_aps();
 
+    // Spread device IRQs over the CPUs that came up, each disk line once.
+    let mut irqs = [IRQ_UART; 1 + virtio::NDISK];
+    let mut n = 1;
+    for irq in virtio::irqs() {
+        if !irqs[..n].contains(&irq) {
+            irqs[n] = irq;
+            n += 1;
+        }
+    }
+    ioapic::balance(&irqs[..n]);
+
     crate::debug!("DEBUG: kernel initialized");
 
     proc::scheduler();
@@ -130,6 +236,14 @@ pub extern "C" fn kmain() -> ! {
     }
 }
 
+fn mount_root(dev: u32) -> bool {
+    let ok = fs::fsinit(dev).is_ok();
+    if ok {
+        crate::info!("Filesystem initialized on {}", bio::dev_name(dev));
+    }
+    ok
+}
+
 fn start_aps() {
     crate::info!("Starting APs...");
     let entry_code = include_bytes!("../asm/build/entryother");
@@ -182,9 +296,17 @@ fn start_aps() {
             }
         }
 
-        // Wait for CPU to start?
-        // We can check proc::CPUS[i].started if we had it exposed.
-        // For now just wait and hope.
+        // Wait (up to ~100ms) for the CPU to initialize its LAPIC, so that
+        // IRQ balancing only targets CPUs that can take interrupts.
+        for _ in 0..1000 {
+            if unsafe { core::ptr::read_volatile(&raw const proc::CPUS[i].started) } {
+                break;
+            }
+            unsafe { util::micro_delay(100) };
+        }
+        if !unsafe { core::ptr::read_volatile(&raw const proc::CPUS[i].started) } {
+            crate::warn!("CPU {} did not start", i);
+        }
     }
 }
 
@@ -193,6 +315,7 @@ pub extern "C" fn mpenter() -> ! {
     // AP Entry Point
     // Get CPUID first
     let cpuid = crate::lapic::id() as usize;
+    crate::proc::init_gs(cpuid);
 
     // 1. Enable paging (already done in entryother)
     // 2. Load GDT (per-CPU)
@@ -201,17 +324,19 @@ pub extern "C" fn mpenter() -> ! {
     // 3. Init LAPIC
     crate::lapic::init();
 
-    // 4. Init Traps (IDT)
+    // 4. Enable SSE
+    crate::fpu::init();
+
+    // 5. Init Traps (IDT)
     crate::trap::init();
 
-    // 5. Init Syscall (MSRs)
-    crate::syscall::init(cpuid);
+    // 6. Init Syscall (MSRs)
+    crate::syscall::init();
 
     crate::info!("CPU {} started!", cpuid);
 
     // Mark started
-    // unsafe { proc::CPUS[cpuid as usize].started = true; }
-    // We need to access CPUS.
+    unsafe { core::ptr::write_volatile(&raw mut crate::proc::mycpu().started, true) };
 
     crate::proc::scheduler();
 
@@ -222,6 +347,9 @@ pub extern "C" fn mpenter() -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    uart_println!("panicked: {}", info.message());
+    // Write to the UART directly: the panic may come from a CPU holding UART_TX.
+    use core::fmt::Write;
+    unsafe { core::arch::asm!("cli") };
+    let _ = write!(uart::Uart, "panicked: {}\r\n", info.message());
     loop {}
 }
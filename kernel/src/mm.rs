@@ -0,0 +1,86 @@
+// Address spaces. A process runs in an Mm: its page table and where things
+// lie in it. fork gives the child a copy; clone shares the caller's, so that
+// threads see each other's heap, mappings and stack growth.
+//
+// Changes to the layout (sbrk, mmap, munmap, stack growth and faults taken in
+// user mode) are made holding the Mm's sleep-lock. A fault the kernel takes
+// in copyin or copyout may already hold an inode lock, which ranks above it,
+// so it goes without: such faults only ever add zeroed anonymous pages, and
+// map_pages refuses a page another thread has mapped meanwhile.
+
+use crate::allocator::Allocator;
+use crate::mmap::{Vma, NVMA};
+use crate::proc::{Users, NPROC};
+use crate::sleeplock::{SleepLockGuard, SleepLockSafe};
+use crate::vm::{self, PageTable};
+
+pub struct Mm {
+    pub pgdir: *mut PageTable,
+    pub sz: usize,         // Size of text, data and heap: [0, sz)
+    pub heap_start: usize, // Heap: [heap_start, sz), paged in on use
+    pub ustack: usize,     // User stack: [ustack, exec::USTACK_TOP)
+    pub vmas: [Vma; NVMA], // Made by mmap
+    lock: SleepLockSafe<()>,
+    users: Users,
+}
+
+// Every process has one Mm at most, so NPROC of them are enough.
+static mut MMS: [Mm; NPROC] = [const { Mm::new() }; NPROC];
+
+impl Mm {
+    const fn new() -> Self {
+        Self {
+            pgdir: core::ptr::null_mut(),
+            sz: 0,
+            heap_start: 0,
+            ustack: 0,
+            vmas: [Vma::new(); NVMA],
+            lock: SleepLockSafe::ranked((), "mm", crate::lockorder::RANK_MM),
+            users: Users::new(),
+        }
+    }
+
+    // Every Mm lives in MMS, so the guard need not borrow it: the holder
+    // goes on to change the fields the lock covers.
+    pub fn lock(&self) -> SleepLockGuard<'static, ()> {
+        unsafe { &*(&self.lock as *const SleepLockSafe<()>) }.lock()
+    }
+
+    // Another process, made by clone, runs in this Mm.
+    pub fn share(&self) {
+        self.users.get();
+    }
+}
+
+// A new Mm with an empty page table, or None if out of memory.
+pub fn alloc(allocator: &mut Allocator) -> Option<&'static mut Mm> {
+    #[allow(static_mut_refs)]
+    let mm = unsafe { MMS.iter_mut().find(|mm| mm.users.claim())? };
+    match vm::uvm_create(allocator) {
+        Some(pgdir) => {
+            mm.pgdir = pgdir;
+            Some(mm)
+        }
+        None => {
+            mm.users.freed();
+            None
+        }
+    }
+}
+
+// Let go of mm. The last user unmaps its mappings, writing back shared file
+// pages, and frees its page table, which no CPU may be running in any more:
+// it is called by the parent reaping a process, and by exec once switched
+// to the new image.
+pub fn put(mm: &mut Mm) {
+    if !mm.users.put() {
+        return;
+    }
+    crate::mmap::unmap_all(mm);
+    vm::uvm_free(mm.pgdir, &mut crate::allocator::ALLOCATOR.lock());
+    mm.pgdir = core::ptr::null_mut();
+    mm.sz = 0;
+    mm.heap_start = 0;
+    mm.ustack = 0;
+    mm.users.freed();
+}
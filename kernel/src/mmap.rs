@@ -0,0 +1,354 @@
+// Memory mappings made by mmap: see abi::mman.
+//
+// A mapping only reserves an address range, in a Vma of the Mm; its
+// pages are allocated and, for a file, read in when first touched, by the
+// page-fault handler. Mappings are placed top-down from MMAP_TOP, above the
+// heap. Dirty pages of a MAP_SHARED file mapping are written back when they
+// are unmapped, which munmap does, and mm::put for the whole address space.
+//
+// The pages of a MAP_SHARED mapping are marked SHARED in the page table, so
+// that fork maps them in the child too rather than copying them. Those of an
+// anonymous one are allocated by mmap itself: a page first touched after a
+// fork would be a different one in each process.
+
+use crate::file::{File, FileType};
+use crate::mm::Mm;
+use crate::proc::myproc;
+use crate::util::{p2v, v2p, PG_SIZE};
+use crate::vm::{self, PageTableEntry};
+use abi::mman::*;
+use abi::syscall::{EACCES, EINVAL, ENODEV, ENOMEM};
+
+pub const NVMA: usize = 16; // Mappings per address space
+
+// Mappings end below the lowest address the stack may grow to, leaving a
+// guard page.
+pub const MMAP_TOP: u64 = crate::exec::USTACK_TOP - crate::exec::USTACK_MAX as u64 - PG_SIZE as u64;
+
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: u64, // Page-aligned; start == end in an unused slot
+    pub end: u64,
+    pub prot: u32,
+    pub flags: u32,
+    pub file: Option<*mut File>, // Referenced; None for MAP_ANONYMOUS
+    pub off: u64,                // File offset of start
+}
+
+impl Vma {
+    pub const fn new() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            prot: 0,
+            flags: 0,
+            file: None,
+            off: 0,
+        }
+    }
+
+    fn used(&self) -> bool {
+        self.start < self.end
+    }
+}
+
+// The lowest mapped address, or MMAP_TOP: the heap must stay a page below.
+pub fn lowest(mm: &Mm) -> u64 {
+    mm.vmas
+        .iter()
+        .filter(|v| v.used())
+        .map(|v| v.start)
+        .fold(MMAP_TOP, u64::min)
+}
+
+// mmap(addr, len, prot, flags, fd, off): addr is only a hint, and ignored.
+// Returns the start of the mapping.
+pub fn mmap(len: u64, prot: u32, flags: u32, fd: usize, off: u64) -> isize {
+    let p = unsafe { &*myproc().unwrap() };
+    let kind = flags & (MAP_SHARED | MAP_PRIVATE);
+    if len == 0
+        || !off.is_multiple_of(PG_SIZE as u64)
+        || flags & !(MAP_SHARED | MAP_PRIVATE | MAP_ANONYMOUS) != 0
+        || (kind != MAP_SHARED && kind != MAP_PRIVATE)
+        || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+    {
+        return -EINVAL;
+    }
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        let Some(f) = p.files().get(fd) else {
+            return -1;
+        };
+        let file = unsafe { &*f };
+        if file.f_type != FileType::Inode {
+            return -ENODEV;
+        }
+        if !file.readable || (kind == MAP_SHARED && prot & PROT_WRITE != 0 && !file.writable) {
+            return -EACCES;
+        }
+        Some(f)
+    };
+    let Some(len) = len.checked_next_multiple_of(PG_SIZE as u64) else {
+        return -ENOMEM;
+    };
+    let mm = p.mm();
+    let _guard = mm.lock();
+    let Some(slot) = mm.vmas.iter().position(|v| !v.used()) else {
+        return -ENOMEM;
+    };
+    let Some(start) = find_gap(mm, len) else {
+        return -ENOMEM;
+    };
+    if let Some(f) = file {
+        unsafe { crate::file::filedup(&mut *f) };
+    }
+    mm.vmas[slot] = Vma {
+        start,
+        end: start + len,
+        prot,
+        flags,
+        file,
+        off,
+    };
+    if kind == MAP_SHARED && file.is_none() && prot != PROT_NONE && populate(mm, slot).is_err() {
+        let v = mm.vmas[slot];
+        unmap_pages(mm, &v, v.start, v.end);
+        release(&mut mm.vmas[slot]);
+        return -ENOMEM;
+    }
+    start as isize
+}
+
+// Map every page of mapping mm.vmas[slot], zeroed.
+fn populate(mm: &mut Mm, slot: usize) -> Result<(), ()> {
+    let v = mm.vmas[slot];
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    for va in (v.start..v.end).step_by(PG_SIZE) {
+        let mem = allocator.kalloc();
+        if mem.is_null() {
+            return Err(());
+        }
+        if !vm::map_pages(
+            mm.pgdir,
+            &mut allocator,
+            va,
+            v2p(mem as usize) as u64,
+            PG_SIZE as u64,
+            perm(&v),
+        ) {
+            allocator.kfree(mem as usize);
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+fn perm(v: &Vma) -> u64 {
+    let mut perm = PageTableEntry::USER;
+    if v.prot & PROT_WRITE != 0 {
+        perm |= PageTableEntry::WRITABLE;
+    }
+    if v.flags & MAP_SHARED != 0 {
+        perm |= PageTableEntry::SHARED;
+    }
+    perm
+}
+
+// The highest free range of len bytes below MMAP_TOP, above the heap and a
+// guard page.
+fn find_gap(mm: &Mm, len: u64) -> Option<u64> {
+    let heap_end = (mm.sz as u64).next_multiple_of(PG_SIZE as u64) + PG_SIZE as u64;
+    let mut end = MMAP_TOP;
+    loop {
+        let start = end.checked_sub(len).filter(|&s| s >= heap_end)?;
+        match mm
+            .vmas
+            .iter()
+            .find(|v| v.used() && v.start < end && start < v.end)
+        {
+            Some(v) => end = v.start,
+            None => return Some(start),
+        }
+    }
+}
+
+// Map the page holding addr, a fault in one of mm's mappings. Fails for an
+// address outside them, PROT_NONE, or a page already there (a write to a
+// read-only one). A file page is only read in for a fault in user mode: the
+// kernel may fault holding the lock of an inode, such as in write(), and
+// user code must touch such a page first.
+pub fn fault(mm: &mut Mm, addr: u64, user: bool) -> Result<(), ()> {
+    let v = *mm
+        .vmas
+        .iter()
+        .find(|v| v.used() && v.start <= addr && addr < v.end)
+        .ok_or(())?;
+    if v.prot == PROT_NONE || (v.file.is_some() && !user) {
+        return Err(());
+    }
+    let va = vm::pgrounddown(addr);
+    let mem = {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        if vm::is_mapped(mm.pgdir, &mut allocator, va) {
+            return Err(());
+        }
+        allocator.kalloc()
+    };
+    if mem.is_null() {
+        return Err(());
+    }
+    // kalloc zeroes the page, which is what is past the end of the file.
+    if let Some(f) = v.file {
+        let ip = unsafe { (*f).ip.unwrap() };
+        let off = v.off + (va - v.start);
+        let read = u32::try_from(off)
+            .map_err(|_| -1)
+            .and_then(|off| crate::fs::readi(ip, mem, off, PG_SIZE as u32));
+        if read.is_err() {
+            crate::allocator::ALLOCATOR.lock().kfree(mem as usize);
+            return Err(());
+        }
+    }
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    // Another thread faulting in the kernel, without the Mm lock, may have
+    // mapped the page meanwhile.
+    if vm::is_mapped(mm.pgdir, &mut allocator, va) {
+        allocator.kfree(mem as usize);
+        return Ok(());
+    }
+    if !vm::map_pages(
+        mm.pgdir,
+        &mut allocator,
+        va,
+        v2p(mem as usize) as u64,
+        PG_SIZE as u64,
+        perm(&v),
+    ) {
+        allocator.kfree(mem as usize);
+        return Err(());
+    }
+    Ok(())
+}
+
+// munmap(addr, len): remove whatever is mapped in [addr, addr + len). A
+// mapping partly inside keeps the rest, split in two if need be.
+pub fn munmap(addr: u64, len: u64) -> isize {
+    let mm = unsafe { (*myproc().unwrap()).mm() };
+    if len == 0 || !addr.is_multiple_of(PG_SIZE as u64) {
+        return -EINVAL;
+    }
+    let Some(end) = len
+        .checked_next_multiple_of(PG_SIZE as u64)
+        .and_then(|len| addr.checked_add(len))
+    else {
+        return -EINVAL;
+    };
+    let _guard = mm.lock();
+    // A hole punched in a mapping leaves two; check first that there is room.
+    let splits = mm
+        .vmas
+        .iter()
+        .filter(|v| v.used() && v.start < addr && end < v.end)
+        .count();
+    if splits > mm.vmas.iter().filter(|v| !v.used()).count() {
+        return -ENOMEM;
+    }
+    for i in 0..NVMA {
+        let v = mm.vmas[i];
+        if !v.used() || end <= v.start || v.end <= addr {
+            continue;
+        }
+        let (lo, hi) = (addr.max(v.start), end.min(v.end));
+        unmap_pages(mm, &v, lo, hi);
+        if lo == v.start && hi == v.end {
+            release(&mut mm.vmas[i]);
+        } else if lo == v.start {
+            mm.vmas[i].start = hi;
+            mm.vmas[i].off += hi - v.start;
+        } else if hi == v.end {
+            mm.vmas[i].end = lo;
+        } else {
+            let slot = mm.vmas.iter().position(|v| !v.used()).unwrap();
+            if let Some(f) = v.file {
+                unsafe { crate::file::filedup(&mut *f) };
+            }
+            mm.vmas[slot] = Vma {
+                start: hi,
+                off: v.off + (hi - v.start),
+                ..v
+            };
+            mm.vmas[i].end = lo;
+        }
+    }
+    0
+}
+
+// Remove all of mm's mappings, before its page table goes: see mm::put.
+pub fn unmap_all(mm: &mut Mm) {
+    for i in 0..NVMA {
+        let v = mm.vmas[i];
+        if v.used() {
+            unmap_pages(mm, &v, v.start, v.end);
+            release(&mut mm.vmas[i]);
+        }
+    }
+}
+
+// Give the child of fork its own references to the parent's mappings. The
+// pages themselves were copied with the rest of the address space.
+pub fn fork_copy(new: &mut Mm, mm: &Mm) {
+    for v in mm.vmas.iter().filter(|v| v.used()) {
+        if let Some(f) = v.file {
+            unsafe { crate::file::filedup(&mut *f) };
+        }
+    }
+    new.vmas = mm.vmas;
+}
+
+fn release(v: &mut Vma) {
+    if let Some(f) = v.file {
+        unsafe { crate::file::fileclose(&mut *f) };
+    }
+    *v = Vma::new();
+}
+
+// Free the pages of [lo, hi) in mapping v, writing back dirty shared ones.
+fn unmap_pages(mm: &Mm, v: &Vma, lo: u64, hi: u64) {
+    if v.flags & MAP_SHARED != 0 && v.file.is_some() {
+        let mut va = lo;
+        while va < hi {
+            let pte = {
+                let mut allocator = crate::allocator::ALLOCATOR.lock();
+                vm::walk(mm.pgdir, &mut allocator, va, false, 0)
+                    .filter(|pte| pte.is_present())
+                    .map(|pte| *pte)
+            };
+            if let Some(pte) = pte.filter(|pte| pte.flags() & PageTableEntry::DIRTY != 0) {
+                writeback(v, va, p2v(pte.addr() as usize) as *const u8);
+            }
+            va += PG_SIZE as u64;
+        }
+    }
+    vm::uvm_unmap(mm.pgdir, &mut crate::allocator::ALLOCATOR.lock(), lo, hi);
+    vm::flush_unmapped(mm.pgdir, lo, hi);
+}
+
+// Write the page at va of mapping v back to its file, up to the end of the
+// file: a mapping does not extend it.
+fn writeback(v: &Vma, va: u64, src: *const u8) {
+    let ip = unsafe { (*v.file.unwrap()).ip.unwrap() };
+    let size = match ip.ilock() {
+        Ok(di) => di.i_size as u64,
+        Err(_) => return,
+    };
+    let off = v.off + (va - v.start);
+    if off >= size {
+        return;
+    }
+    let n = (size - off).min(PG_SIZE as u64);
+    let src = unsafe { core::slice::from_raw_parts(src, n as usize) };
+    if crate::fs::writei_logged(ip, src, off as u32) != Ok(n as u32) {
+        crate::error!("mmap: writeback at offset {} failed", off);
+    }
+}
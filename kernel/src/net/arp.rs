@@ -0,0 +1,113 @@
+// ARP: which MAC address an IPv4 address on the local network has. Answers
+// are learnt into a small cache, which a full one overwrites round-robin;
+// entries do not expire.
+
+use super::{be16, be32, eth, put16, put32, Net, NET};
+use abi::syscall::{EHOSTUNREACH, EINTR};
+
+const NENTRY: usize = 16;
+const LEN: usize = 28; // An Ethernet/IPv4 ARP packet
+
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+const TRIES: usize = 3; // Requests sent by resolve()
+const WAIT_TICKS: usize = 10; // For an answer to each
+
+pub struct Cache {
+    entries: [(u32, [u8; 6]); NENTRY], // IP 0: unused
+    next: usize,                       // Entry to overwrite next
+}
+
+impl Cache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [(0, [0; 6]); NENTRY],
+            next: 0,
+        }
+    }
+
+    pub fn lookup(&self, ip: u32) -> Option<[u8; 6]> {
+        self.entries
+            .iter()
+            .find(|e| e.0 == ip && ip != 0)
+            .map(|e| e.1)
+    }
+
+    pub fn insert(&mut self, ip: u32, mac: [u8; 6]) {
+        if let Some(e) = self.entries.iter_mut().find(|e| e.0 == ip) {
+            e.1 = mac;
+            return;
+        }
+        self.entries[self.next] = (ip, mac);
+        self.next = (self.next + 1) % NENTRY;
+    }
+}
+
+pub fn input(net: &mut Net, pkt: &[u8]) {
+    // Ethernet and IPv4 only: hardware type 1, protocol 0x0800, 6 and 4
+    // byte addresses.
+    if pkt.len() < LEN || pkt[0..6] != [0, 1, 8, 0, 6, 4] {
+        return;
+    }
+    let op = be16(pkt, 6);
+    let mut sha = [0; 6];
+    sha.copy_from_slice(&pkt[8..14]);
+    let spa = be32(pkt, 14);
+    let tpa = be32(pkt, 24);
+
+    // Learn the sender if it asks for us or is known already.
+    if spa != 0 && (tpa == net.ip || net.arp.lookup(spa).is_some()) {
+        net.arp.insert(spa, sha);
+    }
+    if op == OP_REQUEST && tpa == net.ip && net.ip != 0 {
+        let _ = send(net, OP_REPLY, sha, spa);
+    }
+}
+
+// Ask who has ip.
+pub fn request(net: &mut Net, ip: u32) {
+    let _ = send(net, OP_REQUEST, [0; 6], ip);
+}
+
+fn send(net: &mut Net, op: u16, tha: [u8; 6], tpa: u32) -> Result<(), ()> {
+    let (mac, ip) = (net.mac, net.ip);
+    let dst = if op == OP_REQUEST {
+        eth::BROADCAST
+    } else {
+        tha
+    };
+    eth::output(net, dst, eth::TYPE_ARP, |buf| {
+        buf[0..6].copy_from_slice(&[0, 1, 8, 0, 6, 4]);
+        put16(buf, 6, op);
+        buf[8..14].copy_from_slice(&mac);
+        put32(buf, 14, ip);
+        buf[18..24].copy_from_slice(&tha);
+        put32(buf, 24, tpa);
+        LEN
+    })
+}
+
+// The MAC address of ip, asking for it if it is not cached. Sleeps, so
+// must be called without NET held. Fails with -EHOSTUNREACH if nobody
+// answers, or -EINTR if the process is killed.
+pub fn resolve(ip: u32) -> Result<[u8; 6], isize> {
+    for _ in 0..TRIES {
+        {
+            let mut net = NET.lock();
+            if let Some(mac) = net.arp.lookup(ip) {
+                return Ok(mac);
+            }
+            request(&mut net, ip);
+        }
+        for _ in 0..WAIT_TICKS {
+            if !super::wait_tick() {
+                return Err(-EINTR);
+            }
+            if let Some(mac) = NET.lock().arp.lookup(ip) {
+                return Ok(mac);
+            }
+        }
+    }
+    Err(-EHOSTUNREACH)
+}
@@ -0,0 +1,271 @@
+// DHCP client: the interface's address, mask, gateway and DNS server come
+// from a DHCP server, QEMU's under user-mode networking. Run from poll()
+// like TCP's timers: DISCOVER is broadcast until an OFFER comes, then the
+// offer is REQUESTed until it is ACKed.
+//
+// Half way through the lease, the address is asked for again, by
+// broadcast with ciaddr set; when the lease runs out before an ACK, or a
+// NAK comes, the address is dropped and the search starts over. Until
+// there is an address the host takes every datagram (see ip::input).
+
+use super::{be32, eth, ip, put16, put32, udp, Ipv4, Net};
+
+pub const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+const BROADCAST: u32 = 0xFFFF_FFFF;
+
+// The fixed part of a message, up to the options, and the length sent:
+// padded like BOOTP's, which some servers (QEMU's) insist on.
+const FIXED: usize = 240;
+const LEN: usize = 548;
+const MAGIC: u32 = 0x6382_5363;
+const FLAG_BROADCAST: u16 = 0x8000; // Answer by broadcast: no address yet
+
+// Message types (option 53)
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+// Options
+const OPT_PAD: u8 = 0;
+const OPT_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE: u8 = 51;
+const OPT_TYPE: u8 = 53;
+const OPT_SERVER: u8 = 54;
+const OPT_PARAMS: u8 = 55;
+const OPT_END: u8 = 255;
+
+// In ticks
+const HZ: u64 = 100;
+const RETRY: u64 = 2 * HZ; // Doubled for every try, up to RETRY << 4
+const REQUEST_TRIES: u32 = 4; // Before going back to DISCOVER
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Off,        // No interface
+    Selecting,  // DISCOVER sent, waiting for an OFFER
+    Requesting, // REQUEST for an offer sent, waiting for an ACK
+    Bound,      // Has the address until lease_end
+    Renewing,   // REQUEST for it again sent
+}
+
+pub struct Client {
+    state: State,
+    xid: u32, // Transaction ID of the messages sent
+    offered: u32,
+    server: u32,    // Server identifier of the offer
+    timer: u64,     // When to send again, or to renew
+    tries: u32,     // Sent since the last change of state
+    lease_end: u64, // When Bound or Renewing
+    now: u64,       // Tick of the last timer()
+}
+
+impl Client {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Off,
+            xid: 0,
+            offered: 0,
+            server: 0,
+            timer: 0,
+            tries: 0,
+            lease_end: 0,
+            now: 0,
+        }
+    }
+
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.timer = self.now;
+        self.tries = 0;
+    }
+}
+
+// Start looking for an address, once the interface is up.
+pub fn start(net: &mut Net) {
+    let m = net.mac;
+    net.dhcp.xid = u32::from_be_bytes([m[2], m[3], m[4], m[5]]) ^ net.dhcp.now as u32;
+    net.dhcp.enter(State::Selecting);
+}
+
+pub fn timer(net: &mut Net, now: u64) {
+    net.dhcp.now = now;
+    let c = &mut net.dhcp;
+    match c.state {
+        State::Off => return,
+        State::Bound if now >= c.timer => c.enter(State::Renewing),
+        State::Renewing if now >= c.lease_end => {
+            crate::warn!("dhcp: lease on {} ran out", Ipv4(net.ip));
+            unconfigure(net);
+            net.dhcp.enter(State::Selecting);
+        }
+        State::Requesting if c.tries == REQUEST_TRIES && now >= c.timer => {
+            c.enter(State::Selecting)
+        }
+        _ => {}
+    }
+    let c = &mut net.dhcp;
+    if c.state == State::Bound || now < c.timer {
+        return;
+    }
+    c.timer = now + (RETRY << c.tries.min(4));
+    c.tries += 1;
+    let ty = if c.state == State::Selecting {
+        DISCOVER
+    } else {
+        REQUEST
+    };
+    send(net, ty);
+}
+
+// Build and broadcast a message of type ty for the current state.
+fn send(net: &mut Net, ty: u8) {
+    let c = &net.dhcp;
+    let (xid, mac, ciaddr) = (c.xid, net.mac, net.ip);
+    // A REQUEST for an offer names it and its server; one to renew the
+    // address has it in ciaddr instead.
+    let offer = (c.state == State::Requesting).then_some((c.offered, c.server));
+    let mut msg = [0u8; LEN];
+    msg[0] = 1; // BOOTREQUEST
+    msg[1] = 1; // Ethernet
+    msg[2] = 6;
+    put32(&mut msg, 4, xid);
+    put16(&mut msg, 10, if ciaddr == 0 { FLAG_BROADCAST } else { 0 });
+    put32(&mut msg, 12, ciaddr);
+    msg[28..34].copy_from_slice(&mac);
+    put32(&mut msg, 236, MAGIC);
+    let mut opts = FIXED;
+    let mut opt = |code: u8, data: &[u8]| {
+        msg[opts] = code;
+        msg[opts + 1] = data.len() as u8;
+        msg[opts + 2..opts + 2 + data.len()].copy_from_slice(data);
+        opts += 2 + data.len();
+    };
+    opt(OPT_TYPE, &[ty]);
+    if let Some((offered, server)) = offer {
+        opt(OPT_REQUESTED_IP, &offered.to_be_bytes());
+        opt(OPT_SERVER, &server.to_be_bytes());
+    }
+    opt(OPT_PARAMS, &[OPT_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE]);
+    msg[opts] = OPT_END;
+    let route = ip::Route::Mac(eth::BROADCAST);
+    let _ = udp::output(net, route, CLIENT_PORT, BROADCAST, SERVER_PORT, &msg);
+}
+
+// What a message from a server says
+#[derive(Default)]
+struct Reply {
+    ty: u8,
+    server: u32,
+    mask: u32,
+    router: u32,
+    dns: u32,
+    lease: u32, // Seconds
+}
+
+fn parse(mut opts: &[u8]) -> Reply {
+    let mut r = Reply::default();
+    while let [code, rest @ ..] = opts {
+        match *code {
+            OPT_END => break,
+            OPT_PAD => {
+                opts = rest;
+                continue;
+            }
+            _ => {}
+        }
+        let Some((&len, rest)) = rest.split_first() else {
+            break;
+        };
+        let Some(data) = rest.get(..len as usize) else {
+            break;
+        };
+        let word = if data.len() >= 4 { be32(data, 0) } else { 0 };
+        match *code {
+            OPT_TYPE if len == 1 => r.ty = data[0],
+            OPT_SERVER => r.server = word,
+            OPT_MASK => r.mask = word,
+            OPT_ROUTER => r.router = word, // The first of the list
+            OPT_DNS => r.dns = word,
+            OPT_LEASE => r.lease = word,
+            _ => {}
+        }
+        opts = &rest[len as usize..];
+    }
+    r
+}
+
+// A datagram to CLIENT_PORT.
+pub fn input(net: &mut Net, msg: &[u8]) {
+    let c = &net.dhcp;
+    if msg.len() < FIXED
+        || msg[0] != 2 // BOOTREPLY
+        || be32(msg, 4) != c.xid
+        || msg[28..34] != net.mac
+        || be32(msg, 236) != MAGIC
+        || matches!(c.state, State::Off | State::Bound)
+    {
+        return;
+    }
+    let yiaddr = be32(msg, 16);
+    let r = parse(&msg[FIXED..]);
+    match (c.state, r.ty) {
+        (State::Selecting, OFFER) if yiaddr != 0 && r.server != 0 => {
+            net.dhcp.offered = yiaddr;
+            net.dhcp.server = r.server;
+            net.dhcp.enter(State::Requesting);
+            timer(net, net.dhcp.now);
+        }
+        (State::Requesting | State::Renewing, ACK) if yiaddr != 0 => configure(net, yiaddr, &r),
+        (State::Requesting | State::Renewing, NAK) => {
+            crate::warn!("dhcp: server refused {}", Ipv4(net.dhcp.offered));
+            unconfigure(net);
+            net.dhcp.enter(State::Selecting);
+        }
+        _ => {}
+    }
+}
+
+fn configure(net: &mut Net, ip: u32, r: &Reply) {
+    let mask = if r.mask != 0 {
+        r.mask
+    } else {
+        super::ipv4(255, 255, 255, 0)
+    };
+    let changed = (ip, mask, r.router, r.dns) != (net.ip, net.mask, net.gateway, net.dns);
+    net.ip = ip;
+    net.mask = mask;
+    net.gateway = r.router;
+    net.dns = r.dns;
+
+    let c = &mut net.dhcp;
+    let lease = match r.lease {
+        0 | u32::MAX => u64::MAX, // None given, or infinite
+        secs => secs as u64 * HZ,
+    };
+    c.enter(State::Bound);
+    c.lease_end = c.now.saturating_add(lease);
+    c.timer = c.now.saturating_add(lease / 2);
+    if changed {
+        crate::info!(
+            "dhcp: {}/{} via {}, dns {}, lease {}s",
+            Ipv4(ip),
+            mask.count_ones(),
+            Ipv4(r.router),
+            Ipv4(r.dns),
+            r.lease
+        );
+    }
+}
+
+fn unconfigure(net: &mut Net) {
+    net.ip = 0;
+    net.mask = 0;
+    net.gateway = 0;
+    net.dns = 0;
+}
@@ -0,0 +1,40 @@
+// Ethernet II framing.
+
+use super::{be16, Net};
+
+pub const HLEN: usize = 14;
+pub const MTU: usize = 1500;
+pub const FRAME_MAX: usize = HLEN + MTU; // Header and payload, no FCS
+pub const BROADCAST: [u8; 6] = [0xFF; 6];
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+pub fn input(net: &mut Net, frame: &[u8]) {
+    if frame.len() < HLEN {
+        return;
+    }
+    let payload = &frame[HLEN..];
+    match be16(frame, 12) {
+        TYPE_IPV4 => super::ip::input(net, payload),
+        TYPE_ARP => super::arp::input(net, payload),
+        _ => {}
+    }
+}
+
+// Send a frame to dst carrying a payload of type ty, built by fill in the
+// room it is given (MTU bytes) and whose length it returns.
+pub fn output(
+    net: &mut Net,
+    dst: [u8; 6],
+    ty: u16,
+    fill: impl FnOnce(&mut [u8]) -> usize,
+) -> Result<(), ()> {
+    let nic = net.nic.ok_or(())?;
+    nic.transmit(|buf| {
+        buf[0..6].copy_from_slice(&dst);
+        buf[6..12].copy_from_slice(&net.mac);
+        super::put16(buf, 12, ty);
+        HLEN + fill(&mut buf[HLEN..FRAME_MAX])
+    })
+}
@@ -0,0 +1,41 @@
+// ICMP, echo only: requests to this host are answered from input, and
+// replies go to the ping socket (see udp) whose port is their identifier.
+// Everything else is dropped.
+
+use super::ip::{self, PROTO_ICMP};
+use super::{be16, checksum, put16, sum, Net};
+
+pub const HLEN: usize = 8; // Type, code, checksum, identifier, sequence
+pub const ECHO_REPLY: u8 = 0;
+pub const ECHO: u8 = 8;
+
+pub fn input(net: &mut Net, src: u32, dst: u32, pkt: &[u8]) {
+    if pkt.len() < HLEN || checksum(sum(pkt, 0)) != 0 || pkt[1] != 0 {
+        return;
+    }
+    match pkt[0] {
+        // Not those to a broadcast address
+        ECHO if dst == net.ip || dst >> 24 == 127 => {
+            if let Some(route) = ip::route_cached(net, src) {
+                let _ = output(net, route, src, ECHO_REPLY, &pkt[4..]);
+            }
+        }
+        ECHO_REPLY => super::udp::queue(net, PROTO_ICMP, be16(pkt, 4), src, 0, pkt),
+        _ => {}
+    }
+}
+
+// Send a message of type ty to dst along route: rest is what follows the
+// checksum, for an echo the identifier, sequence number and data.
+pub fn output(net: &mut Net, route: ip::Route, dst: u32, ty: u8, rest: &[u8]) -> Result<(), ()> {
+    ip::output(net, route, dst, PROTO_ICMP, |buf| {
+        let len = 4 + rest.len();
+        buf[0] = ty;
+        buf[1] = 0;
+        put16(buf, 2, 0);
+        buf[4..len].copy_from_slice(rest);
+        let sum = checksum(sum(&buf[..len], 0));
+        put16(buf, 2, sum);
+        len
+    })
+}
@@ -0,0 +1,206 @@
+// IPv4. Datagrams come in whole: fragments are dropped, not reassembled.
+// Those sent have no options, and don't fragment either, since nothing
+// larger than the MTU is accepted to send.
+//
+// Datagrams to this host are queued and go up the stack once the one being
+// handled is done with, so that a reply sent from input, which TCP makes
+// to every segment, does not recurse.
+
+use super::eth::MTU;
+use super::{arp, be16, be32, checksum, eth, put16, put32, sum, Net, NET};
+use abi::syscall::{EINTR, ENETDOWN, ENETUNREACH};
+
+pub const HLEN: usize = 20; // Without options
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+const TTL: u8 = 64;
+const FLAG_DF: u16 = 0x4000; // Don't fragment
+const BROADCAST: u32 = 0xFFFF_FFFF;
+
+const NLOOP: usize = 16;
+
+// Ticks route() waits for DHCP to give the host an address
+const ADDRESS_WAIT: usize = 500;
+
+// Datagrams sent to this host, waiting to be delivered: a page each.
+pub struct Loopback {
+    queue: [(usize, usize); NLOOP], // (page, length)
+    head: usize,
+    count: usize,
+    busy: bool, // Being delivered: the queue is drained already
+}
+
+impl Loopback {
+    pub const fn new() -> Self {
+        Self {
+            queue: [(0, 0); NLOOP],
+            head: 0,
+            count: 0,
+            busy: false,
+        }
+    }
+}
+
+// Where a datagram goes: back up the stack, or to a MAC address.
+#[derive(Clone, Copy)]
+pub enum Route {
+    Local,
+    Mac([u8; 6]),
+}
+
+pub fn input(net: &mut Net, pkt: &[u8]) {
+    if pkt.len() < HLEN || pkt[0] >> 4 != 4 {
+        return;
+    }
+    let hlen = (pkt[0] & 0xF) as usize * 4;
+    let total = be16(pkt, 2) as usize;
+    if hlen < HLEN || total < hlen || total > pkt.len() {
+        return;
+    }
+    if checksum(sum(&pkt[..hlen], 0)) != 0 {
+        return;
+    }
+    // More fragments to come, or a fragment offset
+    if be16(pkt, 6) & 0x3FFF != 0 {
+        return;
+    }
+    let (src, dst) = (be32(pkt, 12), be32(pkt, 16));
+    // Before it has an address, the host takes whatever comes.
+    let ours = dst == net.ip || dst == BROADCAST || dst == net.ip | !net.mask || net.ip == 0;
+    if ours {
+        deliver(net, src, dst, pkt[9], &pkt[hlen..total]);
+    }
+}
+
+fn deliver(net: &mut Net, src: u32, dst: u32, proto: u8, payload: &[u8]) {
+    match proto {
+        PROTO_ICMP => super::icmp::input(net, src, dst, payload),
+        PROTO_TCP => super::tcp::input(net, src, dst, payload),
+        PROTO_UDP => super::udp::input(net, src, dst, payload),
+        _ => {}
+    }
+}
+
+// The route to dst if it needs no ARP, or else the next hop to resolve.
+fn hop(net: &Net, dst: u32) -> Result<Result<Route, u32>, isize> {
+    if net.nic.is_none() {
+        return Err(-ENETDOWN);
+    }
+    if dst >> 24 == 127 || dst == net.ip && dst != 0 {
+        return Ok(Ok(Route::Local));
+    }
+    if dst == BROADCAST || dst == net.ip | !net.mask {
+        return Ok(Ok(Route::Mac(eth::BROADCAST)));
+    }
+    if net.ip == 0 {
+        return Err(-ENETUNREACH);
+    }
+    if dst & net.mask == net.ip & net.mask {
+        Ok(Err(dst))
+    } else {
+        Ok(Err(net.gateway))
+    }
+}
+
+// How to get a datagram to dst: on the local network it goes straight to
+// it, elsewhere through the gateway. Resolving that next hop sleeps, so
+// this is called without NET held, as does waiting a while for an address
+// soon after boot.
+pub fn route(dst: u32) -> Result<Route, isize> {
+    for _ in 0..ADDRESS_WAIT {
+        let net = NET.lock();
+        if net.nic.is_none() || net.ip != 0 || dst >> 24 == 127 {
+            break;
+        }
+        drop(net);
+        if !super::wait_tick() {
+            return Err(-EINTR);
+        }
+    }
+    let hop = hop(&NET.lock(), dst)?;
+    match hop {
+        Ok(route) => Ok(route),
+        Err(hop) => arp::resolve(hop).map(Route::Mac),
+    }
+}
+
+// The route to dst if it is known without waiting, for input to answer
+// along. An ARP request goes out for a next hop that is not cached.
+pub fn route_cached(net: &mut Net, dst: u32) -> Option<Route> {
+    match hop(net, dst).ok()? {
+        Ok(route) => Some(route),
+        Err(hop) => match net.arp.lookup(hop) {
+            Some(mac) => Some(Route::Mac(mac)),
+            None => {
+                arp::request(net, hop);
+                None
+            }
+        },
+    }
+}
+
+// Send a datagram of protocol proto to dst along route. Its payload is
+// built by fill in the room it is given (MTU - HLEN bytes), and fill
+// returns its length.
+pub fn output(
+    net: &mut Net,
+    route: Route,
+    dst: u32,
+    proto: u8,
+    fill: impl FnOnce(&mut [u8]) -> usize,
+) -> Result<(), ()> {
+    let (src, id) = (net.ip, net.ip_id);
+    net.ip_id = net.ip_id.wrapping_add(1);
+    let build = |buf: &mut [u8]| {
+        let len = HLEN + fill(&mut buf[HLEN..MTU]);
+        buf[0] = 0x45; // Version 4, 5 words of header
+        buf[1] = 0;
+        put16(buf, 2, len as u16);
+        put16(buf, 4, id);
+        put16(buf, 6, FLAG_DF);
+        buf[8] = TTL;
+        buf[9] = proto;
+        put16(buf, 10, 0);
+        put32(buf, 12, src);
+        put32(buf, 16, dst);
+        let sum = checksum(sum(&buf[..HLEN], 0));
+        put16(buf, 10, sum);
+        len
+    };
+    match route {
+        Route::Mac(mac) => eth::output(net, mac, eth::TYPE_IPV4, build),
+        Route::Local => {
+            if net.lo.count == NLOOP {
+                return Err(());
+            }
+            let page = crate::allocator::ALLOCATOR.lock().kalloc();
+            if page.is_null() {
+                return Err(());
+            }
+            let len = build(unsafe { core::slice::from_raw_parts_mut(page, MTU) });
+            let lo = &mut net.lo;
+            lo.queue[(lo.head + lo.count) % NLOOP] = (page as usize, len);
+            lo.count += 1;
+            if !lo.busy {
+                loopback(net);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Deliver what is queued for this host, and what that sends to it in turn.
+fn loopback(net: &mut Net) {
+    net.lo.busy = true;
+    while net.lo.count > 0 {
+        let (page, len) = net.lo.queue[net.lo.head];
+        net.lo.head = (net.lo.head + 1) % NLOOP;
+        net.lo.count -= 1;
+        let pkt = unsafe { core::slice::from_raw_parts(page as *const u8, len) };
+        deliver(net, be32(pkt, 12), be32(pkt, 16), pkt[9], &pkt[HLEN..]);
+        crate::allocator::ALLOCATOR.lock().kfree(page);
+    }
+    net.lo.busy = false;
+}
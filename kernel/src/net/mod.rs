@@ -0,0 +1,284 @@
+// The network stack: Ethernet, ARP, IPv4, ICMP echo, UDP and TCP on the
+// one interface, on virtio-net or else an e1000. Its address, gateway and
+// DNS server come from DHCP; under QEMU's user-mode networking that is
+// 10.0.2.15/24 behind a gateway at 10.0.2.2.
+//
+// Everything here is under NET. Received frames are taken from the device
+// by poll() on CPU 0's timer tick, or by the NIC's own interrupt if it has
+// one, and go up the stack in the interrupt,
+// which also runs DHCP's and TCP's timers; sends run in the sending
+// process. The only
+// wait that does not sleep on NET, for an ARP answer, is made without it.
+//
+// Addresses are u32 in host byte order; the bytes on the wire are put and
+// taken with be16/be32 and put16/put32.
+
+pub mod arp;
+pub mod dhcp;
+pub mod eth;
+pub mod icmp;
+pub mod ip;
+pub mod tcp;
+pub mod udp;
+
+use crate::spinlock::Spinlock;
+use crate::{e1000, virtio_net};
+use abi::net::{NetConf, EPHEMERAL_PORT, SIOCGNETCONF};
+
+pub struct Net {
+    pub nic: Option<Nic>, // None: there is no interface
+    pub mac: [u8; 6],
+    pub ip: u32, // 0 until DHCP has given one
+    pub mask: u32,
+    pub gateway: u32,
+    pub dns: u32,
+    pub arp: arp::Cache,
+    pub udp: udp::Sockets,
+    pub tcp: tcp::Conns,
+    dhcp: dhcp::Client,
+    lo: ip::Loopback,
+    ip_id: u16, // Identification of the next IPv4 datagram sent
+}
+
+pub static NET: Spinlock<Net> = Spinlock::ranked(
+    Net {
+        nic: None,
+        mac: [0; 6],
+        ip: 0,
+        mask: 0,
+        gateway: 0,
+        dns: 0,
+        arp: arp::Cache::new(),
+        udp: udp::Sockets::new(),
+        tcp: tcp::Conns::new(),
+        dhcp: dhcp::Client::new(),
+        lo: ip::Loopback::new(),
+        ip_id: 0,
+    },
+    "NET",
+    crate::lockorder::RANK_NET,
+);
+
+// The device the interface is on
+#[derive(Clone, Copy, PartialEq)]
+pub enum Nic {
+    VirtioNet,
+    E1000,
+}
+
+impl Nic {
+    fn name(self) -> &'static str {
+        match self {
+            Nic::VirtioNet => "virtio-net",
+            Nic::E1000 => "e1000",
+        }
+    }
+
+    fn transmit(self, fill: impl FnOnce(&mut [u8]) -> usize) -> Result<(), ()> {
+        match self {
+            Nic::VirtioNet => virtio_net::transmit(fill),
+            Nic::E1000 => e1000::transmit(fill),
+        }
+    }
+
+    fn receive(self, f: impl FnMut(&[u8])) {
+        match self {
+            Nic::VirtioNet => virtio_net::receive(f),
+            Nic::E1000 => e1000::receive(f),
+        }
+    }
+}
+
+// What a socket file refers to: an index in net::udp, which has the ping
+// sockets too, or net::tcp.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Socket {
+    Udp(usize),
+    Tcp(usize),
+}
+
+impl Socket {
+    pub fn close(self) {
+        match self {
+            Socket::Udp(s) => udp::close(s),
+            Socket::Tcp(s) => tcp::close(s),
+        }
+    }
+}
+
+pub const fn ipv4(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    u32::from_be_bytes([a, b, c, d])
+}
+
+// Shows an address as a.b.c.d
+pub struct Ipv4(pub u32);
+
+impl core::fmt::Display for Ipv4 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let [a, b, c, d] = self.0.to_be_bytes();
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+pub struct Mac(pub [u8; 6]);
+
+impl core::fmt::Display for Mac {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let m = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+pub fn be16(b: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([b[off], b[off + 1]])
+}
+
+pub fn be32(b: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+pub fn put16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+pub fn put32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+// The ones' complement sum of data, added to sum, which carries that of
+// a pseudo-header if there is one.
+pub fn sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for w in &mut words {
+        sum += u16::from_be_bytes([w[0], w[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+// The sum of the pseudo-header that UDP and TCP checksums cover
+pub fn pseudo(src: u32, dst: u32, proto: u8, len: usize) -> u32 {
+    (src >> 16) + (src & 0xFFFF) + (dst >> 16) + (dst & 0xFFFF) + proto as u32 + len as u32
+}
+
+// A port from EPHEMERAL_PORT up that in_use says is free, searching from
+// *next on; None if all are taken.
+pub fn ephemeral(next: &mut u16, in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    for _ in EPHEMERAL_PORT..=u16::MAX {
+        let port = (*next).max(EPHEMERAL_PORT);
+        *next = port.checked_add(1).unwrap_or(EPHEMERAL_PORT);
+        if !in_use(port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+// The Internet checksum of what sum() added up: 0 over data that carries
+// a correct one.
+pub fn checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Bring up the interface, if there is a network device: a virtio-net, or
+// else an e1000.
+pub fn init() {
+    let virtio = crate::pci::scan_pci(virtio_net::DEVICE_ID)
+        .or_else(|| crate::pci::scan_pci(virtio_net::TRANSITIONAL_DEVICE_ID));
+    let found = match virtio {
+        Some(dev) => virtio_net::init(&dev).map(|mac| (Nic::VirtioNet, mac)),
+        None => e1000::DEVICE_IDS
+            .iter()
+            .find_map(|&id| crate::pci::find(crate::pci::INTEL_VENDOR_ID, id))
+            .and_then(|dev| e1000::init(&dev))
+            .map(|mac| (Nic::E1000, mac)),
+    };
+    let Some((nic, mac)) = found else {
+        crate::info!("net: no network device");
+        return;
+    };
+    let mut net = NET.lock();
+    net.mac = mac;
+    net.nic = Some(nic);
+    dhcp::start(&mut net);
+    crate::info!("net: {} {}", nic.name(), Mac(net.mac));
+}
+
+// Sleep until the next timer tick. False if the process was killed
+// instead.
+pub fn wait_tick() -> bool {
+    let p = unsafe { &*crate::proc::myproc().unwrap() };
+    let mut ticks = crate::trap::TICKS.lock();
+    let start = *ticks;
+    while *ticks == start {
+        if unsafe { crate::proc::killed(p) } {
+            return false;
+        }
+        crate::proc::sleep(
+            core::ptr::addr_of!(crate::trap::TICKS) as usize,
+            Some(ticks),
+        );
+        ticks = crate::trap::TICKS.lock();
+    }
+    true
+}
+
+// ioctl on a socket: see abi::net::SIOCGNETCONF.
+pub fn ioctl(request: usize, arg: u64) -> isize {
+    if request != SIOCGNETCONF {
+        return -abi::syscall::ENOTTY;
+    }
+    let conf = {
+        let net = NET.lock();
+        NetConf {
+            up: net.nic.is_some() as u32,
+            addr: net.ip.to_be_bytes(),
+            mask: net.mask.to_be_bytes(),
+            gateway: net.gateway.to_be_bytes(),
+            dns: net.dns.to_be_bytes(),
+            mac: net.mac,
+            pad: [0; 2],
+        }
+    };
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyout(
+        pgdir,
+        &mut allocator,
+        arg,
+        &conf as *const NetConf as *const u8,
+        core::mem::size_of::<NetConf>(),
+    ) {
+        return -1;
+    }
+    0
+}
+
+// Take in the frames received since the last tick, and run the timers.
+pub fn poll() {
+    let now = crate::trap::ticks();
+    let mut net = NET.lock();
+    let Some(nic) = net.nic else {
+        return;
+    };
+    nic.receive(|frame| eth::input(&mut net, frame));
+    dhcp::timer(&mut net, now);
+    tcp::timer(&mut net, now);
+}
+
+// Take in the frames received, from the NIC's interrupt.
+pub fn input() {
+    let mut net = NET.lock();
+    if let Some(nic) = net.nic {
+        nic.receive(|frame| eth::input(&mut net, frame));
+    }
+}
@@ -0,0 +1,1015 @@
+// TCP. Connections live in a table of NCONN, each with a page to buffer
+// what is sent until it is acknowledged and one for what is received until
+// it is read; the receive window is the room left in the latter.
+//
+// Segments are taken in order only: one that arrives ahead of what is
+// expected is dropped, and what has been received acknowledged again for
+// the peer to retransmit. Every segment with data is acknowledged at once.
+// Data not acknowledged in time is sent again from the first unacknowledged
+// byte (go-back-N), with the timeout doubled every time; a connection that
+// goes MAX_RETRIES timeouts without progress is given up on.
+//
+// A listening socket's SYNs make connections of their own, left for
+// accept() once the handshake completes. A connection outlives the socket
+// it was accepted or connected as until its closing handshake is done.
+
+use super::eth::MTU;
+use super::ip::{self, Route, PROTO_TCP};
+use super::{be16, be32, checksum, pseudo, put16, put32, sum, Net, NET};
+use crate::allocator::ALLOCATOR;
+use crate::proc::myproc;
+use crate::util::PG_SIZE;
+use abi::syscall::{
+    EADDRINUSE, ECONNREFUSED, ECONNRESET, EINTR, EINVAL, EISCONN, ENOTCONN, EPIPE, ETIMEDOUT,
+};
+
+const NCONN: usize = 16;
+const BUF: usize = PG_SIZE; // Bytes of each buffer
+const HLEN: usize = 20; // Without options
+const MSS: usize = MTU - ip::HLEN - HLEN; // Advertised in our SYNs
+const DEFAULT_MSS: usize = 536; // For a peer that doesn't say
+const BACKLOG_MAX: usize = 8;
+
+// In ticks
+const RTO_INIT: u64 = 100;
+const RTO_MAX: u64 = 6000;
+const TIME_WAIT: u64 = 200;
+const FIN_WAIT_2: u64 = 6000; // For a peer that never closes its side
+const MAX_RETRIES: u32 = 8;
+
+// Flags
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Clone, Copy)]
+struct Conn {
+    used: bool,
+    user: bool, // Open as a socket: not freed when closed
+    state: State,
+    error: isize, // Why it was closed under its user: -ECONNRESET, ...
+    lport: u16,
+    rip: u32,
+    rport: u16,
+    route: Route,
+    parent: Option<usize>, // The listening socket it waits to be accepted from
+    backlog: usize,        // Of a listening socket
+
+    // Sending
+    iss: u32,
+    snd_una: u32, // Oldest unacknowledged
+    snd_nxt: u32, // Next to send
+    snd_wnd: u32, // The peer's window
+    mss: usize,   // The peer's
+    sndbuf: usize,
+    snd_head: usize,
+    snd_len: usize,    // Buffered, from snd_seq on
+    snd_seq: u32,      // Sequence number of the byte at snd_head
+    fin_pending: bool, // Closed by its user: a FIN follows the data
+    fin_sent: bool,
+    timer: u64, // Tick it runs out at: retransmit, or end TIME-WAIT; 0 if off
+    rto: u64,
+    retries: u32,
+
+    // Receiving
+    rcv_nxt: u32,
+    rcvbuf: usize,
+    rcv_head: usize,
+    rcv_len: usize,
+    fin_received: bool,
+}
+
+impl Conn {
+    const fn new() -> Self {
+        Self {
+            used: false,
+            user: false,
+            state: State::Closed,
+            error: 0,
+            lport: 0,
+            rip: 0,
+            rport: 0,
+            route: Route::Local,
+            parent: None,
+            backlog: 0,
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            sndbuf: 0,
+            snd_head: 0,
+            snd_len: 0,
+            snd_seq: 0,
+            fin_pending: false,
+            fin_sent: false,
+            timer: 0,
+            rto: RTO_INIT,
+            retries: 0,
+            rcv_nxt: 0,
+            rcvbuf: 0,
+            rcv_head: 0,
+            rcv_len: 0,
+            fin_received: false,
+        }
+    }
+
+    // Whether the handshake is done, and the connection not over
+    fn synchronized(&self) -> bool {
+        !matches!(
+            self.state,
+            State::Closed | State::Listen | State::SynSent | State::SynReceived
+        )
+    }
+
+    fn window(&self) -> u16 {
+        (BUF - self.rcv_len).min(u16::MAX as usize) as u16
+    }
+
+    // Start sending from iss: the SYN, then data.
+    fn start(&mut self, iss: u32) {
+        self.iss = iss;
+        self.snd_una = iss;
+        self.snd_nxt = iss;
+        self.snd_seq = iss.wrapping_add(1);
+        self.rto = RTO_INIT;
+        self.retries = 0;
+    }
+}
+
+pub struct Conns {
+    conns: [Conn; NCONN],
+    next_port: u16, // Where the search for an ephemeral port starts
+    iss: u32,       // The last initial sequence number given out
+    now: u64,       // Tick of the last timer() call
+}
+
+impl Conns {
+    pub const fn new() -> Self {
+        Self {
+            conns: [Conn::new(); NCONN],
+            next_port: 0,
+            iss: 0,
+            now: 0,
+        }
+    }
+
+    fn new_iss(&mut self) -> u32 {
+        self.iss = self.iss.wrapping_add(64000).wrapping_add(self.now as u32);
+        self.iss
+    }
+
+    // Whether a socket has port. Connections left to finish closing after
+    // their socket is gone don't keep it.
+    fn in_use(&self, port: u16) -> bool {
+        self.conns
+            .iter()
+            .any(|c| c.used && c.user && c.lport == port)
+    }
+
+    fn ephemeral(&mut self) -> Option<u16> {
+        let conns = &*self;
+        let mut next = self.next_port;
+        let port = super::ephemeral(&mut next, |port| conns.in_use(port));
+        self.next_port = next;
+        port
+    }
+}
+
+// a < b in sequence space
+fn lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn le(a: u32, b: u32) -> bool {
+    !lt(b, a)
+}
+
+// What sockets sleep on: connect, accept, and reads and writes waiting for
+// room or data.
+fn chan(net: &Net, i: usize) -> usize {
+    &net.tcp.conns[i] as *const Conn as usize
+}
+
+fn wakeup(net: &Net, i: usize) {
+    crate::proc::wakeup(chan(net, i));
+}
+
+// The len bytes of the ring buffer in page from start on, in one piece or
+// two.
+fn ring(page: usize, start: usize, len: usize) -> [&'static [u8]; 2] {
+    let start = start % BUF;
+    let first = len.min(BUF - start);
+    unsafe {
+        [
+            core::slice::from_raw_parts((page + start) as *const u8, first),
+            core::slice::from_raw_parts(page as *const u8, len - first),
+        ]
+    }
+}
+
+struct Seg {
+    sport: u16,
+    dst: u32,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+}
+
+fn emit(net: &mut Net, route: Route, seg: &Seg, data: [&[u8]; 2]) {
+    let src = net.ip;
+    // SYNs carry the MSS option.
+    let hlen = if seg.flags & SYN != 0 { HLEN + 4 } else { HLEN };
+    let _ = ip::output(net, route, seg.dst, PROTO_TCP, |buf| {
+        let len = hlen + data[0].len() + data[1].len();
+        put16(buf, 0, seg.sport);
+        put16(buf, 2, seg.dport);
+        put32(buf, 4, seg.seq);
+        put32(buf, 8, seg.ack);
+        buf[12] = (hlen as u8 / 4) << 4;
+        buf[13] = seg.flags;
+        put16(buf, 14, seg.wnd);
+        put32(buf, 16, 0); // Checksum and urgent pointer
+        if hlen > HLEN {
+            buf[20] = 2; // Kind: MSS
+            buf[21] = 4;
+            put16(buf, 22, MSS as u16);
+        }
+        buf[hlen..hlen + data[0].len()].copy_from_slice(data[0]);
+        buf[hlen + data[0].len()..len].copy_from_slice(data[1]);
+        let sum = checksum(sum(&buf[..len], pseudo(src, seg.dst, PROTO_TCP, len)));
+        put16(buf, 16, sum);
+        len
+    });
+}
+
+// Send connection i a segment with flags, at seq, with len bytes of the
+// send buffer from off on.
+fn segment(net: &mut Net, i: usize, flags: u8, seq: u32, off: usize, len: usize) {
+    let c = &net.tcp.conns[i];
+    let seg = Seg {
+        sport: c.lport,
+        dst: c.rip,
+        dport: c.rport,
+        seq,
+        ack: if flags & ACK != 0 { c.rcv_nxt } else { 0 },
+        flags,
+        wnd: c.window(),
+    };
+    let data = ring(c.sndbuf, c.snd_head + off, len);
+    let route = c.route;
+    emit(net, route, &seg, data);
+}
+
+fn send_ack(net: &mut Net, i: usize) {
+    let seq = net.tcp.conns[i].snd_nxt;
+    segment(net, i, ACK, seq, 0, 0);
+}
+
+// Answer a segment that belongs to no connection with a reset.
+fn reset(net: &mut Net, src: u32, pkt: &[u8], seglen: u32) {
+    let (flags, seq, ack) = if pkt[13] & ACK != 0 {
+        (RST, be32(pkt, 8), 0)
+    } else {
+        (RST | ACK, 0, be32(pkt, 4).wrapping_add(seglen))
+    };
+    let Some(route) = ip::route_cached(net, src) else {
+        return;
+    };
+    let seg = Seg {
+        sport: be16(pkt, 2),
+        dst: src,
+        dport: be16(pkt, 0),
+        seq,
+        ack,
+        flags,
+        wnd: 0,
+    };
+    emit(net, route, &seg, [&[], &[]]);
+}
+
+// Send what the window allows of what is buffered, then the FIN once all
+// of it is out. The state is updated before each segment goes, since one
+// to this host is answered before emit returns.
+fn output(net: &mut Net, i: usize) {
+    loop {
+        let now = net.tcp.now;
+        let c = &mut net.tcp.conns[i];
+        match c.state {
+            State::SynSent | State::SynReceived => {
+                if c.snd_nxt == c.iss {
+                    let flags = if c.state == State::SynSent {
+                        SYN
+                    } else {
+                        SYN | ACK
+                    };
+                    let iss = c.iss;
+                    c.snd_nxt = iss.wrapping_add(1);
+                    arm(c, now);
+                    segment(net, i, flags, iss, 0, 0);
+                }
+                return;
+            }
+            State::Established
+            | State::CloseWait
+            | State::FinWait1
+            | State::Closing
+            | State::LastAck => {}
+            _ => return,
+        }
+        if c.fin_sent {
+            return;
+        }
+        let sent = c.snd_nxt.wrapping_sub(c.snd_seq) as usize;
+        let unsent = c.snd_len - sent;
+        let inflight = c.snd_nxt.wrapping_sub(c.snd_una) as usize;
+        let room = (c.snd_wnd as usize).saturating_sub(inflight);
+        let n = unsent.min(room).min(c.mss);
+        let seq = c.snd_nxt;
+        if n > 0 {
+            c.snd_nxt = seq.wrapping_add(n as u32);
+            arm(c, now);
+            segment(net, i, ACK | PSH, seq, sent, n);
+        } else if unsent == 0 && c.fin_pending {
+            c.snd_nxt = seq.wrapping_add(1);
+            c.fin_sent = true;
+            arm(c, now);
+            segment(net, i, FIN | ACK, seq, 0, 0);
+            return;
+        } else {
+            // With data held back by a closed window, the timer makes
+            // the probe that finds out when it opens.
+            if unsent > 0 && inflight == 0 {
+                arm(c, now);
+            }
+            return;
+        }
+    }
+}
+
+// Start the retransmission timer if it is not running.
+fn arm(c: &mut Conn, now: u64) {
+    if c.timer == 0 {
+        c.timer = now + c.rto;
+    }
+}
+
+// Connection i is over; err is what its user is told. It is freed unless
+// a socket still refers to it.
+fn finish(net: &mut Net, i: usize, err: isize) {
+    let c = &mut net.tcp.conns[i];
+    c.state = State::Closed;
+    c.error = err;
+    c.timer = 0;
+    let (user, parent) = (c.user, c.parent);
+    wakeup(net, i);
+    if let Some(p) = parent {
+        wakeup(net, p);
+    }
+    if !user {
+        free(net, i);
+    }
+}
+
+fn free(net: &mut Net, i: usize) {
+    let c = &mut net.tcp.conns[i];
+    let mut allocator = ALLOCATOR.lock();
+    allocator.kfree(c.sndbuf);
+    allocator.kfree(c.rcvbuf);
+    *c = Conn::new();
+}
+
+// A new connection with its buffers, or None.
+fn alloc(net: &mut Net) -> Option<usize> {
+    let i = net.tcp.conns.iter().position(|c| !c.used)?;
+    let mut allocator = ALLOCATOR.lock();
+    let (snd, rcv) = (allocator.kalloc(), allocator.kalloc());
+    if snd.is_null() || rcv.is_null() {
+        for p in [snd, rcv].into_iter().filter(|p| !p.is_null()) {
+            allocator.kfree(p as usize);
+        }
+        return None;
+    }
+    let c = &mut net.tcp.conns[i];
+    *c = Conn::new();
+    c.used = true;
+    c.sndbuf = snd as usize;
+    c.rcvbuf = rcv as usize;
+    Some(i)
+}
+
+// The MSS option of a SYN, if it has one.
+fn mss_option(opts: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            0 => break,  // End of options
+            1 => i += 1, // No-op
+            kind => {
+                let len = *opts.get(i + 1)? as usize;
+                if len < 2 || i + len > opts.len() {
+                    return None;
+                }
+                if kind == 2 && len == 4 {
+                    return Some(be16(opts, i + 2) as usize);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+pub fn input(net: &mut Net, src: u32, dst: u32, pkt: &[u8]) {
+    if pkt.len() < HLEN {
+        return;
+    }
+    let off = (pkt[12] >> 4) as usize * 4;
+    if off < HLEN || off > pkt.len() {
+        return;
+    }
+    if checksum(sum(pkt, pseudo(src, dst, PROTO_TCP, pkt.len()))) != 0 {
+        return;
+    }
+    let (sport, dport) = (be16(pkt, 0), be16(pkt, 2));
+    let (seq, ack, flags, wnd) = (be32(pkt, 4), be32(pkt, 8), pkt[13], be16(pkt, 14));
+    let data = &pkt[off..];
+    let seglen = data.len() as u32 + (flags & SYN != 0) as u32 + (flags & FIN != 0) as u32;
+    let mss = mss_option(&pkt[HLEN..off]).unwrap_or(DEFAULT_MSS).min(MSS);
+
+    let conns = &net.tcp.conns;
+    let found = conns
+        .iter()
+        .position(|c| {
+            c.used
+                && !matches!(c.state, State::Closed | State::Listen)
+                && (c.lport, c.rip, c.rport) == (dport, src, sport)
+        })
+        .or_else(|| {
+            conns
+                .iter()
+                .position(|c| c.used && c.state == State::Listen && c.lport == dport)
+        });
+    let Some(i) = found else {
+        if flags & RST == 0 {
+            reset(net, src, pkt, seglen);
+        }
+        return;
+    };
+
+    match net.tcp.conns[i].state {
+        State::Listen => {
+            if flags & RST != 0 {
+                return;
+            }
+            if flags & ACK != 0 {
+                reset(net, src, pkt, seglen);
+                return;
+            }
+            if flags & SYN != 0 {
+                listen_syn(net, i, src, sport, seq, wnd, mss);
+            }
+            return;
+        }
+        State::SynSent => {
+            syn_sent(net, i, seq, ack, flags, wnd, mss, src, pkt, seglen);
+            return;
+        }
+        _ => {}
+    }
+
+    // Drop what has been received already: a retransmission may overlap
+    // what is new.
+    let c = &mut net.tcp.conns[i];
+    let (mut seq, mut data, mut flags) = (seq, data, flags);
+    if flags & RST != 0 {
+        if seq == c.rcv_nxt {
+            let err = if c.state == State::SynReceived {
+                0
+            } else {
+                -ECONNRESET
+            };
+            finish(net, i, err);
+        }
+        return;
+    }
+    let dup = lt(seq, c.rcv_nxt);
+    if dup {
+        let mut old = c.rcv_nxt.wrapping_sub(seq) as usize;
+        if flags & SYN != 0 {
+            flags &= !SYN;
+            seq = seq.wrapping_add(1);
+            old -= 1;
+        }
+        let n = old.min(data.len());
+        data = &data[n..];
+        seq = seq.wrapping_add(n as u32);
+        if old > n {
+            flags &= !FIN;
+        }
+    }
+    if seq != c.rcv_nxt || flags & SYN != 0 {
+        // Ahead of what is expected, or a SYN in the middle of it all
+        send_ack(net, i);
+        return;
+    }
+    if flags & ACK == 0 {
+        return;
+    }
+
+    // What was acknowledged
+    let now = net.tcp.now;
+    let c = &mut net.tcp.conns[i];
+    if c.state == State::SynReceived {
+        if !(lt(c.snd_una, ack) && le(ack, c.snd_nxt)) {
+            reset(net, src, pkt, seglen);
+            return;
+        }
+        c.state = State::Established;
+        if let Some(p) = c.parent {
+            wakeup(net, p);
+        }
+    }
+    let c = &mut net.tcp.conns[i];
+    if lt(c.snd_nxt, ack) {
+        send_ack(net, i);
+        return;
+    }
+    if le(c.snd_una, ack) {
+        c.snd_wnd = wnd as u32;
+    }
+    let mut fin_acked = false;
+    if lt(c.snd_una, ack) {
+        if lt(c.snd_seq, ack) {
+            let n = (ack.wrapping_sub(c.snd_seq) as usize).min(c.snd_len);
+            c.snd_head = (c.snd_head + n) % BUF;
+            c.snd_len -= n;
+            c.snd_seq = c.snd_seq.wrapping_add(n as u32);
+        }
+        fin_acked = c.fin_sent && ack == c.snd_nxt;
+        c.snd_una = ack;
+        c.retries = 0;
+        c.rto = RTO_INIT;
+        c.timer = if c.snd_una != c.snd_nxt {
+            now + c.rto
+        } else {
+            0
+        };
+        wakeup(net, i);
+    }
+    let c = &mut net.tcp.conns[i];
+    if fin_acked {
+        match c.state {
+            State::FinWait1 => {
+                c.state = State::FinWait2;
+                c.timer = now + FIN_WAIT_2;
+            }
+            State::Closing => {
+                c.state = State::TimeWait;
+                c.timer = now + TIME_WAIT;
+            }
+            State::LastAck => {
+                finish(net, i, 0);
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    // What was sent
+    let c = &mut net.tcp.conns[i];
+    let mut taken = 0;
+    if !data.is_empty()
+        && matches!(
+            c.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        )
+    {
+        taken = data.len().min(BUF - c.rcv_len);
+        let [a, b] = ring(c.rcvbuf, c.rcv_head + c.rcv_len, taken);
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), a.as_ptr() as *mut u8, a.len());
+            core::ptr::copy_nonoverlapping(
+                data[a.len()..].as_ptr(),
+                b.as_ptr() as *mut u8,
+                b.len(),
+            );
+        }
+        c.rcv_len += taken;
+        c.rcv_nxt = c.rcv_nxt.wrapping_add(taken as u32);
+    }
+    let fin = flags & FIN != 0 && taken == data.len();
+    if fin {
+        c.rcv_nxt = c.rcv_nxt.wrapping_add(1);
+        c.fin_received = true;
+        match c.state {
+            State::Established => c.state = State::CloseWait,
+            State::FinWait1 if fin_acked => {
+                c.state = State::TimeWait;
+                c.timer = now + TIME_WAIT;
+            }
+            State::FinWait1 => c.state = State::Closing,
+            State::FinWait2 => {
+                c.state = State::TimeWait;
+                c.timer = now + TIME_WAIT;
+            }
+            _ => {}
+        }
+    }
+    if taken > 0 || fin {
+        wakeup(net, i);
+    }
+    // A retransmission of what was received is acknowledged again, in
+    // case the first acknowledgement was lost.
+    if !data.is_empty() || fin || dup {
+        send_ack(net, i);
+    }
+    output(net, i);
+}
+
+// A SYN to listening socket l: a connection of its own answers it.
+fn listen_syn(net: &mut Net, l: usize, src: u32, sport: u16, seq: u32, wnd: u16, mss: usize) {
+    let pending = net
+        .tcp
+        .conns
+        .iter()
+        .filter(|c| c.used && c.parent == Some(l))
+        .count();
+    if pending >= net.tcp.conns[l].backlog {
+        return;
+    }
+    // Without a route yet, the peer's next try may find one.
+    let Some(route) = ip::route_cached(net, src) else {
+        return;
+    };
+    let Some(i) = alloc(net) else {
+        return;
+    };
+    let iss = net.tcp.new_iss();
+    let lport = net.tcp.conns[l].lport;
+    let c = &mut net.tcp.conns[i];
+    c.state = State::SynReceived;
+    c.lport = lport;
+    c.rip = src;
+    c.rport = sport;
+    c.route = route;
+    c.parent = Some(l);
+    c.rcv_nxt = seq.wrapping_add(1);
+    c.snd_wnd = wnd as u32;
+    c.mss = mss;
+    c.start(iss);
+    output(net, i);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn syn_sent(
+    net: &mut Net,
+    i: usize,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+    mss: usize,
+    src: u32,
+    pkt: &[u8],
+    seglen: u32,
+) {
+    let c = &mut net.tcp.conns[i];
+    if flags & ACK != 0 && ack != c.iss.wrapping_add(1) {
+        if flags & RST == 0 {
+            reset(net, src, pkt, seglen);
+        }
+        return;
+    }
+    if flags & RST != 0 {
+        if flags & ACK != 0 {
+            finish(net, i, -ECONNREFUSED);
+        }
+        return;
+    }
+    if flags & SYN == 0 || flags & ACK == 0 {
+        return; // A simultaneous open is not supported.
+    }
+    c.rcv_nxt = seq.wrapping_add(1);
+    c.snd_una = ack;
+    c.snd_wnd = wnd as u32;
+    c.mss = mss;
+    c.state = State::Established;
+    c.timer = 0;
+    c.retries = 0;
+    c.rto = RTO_INIT;
+    wakeup(net, i);
+    send_ack(net, i);
+    output(net, i);
+}
+
+// Run the timers that ran out by now.
+pub fn timer(net: &mut Net, now: u64) {
+    net.tcp.now = now;
+    for i in 0..NCONN {
+        let c = &mut net.tcp.conns[i];
+        if !c.used || c.timer == 0 || now < c.timer {
+            continue;
+        }
+        c.timer = 0;
+        if matches!(c.state, State::TimeWait | State::FinWait2) {
+            finish(net, i, 0);
+            continue;
+        }
+        c.retries += 1;
+        if c.retries > MAX_RETRIES {
+            finish(net, i, -ETIMEDOUT);
+            continue;
+        }
+        c.rto = (c.rto * 2).min(RTO_MAX);
+        if c.snd_una == c.snd_nxt && c.snd_wnd == 0 {
+            // Probe the closed window with a byte, which the peer answers
+            // with its window whether it takes the byte or not.
+            c.snd_wnd = 1;
+        } else {
+            c.snd_nxt = c.snd_una;
+            c.fin_sent = false;
+        }
+        output(net, i);
+    }
+}
+
+// A new, closed socket, or None if there are NCONN connections.
+pub fn socket() -> Option<usize> {
+    let mut net = NET.lock();
+    let i = alloc(&mut net)?;
+    net.tcp.conns[i].user = true;
+    Some(i)
+}
+
+// Bind socket i to port, or to an ephemeral one if port is 0.
+pub fn bind(i: usize, port: u16) -> Result<(), isize> {
+    let mut net = NET.lock();
+    let c = &net.tcp.conns[i];
+    if c.lport != 0 || c.state != State::Closed {
+        return Err(-EINVAL);
+    }
+    let port = match port {
+        0 => net.tcp.ephemeral().ok_or(-EADDRINUSE)?,
+        port if net.tcp.in_use(port) => return Err(-EADDRINUSE),
+        port => port,
+    };
+    net.tcp.conns[i].lport = port;
+    Ok(())
+}
+
+// Take connections on socket i, at most backlog of them waiting to be
+// accepted.
+pub fn listen(i: usize, backlog: usize) -> Result<(), isize> {
+    let mut net = NET.lock();
+    if !matches!(net.tcp.conns[i].state, State::Closed | State::Listen) {
+        return Err(-EISCONN);
+    }
+    if net.tcp.conns[i].lport == 0 {
+        let port = net.tcp.ephemeral().ok_or(-EADDRINUSE)?;
+        net.tcp.conns[i].lport = port;
+    }
+    let c = &mut net.tcp.conns[i];
+    c.state = State::Listen;
+    c.backlog = backlog.clamp(1, BACKLOG_MAX);
+    Ok(())
+}
+
+// Wait for a connection to listening socket i. Returns it, and the
+// address and port of its peer.
+pub fn accept(i: usize) -> Result<(usize, u32, u16), isize> {
+    let p = unsafe { &*myproc().unwrap() };
+    let mut net = NET.lock();
+    loop {
+        if net.tcp.conns[i].state != State::Listen {
+            return Err(-EINVAL);
+        }
+        let ready = net
+            .tcp
+            .conns
+            .iter()
+            .position(|c| c.used && c.parent == Some(i) && c.synchronized());
+        if let Some(n) = ready {
+            let c = &mut net.tcp.conns[n];
+            c.parent = None;
+            c.user = true;
+            return Ok((n, c.rip, c.rport));
+        }
+        if unsafe { crate::proc::killed(p) } {
+            return Err(-EINTR);
+        }
+        let chan = chan(&net, i);
+        crate::proc::sleep(chan, Some(net));
+        net = NET.lock();
+    }
+}
+
+// Connect socket i to dport at dst, and wait for the handshake.
+pub fn connect(i: usize, dst: u32, dport: u16) -> Result<(), isize> {
+    if NET.lock().tcp.conns[i].state != State::Closed {
+        return Err(-EISCONN);
+    }
+    let route = ip::route(dst)?;
+    let p = unsafe { &*myproc().unwrap() };
+    let mut net = NET.lock();
+    if net.tcp.conns[i].state != State::Closed {
+        return Err(-EISCONN);
+    }
+    if net.tcp.conns[i].lport == 0 {
+        let port = net.tcp.ephemeral().ok_or(-EADDRINUSE)?;
+        net.tcp.conns[i].lport = port;
+    }
+    let iss = net.tcp.new_iss();
+    let c = &mut net.tcp.conns[i];
+    c.error = 0;
+    c.rip = dst;
+    c.rport = dport;
+    c.route = route;
+    c.state = State::SynSent;
+    c.start(iss);
+    output(&mut net, i);
+    loop {
+        let c = &mut net.tcp.conns[i];
+        match c.state {
+            State::SynSent => {}
+            State::Closed => return Err(if c.error != 0 { c.error } else { -ECONNREFUSED }),
+            _ => return Ok(()),
+        }
+        if unsafe { crate::proc::killed(p) } {
+            c.state = State::Closed;
+            c.timer = 0;
+            return Err(-EINTR);
+        }
+        let chan = chan(&net, i);
+        crate::proc::sleep(chan, Some(net));
+        net = NET.lock();
+    }
+}
+
+// The local address and port of socket i, and those of its peer if it
+// has one.
+pub fn names(i: usize) -> ((u32, u16), Option<(u32, u16)>) {
+    let net = NET.lock();
+    let c = &net.tcp.conns[i];
+    let peer = match c.state {
+        State::Closed | State::Listen | State::SynSent => None,
+        _ => Some((c.rip, c.rport)),
+    };
+    ((net.ip, c.lport), peer)
+}
+
+// Queue the n bytes at user address addr on socket i for sending, waiting
+// for room as needed. Returns how many were queued: n, or fewer if the
+// connection failed or the process was killed meanwhile.
+pub fn send(i: usize, addr: u64, n: usize) -> isize {
+    let p = unsafe { &*myproc().unwrap() };
+    let mut done = 0;
+    let mut net = NET.lock();
+    while done < n {
+        let c = &net.tcp.conns[i];
+        let err = match c.state {
+            State::Established | State::CloseWait if !c.fin_pending => 0,
+            State::Closed if c.error != 0 => c.error,
+            State::Closed | State::Listen | State::SynSent => -ENOTCONN,
+            _ => -EPIPE,
+        };
+        if err != 0 {
+            return if done > 0 { done as isize } else { err };
+        }
+        let room = BUF - c.snd_len;
+        if room == 0 {
+            if unsafe { crate::proc::killed(p) } {
+                return if done > 0 { done as isize } else { -EINTR };
+            }
+            let chan = chan(&net, i);
+            crate::proc::sleep(chan, Some(net));
+            net = NET.lock();
+            continue;
+        }
+        // Copying in with NET held is fine by lock order, as for pipes.
+        let k = room.min(n - done);
+        let parts = ring(c.sndbuf, c.snd_head + c.snd_len, k);
+        let mut src = addr + done as u64;
+        for part in parts {
+            let ok = crate::vm::copyin(
+                p.pgdir,
+                &mut ALLOCATOR.lock(),
+                part.as_ptr() as *mut u8,
+                src,
+                part.len(),
+            );
+            if !ok {
+                return if done > 0 { done as isize } else { -1 };
+            }
+            src += part.len() as u64;
+        }
+        net.tcp.conns[i].snd_len += k;
+        done += k;
+        output(&mut net, i);
+    }
+    done as isize
+}
+
+// Wait for data on socket i and copy up to n bytes of it to user address
+// addr. Returns the bytes copied; 0 once the peer has closed its side and
+// everything it sent has been read.
+pub fn recv(i: usize, addr: u64, n: usize) -> isize {
+    let p = unsafe { &*myproc().unwrap() };
+    let mut net = NET.lock();
+    loop {
+        let c = &mut net.tcp.conns[i];
+        if c.rcv_len > 0 && n > 0 {
+            let k = n.min(c.rcv_len);
+            let mut dst = addr;
+            for part in ring(c.rcvbuf, c.rcv_head, k) {
+                let ok = crate::vm::copyout(
+                    p.pgdir,
+                    &mut ALLOCATOR.lock(),
+                    dst,
+                    part.as_ptr(),
+                    part.len(),
+                );
+                if !ok {
+                    return -1;
+                }
+                dst += part.len() as u64;
+            }
+            // Tell a peer held up by a window this one opens.
+            let small = c.mss.min(BUF / 2);
+            let was = BUF - c.rcv_len;
+            c.rcv_head = (c.rcv_head + k) % BUF;
+            c.rcv_len -= k;
+            if was < small && BUF - c.rcv_len >= small && c.synchronized() {
+                send_ack(&mut net, i);
+            }
+            return k as isize;
+        }
+        if c.fin_received || n == 0 {
+            return 0;
+        }
+        match c.state {
+            State::Closed if c.error != 0 => return c.error,
+            State::Closed | State::Listen | State::SynSent => return -ENOTCONN,
+            _ => {}
+        }
+        if unsafe { crate::proc::killed(p) } {
+            return -EINTR;
+        }
+        let chan = chan(&net, i);
+        crate::proc::sleep(chan, Some(net));
+        net = NET.lock();
+    }
+}
+
+// The socket is closed: start the closing handshake, and leave the
+// connection to finish it. A listening socket resets the connections not
+// yet accepted from it.
+pub fn close(i: usize) {
+    let mut net = NET.lock();
+    net.tcp.conns[i].user = false;
+    match net.tcp.conns[i].state {
+        State::Listen => {
+            for c in 0..NCONN {
+                let conn = &net.tcp.conns[c];
+                if conn.used && conn.parent == Some(i) {
+                    let seq = conn.snd_nxt;
+                    segment(&mut net, c, RST, seq, 0, 0);
+                    free(&mut net, c);
+                }
+            }
+            free(&mut net, i);
+        }
+        State::Closed | State::SynSent => free(&mut net, i),
+        State::Established | State::CloseWait => {
+            let c = &mut net.tcp.conns[i];
+            c.fin_pending = true;
+            c.state = if c.state == State::Established {
+                State::FinWait1
+            } else {
+                State::LastAck
+            };
+            output(&mut net, i);
+        }
+        // Closing already
+        _ => {}
+    }
+}
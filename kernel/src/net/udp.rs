@@ -0,0 +1,352 @@
+// UDP sockets. A socket is bound to a local port by bind(), or by its
+// first sendto() or connect() if not before. Datagrams to that port wait
+// for recvfrom() in a queue of QLEN, each in a page of its own; more than
+// that are dropped. A connected socket has a peer that write() sends to,
+// and takes datagrams from it only.
+//
+// Ping sockets, socket(AF_INET, SOCK_DGRAM, IPPROTO_ICMP) as on Linux, are
+// kept here too, with ports of their own: the port is the identifier of
+// the ICMP echo requests sent, whose replies are queued like datagrams,
+// ICMP header and all (see icmp).
+
+use super::eth::MTU;
+use super::ip::{self, PROTO_ICMP, PROTO_UDP};
+use super::{be16, checksum, icmp, pseudo, put16, sum, Net, NET};
+use crate::allocator::ALLOCATOR;
+use crate::proc::myproc;
+use abi::syscall::{
+    EADDRINUSE, EAGAIN, EDESTADDRREQ, EINTR, EINVAL, EMSGSIZE, ENETDOWN, ENOMEM, ENOTCONN,
+};
+
+const NSOCK: usize = 16;
+const QLEN: usize = 8;
+
+pub const HLEN: usize = 8;
+pub const MAX_PAYLOAD: usize = MTU - ip::HLEN - HLEN;
+
+#[derive(Clone, Copy)]
+struct Dgram {
+    page: usize, // Holds the payload
+    len: usize,
+    src: u32,
+    sport: u16,
+}
+
+#[derive(Clone, Copy)]
+struct Sock {
+    used: bool,
+    proto: u8,                // PROTO_UDP, or PROTO_ICMP for a ping socket
+    port: u16,                // 0: not bound
+    peer: Option<(u32, u16)>, // Set by connect
+    queue: [Dgram; QLEN],
+    head: usize,
+    count: usize,
+}
+
+pub struct Sockets {
+    socks: [Sock; NSOCK],
+    next_port: u16, // Where the search for an ephemeral port starts
+}
+
+impl Sockets {
+    pub const fn new() -> Self {
+        const DGRAM: Dgram = Dgram {
+            page: 0,
+            len: 0,
+            src: 0,
+            sport: 0,
+        };
+        Self {
+            socks: [Sock {
+                used: false,
+                proto: PROTO_UDP,
+                port: 0,
+                peer: None,
+                queue: [DGRAM; QLEN],
+                head: 0,
+                count: 0,
+            }; NSOCK],
+            next_port: 0,
+        }
+    }
+
+    fn bound(&self, proto: u8, port: u16) -> Option<usize> {
+        self.socks
+            .iter()
+            .position(|s| s.used && s.proto == proto && s.port == port)
+    }
+
+    fn ephemeral(&mut self, proto: u8) -> Option<u16> {
+        let socks = &self.socks;
+        super::ephemeral(&mut self.next_port, |port| {
+            socks
+                .iter()
+                .any(|s| s.used && s.proto == proto && s.port == port)
+        })
+    }
+
+    // Bind socket s to an ephemeral port if it is not bound.
+    fn autobind(&mut self, s: usize) -> Result<u16, isize> {
+        if self.socks[s].port == 0 {
+            self.socks[s].port = self.ephemeral(self.socks[s].proto).ok_or(-EADDRINUSE)?;
+        }
+        Ok(self.socks[s].port)
+    }
+}
+
+// What sockets sleep on in recvfrom
+fn chan(net: &Net, s: usize) -> usize {
+    &net.udp.socks[s] as *const Sock as usize
+}
+
+pub fn input(net: &mut Net, src: u32, dst: u32, pkt: &[u8]) {
+    if pkt.len() < HLEN {
+        return;
+    }
+    let len = be16(pkt, 4) as usize;
+    if len < HLEN || len > pkt.len() {
+        return;
+    }
+    // A checksum of 0 means none was computed.
+    if be16(pkt, 6) != 0 && checksum(sum(&pkt[..len], pseudo(src, dst, PROTO_UDP, len))) != 0 {
+        return;
+    }
+    if be16(pkt, 2) == super::dhcp::CLIENT_PORT {
+        super::dhcp::input(net, &pkt[HLEN..len]);
+        return;
+    }
+    queue(
+        net,
+        PROTO_UDP,
+        be16(pkt, 2),
+        src,
+        be16(pkt, 0),
+        &pkt[HLEN..len],
+    );
+}
+
+// Queue payload, from sport at src, on the socket of protocol proto bound
+// to port, if there is one that takes it.
+pub fn queue(net: &mut Net, proto: u8, port: u16, src: u32, sport: u16, payload: &[u8]) {
+    let Some(s) = net.udp.bound(proto, port) else {
+        return;
+    };
+    let sock = &net.udp.socks[s];
+    if sock.count == QLEN || sock.peer.is_some_and(|peer| peer != (src, sport)) {
+        return;
+    }
+    let page = ALLOCATOR.lock().kalloc();
+    if page.is_null() {
+        return;
+    }
+    unsafe { core::ptr::copy_nonoverlapping(payload.as_ptr(), page, payload.len()) };
+    let sock = &mut net.udp.socks[s];
+    sock.queue[(sock.head + sock.count) % QLEN] = Dgram {
+        page: page as usize,
+        len: payload.len(),
+        src,
+        sport,
+    };
+    sock.count += 1;
+    crate::proc::wakeup(chan(net, s));
+}
+
+// A new socket of protocol proto, PROTO_UDP or PROTO_ICMP, or None if
+// there are NSOCK already.
+pub fn socket(proto: u8) -> Option<usize> {
+    let mut net = NET.lock();
+    let s = net.udp.socks.iter().position(|s| !s.used)?;
+    let sock = &mut net.udp.socks[s];
+    sock.used = true;
+    sock.proto = proto;
+    sock.port = 0;
+    sock.peer = None;
+    sock.head = 0;
+    sock.count = 0;
+    Some(s)
+}
+
+// Bind socket s to port, or to an ephemeral one if port is 0.
+pub fn bind(s: usize, port: u16) -> Result<(), isize> {
+    let mut net = NET.lock();
+    if net.udp.socks[s].port != 0 {
+        return Err(-EINVAL);
+    }
+    let proto = net.udp.socks[s].proto;
+    let port = match port {
+        0 => net.udp.ephemeral(proto).ok_or(-EADDRINUSE)?,
+        port if net.udp.bound(proto, port).is_some() => return Err(-EADDRINUSE),
+        port => port,
+    };
+    net.udp.socks[s].port = port;
+    Ok(())
+}
+
+// Make dport at dst the peer of socket s, binding it if it is not. A
+// ping socket's peer is only an address.
+pub fn connect(s: usize, dst: u32, dport: u16) -> Result<(), isize> {
+    let mut net = NET.lock();
+    net.udp.autobind(s)?;
+    let sock = &mut net.udp.socks[s];
+    let dport = if sock.proto == PROTO_ICMP { 0 } else { dport };
+    sock.peer = Some((dst, dport));
+    Ok(())
+}
+
+// The local address and port of socket s, and its peer if connected.
+pub fn names(s: usize) -> ((u32, u16), Option<(u32, u16)>) {
+    let net = NET.lock();
+    let sock = &net.udp.socks[s];
+    ((net.ip, sock.port), sock.peer)
+}
+
+pub fn close(s: usize) {
+    let mut net = NET.lock();
+    let sock = &mut net.udp.socks[s];
+    let mut allocator = ALLOCATOR.lock();
+    for i in 0..sock.count {
+        allocator.kfree(sock.queue[(sock.head + i) % QLEN].page);
+    }
+    sock.used = false;
+    sock.port = 0;
+    sock.count = 0;
+}
+
+// Send the n bytes at user address addr from socket s to dport at dst.
+// On a ping socket they are an echo request, header and all, and dport is
+// ignored. Returns n.
+pub fn sendto(s: usize, addr: u64, n: usize, dst: u32, dport: u16) -> isize {
+    let max = match NET.lock().udp.socks[s].proto {
+        PROTO_ICMP => MTU - ip::HLEN,
+        _ => MAX_PAYLOAD,
+    };
+    if n > max {
+        return -EMSGSIZE;
+    }
+    // Copy the payload in first: ip::route sleeps, and the copy can't be
+    // made while sending.
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    let page = {
+        let mut allocator = ALLOCATOR.lock();
+        let page = allocator.kalloc();
+        if page.is_null() {
+            return -ENOMEM;
+        }
+        if !crate::vm::copyin(pgdir, &mut allocator, page, addr, n) {
+            allocator.kfree(page as usize);
+            return -1;
+        }
+        page
+    };
+    let r = send(s, page, n, dst, dport);
+    ALLOCATOR.lock().kfree(page as usize);
+    match r {
+        Ok(()) => n as isize,
+        Err(e) => e,
+    }
+}
+
+fn send(s: usize, data: *mut u8, n: usize, dst: u32, dport: u16) -> Result<(), isize> {
+    let data = unsafe { core::slice::from_raw_parts_mut(data, n) };
+    let ping = NET.lock().udp.socks[s].proto == PROTO_ICMP;
+    if ping && (n < icmp::HLEN || data[0] != icmp::ECHO || data[1] != 0) {
+        return Err(-EINVAL);
+    }
+    let route = ip::route(dst)?;
+    let mut net = NET.lock();
+    let sport = net.udp.autobind(s)?;
+    let r = if ping {
+        // The identifier is the port, for the reply to find the socket.
+        put16(data, 4, sport);
+        icmp::output(&mut net, route, dst, icmp::ECHO, &data[4..])
+    } else {
+        output(&mut net, route, sport, dst, dport, data)
+    };
+    r.map_err(|()| -ENETDOWN)
+}
+
+// Send data from sport to dport at dst along route.
+pub fn output(
+    net: &mut Net,
+    route: ip::Route,
+    sport: u16,
+    dst: u32,
+    dport: u16,
+    data: &[u8],
+) -> Result<(), ()> {
+    let src = net.ip;
+    ip::output(net, route, dst, PROTO_UDP, |buf| {
+        let len = HLEN + data.len();
+        put16(buf, 0, sport);
+        put16(buf, 2, dport);
+        put16(buf, 4, len as u16);
+        put16(buf, 6, 0);
+        buf[HLEN..len].copy_from_slice(data);
+        // 0 is sent as all ones: 0 means no checksum.
+        let sum = match checksum(sum(&buf[..len], pseudo(src, dst, PROTO_UDP, len))) {
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        put16(buf, 6, sum);
+        len
+    })
+}
+
+// Send the n bytes at user address addr from socket s to its peer.
+pub fn write(s: usize, addr: u64, n: usize) -> isize {
+    let peer = NET.lock().udp.socks[s].peer;
+    match peer {
+        Some((dst, dport)) => sendto(s, addr, n, dst, dport),
+        None => -EDESTADDRREQ,
+    }
+}
+
+// Wait for a datagram on socket s and copy up to n bytes of it to user
+// address addr, as recvfrom without the sender. Nothing comes to a socket
+// that is not bound.
+pub fn read(s: usize, addr: u64, n: usize) -> isize {
+    if NET.lock().udp.socks[s].port == 0 {
+        return -ENOTCONN;
+    }
+    match recvfrom(s, addr, n, true) {
+        Ok((n, _, _)) => n as isize,
+        Err(e) => e,
+    }
+}
+
+// Wait for a datagram on socket s and copy up to n bytes of it to user
+// address addr; the rest is dropped. Returns the length copied and the
+// sender's address and port. Fails with -EAGAIN instead of waiting unless
+// wait.
+pub fn recvfrom(s: usize, addr: u64, n: usize, wait: bool) -> Result<(usize, u32, u16), isize> {
+    let p = unsafe { &*myproc().unwrap() };
+    let mut net = NET.lock();
+    let d = loop {
+        let sock = &mut net.udp.socks[s];
+        if sock.count > 0 {
+            let d = sock.queue[sock.head];
+            sock.head = (sock.head + 1) % QLEN;
+            sock.count -= 1;
+            break d;
+        }
+        if !wait {
+            return Err(-EAGAIN);
+        }
+        if unsafe { crate::proc::killed(p) } {
+            return Err(-EINTR);
+        }
+        let chan = chan(&net, s);
+        crate::proc::sleep(chan, Some(net));
+        net = NET.lock();
+    };
+    drop(net);
+
+    let n = n.min(d.len);
+    let mut allocator = ALLOCATOR.lock();
+    let ok = crate::vm::copyout(p.pgdir, &mut allocator, addr, d.page as *const u8, n);
+    allocator.kfree(d.page);
+    if !ok {
+        return Err(-1);
+    }
+    Ok((n, d.src, d.sport))
+}
@@ -0,0 +1,169 @@
+// Partition tables. At boot, scan() reads the MBR of each disk and, if it
+// is a protective one, the GPT behind it, and records the partitions it
+// finds as (dev, start sector, length). Partition n of disk dev is device
+// dev | n << 8, named like virtio0p1, and bio reads and writes it through
+// map() at its offset on the whole disk.
+//
+// Only the four primary MBR partitions are taken, not the logical ones in
+// an extended partition, and of a GPT only the first MAXPART entries.
+
+use crate::bio;
+use crate::util::PG_SIZE;
+
+pub const MAXPART: u32 = 15; // Per disk
+const NPART: usize = 32; // In all
+const SECTOR: usize = 512;
+
+const MBR_TABLE: usize = 446; // Four 16-byte entries
+const MBR_SIGNATURE: u16 = 0xAA55; // At 510
+const TYPE_PROTECTIVE: u8 = 0xEE; // The whole disk is a GPT
+const TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const GPT_SIGNATURE: &[u8] = b"EFI PART"; // At the start of sector 1
+const GPT_ENTRY_MIN: usize = 128;
+
+#[derive(Clone, Copy)]
+pub struct Part {
+    pub dev: u32,   // 0 if the slot is free
+    pub start: u64, // First sector on the whole disk
+    pub len: u64,   // In sectors
+}
+
+// Filled in by scan() at boot, before there are other CPUs or processes,
+// and never changed after.
+static mut PARTS: [Part; NPART] = [Part {
+    dev: 0,
+    start: 0,
+    len: 0,
+}; NPART];
+
+#[allow(static_mut_refs)]
+fn parts() -> &'static [Part; NPART] {
+    unsafe { &PARTS }
+}
+
+// The disk dev is on, and its partition number; 0 for a whole disk.
+pub fn whole(dev: u32) -> u32 {
+    dev & 0xFF
+}
+
+pub fn number(dev: u32) -> u32 {
+    dev >> 8
+}
+
+pub fn find(dev: u32) -> Option<Part> {
+    parts().iter().find(|p| p.dev != 0 && p.dev == dev).copied()
+}
+
+// The partitions found on disk dev.
+pub fn of(dev: u32) -> impl Iterator<Item = u32> {
+    parts()
+        .iter()
+        .filter(move |p| p.dev != 0 && whole(p.dev) == dev)
+        .map(|p| p.dev)
+}
+
+// Where count sectors from sector of dev are on its disk. Err if they do
+// not all fit in the partition.
+pub fn map(dev: u32, sector: u64, count: u64) -> Result<(u32, u64), ()> {
+    if number(dev) == 0 {
+        return Ok((dev, sector));
+    }
+    let p = find(dev).ok_or(())?;
+    if sector.checked_add(count).is_none_or(|end| end > p.len) {
+        return Err(());
+    }
+    Ok((whole(dev), p.start + sector))
+}
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn le64(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+fn add(disk: u32, n: u32, start: u64, len: u64, kind: &str) {
+    let dev = disk | n << 8;
+    #[allow(static_mut_refs)]
+    let parts = unsafe { &mut PARTS };
+    let Some(slot) = parts.iter_mut().find(|p| p.dev == 0) else {
+        crate::warn!("part: {}: too many partitions", bio::dev_name(dev));
+        return;
+    };
+    *slot = Part { dev, start, len };
+    crate::info!(
+        "part: {}: {} partition, sectors {}..{}",
+        bio::dev_name(dev),
+        kind,
+        start,
+        start + len
+    );
+}
+
+// Find the partitions of disk dev, if it has a partition table.
+pub fn scan(dev: u32) {
+    let page = crate::allocator::ALLOCATOR.lock().kalloc();
+    if page.is_null() {
+        return;
+    }
+    let buf = unsafe { core::slice::from_raw_parts_mut(page, PG_SIZE) };
+    // The MBR, and the GPT header if there is one
+    if bio::read_sectors(dev, 0, &mut buf[..2 * SECTOR]).is_ok() && le16(buf, 510) == MBR_SIGNATURE
+    {
+        let entries: [(u8, u64, u64); 4] = core::array::from_fn(|i| {
+            let e = &buf[MBR_TABLE + 16 * i..];
+            (e[4], le32(e, 8) as u64, le32(e, 12) as u64)
+        });
+        if entries.iter().any(|e| e.0 == TYPE_PROTECTIVE) {
+            gpt(dev, buf);
+        } else {
+            for (i, &(ty, start, len)) in entries.iter().enumerate() {
+                if ty != 0 && !TYPE_EXTENDED.contains(&ty) && len > 0 {
+                    add(dev, i as u32 + 1, start, len, "MBR");
+                }
+            }
+        }
+    }
+    crate::allocator::ALLOCATOR.lock().kfree(page as usize);
+}
+
+// Take the partitions of the GPT whose header is in the second sector of
+// buf, reading its entries into buf a page at a time. Partitions are
+// numbered by entry, as Linux does, so unused entries leave gaps.
+fn gpt(dev: u32, buf: &mut [u8]) {
+    let name = bio::dev_name(dev);
+    let hdr = &buf[SECTOR..2 * SECTOR];
+    if &hdr[..8] != GPT_SIGNATURE {
+        crate::error!("part: {}: protective MBR but no GPT", name);
+        return;
+    }
+    let (lba, count, size) = (le64(hdr, 72), le32(hdr, 80), le32(hdr, 84) as usize);
+    if !(GPT_ENTRY_MIN..=PG_SIZE).contains(&size) || !PG_SIZE.is_multiple_of(size) {
+        crate::error!("part: {}: bad GPT entry size {}", name, size);
+        return;
+    }
+    let per_page = PG_SIZE / size;
+    for i in 0..count.min(MAXPART) as usize {
+        if i % per_page == 0 {
+            let sector = lba + (i * size / SECTOR) as u64;
+            if bio::read_sectors(dev, sector, buf).is_err() {
+                crate::error!("part: {}: cannot read the GPT entries", name);
+                return;
+            }
+        }
+        let e = &buf[i % per_page * size..][..size];
+        // An all-zero type GUID marks an unused entry.
+        if e[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (le64(e, 32), le64(e, 40));
+        if last >= first {
+            add(dev, i as u32 + 1, first, last - first + 1, "GPT");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::util::{inl, outl};
+use crate::util::{inl, io2v, outl, DEVSPACE};
 
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
@@ -13,6 +13,14 @@ pub struct PciDevice {
     pub irq_line: u8,
 }
 
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+pub const INTEL_VENDOR_ID: u16 = 0x8086;
+
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+pub const DEVICE_WINDOW: u64 = 0x20000000; // Mapped at DEVBASE: see vm::map_highmem
+
 unsafe fn pci_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     let address = (1u32 << 31)
         | ((bus as u32) << 16)
@@ -26,6 +34,72 @@ unsafe fn pci_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     }
 }
 
+unsafe fn pci_write(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
+    let address = (1u32 << 31)
+        | ((bus as u32) << 16)
+        | ((slot as u32) << 11)
+        | ((func as u32) << 8)
+        | (offset as u32 & 0xFC);
+
+    unsafe {
+        outl(CONFIG_ADDRESS, address);
+        outl(CONFIG_DATA, value);
+    }
+}
+
+impl PciDevice {
+    // The dword of config space at offset
+    pub fn read(&self, offset: u8) -> u32 {
+        unsafe { pci_read(self.bus, self.slot, self.func, offset) }
+    }
+
+    // The physical address of memory BAR n, or None if it is an IO BAR or
+    // unassigned.
+    pub fn bar(&self, n: u8) -> Option<u64> {
+        if n > 5 {
+            return None;
+        }
+        let lo = self.read(0x10 + n * 4);
+        if lo & 1 != 0 {
+            return None;
+        }
+        let addr = if lo & 0x6 == 0x4 && n < 5 {
+            // 64-bit BAR: the high half is in the next one
+            (self.read(0x14 + n * 4) as u64) << 32 | (lo & !0xF) as u64
+        } else {
+            (lo & !0xF) as u64
+        };
+        Some(addr).filter(|&a| a != 0)
+    }
+
+    // The kernel address of [BAR n + off], a memory BAR, or None if it is
+    // not inside the device window mapped at DEVBASE. That is where the
+    // firmware puts them under QEMU.
+    pub fn map_bar(&self, n: u8, off: u32) -> Option<usize> {
+        let pa = self.bar(n)? + off as u64;
+        let window = DEVSPACE as u64..DEVSPACE as u64 + DEVICE_WINDOW;
+        if !window.contains(&pa) {
+            crate::error!("PCI: BAR{} at {:x} is outside the device window", n, pa);
+            return None;
+        }
+        Some(io2v(pa as usize))
+    }
+
+    // Keep the device from raising its INTx line, for a driver that polls.
+    pub fn disable_intx(&self) {
+        let command = self.read(PCI_COMMAND);
+        unsafe {
+            pci_write(
+                self.bus,
+                self.slot,
+                self.func,
+                PCI_COMMAND,
+                command | PCI_COMMAND_INTX_DISABLE,
+            )
+        };
+    }
+}
+
 pub unsafe fn check_device(bus: u8, slot: u8) -> Option<PciDevice> {
     let vendor_id = unsafe { pci_read(bus, slot, 0, 0) } & 0xFFFF;
     if vendor_id == 0xFFFF {
@@ -34,65 +108,66 @@ pub unsafe fn check_device(bus: u8, slot: u8) -> Option<PciDevice> {
 
     let device_id = (unsafe { pci_read(bus, slot, 0, 0) } >> 16) & 0xFFFF;
 
-    // Check for Virtio Vendor ID (0x1AF4)
-    if vendor_id == 0x1AF4 {
-        // Read BAR0
-        let bar0 = unsafe { pci_read(bus, slot, 0, 0x10) };
-        // Read Interrupt Line
-        let irq_line = (unsafe { pci_read(bus, slot, 0, 0x3C) } & 0xFF) as u8;
+    // Read BAR0
+    let bar0 = unsafe { pci_read(bus, slot, 0, 0x10) };
+    // Read Interrupt Line
+    let irq_line = (unsafe { pci_read(bus, slot, 0, 0x3C) } & 0xFF) as u8;
 
-        // If it's an IO BAR, the lowest bit is 1. We mask it out to get the address.
-        // For Legacy virtio, BAR0 is typically the IO base.
-        let base_addr = bar0 & !0x3;
+    // If it's an IO BAR, the lowest bit is 1. We mask it out to get the address.
+    // For Legacy virtio, BAR0 is typically the IO base.
+    let base_addr = bar0 & !0x3;
 
-        // Enable Bus Master (Bit 2) and IO Space (Bit 0)
-        let command = unsafe { pci_read(bus, slot, 0, 0x04) };
-        unsafe {
-            outl(
-                CONFIG_ADDRESS,
-                (1u32 << 31) | ((bus as u32) << 16) | ((slot as u32) << 11) | (0x04),
-            );
-            outl(CONFIG_DATA, command | 0x4 | 0x1);
-        }
+    Some(PciDevice {
+        bus,
+        slot,
+        func: 0,
+        vendor_id: vendor_id as u16,
+        device_id: device_id as u16,
+        base_addr,
+        irq_line,
+    })
+}
 
-        return Some(PciDevice {
-            bus,
-            slot,
-            func: 0,
-            vendor_id: vendor_id as u16,
-            device_id: device_id as u16,
-            base_addr,
-            irq_line,
-        });
-    }
+// Find a virtio device.
+pub fn scan_pci(device_id: u16) -> Option<PciDevice> {
+    find(VIRTIO_VENDOR_ID, device_id)
+}
 
-    None
+// Find every virtio device with one of device_ids, in bus and slot order.
+pub fn scan_pci_all(device_ids: &[u16]) -> impl Iterator<Item = PciDevice> + '_ {
+    find_all(VIRTIO_VENDOR_ID, device_ids)
 }
 
-pub fn scan_pci(device_id: u16) -> Option<PciDevice> {
-    for bus in 0..256 {
-        for slot in 0..32 {
-            // Only checking function 0 for simplicity.
-            // In a real OS we should check header type for multifunction.
-            unsafe {
-                if let Some(dev) = check_device(bus as u8, slot as u8) {
-                    crate::info!(
-                        "PCI: {:02x}:{:02x}.0 Vendor={:04x} Device={:04x} BAR0={:x} IRQ={}",
-                        dev.bus,
-                        dev.slot,
-                        dev.vendor_id,
-                        dev.device_id,
-                        dev.base_addr,
-                        dev.irq_line
-                    );
-
-                    // Look for Virtio Block Device
-                    if dev.device_id == device_id {
-                        return Some(dev);
-                    }
-                }
-            }
-        }
-    }
-    None
+// Find a device and let it answer to memory and IO accesses and master
+// the bus.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    find_all(vendor_id, &[device_id]).next()
+}
+
+// Like find, for every device of vendor_id with one of device_ids, as the
+// scan comes to them.
+pub fn find_all(vendor_id: u16, device_ids: &[u16]) -> impl Iterator<Item = PciDevice> + '_ {
+    // Only checking function 0 for simplicity.
+    // In a real OS we should check header type for multifunction.
+    (0..256u32)
+        .flat_map(|bus| (0..32u32).map(move |slot| (bus as u8, slot as u8)))
+        .filter_map(|(bus, slot)| unsafe { check_device(bus, slot) })
+        .filter(move |dev| dev.vendor_id == vendor_id)
+        .inspect(|dev| {
+            crate::info!(
+                "PCI: {:02x}:{:02x}.0 Vendor={:04x} Device={:04x} BAR0={:x} IRQ={}",
+                dev.bus,
+                dev.slot,
+                dev.vendor_id,
+                dev.device_id,
+                dev.base_addr,
+                dev.irq_line
+            )
+        })
+        .filter(move |dev| device_ids.contains(&dev.device_id))
+        .inspect(|dev| {
+            // Enable Bus Master (Bit 2), Memory Space (Bit 1) and IO Space (Bit 0)
+            let command = dev.read(PCI_COMMAND);
+            unsafe { pci_write(dev.bus, dev.slot, 0, PCI_COMMAND, command | 0x4 | 0x2 | 0x1) };
+        })
 }
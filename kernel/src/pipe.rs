@@ -32,7 +32,8 @@ pub fn pipealloc(f0: &mut crate::file::File, f1: &mut crate::file::File) -> Resu
     }
 
     unsafe {
-        *(p_ptr as *mut Spinlock<PipeData>) = Spinlock::new(PipeData::new(), "pipe");
+        *(p_ptr as *mut Spinlock<PipeData>) =
+            Spinlock::ranked(PipeData::new(), "pipe", crate::lockorder::RANK_PIPE);
     }
 
     f0.f_type = crate::file::FileType::Pipe;
@@ -104,7 +105,7 @@ pub fn pipewrite(pi: *mut Spinlock<PipeData>, addr: u64, mut n: usize) -> isize
     crate::debug!("pipewrite: entry pi={:?} n={}", pi, n);
     let mut p = unsafe { (*pi).lock() };
     let mut written = 0;
-    let pgdir = unsafe { (*crate::proc::mycpu().process.unwrap()).pgdir };
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
 
     while n > 0 {
         if !p.readopen {
@@ -156,11 +157,11 @@ pub fn piperead(pi: *mut Spinlock<PipeData>, addr: u64, mut n: usize) -> isize {
 
     crate::debug!("piperead: entry pi={:?} n={}", pi, n);
     let mut p = unsafe { (*pi).lock() };
-    let pgdir = unsafe { (*crate::proc::mycpu().process.unwrap()).pgdir };
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
 
     while p.nread == p.nwrite && p.writeopen {
         crate::debug!("piperead: empty, sleeping");
-        let process_ptr = crate::proc::mycpu().process.unwrap() as *const crate::proc::Process;
+        let process_ptr = crate::proc::myproc().unwrap() as *const crate::proc::Process;
         // Convert *const Process to &Process unsafe
         if unsafe { crate::proc::killed(&*process_ptr) } {
             return -1;
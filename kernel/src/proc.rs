@@ -5,9 +5,9 @@ use crate::gdt::{UCODE_SELECTOR, UDATA_SELECTOR};
 use crate::trap::TrapFrame;
 
 use crate::util::PG_SIZE;
-use crate::vm::{self, PageTable, PageTableEntry};
+use crate::vm::{self, PageTable};
 use core::arch::global_asm;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const NPROC: usize = 64;
 pub const KSTACK_SIZE: usize = PG_SIZE;
@@ -32,136 +32,282 @@ pub enum ProcessState {
     RUNNABLE,
     RUNNING,
     ZOMBIE,
+    STOPPED, // Traced and stopped; resumed by its tracer (see ptrace.rs)
 }
 
 pub const NFILE: usize = 16;
-use crate::file::File;
+use crate::file::Files;
+use crate::mm::Mm;
 
 #[derive(Clone, Copy)]
 pub struct Process {
     pub state: ProcessState,
     pub kstack: *mut u8,
     pub context: *mut Context,
-    pub pgdir: *mut PageTable,
+    pub mm: Option<*mut Mm>,   // Address space, shared with clone's threads
+    pub pgdir: *mut PageTable, // mm's page table, for the scheduler and copyin/copyout
     pub pid: usize,
     pub chan: usize,
     pub name: [u8; 16],
-    pub ofile: [Option<*mut File>; NFILE],
+    pub files: Option<*mut Files>, // Open files and cwd, shared like mm
     pub parent: Option<*mut Process>,
     pub killed: bool,
-    pub sz: usize,
+    pub xstate: i32,                         // Exit status, for the parent's wait
+    pub traced: bool,                        // Traced by its parent (see ptrace.rs)
+    pub stop_pending: bool,                  // Stop before returning to user mode
+    pub debugregs: crate::ptrace::DebugRegs, // Hardware breakpoints set by the tracer
+    pub fpu: crate::fpu::FpuState,           // FPU/SSE registers while switched out
+    pub dumpable: bool,                      // Write a core file on a fatal fault
+    pub exe: (u32, u32),                     // (dev, inum) of the executable, for symtab
+    pub sig_pending: u32,                    // Signals sent and not yet taken
+    pub sig_blocked: u32,                    // Signals held pending
+    pub sigactions: [abi::signal::SigAction; abi::signal::NSIG],
+    pub utime: u64,                   // Timer ticks in user mode
+    pub stime: u64,                   // Timer ticks in the kernel
+    pub cutime: u64,                  // utime of waited-for children
+    pub cstime: u64,                  // stime of waited-for children
+    pub busy_ticks: u64,              // Ticks since the process last blocked
+    pub level: usize,                 // Scheduling level, 0 the highest
+    pub slice: u64,                   // Ticks run at level
+    pub nice: i32,                    // NICE_MIN..=NICE_MAX, see levels
+    pub pgid: usize,                  // Process group, for Ctrl-C (see console.rs)
+    pub held: crate::lockorder::Held, // Sleep-locks held, for lock order checks
 }
 
 impl Process {
+    // Take this process's lock. It lives outside the slot, which freeproc
+    // overwrites with the lock held.
+    pub fn lock(&self) -> SpinlockGuard<'static, ()> {
+        self.spinlock().lock()
+    }
+
+    fn spinlock(&self) -> &'static Spinlock<()> {
+        let slot =
+            unsafe { (self as *const Process).offset_from(&raw const PROCS as *const Process) };
+        &PROC_LOCKS[slot as usize]
+    }
+
+    pub fn mm(&self) -> &'static mut Mm {
+        unsafe { &mut *self.mm.unwrap() }
+    }
+
+    pub fn files(&self) -> &'static mut Files {
+        unsafe { &mut *self.files.unwrap() }
+    }
+
     pub const fn new() -> Self {
         Self {
             state: ProcessState::UNUSED,
             kstack: core::ptr::null_mut(),
             context: core::ptr::null_mut(),
+            mm: None,
             pgdir: core::ptr::null_mut(),
             pid: 0,
             chan: 0,
             name: [0; 16],
-            ofile: [None; NFILE],
+            files: None,
             parent: None,
             killed: false,
-            sz: 0,
+            xstate: 0,
+            traced: false,
+            stop_pending: false,
+            debugregs: crate::ptrace::DebugRegs::new(),
+            fpu: crate::fpu::FpuState::new(),
+            dumpable: false,
+            exe: (0, 0),
+            sig_pending: 0,
+            sig_blocked: 0,
+            sigactions: [abi::signal::SigAction {
+                handler: abi::signal::SIG_DFL,
+                mask: 0,
+                flags: 0,
+                restorer: 0,
+            }; abi::signal::NSIG],
+            utime: 0,
+            stime: 0,
+            cutime: 0,
+            cstime: 0,
+            busy_ticks: 0,
+            level: 0,
+            slice: 0,
+            nice: 0,
+            pgid: 0,
+            held: crate::lockorder::Held::new(),
+        }
+    }
+}
+
+// How many processes use a shared Mm or Files; 0 is a free slot. The last
+// user to let go marks it DYING while tearing it down, so it is not claimed
+// until freed.
+pub struct Users(AtomicUsize);
+
+impl Users {
+    const DYING: usize = usize::MAX;
+
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    // Take a free slot for its first user.
+    pub fn claim(&self) -> bool {
+        self.0
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn get(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Let go; true for the last user, who must tear down and call freed.
+    pub fn put(&self) -> bool {
+        let mut n = self.0.load(Ordering::Relaxed);
+        loop {
+            assert!(n != 0 && n != Self::DYING, "Users::put");
+            let next = if n == 1 { Self::DYING } else { n - 1 };
+            match self
+                .0
+                .compare_exchange(n, next, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => return n == 1,
+                Err(now) => n = now,
+            }
         }
     }
+
+    pub fn freed(&self) {
+        self.0.store(0, Ordering::Release);
+    }
 }
 
 pub const NCPU: usize = 8;
 
+// While in the kernel, GS points at the running CPU's Cpu (see init_gs), so
+// mycpu() and myproc() are each one load, and cannot be torn by a move to
+// another CPU. The first fields are at fixed offsets, for asm/syscall.S.
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct Cpu {
+    this: *mut Cpu,            // gs:0
+    pub kstack: u64,           // gs:8, top of the running process's kernel stack
+    user_rsp: u64,             // gs:16, saved by syscall entry
+    pub process: *mut Process, // gs:24, null in the scheduler
     pub lapicid: u32,
     pub scheduler_context: *mut Context,
-    pub process: Option<*mut Process>, // Helper to track current process ptr
     pub started: bool,
     pub ncli: usize,
     pub intena: bool,
+    pub held: crate::lockorder::Held, // Spinlocks held, for lock order checks
 }
 
 impl Cpu {
     pub const fn new() -> Self {
         Self {
+            this: core::ptr::null_mut(),
+            kstack: 0,
+            user_rsp: 0,
+            process: core::ptr::null_mut(),
             lapicid: 0,
             scheduler_context: core::ptr::null_mut(),
-            process: None,
             started: false,
             ncli: 0,
             intena: false,
+            held: crate::lockorder::Held::new(),
         }
     }
 }
 
+const _: () = {
+    assert!(core::mem::offset_of!(Cpu, this) == 0);
+    assert!(core::mem::offset_of!(Cpu, kstack) == 8);
+    assert!(core::mem::offset_of!(Cpu, user_rsp) == 16);
+    assert!(core::mem::offset_of!(Cpu, process) == 24);
+};
+
 pub static mut CPUS: [Cpu; NCPU] = [Cpu::new(); NCPU];
 pub static mut PROCS: [Process; NPROC] = [Process::new(); NPROC];
-pub static PROCS_LOCK: crate::spinlock::Spinlock<()> =
-    crate::spinlock::Spinlock::new((), "PROCS_LOCK");
-static mut PID_COUNTER: usize = 0;
-pub static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Each process has a lock, PROC_LOCKS[slot] (see Process::lock), held to
+// change its state, chan, killed, signal and scheduling fields, and across
+// the switch to and from the scheduler. At most one is held at a time.
+// WAIT_LOCK guards every parent link, and is what wait() and a tracer sleep
+// under, so that a child exiting or stopping cannot be missed. It is taken
+// before a process lock.
+static PROC_LOCKS: [Spinlock<()>; NPROC] =
+    [const { Spinlock::ranked((), "proc", crate::lockorder::RANK_PROC) }; NPROC];
+pub static WAIT_LOCK: Spinlock<()> = Spinlock::ranked((), "WAIT_LOCK", crate::lockorder::RANK_WAIT);
+static PID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// The first user process. Orphaned children are re-parented to it.
+static mut INITPROC: *mut Process = core::ptr::null_mut();
 
 pub fn init_cpus() {
     unsafe {
         for (i, cpu) in CPUS.iter_mut().enumerate() {
             cpu.lapicid = i as u32;
         }
-        INITIALIZED.store(true, Ordering::Release);
+    }
+}
+
+// Point GS at CPU id's Cpu. Each CPU does this first, before anything takes
+// a lock. Entry from user mode swaps it with KERNEL_GS_BASE (swapgs), which
+// holds the user's base, 0, while in the kernel.
+pub fn init_gs(id: usize) {
+    unsafe {
+        let cpu = &raw mut CPUS[id];
+        (*cpu).this = cpu;
+        crate::util::wrmsr(crate::util::MSR_GS_BASE, cpu as u64);
+        crate::util::wrmsr(crate::util::MSR_KERNEL_GS_BASE, 0);
     }
 }
 
 pub fn mycpu() -> &'static mut Cpu {
-    if !INITIALIZED.load(Ordering::Acquire) {
-        return unsafe { &mut CPUS[0] };
+    let cpu: *mut Cpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+        &mut *cpu
     }
-    let apic_id = crate::lapic::id();
+}
+
+// The process running on this CPU, if any.
+pub fn myproc() -> Option<*mut Process> {
+    let p: *mut Process;
     unsafe {
-        for cpu in CPUS.iter_mut() {
-            if cpu.lapicid == apic_id {
-                return cpu;
-            }
-        }
-        // Fallback for bootstrap before APIC init? Or just assume index 0?
-        // Actually, initial CPU often has ID 0, but not always.
-        // For now, let's assume we can always find it.
-        // If not found, it's a panic.
-        panic!("mycpu: unknown apicid {}", apic_id);
+        core::arch::asm!("mov {}, gs:[24]", out(reg) p, options(nostack, preserves_flags, readonly));
     }
+    (!p.is_null()).then_some(p)
 }
 
-use crate::spinlock::SpinlockGuard;
+use crate::spinlock::{Spinlock, SpinlockGuard};
 
+// Sleep on chan, letting go of guard. Taking the process lock first means a
+// wakeup issued under guard cannot slip in before the process sleeps.
 pub fn sleep<T>(chan: usize, guard: Option<SpinlockGuard<T>>) {
-    let cpu = mycpu();
-
-    // Acquire ptable lock
-    let ptable_guard = PROCS_LOCK.lock();
+    // Without a process (at boot), callers just poll.
+    let Some(p) = myproc() else {
+        drop(guard);
+        return;
+    };
+    let p = unsafe { &mut *p };
 
-    // Release guard
+    let p_guard = p.lock();
     drop(guard);
 
-    unsafe {
-        if let Some(p) = cpu.process.as_mut() {
-            let p = &mut **p;
-            p.chan = chan;
-            p.state = ProcessState::SLEEPING;
-        }
-
-        sched(ptable_guard);
-
-        if let Some(p) = cpu.process.as_mut() {
-            let p = &mut **p;
-            p.chan = 0;
-        }
-    }
-    // ptable_guard dropped here
+    p.chan = chan;
+    p.state = ProcessState::SLEEPING;
+    unsafe { sched(p_guard) };
+    p.chan = 0;
 }
 
+// Wake every process sleeping on chan. Call without holding a process lock.
 pub fn wakeup(chan: usize) {
-    let _guard = PROCS_LOCK.lock();
+    let me = myproc();
     unsafe {
         for p in PROCS.iter_mut() {
+            if Some(p as *mut Process) == me {
+                continue;
+            }
+            let _guard = p.lock();
             if p.state == ProcessState::SLEEPING && p.chan == chan {
                 p.state = ProcessState::RUNNABLE;
                 p.chan = 0;
@@ -170,15 +316,15 @@ pub fn wakeup(chan: usize) {
     }
 }
 
+// Switch to the scheduler, holding guard, the lock of the current process,
+// which has already left RUNNING. It is let go once the process runs again.
 pub unsafe fn sched(guard: SpinlockGuard<()>) {
     let cpu = mycpu();
 
     if let Some(p) = cpu.process.as_mut() {
-        let p = &mut **p;
-
         if cpu.ncli != 1 {
             crate::error!("PANIC: sched ncli={}", cpu.ncli);
-            crate::error!("PROCS_LOCK held: {}", PROCS_LOCK.holding());
+            crate::error!("process lock held: {}", p.spinlock().holding());
             // crate::uart_println!("VIRTIO_LOCK held: {}", crate::virtio::VIRTIO_LOCK.holding());
             // crate::uart_println!("BCACHE held: {}", crate::bio::BCACHE.holding());
             // crate::uart_println!("ALLOCATOR held: {}", crate::allocator::ALLOCATOR.holding());
@@ -190,30 +336,155 @@ pub unsafe fn sched(guard: SpinlockGuard<()>) {
         if unsafe { crate::util::readeflags() } & 0x200 != 0 {
             panic!("sched: interrupts enabled");
         }
+        // Only being preempted (RUNNABLE) keeps a CPU-bound stretch going.
+        if p.state != ProcessState::RUNNABLE {
+            p.busy_ticks = 0;
+            if p.slice < quantum(p.level) / 2 {
+                p.level = p.level.saturating_sub(1).max(levels(p.nice).0);
+                p.slice = 0;
+            }
+        }
 
         swtch(&mut p.context as *mut _, cpu.scheduler_context);
     }
     drop(guard);
 }
 
-pub fn yield_proc() {
-    let guard = PROCS_LOCK.lock();
-    let cpu = mycpu();
+// A process that runs this many ticks without ever blocking is reported as
+// a likely runaway (e.g. stuck in an infinite loop). Diagnostic only.
+pub const RUNAWAY_TICKS: u64 = 200;
+
+// Charge a timer tick to the process it interrupted, if any, and return
+// whether that process should give up the CPU: its time slice is used up, or
+// a process at a higher level is waiting. Called from the timer interrupt,
+// so with interrupts disabled.
+pub fn account_tick(user: bool) -> bool {
+    let Some(p) = myproc() else {
+        return false;
+    };
+    let p = unsafe { &mut *p };
+    if user {
+        p.utime += 1;
+    } else {
+        p.stime += 1;
+    }
+    p.busy_ticks += 1;
+    if p.busy_ticks == RUNAWAY_TICKS {
+        crate::warn!(
+            "high CPU: pid={} name={:?} ran {} ticks without sleeping",
+            p.pid,
+            p.name,
+            p.busy_ticks
+        );
+    }
+
+    let level = {
+        let _guard = p.lock();
+        p.slice += 1;
+        if p.slice >= quantum(p.level) {
+            p.level = (p.level + 1).min(levels(p.nice).1);
+            p.slice = 0;
+            return true;
+        }
+        p.level
+    };
+    // A hint only, so read without the locks.
     unsafe {
-        if let Some(p) = cpu.process.as_mut() {
-            let p = &mut **p;
-            p.state = ProcessState::RUNNABLE;
-            sched(guard);
-        } else {
-            drop(guard);
+        PROCS.iter().any(|q| {
+            core::ptr::read_volatile(&q.state) == ProcessState::RUNNABLE
+                && core::ptr::read_volatile(&q.level) < level
+        })
+    }
+}
+
+// Scheduling is a multi-level feedback queue. A process runs at one of
+// NQUEUE levels, 0 the highest, and the scheduler picks the first runnable
+// process at the highest level, round-robin within it. Using up a time
+// slice at a level, quantum(level) ticks, moves a process down one; blocking
+// before half of it is used moves it up one, so interactive processes stay
+// above CPU-bound ones. Every BOOST_TICKS all go back to the top, so that
+// none starves.
+pub const NQUEUE: usize = 4;
+pub const BOOST_TICKS: u64 = 100;
+
+fn quantum(level: usize) -> u64 {
+    2 << level
+}
+
+// The highest and lowest level a process with nice value nice moves between:
+// a positive nice value starts it further down, a negative one keeps it from
+// sinking as far.
+fn levels(nice: i32) -> (usize, usize) {
+    let top = nice.max(0) as usize * NQUEUE / 20;
+    let bottom = (20 + nice.min(0)) as usize * (NQUEUE - 1) / 20;
+    (top, bottom)
+}
+
+// Move every process back to its top level. Called from the timer interrupt
+// every BOOST_TICKS.
+pub fn boost() {
+    unsafe {
+        for p in PROCS.iter_mut() {
+            let _guard = p.lock();
+            p.level = levels(p.nice).0;
+            p.slice = 0;
+        }
+    }
+}
+
+// nice(inc): add inc to the caller's nice value, kept within NICE_MIN and
+// NICE_MAX, and return the new value.
+pub fn nice(inc: isize) -> isize {
+    use abi::syscall::{NICE_MAX, NICE_MIN};
+    let p = unsafe { &mut *myproc().unwrap() };
+    let _guard = p.lock();
+    p.nice = (p.nice as isize)
+        .saturating_add(inc)
+        .clamp(NICE_MIN, NICE_MAX) as i32;
+    let (top, bottom) = levels(p.nice);
+    p.level = p.level.clamp(top, bottom);
+    p.nice as isize
+}
+
+// The next process to run, a RUNNABLE one at the highest level. The scan
+// starts after the last one picked, so that each level is served
+// round-robin. It reads without the process locks: the scheduler checks the
+// state again under the lock of the one picked.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn pick() -> Option<&'static mut Process> {
+    let next = NEXT.load(Ordering::Relaxed);
+    let mut best: Option<(usize, usize)> = None;
+    for k in 0..NPROC {
+        let i = (next + k) % NPROC;
+        let p = &PROCS[i];
+        let level = core::ptr::read_volatile(&p.level);
+        if core::ptr::read_volatile(&p.state) == ProcessState::RUNNABLE
+            && best.is_none_or(|(_, l)| level < l)
+        {
+            best = Some((i, level));
         }
     }
+    let (i, _) = best?;
+    NEXT.store((i + 1) % NPROC, Ordering::Relaxed);
+    Some(&mut PROCS[i])
+}
+
+pub fn yield_proc() {
+    let Some(p) = myproc() else {
+        return;
+    };
+    let p = unsafe { &mut *p };
+    let guard = p.lock();
+    p.state = ProcessState::RUNNABLE;
+    unsafe { sched(guard) };
 }
 
+// A new process starts out holding its lock, taken by the scheduler.
 #[unsafe(no_mangle)]
-extern "C" fn release_procs_lock() {
+extern "C" fn release_proc_lock() {
     unsafe {
-        PROCS_LOCK.unlock();
+        (*myproc().unwrap()).spinlock().unlock();
     }
 }
 
@@ -224,10 +495,45 @@ unsafe extern "C" {
 global_asm!(
     ".global forkret",
     "forkret:",
-    "call release_procs_lock",
+    "call release_proc_lock",
     "jmp trapret"
 );
 
+// Program run as the first process, unless overridden by init= on the
+// kernel command line.
+pub const INIT_PATH: &str = "/init";
+
+unsafe extern "C" {
+    fn initret();
+}
+
+// Like forkret, but the first process has no user image yet: load it from
+// the file system before returning to user mode.
+global_asm!(
+    ".global initret",
+    "initret:",
+    "call release_proc_lock",
+    "call exec_init",
+    "jmp trapret"
+);
+
+#[unsafe(no_mangle)]
+extern "C" fn exec_init() {
+    let path = crate::cmdline::get("init").unwrap_or(INIT_PATH);
+    // Without a root filesystem there is nothing to run. Stay asleep rather
+    // than exit, which would panic.
+    if crate::fs::rootdev() == 0 {
+        crate::error!("exec_init: no root filesystem, not starting {}", path);
+        loop {
+            sleep::<()>(core::ptr::addr_of!(INITPROC) as usize, None);
+        }
+    }
+    let mut argv = crate::exec::ExecArgs::new().expect("exec_init: out of memory");
+    if argv.push(path).is_err() || crate::exec::exec(path, &argv) < 0 {
+        panic!("exec_init: cannot exec {}", path);
+    }
+}
+
 unsafe extern "C" {
     fn swtch(old: *mut *mut Context, new: *mut Context);
 }
@@ -265,14 +571,19 @@ pub fn init_process(allocator: &mut Allocator) {
     }
 
     if let Some(p) = p_option {
+        p.pid = PID_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+        p.pgid = p.pid;
+        p.state = ProcessState::EMBRYO;
         unsafe {
-            PID_COUNTER += 1;
-            p.pid = PID_COUNTER;
+            INITPROC = p as *mut Process;
         }
-        p.state = ProcessState::EMBRYO;
+        p.dumpable = crate::cmdline::get("coredump").is_some();
 
         // Allocation User Page Table
-        p.pgdir = vm::uvm_create(allocator).expect("uvm_create failed");
+        let mm = crate::mm::alloc(allocator).expect("init_process: no address space");
+        p.pgdir = mm.pgdir;
+        p.mm = Some(mm);
+        p.files = Some(crate::file::files_alloc().expect("init_process: no file table"));
 
         // Allocate kernel stack
         p.kstack = allocator.kalloc();
@@ -282,25 +593,8 @@ pub fn init_process(allocator: &mut Allocator) {
         }
         crate::debug!("kstack: 0x{:x}", p.kstack as usize);
 
-        // Init code
-        let initcode: &[u8] = include_bytes!("../asm/build/initcode");
-        let mem = allocator.kalloc();
-        if mem.is_null() {
-            panic!("init_process: kalloc failed");
-        }
-        unsafe {
-            core::ptr::copy_nonoverlapping(initcode.as_ptr(), mem, initcode.len());
-        }
-        // Map init code at 0
-        vm::map_pages(
-            p.pgdir,
-            allocator,
-            0,
-            crate::util::v2p(mem as usize) as u64,
-            PG_SIZE as u64,
-            PageTableEntry::WRITABLE | PageTableEntry::USER,
-        );
-
+        // The address space stays empty: initret execs INIT_PATH from disk,
+        // which fills in the user code, stack and trap frame.
         let sp = p.kstack as usize + KSTACK_SIZE;
 
         // Setup context
@@ -308,22 +602,20 @@ pub fn init_process(allocator: &mut Allocator) {
         let tf_addr = sp - core::mem::size_of::<TrapFrame>();
         let tf = tf_addr as *mut TrapFrame;
 
-        // Set up TrapFrame
+        // Set up TrapFrame (rip and rsp are set by exec)
         unsafe {
             (*tf).cs = UCODE_SELECTOR as u64;
             (*tf).ss = UDATA_SELECTOR as u64;
-            (*tf).rsp = PG_SIZE as u64; // User stack at top of page
             (*tf).rflags = 0x202; // IF | Reserved
-            (*tf).rip = 0; // Entry point
         }
 
         // Reserve space for Context below TrapFrame
         let context_addr = tf_addr - core::mem::size_of::<Context>();
         p.context = context_addr as *mut Context;
 
-        // Set context to return to forkret
+        // Set context to return to initret
         unsafe {
-            (*p.context).rip = forkret as *const () as usize as u64;
+            (*p.context).rip = initret as *const () as usize as u64;
             (*p.context).r15 = 0;
             (*p.context).r14 = 0;
             (*p.context).r13 = 0;
@@ -338,63 +630,109 @@ pub fn init_process(allocator: &mut Allocator) {
         p.name[2] = b'i';
         p.name[3] = b't';
 
-        for i in 0..3 {
+        for _ in 0..3 {
             if let Some(f) = crate::file::filealloc() {
                 f.f_type = crate::file::FileType::Device;
-                f.major = 1; // Console
+                f.major = abi::fs::CONSOLE_MAJOR;
+                f.minor = 0;
                 f.readable = true;
                 f.writable = true;
-                p.ofile[i] = Some(f as *mut _);
+                p.files().fdalloc(f);
             }
         }
-        p.sz = PG_SIZE; // Init code page
     }
 }
 
+unsafe extern "C" {
+    fn kthreadret();
+}
+
+// A kernel thread starts here, with its entry point in r12 (see swtch).
+global_asm!(
+    ".global kthreadret",
+    "kthreadret:",
+    "call release_proc_lock",
+    "call r12",
+    "ud2"
+);
+
+// Start a kernel thread: a process with no address space or files of its
+// own that runs entry in the kernel, on the kernel's page table, and never
+// returns to user mode or exits. Signals are not sent to it.
+pub fn spawn_kthread(allocator: &mut Allocator, name: &str, entry: extern "C" fn() -> !) {
+    let Some(p) = (unsafe { PROCS.iter_mut() }).find(|p| p.state == ProcessState::UNUSED) else {
+        panic!("spawn_kthread: no process for {}", name);
+    };
+    p.kstack = allocator.kalloc();
+    if p.kstack.is_null() {
+        panic!("spawn_kthread: no kernel stack for {}", name);
+    }
+    p.pid = PID_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+    p.pgid = p.pid;
+    p.pgdir = vm::kpgdir();
+    let len = name.len().min(p.name.len() - 1);
+    p.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    let sp = p.kstack as usize + KSTACK_SIZE;
+    p.context = (sp - core::mem::size_of::<Context>()) as *mut Context;
+    unsafe {
+        p.context.write(Context {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: entry as usize as u64,
+            rbx: 0,
+            rbp: 0,
+            rip: kthreadret as *const () as usize as u64,
+        });
+    }
+    p.state = ProcessState::RUNNABLE;
+}
+
 pub fn scheduler() {
     let cpu = mycpu();
-    cpu.process = None; // Ensure no process running
+    cpu.process = core::ptr::null_mut(); // Ensure no process running
 
     crate::info!("Scheduler starting on CPU {}", cpu.lapicid);
     loop {
         // Enable interrupts to allow IRQs to wake us up
         unsafe { core::arch::asm!("sti") };
 
-        // Acquire PTABLE LOCK
-        // rate::debug!("DEBUG: sched acquiring lock");
-        let guard = PROCS_LOCK.lock();
-        // crate::debug!("DEBUG: sched lock acquired");
-
         let mut ran_process = false;
         unsafe {
-            for i in 0..NPROC {
-                let p = &mut PROCS[i];
-                if p.state == ProcessState::RUNNABLE {
-                    p.state = ProcessState::RUNNING;
+            let picked = pick();
+            // The process gives its lock back by the time it switches back.
+            let guard = picked.as_ref().map(|p| p.lock());
+            if let Some(p) = picked.filter(|p| p.state == ProcessState::RUNNABLE) {
+                p.state = ProcessState::RUNNING;
 
-                    cpu.process = Some(p as *mut Process);
+                cpu.process = p as *mut Process;
 
-                    // Switch to user page table
-                    vm::switch(p.pgdir);
+                // Switch to user page table
+                vm::switch(p.pgdir);
 
-                    // Set Kernel Stack in TSS
-                    let kstack_top = p.kstack as usize + KSTACK_SIZE;
-                    crate::gdt::set_kernel_stack(kstack_top as u64, cpu.lapicid as usize);
+                // Set the kernel stack for traps (TSS) and syscalls
+                let kstack_top = p.kstack as usize + KSTACK_SIZE;
+                crate::gdt::set_kernel_stack(kstack_top as u64, cpu.lapicid as usize);
+                cpu.kstack = kstack_top as u64;
 
-                    // Switch to process
-                    swtch(&mut cpu.scheduler_context as *mut _, p.context);
+                crate::ptrace::load_debugregs(p);
+                crate::fpu::disable();
 
-                    // Back from process
-                    vm::switch(crate::vm::kpgdir()); // switch back to kvm
+                // Switch to process
+                swtch(&mut cpu.scheduler_context as *mut _, p.context);
 
-                    cpu.process = None;
+                crate::fpu::save_if_used(&mut p.fpu);
 
-                    ran_process = true;
-                }
+                // Back from process
+                vm::switch(crate::vm::kpgdir()); // switch back to kvm
+
+                cpu.process = core::ptr::null_mut();
+
+                ran_process = true;
             }
+            drop(guard);
         }
-        // Release lock
-        drop(guard);
 
         if !ran_process {
             // unsafe { core::arch::asm!("hlt") };
@@ -406,17 +744,37 @@ pub fn scheduler() {
 }
 
 pub fn fork() -> isize {
-    let mut pid: isize = -1;
+    copy_process(None)
+}
 
-    let cpu = mycpu();
-    let curproc = unsafe { &mut *cpu.process.unwrap() };
+// clone(entry, stack, arg): start a thread, a child process sharing the
+// caller's address space, open files and working directory, running
+// entry(arg) on stack. It is entered as if called, but must not return.
+// Both go straight into the thread's trap frame, so they must lie in user
+// space, which a non-canonical address never does.
+pub fn clone(entry: u64, stack: u64, arg: u64) -> isize {
+    use crate::exec::USTACK_TOP;
+    if entry >= USTACK_TOP || !(16..=USTACK_TOP).contains(&stack) {
+        return -abi::syscall::EINVAL;
+    }
+    copy_process(Some((entry, stack, arg)))
+}
+
+// A child of the current process for fork, or for clone if thread is the
+// thread's (entry, stack, arg). Returns its pid.
+fn copy_process(thread: Option<(u64, u64, u64)>) -> isize {
+    let pid: isize;
+
+    let curproc = unsafe { &mut *myproc().unwrap() };
 
     // Allocate process
     let mut np_opt = None;
-    let mut guard = PROCS_LOCK.lock();
     unsafe {
         for p in PROCS.iter_mut() {
+            let _guard = p.lock();
             if p.state == ProcessState::UNUSED {
+                p.pid = PID_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+                p.state = ProcessState::EMBRYO;
                 np_opt = Some(p);
                 break;
             }
@@ -424,59 +782,69 @@ pub fn fork() -> isize {
     }
 
     if let Some(np) = np_opt {
-        unsafe {
-            PID_COUNTER += 1;
-            np.pid = PID_COUNTER;
-            pid = np.pid as isize;
-            np.state = ProcessState::EMBRYO;
-        }
-        // Drop lock to avoid deadlock with filedup (FTABLE lock)
-        drop(guard);
+        pid = np.pid as isize;
 
         unsafe {
-            // Allocate kernel stack
-            np.kstack = crate::allocator::ALLOCATOR.lock().kalloc();
-            if np.kstack.is_null() {
-                // Cleanup
-                guard = PROCS_LOCK.lock();
-                np.state = ProcessState::UNUSED;
-                // pid assignment is permanent? PID_COUNTER incremented. That's fine.
-                drop(guard);
-                return -1;
-            }
-
-            // Copy user memory
-            match vm::uvm_create(&mut crate::allocator::ALLOCATOR.lock()) {
-                Some(pgdir) => np.pgdir = pgdir,
-                None => {
-                    // Cleanup kstack
-                    // crate::allocator::ALLOCATOR.lock().kfree(np.kstack); // TODO implementations
-                    guard = PROCS_LOCK.lock();
-                    np.kstack = core::ptr::null_mut();
-                    np.state = ProcessState::UNUSED;
-                    drop(guard);
-                    return -1;
+            // Allocate kernel stack and, for fork, copy every mapped user
+            // page, including the stack above sz. Other threads must not
+            // change the address space meanwhile.
+            let mm = curproc.mm();
+            let mm_guard = mm.lock();
+            let ok = {
+                let mut allocator = crate::allocator::ALLOCATOR.lock();
+                np.kstack = allocator.kalloc();
+                !np.kstack.is_null()
+                    && match thread {
+                        Some(_) => {
+                            mm.share();
+                            np.mm = Some(mm);
+                            true
+                        }
+                        None => match crate::mm::alloc(&mut allocator) {
+                            Some(new) => {
+                                np.mm = Some(new);
+                                vm::uvm_copy(mm.pgdir, new.pgdir, &mut allocator)
+                            }
+                            None => false,
+                        },
+                    }
+            };
+            let files = match thread {
+                Some(_) => {
+                    curproc.files().share();
+                    curproc.files
                 }
-            }
-
-            if !vm::uvm_copy(
-                curproc.pgdir,
-                np.pgdir,
-                curproc.sz as u64,
-                &mut crate::allocator::ALLOCATOR.lock(),
-            ) {
-                // Cleanup
-                guard = PROCS_LOCK.lock();
-                // Helper to free vm and stack?
-                // For now just leakage or manual cleanup if implemented
-                np.pgdir = core::ptr::null_mut(); // Leak?
-                np.kstack = core::ptr::null_mut();
-                np.state = ProcessState::UNUSED;
+                None => crate::file::files_alloc().map(|f| f as *mut Files),
+            };
+            np.files = files;
+            if !ok || files.is_none() {
+                drop(mm_guard);
+                let guard = np.lock();
+                let left = freeproc(np);
                 drop(guard);
+                left.free();
                 return -1;
             }
-
-            np.sz = curproc.sz;
+            np.pgdir = np.mm().pgdir;
+            if thread.is_none() {
+                let new = np.mm();
+                new.sz = mm.sz;
+                new.heap_start = mm.heap_start;
+                new.ustack = mm.ustack;
+                crate::mmap::fork_copy(new, mm);
+                np.files().copy_from(curproc.files());
+            }
+            drop(mm_guard);
+
+            np.nice = curproc.nice;
+            np.pgid = curproc.pgid;
+            np.level = levels(np.nice).0;
+            np.dumpable = curproc.dumpable;
+            np.exe = curproc.exe;
+            np.sig_blocked = curproc.sig_blocked;
+            np.sigactions = curproc.sigactions;
+            crate::fpu::save_if_used(&mut curproc.fpu);
+            np.fpu = curproc.fpu;
 
             // Copy trap frame
             let sp = np.kstack as usize + KSTACK_SIZE;
@@ -488,6 +856,11 @@ pub fn fork() -> isize {
 
             // Set return value for child
             (*tf).rax = 0;
+            if let Some((entry, stack, arg)) = thread {
+                (*tf).rip = entry;
+                (*tf).rsp = (stack & !15).wrapping_sub(8);
+                (*tf).rdi = arg;
+            }
 
             // Setup context
             let context_addr = tf_addr - core::mem::size_of::<Context>();
@@ -501,52 +874,73 @@ pub fn fork() -> isize {
             (*np.context).rbx = 0;
             (*np.context).rbp = 0;
 
-            // Copy open files
-            for fd in 0..NFILE {
-                if let Some(f) = curproc.ofile[fd] {
-                    crate::file::filedup(&mut *f);
-                    np.ofile[fd] = Some(f);
-                }
-            }
             // Safely copying name
             np.name = curproc.name;
 
-            // Re-acquire lock to set state and parent
-            guard = PROCS_LOCK.lock();
-            np.parent = Some(curproc as *mut Process);
+            {
+                let _guard = WAIT_LOCK.lock();
+                np.parent = Some(curproc as *mut Process);
+            }
+            let _guard = np.lock();
             np.state = ProcessState::RUNNABLE;
         }
     } else {
-        drop(guard);
         return -1;
     }
 
-    drop(guard);
     pid
 }
 
-pub fn exit(status: isize) {
-    let cpu = mycpu();
-    let curproc = unsafe { &mut *cpu.process.unwrap() };
+pub fn exit(status: isize) -> ! {
+    let curproc = unsafe { &mut *myproc().unwrap() };
 
     crate::info!("Exit: pid={} status={}", curproc.pid, status);
 
-    // Close all open files
-    for fd in 0..NFILE {
-        if let Some(mut f) = curproc.ofile[fd] {
-            curproc.ofile[fd] = None;
-            unsafe { crate::file::fileclose(&mut *f) };
-        }
+    // init loops forever re-spawning the shell, so it exiting means the system is unusable.
+    if core::ptr::eq(curproc, unsafe { INITPROC }) {
+        panic!("init exiting (status={})", status);
     }
 
-    let guard = PROCS_LOCK.lock();
+    // Close all open files, unless threads still share them. The address
+    // space is in use until the switch away: the parent lets go of it.
+    if let Some(files) = curproc.files.take() {
+        crate::file::files_put(unsafe { &mut *files });
+    }
 
-    // Wake up parent
+    let wait_guard = WAIT_LOCK.lock();
+
+    // Pass abandoned children to init, which reaps them in its wait loop.
+    let mut abandoned = false;
     unsafe {
-        wakeup1(curproc.parent);
+        for p in PROCS.iter_mut() {
+            if p.parent == Some(curproc as *mut Process) {
+                p.parent = Some(INITPROC);
+                abandoned = true;
+            }
+        }
+        if abandoned {
+            wakeup(INITPROC as usize);
+        }
+    }
+
+    // Wake up parent
+    if let Some(parent) = curproc.parent {
+        let parent = unsafe { &mut *parent };
+        let guard = parent.lock();
+        crate::signal::send(parent, abi::signal::SIGCHLD);
+        drop(guard);
+        wakeup(parent as *mut Process as usize);
+    }
+    if curproc.traced {
+        wakeup(crate::ptrace::trace_chan(curproc));
     }
 
+    // The parent cannot look at this process before WAIT_LOCK goes, and the
+    // slot cannot be freed before the switch away lets go of its lock.
+    let guard = curproc.lock();
+    curproc.xstate = status as i32;
     curproc.state = ProcessState::ZOMBIE;
+    drop(wait_guard);
 
     unsafe {
         sched(guard);
@@ -554,77 +948,227 @@ pub fn exit(status: isize) {
     panic!("zombie exit");
 }
 
-pub fn wait(_pid: isize) -> isize {
-    let cpu = mycpu();
-    let curproc = unsafe { &mut *cpu.process.unwrap() };
+// Wait for the child pid, or any child if pid <= 0, to exit. Its exit status
+// is copied out to status_addr unless that is 0. Returns the child's pid, or
+// -1 if there is no such child.
+pub fn wait(pid: isize, status_addr: u64) -> isize {
+    let curproc = unsafe { &mut *myproc().unwrap() };
 
-    let mut guard = PROCS_LOCK.lock();
+    let mut wait_guard = WAIT_LOCK.lock();
     loop {
         let mut have_kids = false;
-        let mut child_pid: isize = -1;
+        let mut reaped = None;
 
         unsafe {
             for p in PROCS.iter_mut() {
-                if p.parent == Some(curproc as *mut Process) {
-                    have_kids = true;
-                    if p.state == ProcessState::ZOMBIE {
-                        // Found one
-                        child_pid = p.pid as isize;
-
-                        // Clean up
-                        // kfree(p.kstack)
-                        // freevm(p.pgdir)
-                        p.kstack = core::ptr::null_mut();
-                        p.pgdir = core::ptr::null_mut();
-                        p.state = ProcessState::UNUSED;
-                        p.pid = 0;
-                        p.parent = None;
-                        p.name = [0; 16];
-                        p.killed = false;
-
-                        break;
-                    }
+                if p.parent != Some(curproc as *mut Process) || (pid > 0 && p.pid as isize != pid) {
+                    continue;
+                }
+                have_kids = true;
+                let _guard = p.lock();
+                if p.state == ProcessState::ZOMBIE {
+                    curproc.cutime += p.utime + p.cutime;
+                    curproc.cstime += p.stime + p.cstime;
+                    reaped = Some((p.pid as isize, p.xstate, freeproc(p)));
+                    break;
                 }
             }
         }
 
-        if child_pid != -1 {
-            drop(guard);
+        if let Some((child_pid, xstate, left)) = reaped {
+            drop(wait_guard);
+            left.free();
+            // The child is reaped either way; a bad address only costs the
+            // caller its status.
+            if status_addr != 0 {
+                let mut allocator = crate::allocator::ALLOCATOR.lock();
+                if !crate::vm::copyout(
+                    curproc.pgdir,
+                    &mut allocator,
+                    status_addr,
+                    &xstate as *const i32 as *const u8,
+                    core::mem::size_of::<i32>(),
+                ) {
+                    return -1;
+                }
+            }
             return child_pid;
         }
 
-        if !have_kids || curproc.killed {
-            drop(guard);
+        if !have_kids || unsafe { killed(curproc) } {
             return -1;
         }
 
-        // Wait for children to exit (sleep on self)
-        unsafe {
-            // Manual sleep to avoid deadlock (sleep tries to acquire PROCS_LOCK)
-            // We already hold PROCS_LOCK (guard), so just setup state and sched.
-            curproc.chan = curproc as *mut Process as usize;
-            curproc.state = ProcessState::SLEEPING;
-            sched(guard);
-            curproc.chan = 0;
-            // sleep(curproc as *mut Process as usize, Some(guard));
+        // Children exit and wake this process under WAIT_LOCK.
+        sleep(curproc as *mut Process as usize, Some(wait_guard));
+        wait_guard = WAIT_LOCK.lock();
+    }
+}
+
+// What a process slot held that can only be freed without its lock.
+struct Leftovers {
+    kstack: *mut u8,
+    mm: Option<*mut Mm>,
+    files: Option<*mut Files>,
+}
+
+impl Leftovers {
+    fn free(self) {
+        if let Some(files) = self.files {
+            crate::file::files_put(unsafe { &mut *files });
+        }
+        if let Some(mm) = self.mm {
+            crate::mm::put(unsafe { &mut *mm });
+        }
+        if !self.kstack.is_null() {
+            crate::allocator::ALLOCATOR
+                .lock()
+                .kfree(self.kstack as usize);
         }
-        guard = PROCS_LOCK.lock();
     }
 }
 
-unsafe fn wakeup1(chan: Option<*mut Process>) {
-    // Only wake up processes sleeping on chan (in this case, parent pointer for wait)
-    // Actually wait uses parent pointer as channel? Or simpler convention.
-    // xv6 uses parent ptr.
-    if let Some(c) = chan {
-        for p in PROCS.iter_mut() {
-            if p.state == ProcessState::SLEEPING && p.chan == c as usize {
-                p.state = ProcessState::RUNNABLE;
-            }
+// Return p's slot to the table. p must not be running: a zombie is safe,
+// since the scheduler switches off its stack and page table before letting
+// go of its lock. Call with p's lock held, then free what is returned.
+fn freeproc(p: &mut Process) -> Leftovers {
+    let left = Leftovers {
+        kstack: p.kstack,
+        mm: p.mm,
+        files: p.files,
+    };
+    *p = Process::new();
+    left
+}
+
+// Send signal sig to process pid (see signal::send). init only takes
+// signals it handles: it cannot be killed, since its exit panics.
+pub fn kill(pid: usize, sig: usize) -> isize {
+    if sig == 0 || sig >= abi::signal::NSIG {
+        return -abi::syscall::EINVAL;
+    }
+    for p in unsafe { PROCS.iter_mut() } {
+        let _guard = p.lock();
+        if p.pid != pid || matches!(p.state, ProcessState::UNUSED | ProcessState::ZOMBIE) {
+            continue;
+        }
+        return if send_locked(p, sig) { 0 } else { -1 };
+    }
+    -1
+}
+
+// Send signal sig to every process in group pgid, as kill does.
+pub fn kill_group(pgid: usize, sig: usize) {
+    for p in unsafe { PROCS.iter_mut() } {
+        let _guard = p.lock();
+        if p.pgid == pgid && !matches!(p.state, ProcessState::UNUSED | ProcessState::ZOMBIE) {
+            send_locked(p, sig);
         }
     }
 }
 
+// Send sig to p, whose lock is held, unless p is init and would die of it,
+// or a kernel thread (no address space).
+fn send_locked(p: &mut Process, sig: usize) -> bool {
+    if p.mm.is_none() {
+        return false;
+    }
+    let handled = p.sigactions[sig].handler > abi::signal::SIG_IGN;
+    if core::ptr::eq(p, unsafe { INITPROC }) && !handled {
+        return false;
+    }
+    crate::signal::send(p, sig);
+    true
+}
+
+// setpgid(pid, pgid): move pid (0: the caller), which must be the caller or
+// a child of it, into group pgid (0: a group of its own, numbered pid).
+pub fn setpgid(pid: usize, pgid: usize) -> isize {
+    let curproc = unsafe { &mut *myproc().unwrap() };
+    let pid = if pid == 0 { curproc.pid } else { pid };
+    let pgid = if pgid == 0 { pid } else { pgid };
+    // Parent links are under WAIT_LOCK.
+    let _wait_guard = WAIT_LOCK.lock();
+    for p in unsafe { PROCS.iter_mut() } {
+        let _guard = p.lock();
+        if p.pid != pid || matches!(p.state, ProcessState::UNUSED | ProcessState::ZOMBIE) {
+            continue;
+        }
+        if p.pid != curproc.pid && p.parent != Some(curproc as *mut Process) {
+            return -abi::syscall::ESRCH;
+        }
+        p.pgid = pgid;
+        return 0;
+    }
+    -abi::syscall::ESRCH
+}
+
+// getpgid(pid): the group of pid (0: the caller).
+pub fn getpgid(pid: usize) -> isize {
+    let curproc = unsafe { &*myproc().unwrap() };
+    if pid == 0 || pid == curproc.pid {
+        return curproc.pgid as isize;
+    }
+    for p in unsafe { PROCS.iter_mut() } {
+        let _guard = p.lock();
+        if p.pid == pid && p.state != ProcessState::UNUSED {
+            return p.pgid as isize;
+        }
+    }
+    -abi::syscall::ESRCH
+}
+
+// Whether p should give up a blocking wait: it was killed, or has a signal
+// to take on its way back to user mode.
 pub unsafe fn killed(p: &Process) -> bool {
-    p.killed
+    p.killed || crate::signal::deliverable(p)
+}
+
+// Print every process, with a backtrace of the kernel stack of those
+// switched out, for Ctrl-P on the console. Takes no locks, so that it works
+// on a wedged system; what it prints may be inconsistent.
+pub fn procdump() {
+    crate::uart_println!();
+    for p in unsafe { PROCS.iter() } {
+        let state = match p.state {
+            ProcessState::UNUSED => continue,
+            ProcessState::EMBRYO => "embryo",
+            ProcessState::SLEEPING => "sleep",
+            ProcessState::RUNNABLE => "runble",
+            ProcessState::RUNNING => "run",
+            ProcessState::ZOMBIE => "zombie",
+            ProcessState::STOPPED => "stop",
+        };
+        let len = p.name.iter().position(|&b| b == 0).unwrap_or(p.name.len());
+        let name = core::str::from_utf8(&p.name[..len]).unwrap_or("?");
+        crate::uart_print!("{} {} {}", p.pid, state, name);
+        if matches!(
+            p.state,
+            ProcessState::SLEEPING | ProcessState::RUNNABLE | ProcessState::STOPPED
+        ) {
+            backtrace(p);
+        }
+        crate::uart_println!();
+    }
+}
+
+// The return addresses on p's kernel stack, from where it switched out, by
+// following the frame pointers (the kernel is built with them).
+fn backtrace(p: &Process) {
+    let lo = p.kstack as u64;
+    let hi = lo + KSTACK_SIZE as u64;
+    if p.context.is_null() {
+        return;
+    }
+    let context = unsafe { &*p.context };
+    crate::uart_print!(" {:x}", context.rip);
+    let mut rbp = context.rbp;
+    for _ in 0..10 {
+        if rbp < lo || rbp + 16 > hi || rbp % 8 != 0 {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        crate::uart_print!(" {:x}", ret);
+        rbp = next;
+    }
 }
@@ -0,0 +1,276 @@
+// ptrace-lite: a parent can stop one of its children, step it one
+// instruction at a time with the trap flag (RFLAGS.TF) and read its
+// registers. A stopped tracee is in state STOPPED, which the scheduler skips;
+// its tracer sleeps on trace_chan(tracee) until it stops or exits.
+// Hardware breakpoints live in the tracee's DebugRegs, which the scheduler
+// loads into DR0-DR3/DR7 while it runs; hitting one raises #DB like a step.
+
+use crate::proc::{myproc, Process, ProcessState, KSTACK_SIZE, PROCS, WAIT_LOCK};
+use crate::spinlock::SpinlockGuard;
+use crate::trap::TrapFrame;
+use abi::ptrace::*;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_RF: u64 = 1 << 16; // Resume: skip instruction breakpoints once
+
+const DR6_HIT: u64 = 0xf; // B0-B3: breakpoint n was hit
+const DR6_CLEAR: u64 = 0xffff_0ff0; // Value with no status bits set
+const DR7_ENABLE: u64 = 0xff; // L0-L3, G0-G3
+
+// A process's hardware breakpoints, in DR0-DR3 and DR7 format.
+#[derive(Clone, Copy)]
+pub struct DebugRegs {
+    addr: [u64; NBREAKPOINT],
+    dr7: u64,
+}
+
+impl DebugRegs {
+    pub const fn new() -> Self {
+        Self {
+            addr: [0; NBREAKPOINT],
+            dr7: 0,
+        }
+    }
+
+    fn set(&mut self, bp: &Breakpoint) -> Result<(), ()> {
+        let slot = bp.slot as usize;
+        if slot >= NBREAKPOINT {
+            return Err(());
+        }
+        // DR7 holds the enable bit at 2*slot and the R/W and LEN fields at
+        // 16+4*slot.
+        let (rw, len) = match (bp.kind, bp.len) {
+            (BP_CLEAR, _) => (0, 0),
+            (BP_EXEC, _) => (0b00, 0b00),
+            (BP_WRITE, 1) => (0b01, 0b00),
+            (BP_WRITE, 2) => (0b01, 0b01),
+            (BP_WRITE, 4) => (0b01, 0b11),
+            (BP_WRITE, 8) => (0b01, 0b10),
+            _ => return Err(()),
+        };
+        let align = if bp.kind == BP_WRITE {
+            bp.len as u64
+        } else {
+            1
+        };
+        if bp.kind != BP_CLEAR
+            && (bp.addr >= crate::exec::USTACK_TOP || !bp.addr.is_multiple_of(align))
+        {
+            return Err(());
+        }
+        self.dr7 &= !((0b11 << (2 * slot)) | (0xf << (16 + 4 * slot)));
+        self.addr[slot] = 0;
+        if bp.kind != BP_CLEAR {
+            self.addr[slot] = bp.addr;
+            self.dr7 |= (1 << (2 * slot)) | ((rw | len << 2) << (16 + 4 * slot));
+        }
+        Ok(())
+    }
+}
+
+// Load p's breakpoints; called by the scheduler before switching to p.
+pub fn load_debugregs(p: &Process) {
+    let d = &p.debugregs;
+    unsafe {
+        if d.dr7 != 0 {
+            for (n, &addr) in d.addr.iter().enumerate() {
+                crate::util::ldr(n, addr);
+            }
+            crate::util::ldr7(d.dr7);
+        } else if crate::util::rdr7() & DR7_ENABLE != 0 {
+            crate::util::ldr7(0);
+        }
+    }
+}
+
+pub fn trace_chan(p: *const Process) -> usize {
+    p as usize + 1
+}
+
+// The user registers saved on entry to the kernel.
+fn user_tf(p: &Process) -> &'static mut TrapFrame {
+    unsafe {
+        &mut *((p.kstack as usize + KSTACK_SIZE - core::mem::size_of::<TrapFrame>())
+            as *mut TrapFrame)
+    }
+}
+
+pub fn ptrace(req: usize, pid: usize, addr: u64) -> isize {
+    let curproc = unsafe { &mut *myproc().unwrap() };
+    if req == PTRACE_TRACEME {
+        curproc.traced = true;
+        return 0;
+    }
+    // Copy in before taking WAIT_LOCK, which ranks above ALLOCATOR.
+    let mut bp = Breakpoint::default();
+    if req == PTRACE_SETBP
+        && !crate::vm::copyin(
+            curproc.pgdir,
+            &mut crate::allocator::ALLOCATOR.lock(),
+            &mut bp as *mut Breakpoint as *mut u8,
+            addr,
+            core::mem::size_of::<Breakpoint>(),
+        )
+    {
+        return -1;
+    }
+
+    let wait_guard = WAIT_LOCK.lock();
+    #[allow(static_mut_refs)]
+    let child = unsafe {
+        PROCS.iter_mut().find(|p| {
+            p.pid == pid
+                && p.parent == Some(curproc as *mut Process)
+                && p.state != ProcessState::UNUSED
+        })
+    };
+    let Some(child) = child else {
+        return -1;
+    };
+    let guard = child.lock();
+
+    if req == PTRACE_ATTACH {
+        // A child that asked to be traced stops at exec by itself.
+        if !child.traced {
+            child.traced = true;
+            child.stop_pending = true;
+        }
+        drop(guard);
+        return wait_stopped(child, wait_guard);
+    }
+    if !child.traced || child.state != ProcessState::STOPPED {
+        return -1;
+    }
+
+    let tf = user_tf(child);
+    match req {
+        PTRACE_GETREGS => {
+            let regs = regs(tf);
+            drop(guard);
+            drop(wait_guard);
+            let mut allocator = crate::allocator::ALLOCATOR.lock();
+            if !crate::vm::copyout(
+                curproc.pgdir,
+                &mut allocator,
+                addr,
+                &regs as *const Regs as *const u8,
+                core::mem::size_of::<Regs>(),
+            ) {
+                return -1;
+            }
+            0
+        }
+        PTRACE_SETBP => match child.debugregs.set(&bp) {
+            Ok(()) => 0,
+            Err(()) => -1,
+        },
+        PTRACE_SINGLESTEP => {
+            tf.rflags |= RFLAGS_TF;
+            child.state = ProcessState::RUNNABLE;
+            drop(guard);
+            wait_stopped(child, wait_guard)
+        }
+        PTRACE_CONT => {
+            tf.rflags &= !RFLAGS_TF;
+            child.state = ProcessState::RUNNABLE;
+            drop(guard);
+            wait_stopped(child, wait_guard)
+        }
+        PTRACE_DETACH | PTRACE_KILL => {
+            tf.rflags &= !RFLAGS_TF;
+            child.traced = false;
+            child.debugregs = DebugRegs::new();
+            child.killed |= req == PTRACE_KILL;
+            child.state = ProcessState::RUNNABLE;
+            0
+        }
+        _ => -1,
+    }
+}
+
+// The user registers in a trap frame, as reported to user space.
+pub fn regs(tf: &TrapFrame) -> Regs {
+    Regs {
+        rax: tf.rax,
+        rbx: tf.rbx,
+        rcx: tf.rcx,
+        rdx: tf.rdx,
+        rbp: tf.rbp,
+        rsi: tf.rsi,
+        rdi: tf.rdi,
+        r8: tf.r8,
+        r9: tf.r9,
+        r10: tf.r10,
+        r11: tf.r11,
+        r12: tf.r12,
+        r13: tf.r13,
+        r14: tf.r14,
+        r15: tf.r15,
+        rip: tf.rip,
+        rflags: tf.rflags,
+        rsp: tf.rsp,
+    }
+}
+
+// Sleep until child stops (0) or exits (-1). Both happen under WAIT_LOCK,
+// held here as guard.
+fn wait_stopped(child: &Process, mut guard: SpinlockGuard<()>) -> isize {
+    loop {
+        let state = {
+            let _child = child.lock();
+            child.state
+        };
+        match state {
+            ProcessState::STOPPED => return 0,
+            ProcessState::ZOMBIE | ProcessState::UNUSED => return -1,
+            _ => {}
+        }
+        crate::proc::sleep(trace_chan(child), Some(guard));
+        guard = WAIT_LOCK.lock();
+    }
+}
+
+// Stop the current process and let its tracer run. Returns when resumed.
+fn stop() {
+    let p = unsafe { &mut *myproc().unwrap() };
+    let wait_guard = WAIT_LOCK.lock();
+    crate::proc::wakeup(trace_chan(p));
+    let guard = p.lock();
+    p.stop_pending = false;
+    p.state = ProcessState::STOPPED;
+    drop(wait_guard);
+    unsafe { crate::proc::sched(guard) };
+    if p.killed {
+        crate::proc::exit(-1);
+    }
+}
+
+// Called before returning to user mode.
+pub fn stop_if_requested() {
+    let p = unsafe { &*myproc().unwrap() };
+    if p.traced && p.stop_pending {
+        stop();
+    }
+}
+
+// Debug exception (#DB): a single step finished or a breakpoint was hit.
+pub fn debug_trap(tf: &mut TrapFrame) {
+    let dr6 = unsafe { crate::util::rdr6() };
+    unsafe { crate::util::ldr6(DR6_CLEAR) };
+    if tf.cs & 3 == 0 {
+        // The kernel wrote a watched user address (e.g. copyout); the
+        // write is not reported.
+        return;
+    }
+    // Return to the instruction at an exec breakpoint without hitting it again.
+    if dr6 & DR6_HIT != 0 {
+        tf.rflags |= RFLAGS_RF;
+    }
+    let p = unsafe { &*myproc().unwrap() };
+    if p.traced {
+        stop();
+    } else {
+        // TF set by the program itself; nobody is listening.
+        tf.rflags &= !RFLAGS_TF;
+    }
+}
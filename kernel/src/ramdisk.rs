@@ -0,0 +1,60 @@
+// RAM disk: a disk image loaded into memory by the boot loader as the first
+// multiboot module (QEMU: -initrd disk.img). Writes only change memory.
+
+use crate::cmdline::{MultibootModule, MULTIBOOT_INFO_MODS};
+use crate::util::p2v;
+
+const SECTOR_SIZE: usize = 512;
+
+// Virtual range of the image; empty if there is none.
+static mut START: usize = 0;
+static mut END: usize = 0;
+
+// Find the image. Must run before the allocator is initialized, which has
+// to start after end().
+pub fn init(mb_magic: u64, mb_info: u64) {
+    let Some(info) = crate::cmdline::multiboot_info(mb_magic, mb_info) else {
+        return;
+    };
+    if info.flags & MULTIBOOT_INFO_MODS == 0 || info.mods_count == 0 {
+        return;
+    }
+    let m = unsafe { &*(p2v(info.mods_addr as usize) as *const MultibootModule) };
+    unsafe {
+        START = p2v(m.mod_start as usize);
+        END = p2v(m.mod_end as usize);
+    }
+}
+
+pub fn present() -> bool {
+    unsafe { END > START }
+}
+
+// End of the image, or 0 if there is none.
+pub fn end() -> usize {
+    unsafe { END }
+}
+
+// Address of `len` bytes at `sector`, if they lie inside the image.
+fn addr(sector: u64, len: usize) -> Result<usize, ()> {
+    let (start, end) = unsafe { (START, END) };
+    let off = sector as usize * SECTOR_SIZE;
+    if off + len > end - start {
+        return Err(());
+    }
+    Ok(start + off)
+}
+
+// Like virtio::read_block, for the one ramdisk. No lock is needed: unlike virtio
+// there is no shared queue, each call is a plain memory copy.
+pub fn read_block(sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+    let src = addr(sector, buf.len())?;
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, buf.as_mut_ptr(), buf.len()) };
+    Ok(())
+}
+
+pub fn write_block(sector: u64, buf: &[u8]) -> Result<(), ()> {
+    let dst = addr(sector, buf.len())?;
+    unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), dst as *mut u8, buf.len()) };
+    Ok(())
+}
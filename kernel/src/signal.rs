@@ -0,0 +1,277 @@
+// Signals: see abi::signal.
+//
+// A handler is run by rewriting the trap frame on the way back to user mode:
+// the interrupted registers, the signal mask and the FPU state are saved on
+// the user stack in a SigFrame, below a return address pointing at the
+// handler's restorer, and the process resumes at the handler. The restorer's
+// sigreturn syscall reads the SigFrame back. The user may have changed it
+// meanwhile. Only the user flags are taken from its rflags, and a rip at or
+// above USTACK_TOP (which covers every non-canonical address) kills the
+// process instead of reaching iretq, where it would fault in the kernel.
+// sigaction holds handlers to the same bound.
+
+use crate::proc::{myproc, Process, ProcessState};
+use crate::ptrace::regs;
+use crate::trap::TrapFrame;
+use abi::ptrace::Regs;
+use abi::signal::*;
+
+// Signals that cannot be blocked.
+const UNBLOCKABLE: u32 = sigbit(SIGKILL);
+
+// Flags user code may change by sigreturn: CF, PF, AF, ZF, SF, DF and OF.
+const RFLAGS_USER: u64 = 0xcd5;
+
+// Below the interrupted stack pointer: the red zone the System V ABI lets
+// leaf functions use without moving rsp.
+const RED_ZONE: u64 = 128;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigFrame {
+    regs: Regs,
+    blocked: u32, // Mask to restore
+    sig: u32,
+}
+
+// The FPU state (FPU_STATE_SIZE bytes) follows the SigFrame, aligned.
+const FPU_OFFSET: u64 = core::mem::size_of::<SigFrame>().next_multiple_of(64) as u64;
+
+fn ignored(sig: usize, act: &SigAction) -> bool {
+    act.handler == SIG_IGN || (act.handler == SIG_DFL && sig == SIGCHLD)
+}
+
+// Mark sig pending for p, waking it if it sleeps and can take the signal now.
+// A signal p ignores is dropped. Only SIGKILL resumes a stopped tracee.
+// Call with p's lock held.
+pub fn send(p: &mut Process, sig: usize) {
+    if sig == SIGKILL {
+        p.killed = true;
+        if p.state == ProcessState::STOPPED {
+            p.state = ProcessState::RUNNABLE;
+        }
+    } else if ignored(sig, &p.sigactions[sig]) {
+        return;
+    } else {
+        p.sig_pending |= sigbit(sig);
+        if p.sig_blocked & sigbit(sig) != 0 {
+            return;
+        }
+    }
+    if p.state == ProcessState::SLEEPING {
+        p.state = ProcessState::RUNNABLE;
+    }
+}
+
+// Whether p has a signal to take, which should cut short a blocking call.
+pub fn deliverable(p: &Process) -> bool {
+    p.sig_pending & !p.sig_blocked != 0
+}
+
+// Called before returning to user mode with frame tf: act on the lowest
+// pending signal that is not blocked, if any.
+pub fn deliver(tf: &mut TrapFrame) {
+    let p = unsafe { &mut *myproc().unwrap() };
+    let (sig, act) = {
+        let _guard = p.lock();
+        let ready = p.sig_pending & !p.sig_blocked;
+        if ready == 0 {
+            return;
+        }
+        let sig = ready.trailing_zeros() as usize;
+        p.sig_pending &= !sigbit(sig);
+        (sig, p.sigactions[sig])
+    };
+    match act.handler {
+        _ if ignored(sig, &act) => {}
+        SIG_DFL => {
+            crate::info!("pid={} killed by signal {}", p.pid, sig);
+            crate::proc::exit(-1);
+        }
+        _ => {
+            if push_frame(p, tf, sig, &act).is_err() {
+                crate::info!(
+                    "pid={} cannot take signal {}: bad stack or handler",
+                    p.pid,
+                    sig
+                );
+                crate::proc::exit(-1);
+            }
+        }
+    }
+}
+
+fn push_frame(p: &mut Process, tf: &mut TrapFrame, sig: usize, act: &SigAction) -> Result<(), ()> {
+    // sigaction already refused such a handler; iretq must never see one.
+    if act.handler as u64 >= crate::exec::USTACK_TOP {
+        return Err(());
+    }
+    let frame = SigFrame {
+        regs: regs(tf),
+        blocked: p.sig_blocked,
+        sig: sig as u32,
+    };
+    let size = FPU_OFFSET + core::mem::size_of_val(&p.fpu) as u64;
+    let sp = tf
+        .rsp
+        .checked_sub(RED_ZONE + size)
+        .filter(|&sp| tf.rsp <= crate::exec::USTACK_TOP && sp >= 8)
+        .ok_or(())?
+        & !63;
+    crate::fpu::save_if_used(&mut p.fpu);
+    let ret = act.restorer as u64;
+    {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        let mut put = |va: u64, src: *const u8, len: usize| {
+            if crate::vm::copyout(p.pgdir, &mut allocator, va, src, len) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        };
+        put(sp - 8, &ret as *const u64 as *const u8, 8)?;
+        put(
+            sp,
+            &frame as *const SigFrame as *const u8,
+            core::mem::size_of::<SigFrame>(),
+        )?;
+        put(
+            sp + FPU_OFFSET,
+            &p.fpu as *const _ as *const u8,
+            core::mem::size_of_val(&p.fpu),
+        )?;
+    }
+
+    // The handler is entered as if called: the return address at rsp, and
+    // rsp + 8 16-byte aligned.
+    tf.rsp = sp - 8;
+    tf.rip = act.handler as u64;
+    tf.rdi = sig as u64;
+    p.sig_blocked |= (act.mask | sigbit(sig)) & !UNBLOCKABLE;
+    Ok(())
+}
+
+// sigreturn(): undo push_frame, whose SigFrame the restorer's return left at
+// rsp. Returns the interrupted rax, which the syscall return puts back.
+pub fn sigreturn(tf: &mut TrapFrame) -> isize {
+    let p = unsafe { &mut *myproc().unwrap() };
+    let sp = tf.rsp;
+    // Whatever is loaded belongs to the handler. Drop it before p.fpu is
+    // overwritten, so a switch meanwhile cannot save it over the copy.
+    crate::fpu::disable();
+    let mut frame = core::mem::MaybeUninit::<SigFrame>::uninit();
+    let ok = {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        sp.checked_add(FPU_OFFSET + core::mem::size_of_val(&p.fpu) as u64)
+            .is_some_and(|end| end <= crate::exec::USTACK_TOP)
+            && crate::vm::copyin(
+                p.pgdir,
+                &mut allocator,
+                frame.as_mut_ptr() as *mut u8,
+                sp,
+                core::mem::size_of::<SigFrame>(),
+            )
+            && crate::vm::copyin(
+                p.pgdir,
+                &mut allocator,
+                &mut p.fpu as *mut _ as *mut u8,
+                sp + FPU_OFFSET,
+                core::mem::size_of_val(&p.fpu),
+            )
+    };
+    if !ok {
+        crate::info!("pid={} sigreturn: bad signal frame", p.pid);
+        crate::proc::exit(-1);
+    }
+    let frame = unsafe { frame.assume_init() };
+    p.fpu.sanitize();
+
+    let r = frame.regs;
+    if r.rip >= crate::exec::USTACK_TOP {
+        crate::info!("pid={} sigreturn: bad rip {:x}", p.pid, r.rip);
+        crate::proc::exit(-1);
+    }
+    (tf.rax, tf.rbx, tf.rcx, tf.rdx) = (r.rax, r.rbx, r.rcx, r.rdx);
+    (tf.rbp, tf.rsi, tf.rdi) = (r.rbp, r.rsi, r.rdi);
+    (tf.r8, tf.r9, tf.r10, tf.r11) = (r.r8, r.r9, r.r10, r.r11);
+    (tf.r12, tf.r13, tf.r14, tf.r15) = (r.r12, r.r13, r.r14, r.r15);
+    tf.rip = r.rip;
+    tf.rsp = r.rsp;
+    tf.rflags = (tf.rflags & !RFLAGS_USER) | (r.rflags & RFLAGS_USER);
+    {
+        let _guard = p.lock();
+        p.sig_blocked = frame.blocked & !UNBLOCKABLE;
+    }
+    r.rax as isize
+}
+
+// sigaction(sig, act, oldact).
+pub fn sigaction(sig: usize, act: u64, oldact: u64) -> isize {
+    let p = unsafe { &mut *myproc().unwrap() };
+    if sig == 0 || sig >= NSIG || (sig == SIGKILL && act != 0) {
+        return -abi::syscall::EINVAL;
+    }
+    let mut new = SigAction::default();
+    {
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        let size = core::mem::size_of::<SigAction>();
+        if oldact != 0
+            && !crate::vm::copyout(
+                p.pgdir,
+                &mut allocator,
+                oldact,
+                &p.sigactions[sig] as *const SigAction as *const u8,
+                size,
+            )
+        {
+            return -1;
+        }
+        if act != 0
+            && !crate::vm::copyin(
+                p.pgdir,
+                &mut allocator,
+                &mut new as *mut SigAction as *mut u8,
+                act,
+                size,
+            )
+        {
+            return -1;
+        }
+    }
+    if act != 0 {
+        // push_frame resumes at the handler, so it must be a user address.
+        if new.flags != 0 || new.handler as u64 >= crate::exec::USTACK_TOP {
+            return -abi::syscall::EINVAL;
+        }
+        let _guard = p.lock();
+        p.sigactions[sig] = new;
+        // Ignoring a signal discards it if pending.
+        if ignored(sig, &new) {
+            p.sig_pending &= !sigbit(sig);
+        }
+    }
+    0
+}
+
+// sigprocmask(how, set): returns the old mask.
+pub fn sigprocmask(how: usize, set: u32) -> isize {
+    let p = unsafe { &mut *myproc().unwrap() };
+    let _guard = p.lock();
+    let old = p.sig_blocked;
+    p.sig_blocked = match how {
+        SIG_BLOCK => old | set,
+        SIG_UNBLOCK => old & !set,
+        SIG_SETMASK => set,
+        _ => return -abi::syscall::EINVAL,
+    } & !UNBLOCKABLE;
+    old as isize
+}
+
+// exec keeps ignored signals ignored; handlers are gone with the old image.
+pub fn exec_reset(p: &mut Process) {
+    for act in p.sigactions.iter_mut() {
+        if act.handler != SIG_IGN {
+            *act = SigAction::default();
+        }
+    }
+}
@@ -4,6 +4,8 @@ use core::cell::UnsafeCell;
 
 pub struct SleepLockSafe<T> {
     lock: Spinlock<bool>,
+    name: &'static str,
+    rank: u8, // See lockorder.rs
     data: UnsafeCell<T>,
 }
 
@@ -11,13 +13,21 @@ unsafe impl<T: Send> Sync for SleepLockSafe<T> {}
 
 impl<T> SleepLockSafe<T> {
     pub const fn new(data: T) -> Self {
+        Self::ranked(data, "SLEEPLOCK", crate::lockorder::RANK_NONE)
+    }
+
+    // A sleep-lock taking part in the global lock order (see lockorder.rs).
+    pub const fn ranked(data: T, name: &'static str, rank: u8) -> Self {
         Self {
-            lock: Spinlock::new(false, "SLEEPLOCK"),
+            lock: Spinlock::ranked(false, "SLEEPLOCK", crate::lockorder::RANK_SLEEPLOCK),
+            name,
+            rank,
             data: UnsafeCell::new(data),
         }
     }
 
     pub fn lock(&self) -> SleepLockGuard<T> {
+        crate::lockorder::acquire_sleep(self as *const _ as usize, self.rank, self.name);
         let mut lk = self.lock.lock();
         while *lk {
             proc::sleep(self as *const _ as usize, Some(lk));
@@ -41,6 +51,8 @@ impl<'a, T> Drop for SleepLockGuard<'a, T> {
         let mut lk = self.lock.lock.lock();
         *lk = false;
         proc::wakeup(self.lock as *const _ as usize);
+        drop(lk);
+        crate::lockorder::release_sleep(self.lock as *const _ as usize, self.lock.rank);
     }
 }
 
@@ -1,12 +1,14 @@
-use crate::proc::mycpu;
+use crate::proc::{mycpu, Cpu};
 use crate::util::readeflags;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub struct Spinlock<T> {
     lock: AtomicBool,
+    cpu: AtomicUsize, // Address of the holding Cpu, for recursion checks
     name: &'static str,
+    rank: u8, // See lockorder.rs
     data: UnsafeCell<T>,
 }
 
@@ -20,9 +22,16 @@ unsafe impl<T> Send for Spinlock<T> {}
 
 impl<T> Spinlock<T> {
     pub const fn new(data: T, name: &'static str) -> Self {
+        Self::ranked(data, name, crate::lockorder::RANK_NONE)
+    }
+
+    // A lock taking part in the global lock order (see lockorder.rs).
+    pub const fn ranked(data: T, name: &'static str, rank: u8) -> Self {
         Self {
             lock: AtomicBool::new(false),
+            cpu: AtomicUsize::new(0),
             name,
+            rank,
             data: UnsafeCell::new(data),
         }
     }
@@ -32,6 +41,12 @@ impl<T> Spinlock<T> {
         if self.name != "UART_TX" {
             // crate::uart_println!("LOCK: {} ncli={}", self.name, mycpu().ncli);
         }
+        // Re-acquiring a lock this CPU already holds would spin forever
+        // (e.g. a page fault taken while ALLOCATOR is held).
+        if self.holding() {
+            panic!("acquire: {} already held by this CPU", self.name);
+        }
+        crate::lockorder::acquire_spin(self as *const Self as usize, self.rank, self.name);
 
         while self
             .lock
@@ -43,14 +58,19 @@ impl<T> Spinlock<T> {
             }
         }
 
+        self.cpu
+            .store(mycpu() as *const Cpu as usize, Ordering::Relaxed);
+
         SpinlockGuard {
             lock: self,
             data: unsafe { &mut *self.data.get() },
         }
     }
 
+    // Is this lock held by the current CPU? Call with interrupts disabled.
     pub fn holding(&self) -> bool {
         self.lock.load(Ordering::Relaxed)
+            && self.cpu.load(Ordering::Relaxed) == mycpu() as *const Cpu as usize
     }
 
     pub fn as_ptr(&self) -> *mut T {
@@ -61,6 +81,8 @@ impl<T> Spinlock<T> {
         if self.name != "UART_TX" {
             // crate::uart_println!("UNLOCK: {} ncli={}", self.name, mycpu().ncli);
         }
+        crate::lockorder::release_spin(self as *const Self as usize, self.rank);
+        self.cpu.store(0, Ordering::Relaxed);
         self.lock.store(false, Ordering::Release);
         pop_cli();
     }
@@ -84,6 +106,8 @@ impl<'a, T> Drop for SpinlockGuard<'a, T> {
         if self.lock.name != "UART_TX" {
             // crate::uart_println!("DROP: {} ncli={}", self.lock.name, mycpu().ncli);
         }
+        crate::lockorder::release_spin(self.lock as *const Spinlock<T> as usize, self.lock.rank);
+        self.lock.cpu.store(0, Ordering::Relaxed);
         self.lock.lock.store(false, Ordering::Release);
         pop_cli();
     }
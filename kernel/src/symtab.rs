@@ -0,0 +1,80 @@
+// Symbol lookup in user executables, so that a fault message can name the
+// function it happened in. Needs the executable's .symtab, so it finds
+// nothing for stripped binaries.
+
+use crate::elf::{ElfHeader, SectionHeader, Symbol, ELF_MAGIC, SHT_SYMTAB, STT_FUNC};
+use crate::fs::{self, Inode};
+
+// Symbols read from the file at a time.
+const NSYM: usize = 32;
+
+// The function containing addr in the executable with inode (dev, inum),
+// and the offset of addr into it. The name is copied into buf, truncated to
+// fit.
+pub fn lookup(dev: u32, inum: u32, addr: u64, buf: &mut [u8]) -> Option<(&str, u64)> {
+    if inum == 0 {
+        return None;
+    }
+    let ip = fs::iget(dev, inum).ok()?;
+    let r = find(ip, addr).and_then(move |(sym, strtab)| {
+        let name = read_name(ip, strtab + sym.name as u64, buf)?;
+        Some((name, addr - sym.value))
+    });
+    fs::iput(ip);
+    r
+}
+
+// The function symbol containing addr, and the file offset of the string
+// table holding its name.
+fn find(ip: &Inode, addr: u64) -> Option<(Symbol, u64)> {
+    let elf: ElfHeader = read(ip, 0)?;
+    let shsize = core::mem::size_of::<SectionHeader>() as u64;
+    if elf.magic != ELF_MAGIC || elf.shentsize as u64 != shsize {
+        return None;
+    }
+    let symtab = (0..elf.shnum as u64)
+        .filter_map(|i| read::<SectionHeader>(ip, elf.shoff + i * shsize))
+        .find(|sh| sh.type_ == SHT_SYMTAB)?;
+    let strtab: SectionHeader = read(ip, elf.shoff + symtab.link as u64 * shsize)?;
+
+    let nsyms = symtab.size as usize / core::mem::size_of::<Symbol>();
+    let mut syms = [Symbol::default(); NSYM];
+    let mut i = 0;
+    while i < nsyms {
+        let n = core::cmp::min(NSYM, nsyms - i);
+        let off = symtab.offset + (i * core::mem::size_of::<Symbol>()) as u64;
+        let len = (n * core::mem::size_of::<Symbol>()) as u32;
+        if fs::readi(ip, syms.as_mut_ptr() as *mut u8, off as u32, len) != Ok(len) {
+            return None;
+        }
+        for sym in &syms[..n] {
+            if sym.info & 0xf == STT_FUNC && sym.value <= addr && addr < sym.value + sym.size.max(1)
+            {
+                return Some((*sym, strtab.offset));
+            }
+        }
+        i += n;
+    }
+    None
+}
+
+// The NUL-terminated string at file offset off.
+fn read_name<'a>(ip: &Inode, off: u64, buf: &'a mut [u8]) -> Option<&'a str> {
+    let n = fs::readi(ip, buf.as_mut_ptr(), off as u32, buf.len() as u32).ok()?;
+    let bytes = &buf[..n as usize];
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    match core::str::from_utf8(&bytes[..len]) {
+        Ok(s) => Some(s),
+        // Cut inside a multi-byte character.
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).ok(),
+    }
+}
+
+fn read<T: Copy>(ip: &Inode, off: u64) -> Option<T> {
+    let mut val: T = unsafe { core::mem::zeroed() };
+    let n = core::mem::size_of::<T>() as u32;
+    if fs::readi(ip, &mut val as *mut T as *mut u8, off as u32, n) != Ok(n) {
+        return None;
+    }
+    Some(val)
+}
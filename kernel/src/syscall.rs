@@ -1,9 +1,9 @@
-use crate::gdt::{tss_addr, KCODE_SELECTOR, KDATA_SELECTOR};
-use crate::util::{
-    rdmsr, wrmsr, EFER_SCE, MSR_EFER, MSR_KERNEL_GS_BASE, MSR_LSTAR, MSR_SFMASK, MSR_STAR,
-};
+use crate::gdt::{KCODE_SELECTOR, KDATA_SELECTOR};
+use crate::util::{rdmsr, wrmsr, EFER_SCE, MSR_EFER, MSR_LSTAR, MSR_SFMASK, MSR_STAR};
 
-pub fn init(cpuid: usize) {
+// syscall_entry finds the kernel stack through GS, which proc::init_gs has
+// pointed at this CPU's Cpu.
+pub fn init() {
     unsafe {
         // Syscall Setup
         // 1. Enable EFER.SCE
@@ -20,15 +20,9 @@ pub fn init(cpuid: usize) {
         wrmsr(MSR_LSTAR, syscall_entry as u64);
 
         // 4. Setup SFMASK
-        // Mask RFLAGS on syscall. Clear Interrupts (IF=0x200).
-        wrmsr(MSR_SFMASK, 0x200);
-
-        // 5. Setup KERNEL_GS_BASE
-        // Point to TSS to find RSP0.
-        wrmsr(MSR_KERNEL_GS_BASE, tss_addr(cpuid));
-
-        // Switch to Kernel GS Base (Active GS = TSS, Shadow GS = User/0)
-        core::arch::asm!("swapgs");
+        // Mask RFLAGS on syscall. Clear Interrupts (IF=0x200) and the trap
+        // flag (TF=0x100), which a single-stepped process runs with.
+        wrmsr(MSR_SFMASK, 0x300);
     }
 }
 
@@ -37,51 +31,100 @@ unsafe extern "C" {
     fn syscall_entry();
 }
 
-use crate::proc::mycpu;
+use crate::proc::myproc;
 use crate::trap::TrapFrame;
 
-pub const SYS_READ: u64 = 0;
-pub const SYS_WRITE: u64 = 1;
-pub const SYS_OPEN: u64 = 2;
-pub const SYS_CLOSE: u64 = 3;
-pub const SYS_SBRK: u64 = 12;
-pub const SYS_PIPE: u64 = 22;
-pub const SYS_DUP: u64 = 32;
-pub const SYS_FORK: u64 = 57;
-pub const SYS_EXEC: u64 = 59;
-pub const SYS_EXIT: u64 = 60;
-pub const SYS_WAIT: u64 = 61;
+use crate::net::ip::{PROTO_ICMP, PROTO_UDP};
+use crate::net::Socket;
+use abi::net::SockAddrIn;
+use abi::syscall::*;
 
 pub fn syscall() {
-    #[allow(static_mut_refs)]
-    let p = unsafe { &mut *mycpu().process.unwrap() };
+    let p = unsafe { &mut *myproc().unwrap() };
     let tf = unsafe {
         &mut *(((p.kstack as usize) + crate::proc::KSTACK_SIZE - core::mem::size_of::<TrapFrame>())
             as *mut TrapFrame)
     };
 
-    let num = tf.rax;
-    let ret = match num {
-        SYS_READ => sys_read(tf),
-        SYS_WRITE => sys_write(tf),
-        SYS_OPEN => sys_open(tf),
-        SYS_CLOSE => sys_close(tf),
-        SYS_SBRK => sys_sbrk(tf),
-        SYS_EXEC => sys_exec(tf),
-        SYS_FORK => sys_fork(tf),
-        SYS_EXIT => sys_exit(tf),
-        SYS_WAIT => sys_wait(tf),
-        SYS_PIPE => sys_pipe(tf),
-        SYS_DUP => sys_dup(tf),
-        _ => {
+    let num = tf.rax as usize;
+    let ret = match handler(num) {
+        Some(f) => f(tf),
+        None => {
             crate::error!("Unknown syscall {}", num);
-            -1
+            -ENOSYS
         }
     };
 
     tf.rax = ret as u64;
 }
 
+const fn handler(num: usize) -> Option<fn(&TrapFrame) -> isize> {
+    Some(match num {
+        SYS_READ => sys_read,
+        SYS_WRITE => sys_write,
+        SYS_OPEN => sys_open,
+        SYS_CLOSE => sys_close,
+        SYS_STAT => sys_stat,
+        SYS_FSTAT => sys_fstat,
+        SYS_FTRUNCATE => sys_ftruncate,
+        SYS_FSYNC => sys_fsync,
+        SYS_SYNC => sys_sync,
+        SYS_CHDIR => sys_chdir,
+        SYS_MKDIR => sys_mkdir,
+        SYS_MKNOD => sys_mknod,
+        SYS_UNLINK => sys_unlink,
+        SYS_STATFS => sys_statfs,
+        SYS_LSEEK => sys_lseek,
+        SYS_MMAP => sys_mmap,
+        SYS_MUNMAP => sys_munmap,
+        SYS_SBRK => sys_sbrk,
+        SYS_EXEC => sys_exec,
+        SYS_FORK => sys_fork,
+        SYS_EXIT => sys_exit,
+        SYS_WAIT => sys_wait,
+        SYS_KILL => sys_kill,
+        SYS_GETRUSAGE => sys_getrusage,
+        SYS_PTRACE => sys_ptrace,
+        SYS_PRCTL => sys_prctl,
+        SYS_GETDENTS => sys_getdents,
+        SYS_SIGACTION => sys_sigaction,
+        SYS_SIGPROCMASK => sys_sigprocmask,
+        SYS_SIGRETURN => sys_sigreturn,
+        SYS_PIPE => sys_pipe,
+        SYS_DUP => sys_dup,
+        SYS_DUP2 => sys_dup2,
+        SYS_SOCKET => sys_socket,
+        SYS_BIND => sys_bind,
+        SYS_LISTEN => sys_listen,
+        SYS_ACCEPT => sys_accept,
+        SYS_CONNECT => sys_connect,
+        SYS_SENDTO => sys_sendto,
+        SYS_RECVFROM => sys_recvfrom,
+        SYS_GETSOCKNAME => sys_getsockname,
+        SYS_GETPEERNAME => sys_getpeername,
+        SYS_IRQSTAT => sys_irqstat,
+        SYS_SYSINFO => sys_sysinfo,
+        SYS_UPTIME => sys_uptime,
+        SYS_SLEEP => sys_sleep,
+        SYS_WAITPID => sys_waitpid,
+        SYS_NICE => sys_nice,
+        SYS_CLONE => sys_clone,
+        SYS_IOCTL => sys_ioctl,
+        SYS_SETPGID => sys_setpgid,
+        SYS_GETPGID => sys_getpgid,
+        _ => return None,
+    })
+}
+
+// A syscall number added to abi without a handler here fails the build.
+const _: () = {
+    let mut i = 0;
+    while i < SYSCALLS.len() {
+        assert!(handler(SYSCALLS[i]).is_some(), "syscall without a handler");
+        i += 1;
+    }
+};
+
 fn argraw(n: usize, tf: &TrapFrame) -> u64 {
     match n {
         0 => tf.rdi,
@@ -105,12 +148,8 @@ fn argptr(n: usize, tf: &TrapFrame) -> u64 {
 
 fn argfd(n: usize, tf: &TrapFrame) -> Result<&'static mut crate::file::File, ()> {
     let fd = argint(n, tf);
-    #[allow(static_mut_refs)]
-    let p = unsafe { &mut *mycpu().process.unwrap() };
-    if fd >= p.ofile.len() {
-        return Err(());
-    }
-    match p.ofile[fd] {
+    let p = unsafe { &*myproc().unwrap() };
+    match p.files().get(fd) {
         Some(f_ptr) => unsafe { Ok(&mut *f_ptr) },
         None => Err(()),
     }
@@ -136,17 +175,52 @@ fn fetch_str(ptr_val: u64) -> Result<&'static str, ()> {
             break;
         }
         len += 1;
-        if len > 1024 {
+        if len >= abi::fs::PATH_MAX as u64 {
             return Err(());
-        } // Max string length
+        }
     }
 
     let slice = unsafe { core::slice::from_raw_parts(ptr_val as *const u8, len as usize) };
     core::str::from_utf8(slice).map_err(|_| ())
 }
 
+// Copy a NUL-terminated string from the current process into buf.
+fn fetch_str_into(addr: u64, buf: &mut [u8]) -> Result<&str, ()> {
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    for i in 0..buf.len() {
+        if !crate::vm::copyin(pgdir, &mut allocator, &mut buf[i], addr + i as u64, 1) {
+            return Err(());
+        }
+        if buf[i] == 0 {
+            return core::str::from_utf8(&buf[..i]).map_err(|_| ());
+        }
+    }
+    Err(()) // Too long
+}
+
+// Fetch the u64 at addr in the current process.
+fn fetch_addr(addr: u64) -> Result<u64, ()> {
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    let mut val = 0u64;
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyin(
+        pgdir,
+        &mut allocator,
+        &mut val as *mut u64 as *mut u8,
+        addr,
+        8,
+    ) {
+        return Err(());
+    }
+    Ok(val)
+}
+
 fn sys_exec(tf: &TrapFrame) -> isize {
-    let path = match argstr(0, tf) {
+    // Stage the path and arguments in kernel memory before exec starts
+    // building the new address space.
+    let mut path_buf = [0u8; crate::fs::MAXPATH];
+    let path = match fetch_str_into(argptr(0, tf), &mut path_buf) {
         Ok(s) => s,
         Err(_) => {
             return -1;
@@ -154,26 +228,29 @@ fn sys_exec(tf: &TrapFrame) -> isize {
     };
 
     let argv_ptr = argptr(1, tf);
-    let mut argv: [&str; 16] = [""; 16];
+    let mut argv = match crate::exec::ExecArgs::new() {
+        Some(a) => a,
+        None => return -1,
+    };
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
     let mut argc = 0;
 
     if argv_ptr != 0 {
         loop {
-            if argc >= 16 {
-                return -1;
-            }
-            let uarg = unsafe { *((argv_ptr + (argc as u64) * 8) as *const u64) };
+            let uarg = match fetch_addr(argv_ptr + (argc as u64) * 8) {
+                Ok(a) => a,
+                Err(_) => return -1,
+            };
             if uarg == 0 {
                 break;
             }
-            match fetch_str(uarg) {
-                Ok(s) => argv[argc] = s,
-                Err(_) => return -1,
+            if argv.push_user(pgdir, uarg).is_err() {
+                return -1; // Bad pointer or exceeds ARG_MAX
             }
             argc += 1;
         }
     }
-    crate::exec::exec(path, &argv[0..argc])
+    crate::exec::exec(path, &argv)
 }
 
 fn sys_fork(_tf: &TrapFrame) -> isize {
@@ -182,15 +259,74 @@ fn sys_fork(_tf: &TrapFrame) -> isize {
 
 fn sys_exit(tf: &TrapFrame) -> isize {
     let status = argint(0, tf) as isize;
-    crate::proc::exit(status);
-    0
+    crate::proc::exit(status)
 }
 
+// wait(status): wait for any child to exit.
 fn sys_wait(tf: &TrapFrame) -> isize {
-    let _pid = argint(0, tf) as isize; // We don't support waiting for specific PID yet in bare wait?
-                                       // Actually standard wait(status) waits for ANY child. waitpid(pid, status, options) waits for specific.
-                                       // Let's implement wait() as wait for any child.
-    crate::proc::wait(-1)
+    crate::proc::wait(-1, argptr(0, tf))
+}
+
+// waitpid(pid, status): wait for the child pid to exit.
+fn sys_waitpid(tf: &TrapFrame) -> isize {
+    crate::proc::wait(argint(0, tf) as isize, argptr(1, tf))
+}
+
+// clone(entry, stack, arg): start a thread running entry(arg) on stack, in
+// the caller's address space. Returns its pid, which waitpid joins.
+fn sys_clone(tf: &TrapFrame) -> isize {
+    crate::proc::clone(argptr(0, tf), argptr(1, tf), argptr(2, tf))
+}
+
+// nice(inc): lower (or, negative, raise) the caller's priority.
+fn sys_nice(tf: &TrapFrame) -> isize {
+    crate::proc::nice(argint(0, tf) as isize)
+}
+
+// kill(pid, sig): send signal sig to process pid.
+fn sys_kill(tf: &TrapFrame) -> isize {
+    crate::proc::kill(argint(0, tf), argint(1, tf))
+}
+
+// sigaction(sig, act, oldact): see abi::signal.
+fn sys_sigaction(tf: &TrapFrame) -> isize {
+    crate::signal::sigaction(argint(0, tf), argptr(1, tf), argptr(2, tf))
+}
+
+// sigprocmask(how, set): returns the old mask.
+fn sys_sigprocmask(tf: &TrapFrame) -> isize {
+    crate::signal::sigprocmask(argint(0, tf), argint(1, tf) as u32)
+}
+
+// sigreturn(): return from a signal handler.
+fn sys_sigreturn(_tf: &TrapFrame) -> isize {
+    let p = unsafe { &*myproc().unwrap() };
+    let tf = unsafe {
+        &mut *(((p.kstack as usize) + crate::proc::KSTACK_SIZE - core::mem::size_of::<TrapFrame>())
+            as *mut TrapFrame)
+    };
+    crate::signal::sigreturn(tf)
+}
+
+// ptrace(request, pid, addr): see abi::ptrace.
+fn sys_ptrace(tf: &TrapFrame) -> isize {
+    crate::ptrace::ptrace(argint(0, tf), argint(1, tf), argptr(2, tf))
+}
+
+// prctl(option, arg): only the core dump options of abi::coredump.
+fn sys_prctl(tf: &TrapFrame) -> isize {
+    let p = unsafe { &mut *myproc().unwrap() };
+    match argint(0, tf) {
+        abi::coredump::PR_GET_DUMPABLE => p.dumpable as isize,
+        abi::coredump::PR_SET_DUMPABLE => match argint(1, tf) {
+            0 | 1 => {
+                p.dumpable = argint(1, tf) == 1;
+                0
+            }
+            _ => -1,
+        },
+        _ => -1,
+    }
 }
 
 fn sys_read(tf: &TrapFrame) -> isize {
@@ -219,6 +355,9 @@ fn sys_open(tf: &TrapFrame) -> isize {
         Err(_) => return -1,
     };
     let mode = argint(1, tf);
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
 
     // 1. Alloc file
     let f = match crate::file::filealloc() {
@@ -226,87 +365,118 @@ fn sys_open(tf: &TrapFrame) -> isize {
         None => return -1,
     };
 
+    // The last reference to an unlinked inode frees it, which needs an
+    // operation.
+    let put = |ip| {
+        crate::journal::begin_op();
+        crate::fs::iput(ip);
+        crate::journal::end_op();
+    };
+
     // 2. Open inode
-    let ip = match crate::fs::namei(path) {
-        Some(ip) => ip,
-        None => {
+    let ip = if mode as i32 & abi::fs::O_CREATE != 0 {
+        crate::journal::begin_op();
+        let ip = crate::fs::create(path);
+        crate::journal::end_op();
+        ip
+    } else {
+        crate::fs::namei(path).ok_or(-1)
+    };
+    let ip = match ip {
+        Ok(ip) => ip,
+        Err(e) => {
+            // iget gives up rather than wait when the caller holds every inode.
+            let full = crate::fs::inodes_free() == 0;
             f.refcnt = 0; // Manual rollback
-            return -1;
+            return if full { -ENFILE } else { e };
         }
     };
 
-    let guard = ip.ilock();
-    if (guard.i_mode & 0xF000) == 0x2000 {
-        f.f_type = crate::file::FileType::Device;
-        f.major = guard.i_block[0] as u16;
-        f.ip = Some(ip); // We still keep IP to hold refcnt? Fileclose decreases refcnt on IP only if type Inode?
-                         // Wait, fileclose handles Inode and Device separately?
-                         // file.rs: fileclose only iput if FileType::Inode.
-                         // If Device, we leak refcnt on ip?
-                         // We should arguably keep type Inode but set major?
-                         // Or update fileclose to iput if ip is set?
-
-    // file.rs:
-    /*
-    if f.f_type == FileType::Inode {
-        if let Some(ip) = f.ip {
-            crate::fs::iput(ip);
+    let guard = match ip.ilock() {
+        Ok(guard) => guard,
+        Err(e) => {
+            put(ip);
+            f.refcnt = 0;
+            return e;
         }
+    };
+    // Directories are only opened for getdents, and only when asked for.
+    let is_dir = guard.i_mode & 0xF000 == 0x4000;
+    let accmode = mode as i32 & 3;
+    if is_dir && accmode != abi::fs::O_RDONLY {
+        drop(guard);
+        put(ip);
+        f.refcnt = 0;
+        return -EISDIR;
     }
-    */
-    // It doesn't check Device.
-    // So if we set Device, we must NOT set ip in f.ip OR update fileclose.
-
-    // But we NEED to iput eventually.
-    // So we should update fileclose.
-    // For now, let's update fileclose too?
-    // OR, simpler:
-    // Keep f.f_type = Inode? But then read/write uses readi/writei.
-    // We need read/write to dispatch to console.
-
-    // So we MUST use FileType::Device.
-    // And we MUST update fileclose to iput if f.ip is set, regardless of type?
-    // Or add Device handling in fileclose.
-
-    // Let's check file.rs.
+    if is_dir != (mode as i32 & abi::fs::O_DIRECTORY != 0) {
+        drop(guard);
+        put(ip);
+        f.refcnt = 0;
+        return if is_dir { -EISDIR } else { -ENOTDIR };
+    }
+    if is_dir {
+        f.f_type = crate::file::FileType::Dir;
+    } else if (guard.i_mode & 0xF000) == 0x2000 {
+        let (major, minor) = crate::fs::devnum(&guard);
+        if !crate::file::devpresent(major as u16, minor as u16) {
+            drop(guard);
+            put(ip);
+            f.refcnt = 0;
+            return -ENXIO;
+        }
+        f.f_type = crate::file::FileType::Device;
+        f.major = major as u16;
+        f.minor = minor as u16;
     } else {
         f.f_type = crate::file::FileType::Inode;
     }
     drop(guard);
 
+    let writable = accmode != abi::fs::O_RDONLY;
+    // write, O_TRUNC and ftruncate all need a writable fd, so refusing one
+    // keeps the journal's blocks as the log left them.
+    if writable && crate::fs::is_log(ip) {
+        put(ip);
+        f.refcnt = 0;
+        return -EPERM;
+    }
+    if f.f_type == crate::file::FileType::Inode && writable && mode as i32 & abi::fs::O_TRUNC != 0 {
+        crate::journal::begin_op();
+        let r = crate::fs::itrunc(ip, 0);
+        crate::journal::end_op();
+        if let Err(e) = r {
+            put(ip);
+            f.refcnt = 0;
+            return e;
+        }
+    }
+
     f.ip = Some(ip);
     f.off = 0;
-    f.readable = true;
-    f.writable = false;
-    // TODO: use mode
-    if mode != 0 {}
+    f.readable = accmode != abi::fs::O_WRONLY;
+    f.writable = writable;
+    f.append = mode as i32 & abi::fs::O_APPEND != 0;
 
     // 3. Alloc fd
-    #[allow(static_mut_refs)]
-    let p = unsafe { &mut *mycpu().process.unwrap() };
-    for (i, fd_slot) in p.ofile.iter_mut().enumerate() {
-        if fd_slot.is_none() {
-            *fd_slot = Some(f as *mut crate::file::File);
-            return i as isize;
-        }
+    let p = unsafe { &*myproc().unwrap() };
+    if let Some(fd) = p.files().fdalloc(f as *mut crate::file::File) {
+        return fd as isize;
     }
 
     // Fail
+    put(ip);
+    f.ip = None;
+    f.f_type = crate::file::FileType::None;
     f.refcnt = 0;
     -1
 }
 
 fn sys_close(tf: &TrapFrame) -> isize {
     let fd = argint(0, tf) as usize;
-    #[allow(static_mut_refs)]
-    let p = unsafe { &mut *mycpu().process.unwrap() };
-
-    if fd >= p.ofile.len() {
-        return -1;
-    }
+    let p = unsafe { &*myproc().unwrap() };
 
-    if let Some(f_ptr) = p.ofile[fd] {
-        p.ofile[fd] = None;
+    if let Some(f_ptr) = p.files().take(fd) {
         unsafe {
             crate::file::fileclose(&mut *f_ptr);
         }
@@ -315,16 +485,216 @@ fn sys_close(tf: &TrapFrame) -> isize {
     -1
 }
 
-fn sys_sbrk(tf: &TrapFrame) -> isize {
-    let n = argint(0, tf) as isize;
-    let cpu = crate::proc::mycpu();
-    let sz = unsafe { (*cpu.process.unwrap()).sz };
+// getdents(fd, buf, n): fill buf with up to n bytes of directory entries.
+// Returns the bytes filled, 0 at the end of the directory.
+fn sys_getdents(tf: &TrapFrame) -> isize {
+    let f = match argfd(0, tf) {
+        Ok(f) => f,
+        Err(_) => return -1,
+    };
+    crate::file::filegetdents(f, argptr(1, tf), argint(2, tf))
+}
+
+// fstat(fd, buf): copy a Stat for fd into buf.
+fn sys_fstat(tf: &TrapFrame) -> isize {
+    let f = match argfd(0, tf) {
+        Ok(f) => f,
+        Err(_) => return -1,
+    };
+    crate::file::filestat(f, argptr(1, tf))
+}
+
+// stat(path, buf): copy a Stat for path into buf.
+fn sys_stat(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
+    let ip = match crate::fs::namei(path) {
+        Some(ip) => ip,
+        None => return -1,
+    };
+    let r = crate::file::inodestat(ip, argptr(1, tf));
+    crate::journal::begin_op();
+    crate::fs::iput(ip);
+    crate::journal::end_op();
+    r
+}
+
+// lseek(fd, off, whence): returns the new offset.
+fn sys_lseek(tf: &TrapFrame) -> isize {
+    let f = match argfd(0, tf) {
+        Ok(f) => f,
+        Err(_) => return -1,
+    };
+    crate::file::filelseek(f, argint(1, tf) as i64, argint(2, tf) as i32)
+}
+
+fn sys_ftruncate(tf: &TrapFrame) -> isize {
+    let f = match argfd(0, tf) {
+        Ok(f) => f,
+        Err(_) => return -1,
+    };
+    crate::file::filetruncate(f, argint(1, tf))
+}
+
+// fsync(fd): write what has been written to the file system fd is on
+// through to the disk.
+fn sys_fsync(tf: &TrapFrame) -> isize {
+    match argfd(0, tf) {
+        Ok(f) => crate::file::filesync(f),
+        Err(_) => -1,
+    }
+}
+
+// sync(): write every file system through to its disk.
+fn sys_sync(_tf: &TrapFrame) -> isize {
+    crate::journal::checkpoint();
+    let _ = crate::bio::flush(None);
+    0
+}
+
+// chdir(path): make path the directory relative paths start from.
+fn sys_chdir(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
+    let ip = match crate::fs::namei(path) {
+        Some(ip) => ip,
+        None => return -1,
+    };
+    let is_dir = ip.ilock().map(|di| di.i_mode & 0xF000 == 0x4000);
+    let (dev, inum) = (ip.dev, ip.inum);
+    crate::journal::begin_op();
+    crate::fs::iput(ip);
+    crate::journal::end_op();
+    match is_dir {
+        Ok(true) => {
+            let p = unsafe { &*myproc().unwrap() };
+            p.files().cwd = (dev, inum);
+            0
+        }
+        Ok(false) => -ENOTDIR,
+        Err(e) => e,
+    }
+}
 
-    if crate::growproc::growproc(n).is_err() {
+// mkdir(path): create an empty directory.
+fn sys_mkdir(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
+    crate::journal::begin_op();
+    let r = crate::fs::mkdir(path);
+    crate::journal::end_op();
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// mknod(path, major, minor): create a character device node.
+fn sys_mknod(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
+    let (major, minor) = (argint(1, tf), argint(2, tf));
+    if major > 0xff || minor > 0xff {
+        return -EINVAL;
+    }
+    crate::journal::begin_op();
+    let r = crate::fs::mknod(path, major as u8, minor as u8);
+    crate::journal::end_op();
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// unlink(path): remove the directory entry path. The file is freed once
+// nothing else links to it and no open file refers to it.
+fn sys_unlink(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    if crate::fs::name_too_long(path) {
+        return -ENAMETOOLONG;
+    }
+    crate::journal::begin_op();
+    let r = crate::fs::unlink(path);
+    crate::journal::end_op();
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+fn sys_statfs(tf: &TrapFrame) -> isize {
+    let path = match argstr(0, tf) {
+        Ok(s) => s,
+        Err(_) => return -1,
+    };
+    // There is only the root filesystem; the path just has to exist.
+    match crate::fs::namei(path) {
+        Some(ip) => {
+            crate::journal::begin_op();
+            crate::fs::iput(ip);
+            crate::journal::end_op();
+        }
+        None => return -1,
+    }
+    let st = crate::fs::statfs();
+    let p = unsafe { &*myproc().unwrap() };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyout(
+        p.pgdir,
+        &mut allocator,
+        argptr(1, tf),
+        &st as *const abi::fs::StatFs as *const u8,
+        core::mem::size_of::<abi::fs::StatFs>(),
+    ) {
         return -1;
     }
+    0
+}
+// mmap(addr, len, prot, flags, fd, off): see mmap.rs.
+fn sys_mmap(tf: &TrapFrame) -> isize {
+    crate::mmap::mmap(
+        argptr(1, tf),
+        argint(2, tf) as u32,
+        argint(3, tf) as u32,
+        argint(4, tf),
+        argptr(5, tf),
+    )
+}
+
+// munmap(addr, len)
+fn sys_munmap(tf: &TrapFrame) -> isize {
+    crate::mmap::munmap(argptr(0, tf), argptr(1, tf))
+}
 
-    sz as isize
+fn sys_sbrk(tf: &TrapFrame) -> isize {
+    let n = argint(0, tf) as isize;
+    match crate::growproc::growproc(n) {
+        Ok(sz) => sz as isize,
+        Err(()) => -1,
+    }
 }
 
 fn sys_pipe(tf: &TrapFrame) -> isize {
@@ -349,18 +719,9 @@ fn sys_pipe(tf: &TrapFrame) -> isize {
         return -1;
     }
 
-    let cpu = crate::proc::mycpu();
-    let p = unsafe { &mut *cpu.process.unwrap() };
+    let files = unsafe { (*myproc().unwrap()).files() };
 
-    let mut fd0 = -1;
-    for (i, fd) in p.ofile.iter_mut().enumerate() {
-        if fd.is_none() {
-            *fd = Some(f0 as *mut crate::file::File);
-            fd0 = i as isize;
-            break;
-        }
-    }
-    if fd0 == -1 {
+    let Some(fd0) = files.fdalloc(f0 as *mut crate::file::File) else {
         // Cleanup pipe
         f0.refcnt = 0;
         f1.refcnt = 0;
@@ -368,23 +729,15 @@ fn sys_pipe(tf: &TrapFrame) -> isize {
         // For now, let's assume we won't run out of fds often, but this is a leak if it happens.
         // To fix: manually free pipe or implement proper cleanup.
         return -1;
-    }
+    };
 
-    let mut fd1 = -1;
-    for (i, fd) in p.ofile.iter_mut().enumerate() {
-        if fd.is_none() {
-            *fd = Some(f1 as *mut crate::file::File);
-            fd1 = i as isize;
-            break;
-        }
-    }
-    if fd1 == -1 {
-        p.ofile[fd0 as usize] = None;
+    let Some(fd1) = files.fdalloc(f1 as *mut crate::file::File) else {
+        files.take(fd0);
         f0.refcnt = 0;
         f1.refcnt = 0;
         // Leak pipe
         return -1;
-    }
+    };
 
     fds[0] = fd0 as i32;
     fds[1] = fd1 as i32;
@@ -394,32 +747,438 @@ fn sys_pipe(tf: &TrapFrame) -> isize {
 
 fn sys_dup(tf: &TrapFrame) -> isize {
     let oldfd = argint(0, tf);
-    let cpu = crate::proc::mycpu();
-    let p = unsafe { &mut *cpu.process.unwrap() };
+    let files = unsafe { (*myproc().unwrap()).files() };
+
+    let Some(f) = files.get(oldfd) else {
+        return -1;
+    };
+    // Use proper filedup to manage refcnt safely (with lock)
+    unsafe {
+        crate::file::filedup(&mut *f);
+    }
+    match files.fdalloc(f) {
+        Some(newfd) => newfd as isize,
+        None => {
+            unsafe { crate::file::fileclose(&mut *f) };
+            -1
+        }
+    }
+}
+
+// dup2(oldfd, newfd): make newfd refer to oldfd's file, closing whatever
+// newfd had open. Returns newfd.
+fn sys_dup2(tf: &TrapFrame) -> isize {
+    let oldfd = argint(0, tf);
+    let newfd = argint(1, tf);
+    let files = unsafe { (*myproc().unwrap()).files() };
 
-    if oldfd >= p.ofile.len() || p.ofile[oldfd].is_none() {
+    if newfd >= crate::proc::NFILE {
+        return -1;
+    }
+    let Some(f) = files.get(oldfd) else {
         return -1;
+    };
+    if oldfd == newfd {
+        return newfd as isize;
+    }
+
+    unsafe {
+        crate::file::filedup(&mut *f);
+    }
+    let old = files.lock()[newfd].replace(f);
+    if let Some(old) = old {
+        unsafe {
+            crate::file::fileclose(&mut *old);
+        }
     }
+    newfd as isize
+}
 
-    // Find new fd
-    let mut newfd = -1;
-    for (i, fd) in p.ofile.iter_mut().enumerate() {
-        if fd.is_none() {
-            newfd = i as isize;
+// irqstat(buf, n): copy up to n u64 counters into buf, laid out as
+// [NCPU][NIRQ] like trap::IRQ_COUNTS. Returns the number copied.
+fn sys_irqstat(tf: &TrapFrame) -> isize {
+    use crate::trap::{IRQ_COUNTS, NIRQ};
+    let buf = argptr(0, tf);
+    let n = core::cmp::min(argint(1, tf), crate::proc::NCPU * NIRQ);
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+
+    let mut copied = 0;
+    for counts in IRQ_COUNTS.iter() {
+        if copied >= n {
             break;
         }
+        let mut row = [0u64; NIRQ];
+        for (r, c) in row.iter_mut().zip(counts.iter()) {
+            *r = c.load(core::sync::atomic::Ordering::Relaxed);
+        }
+        let len = core::cmp::min(NIRQ, n - copied);
+        let mut allocator = crate::allocator::ALLOCATOR.lock();
+        if !crate::vm::copyout(
+            pgdir,
+            &mut allocator,
+            buf + (copied * 8) as u64,
+            row.as_ptr() as *const u8,
+            len * 8,
+        ) {
+            return -1;
+        }
+        copied += len;
     }
+    copied as isize
+}
 
-    if newfd == -1 {
+// sysinfo(buf): copy a SysInfo into buf.
+fn sys_sysinfo(tf: &TrapFrame) -> isize {
+    use core::sync::atomic::Ordering::Relaxed;
+    let buf = argptr(0, tf);
+    let mut info = SysInfo {
+        breads: crate::bio::BREADS.load(Relaxed),
+        dcache_hits: crate::fs::DCACHE_HITS.load(Relaxed),
+        dcache_misses: crate::fs::DCACHE_MISSES.load(Relaxed),
+        fpu_restores: crate::fpu::FPU_RESTORES.load(Relaxed),
+        fpu_saves: crate::fpu::FPU_SAVES.load(Relaxed),
+        free_pages: 0,
+        bcache_hits: crate::bio::HITS.load(Relaxed),
+        bcache_misses: crate::bio::MISSES.load(Relaxed),
+        blocks_read: crate::bio::BLOCKS_READ.load(Relaxed),
+        blocks_written: crate::bio::BLOCKS_WRITTEN.load(Relaxed),
+        readaheads: crate::bio::READAHEADS.load(Relaxed),
+        dirty_waits: crate::bio::DIRTY_WAITS.load(Relaxed),
+        inodes_free: crate::fs::inodes_free() as u64,
+        disk_queue: [0; SYSINFO_NDISK],
+        disk_queue_max: [0; SYSINFO_NDISK],
+    };
+    for disk in 0..crate::virtio::NDISK.min(SYSINFO_NDISK) {
+        (info.disk_queue[disk], info.disk_queue_max[disk]) = crate::virtio::queue_depth(disk);
+    }
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    info.free_pages = allocator.nfree as u64;
+    if !crate::vm::copyout(
+        pgdir,
+        &mut allocator,
+        buf,
+        &info as *const SysInfo as *const u8,
+        core::mem::size_of::<SysInfo>(),
+    ) {
         return -1;
     }
+    0
+}
 
-    let f = p.ofile[oldfd].unwrap();
-    p.ofile[newfd as usize] = Some(f);
-    // Use proper filedup to manage refcnt safely (with lock)
-    unsafe {
-        crate::file::filedup(&mut *f);
+// ioctl(fd, request, arg): see abi::tty.
+fn sys_ioctl(tf: &TrapFrame) -> isize {
+    match argfd(0, tf) {
+        Ok(f) => crate::file::fileioctl(f, argint(1, tf), argptr(2, tf)),
+        Err(()) => -1,
     }
+}
+
+// setpgid(pid, pgid): see proc::setpgid.
+fn sys_setpgid(tf: &TrapFrame) -> isize {
+    crate::proc::setpgid(argint(0, tf), argint(1, tf))
+}
 
-    newfd
+// getpgid(pid): the process group of pid, or of the caller for 0.
+fn sys_getpgid(tf: &TrapFrame) -> isize {
+    crate::proc::getpgid(argint(0, tf))
+}
+
+// getrusage(who, buf): copy a Rusage for RUSAGE_SELF or RUSAGE_CHILDREN into buf.
+fn sys_getrusage(tf: &TrapFrame) -> isize {
+    let p = unsafe { &*myproc().unwrap() };
+    let ru = match argint(0, tf) as isize {
+        RUSAGE_SELF => Rusage {
+            utime: p.utime,
+            stime: p.stime,
+        },
+        RUSAGE_CHILDREN => Rusage {
+            utime: p.cutime,
+            stime: p.cstime,
+        },
+        _ => return -1,
+    };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyout(
+        p.pgdir,
+        &mut allocator,
+        argptr(1, tf),
+        &ru as *const Rusage as *const u8,
+        core::mem::size_of::<Rusage>(),
+    ) {
+        return -1;
+    }
+    0
+}
+
+// uptime(): timer ticks since boot.
+fn sys_uptime(_tf: &TrapFrame) -> isize {
+    crate::trap::ticks() as isize
+}
+
+// sleep(n): return after n timer ticks, or -EINTR if a signal comes first.
+fn sys_sleep(tf: &TrapFrame) -> isize {
+    let n = argint(0, tf) as u64;
+    let p = unsafe { &*myproc().unwrap() };
+    let mut ticks = crate::trap::TICKS.lock();
+    let start = *ticks;
+    while *ticks - start < n {
+        if unsafe { crate::proc::killed(p) } {
+            return -EINTR;
+        }
+        crate::proc::sleep(
+            core::ptr::addr_of!(crate::trap::TICKS) as usize,
+            Some(ticks),
+        );
+        ticks = crate::trap::TICKS.lock();
+    }
+    0
+}
+
+// The socket behind fd argument n.
+fn argsock(n: usize, tf: &TrapFrame) -> Result<Socket, isize> {
+    match argfd(n, tf) {
+        Ok(f) => f.sock.ok_or(-ENOTSOCK),
+        Err(()) => Err(-1),
+    }
+}
+
+// Copy in the SockAddrIn of len bytes at user address addr.
+fn fetch_sockaddr(addr: u64, len: usize) -> Result<SockAddrIn, isize> {
+    let mut sa = SockAddrIn::default();
+    if len < core::mem::size_of::<SockAddrIn>() {
+        return Err(-EINVAL);
+    }
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    if !crate::vm::copyin(
+        pgdir,
+        &mut crate::allocator::ALLOCATOR.lock(),
+        &mut sa as *mut SockAddrIn as *mut u8,
+        addr,
+        core::mem::size_of::<SockAddrIn>(),
+    ) {
+        return Err(-1);
+    }
+    if sa.family != abi::net::AF_INET {
+        return Err(-EAFNOSUPPORT);
+    }
+    Ok(sa)
+}
+
+// Store the address of ip and port at user address addr, unless it is
+// null, and its size at addrlen.
+fn put_sockaddr(addr: u64, addrlen: u64, ip: u32, port: u16) -> Result<(), isize> {
+    if addr == 0 {
+        return Ok(());
+    }
+    let sa = SockAddrIn::new(ip.to_be_bytes(), port);
+    let len = core::mem::size_of::<SockAddrIn>() as u32;
+    let pgdir = unsafe { (*myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    let ok = crate::vm::copyout(
+        pgdir,
+        &mut allocator,
+        addr,
+        &sa as *const SockAddrIn as *const u8,
+        len as usize,
+    ) && (addrlen == 0
+        || crate::vm::copyout(
+            pgdir,
+            &mut allocator,
+            addrlen,
+            &len as *const u32 as *const u8,
+            4,
+        ));
+    if ok {
+        Ok(())
+    } else {
+        Err(-1)
+    }
+}
+
+// A new fd for sock, which is closed if there is none.
+fn sockfd(sock: Socket) -> isize {
+    let Some(f) = crate::file::filealloc() else {
+        sock.close();
+        return -1;
+    };
+    f.f_type = crate::file::FileType::Socket;
+    f.sock = Some(sock);
+    f.readable = true;
+    f.writable = true;
+    f.off = 0;
+    let files = unsafe { (*myproc().unwrap()).files() };
+    match files.fdalloc(f) {
+        Some(fd) => fd as isize,
+        None => {
+            crate::file::fileclose(f);
+            -1
+        }
+    }
+}
+
+// socket(domain, type, protocol): a new UDP (SOCK_DGRAM) or TCP
+// (SOCK_STREAM) socket. Only AF_INET, with protocol 0 or the type's own,
+// or IPPROTO_ICMP for a SOCK_DGRAM ping socket.
+fn sys_socket(tf: &TrapFrame) -> isize {
+    if argint(0, tf) != abi::net::AF_INET as usize {
+        return -EAFNOSUPPORT;
+    }
+    let sock = match (argint(1, tf), argint(2, tf)) {
+        (abi::net::SOCK_DGRAM, 0 | 17) => crate::net::udp::socket(PROTO_UDP).map(Socket::Udp),
+        (abi::net::SOCK_DGRAM, abi::net::IPPROTO_ICMP) => {
+            crate::net::udp::socket(PROTO_ICMP).map(Socket::Udp)
+        }
+        (abi::net::SOCK_STREAM, 0 | 6) => crate::net::tcp::socket().map(Socket::Tcp),
+        _ => return -EPROTONOSUPPORT,
+    };
+    match sock {
+        Some(sock) => sockfd(sock),
+        None => -1,
+    }
+}
+
+// bind(fd, addr, addrlen): give the socket the port in addr, or a free
+// one if that is 0. The address part is ignored: there is one interface.
+fn sys_bind(tf: &TrapFrame) -> isize {
+    let r = argsock(0, tf).and_then(|sock| {
+        let sa = fetch_sockaddr(argptr(1, tf), argint(2, tf))?;
+        match sock {
+            Socket::Udp(s) => crate::net::udp::bind(s, sa.port()),
+            Socket::Tcp(s) => crate::net::tcp::bind(s, sa.port()),
+        }
+    });
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// listen(fd, backlog): take TCP connections, up to backlog of them
+// waiting for accept.
+fn sys_listen(tf: &TrapFrame) -> isize {
+    let r = argsock(0, tf).and_then(|sock| match sock {
+        Socket::Tcp(s) => crate::net::tcp::listen(s, argint(1, tf)),
+        Socket::Udp(_) => Err(-EOPNOTSUPP),
+    });
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// accept(fd, addr, addrlen): wait for a connection to a listening socket
+// and return a new fd for it. The peer's address is stored as recvfrom
+// does.
+fn sys_accept(tf: &TrapFrame) -> isize {
+    let r = argsock(0, tf).and_then(|sock| match sock {
+        Socket::Tcp(s) => crate::net::tcp::accept(s),
+        Socket::Udp(_) => Err(-EOPNOTSUPP),
+    });
+    let (conn, ip, port) = match r {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let fd = sockfd(Socket::Tcp(conn));
+    if fd >= 0 {
+        if let Err(e) = put_sockaddr(argptr(1, tf), argptr(2, tf), ip, port) {
+            return e;
+        }
+    }
+    fd
+}
+
+// connect(fd, addr, addrlen): open a TCP connection to addr, waiting for
+// the handshake. A UDP socket only takes addr as its peer, which write
+// sends to and the only one it receives from.
+fn sys_connect(tf: &TrapFrame) -> isize {
+    let r = argsock(0, tf).and_then(|sock| {
+        let sa = fetch_sockaddr(argptr(1, tf), argint(2, tf))?;
+        let dst = u32::from_be_bytes(sa.addr);
+        match sock {
+            Socket::Tcp(s) => crate::net::tcp::connect(s, dst, sa.port()),
+            Socket::Udp(s) => crate::net::udp::connect(s, dst, sa.port()),
+        }
+    });
+    match r {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// sendto(fd, buf, n, flags, addr, addrlen): send n bytes as one datagram
+// to addr, or to the peer if addr is null, as write() does; on a TCP
+// socket addr is ignored. Returns the bytes sent. Flags are ignored.
+fn sys_sendto(tf: &TrapFrame) -> isize {
+    let r = argsock(0, tf).and_then(|sock| match sock {
+        Socket::Udp(s) if argptr(4, tf) == 0 => {
+            Ok(crate::net::udp::write(s, argptr(1, tf), argint(2, tf)))
+        }
+        Socket::Udp(s) => {
+            let sa = fetch_sockaddr(argptr(4, tf), argint(5, tf))?;
+            let dst = u32::from_be_bytes(sa.addr);
+            Ok(crate::net::udp::sendto(
+                s,
+                argptr(1, tf),
+                argint(2, tf),
+                dst,
+                sa.port(),
+            ))
+        }
+        Socket::Tcp(s) => Ok(crate::net::tcp::send(s, argptr(1, tf), argint(2, tf))),
+    });
+    r.unwrap_or_else(|e| e)
+}
+
+// recvfrom(fd, buf, n, flags, addr, addrlen): wait for a datagram and copy
+// up to n bytes of it to buf. If addr is not null, the sender's address is
+// stored there and its size at addrlen. On a TCP socket, as read(), with
+// addr left alone. Returns the bytes copied. Of the flags, MSG_DONTWAIT
+// is taken by UDP sockets.
+fn sys_recvfrom(tf: &TrapFrame) -> isize {
+    let s = match argsock(0, tf) {
+        Ok(Socket::Udp(s)) => s,
+        Ok(Socket::Tcp(s)) => return crate::net::tcp::recv(s, argptr(1, tf), argint(2, tf)),
+        Err(e) => return e,
+    };
+    let wait = argint(3, tf) & abi::net::MSG_DONTWAIT == 0;
+    let (n, src, sport) = match crate::net::udp::recvfrom(s, argptr(1, tf), argint(2, tf), wait) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    match put_sockaddr(argptr(4, tf), argptr(5, tf), src, sport) {
+        Ok(()) => n as isize,
+        Err(e) => e,
+    }
+}
+
+// getsockname(fd, addr, addrlen): store the socket's own address and port
+// as recvfrom does.
+fn sys_getsockname(tf: &TrapFrame) -> isize {
+    let ((ip, port), _) = match argsock(0, tf) {
+        Ok(Socket::Udp(s)) => crate::net::udp::names(s),
+        Ok(Socket::Tcp(s)) => crate::net::tcp::names(s),
+        Err(e) => return e,
+    };
+    match put_sockaddr(argptr(1, tf), argptr(2, tf), ip, port) {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+// getpeername(fd, addr, addrlen): store the address and port of the
+// socket's peer as recvfrom does; ENOTCONN without one.
+fn sys_getpeername(tf: &TrapFrame) -> isize {
+    let (_, peer) = match argsock(0, tf) {
+        Ok(Socket::Udp(s)) => crate::net::udp::names(s),
+        Ok(Socket::Tcp(s)) => crate::net::tcp::names(s),
+        Err(e) => return e,
+    };
+    let Some((ip, port)) = peer else {
+        return -ENOTCONN;
+    };
+    match put_sockaddr(argptr(1, tf), argptr(2, tf), ip, port) {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
 }
@@ -1,6 +1,44 @@
 use crate::gdt::KCODE_SELECTOR;
+use crate::proc::NCPU;
+use crate::spinlock::Spinlock;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use crate::util::{IRQ_TIMER, IRQ_UART, IRQ_VIRTIO, T_IRQ0, T_PAGE_FAULT, T_SYSCALL};
+use crate::util::{
+    IRQ_ERROR, IRQ_SPURIOUS, IRQ_TIMER, IRQ_TLB, IRQ_UART, T_DEBUG, T_DEVICE, T_IRQ0, T_PAGE_FAULT,
+    T_SYSCALL,
+};
+
+// Number of IRQ vectors (T_IRQ0..T_IRQ0 + NIRQ) counted per CPU.
+pub const NIRQ: usize = 32;
+
+// Interrupt counts, indexed by [LAPIC ID][IRQ].
+pub static IRQ_COUNTS: [[AtomicU64; NIRQ]; NCPU] =
+    [const { [const { AtomicU64::new(0) }; NIRQ] }; NCPU];
+
+// Timer interrupts on CPU 0 since boot. Sleepers wait on its address.
+pub static TICKS: Spinlock<u64> = Spinlock::ranked(0, "TICKS", crate::lockorder::RANK_TICKS);
+
+pub fn ticks() -> u64 {
+    *TICKS.lock()
+}
+
+fn count_irq(trap_num: u64) {
+    let irq = trap_num.wrapping_sub(T_IRQ0 as u64) as usize;
+    let cpu = crate::lapic::id() as usize;
+    if irq < NIRQ && cpu < NCPU {
+        IRQ_COUNTS[cpu][irq].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Raise the spurious vector by hand, for make test-spurious: it must be
+// counted and otherwise ignored, and the boot must go on.
+pub fn spurious_check() {
+    let count =
+        || IRQ_COUNTS[crate::lapic::id() as usize][IRQ_SPURIOUS as usize].load(Ordering::Relaxed);
+    let before = count();
+    unsafe { core::arch::asm!("int {}", const T_IRQ0 + IRQ_SPURIOUS) };
+    crate::warn!("spurious: counted {}", count() - before);
+}
 
 pub fn init() {
     unsafe {
@@ -91,26 +129,87 @@ unsafe extern "C" {
 
 #[unsafe(no_mangle)]
 extern "C" fn trap_handler(tf: &mut TrapFrame) {
+    count_irq(tf.trap_num);
     match tf.trap_num {
         n if n == (T_IRQ0 + IRQ_TIMER) as u64 => {
-            crate::proc::yield_proc();
+            if crate::lapic::id() == 0 {
+                let mut ticks = TICKS.lock();
+                *ticks += 1;
+                crate::proc::wakeup(core::ptr::addr_of!(TICKS) as usize);
+                let boost = ticks.is_multiple_of(crate::proc::BOOST_TICKS);
+                drop(ticks);
+                if boost {
+                    crate::proc::boost();
+                }
+                crate::net::poll();
+                crate::virtio_console::poll();
+            }
+            if crate::proc::account_tick(tf.cs & 3 == 3) {
+                crate::proc::yield_proc();
+            }
             crate::lapic::eoi();
         }
         n if n == (T_IRQ0 + IRQ_UART) as u64 => {
             crate::uart::uartintr();
+            crate::lapic::eoi_irq(IRQ_UART);
+        }
+        n if crate::e1000::irq() == Some((n as u32).wrapping_sub(T_IRQ0)) => {
+            crate::e1000::intr();
+            // A PCI line, which disks may share
+            let irq = n as u32 - T_IRQ0;
+            if crate::virtio::has_irq(irq) {
+                unsafe { crate::virtio::intr(irq) };
+            }
+            crate::lapic::eoi_irq(irq);
+        }
+        n if crate::virtio::has_irq((n as u32).wrapping_sub(T_IRQ0)) => {
+            let irq = n as u32 - T_IRQ0;
+            unsafe { crate::virtio::intr(irq) };
+            crate::lapic::eoi_irq(irq);
+        }
+        n if n == (T_IRQ0 + IRQ_TLB) as u64 => {
+            crate::vm::tlb_interrupt();
             crate::lapic::eoi();
         }
-        n if n == (T_IRQ0 + IRQ_VIRTIO) as u64 => {
-            unsafe { crate::virtio::intr() };
+        n if n == (T_IRQ0 + IRQ_SPURIOUS) as u64 => {
+            // Spurious interrupts are not in service, so they must not be EOI'd.
+            crate::warn!("Spurious interrupt on CPU {}", crate::lapic::id());
+        }
+        n if n == (T_IRQ0 + IRQ_ERROR) as u64 => {
+            crate::error!(
+                "LAPIC error on CPU {}: ESR={:x}",
+                crate::lapic::id(),
+                crate::lapic::esr()
+            );
             crate::lapic::eoi();
         }
         n if n == T_SYSCALL as u64 => {
             crate::syscall::syscall();
         }
+        n if n == T_DEBUG as u64 => {
+            crate::ptrace::debug_trap(tf);
+        }
+        n if n == T_DEVICE as u64 => {
+            crate::fpu::nm_trap(tf);
+        }
         n if n == T_PAGE_FAULT as u64 => {
             let addr = unsafe { crate::util::rcr2() };
             handle_page_fault(addr, tf);
         }
+        n if n < T_IRQ0 as u64 && tf.cs & 3 == 3 => {
+            // Any other exception in user code (#UD, an unmasked SIMD
+            // floating-point exception, ...) kills only the process.
+            let p = unsafe { &*crate::proc::myproc().unwrap() };
+            crate::info!(
+                "Trap {}: pid={} name={:?} ip={:x} error={:x}",
+                n,
+                p.pid,
+                p.name,
+                tf.rip,
+                tf.error_code
+            );
+            crate::proc::exit(-1);
+        }
         _ => {
             crate::error!("Trap {} on CPU {}", tf.trap_num, crate::lapic::id());
             crate::error!("Error Code: {:x}", tf.error_code);
@@ -121,22 +220,105 @@ extern "C" fn trap_handler(tf: &mut TrapFrame) {
             loop {}
         }
     }
+
+    if tf.cs & 3 == 3 {
+        // Killed while in the kernel, or (from the timer) while running.
+        if unsafe { (*crate::proc::myproc().unwrap()).killed } {
+            crate::proc::exit(-1);
+        }
+        crate::ptrace::stop_if_requested();
+        crate::signal::deliver(tf);
+        check_user_return(tf);
+    }
+}
+
+// Invariants for returning to user mode. The trap frame may belong to a process
+// that was switched in by yield_proc, so check the frame actually being restored.
+fn check_user_return(tf: &TrapFrame) {
+    if tf.rflags & 0x200 == 0 {
+        panic!(
+            "trap return: IF clear in user rflags {:x} (rip={:x})",
+            tf.rflags, tf.rip
+        );
+    }
+    let ncli = crate::proc::mycpu().ncli;
+    if ncli != 0 {
+        panic!("trap return: returning to user with ncli={}", ncli);
+    }
 }
 
 fn handle_page_fault(addr: u64, tf: &TrapFrame) {
-    let cpu = crate::proc::mycpu();
-    let p = unsafe { &mut *cpu.process.unwrap() };
+    let p = unsafe { &mut *crate::proc::myproc().unwrap() };
+    let user = tf.cs & 3 == 3;
 
-    // Check if address is valid.
-    // Must be < p.sz.
-    if addr >= p.sz as u64 {
-        crate::info!(
-            "Segmentation Fault: pid={} name={:?} ip={:x} addr={:x}",
-            p.pid,
-            p.name,
-            tf.rip,
-            addr
+    // Kernel code must not touch user memory while holding ALLOCATOR:
+    // the fault handler needs it to map the page.
+    if crate::allocator::ALLOCATOR.holding() {
+        panic!(
+            "page fault at {:x} (rip={:x}) while holding ALLOCATOR",
+            addr, tf.rip
         );
+    }
+
+    // A fault in user mode may grow the stack, so it holds the Mm lock: see
+    // mm.rs. Let go of it before exit.
+    let mm = p.mm();
+    let guard = user.then(|| mm.lock());
+
+    // Another thread may have mapped the page first.
+    if tf.error_code & 1 == 0
+        && crate::vm::is_mapped(p.pgdir, &mut crate::allocator::ALLOCATOR.lock(), addr)
+    {
+        return;
+    }
+
+    // Check if address is valid.
+    // Must be in the heap, just below the stack, which then grows, or mapped.
+    if addr >= mm.sz as u64 || addr < mm.heap_start as u64 {
+        if (user && crate::growproc::grow_stack(mm, addr))
+            || crate::mmap::fault(mm, addr, user).is_ok()
+        {
+            return;
+        }
+        // The page under the stack is the guard page, once the stack cannot
+        // grow past it.
+        let what =
+            if addr < mm.ustack as u64 && addr + crate::util::PG_SIZE as u64 >= mm.ustack as u64 {
+                "Stack overflow"
+            } else {
+                "Segmentation Fault"
+            };
+        let mut buf = [0u8; 64];
+        let sym = if user {
+            crate::symtab::lookup(p.exe.0, p.exe.1, tf.rip, &mut buf)
+        } else {
+            None
+        };
+        match sym {
+            Some((name, off)) => crate::info!(
+                "{}: pid={} name={:?} ip={:x} <{}+0x{:x}> addr={:x}",
+                what,
+                p.pid,
+                p.name,
+                tf.rip,
+                name,
+                off,
+                addr
+            ),
+            None => crate::info!(
+                "{}: pid={} name={:?} ip={:x} addr={:x}",
+                what,
+                p.pid,
+                p.name,
+                tf.rip,
+                addr
+            ),
+        }
+        // The dump creates a file, which takes locks ranked below the Mm's.
+        drop(guard);
+        if p.dumpable && user {
+            crate::coredump::dump(p, tf, addr);
+        }
         crate::proc::exit(-1);
     }
 
@@ -147,13 +329,21 @@ fn handle_page_fault(addr: u64, tf: &TrapFrame) {
     let mut allocator = crate::allocator::ALLOCATOR.lock();
     let mem = allocator.kalloc();
     if mem.is_null() {
+        drop(allocator);
+        drop(guard);
         crate::info!("OOM: pid={} name={:?}", p.pid, p.name);
         crate::proc::exit(-1);
     }
     unsafe {
-        core::ptr::write_bytes(mem, 0, crate::util::PG_SIZE);
+        crate::util::fast_zero(mem, crate::util::PG_SIZE);
     }
 
+    // A kernel fault takes no Mm lock, so another thread may have got here
+    // first; see mm.rs.
+    if crate::vm::is_mapped(p.pgdir, &mut allocator, page_addr) {
+        allocator.kfree(mem as usize);
+        return;
+    }
     if !crate::vm::map_pages(
         p.pgdir,
         &mut allocator,
@@ -163,6 +353,8 @@ fn handle_page_fault(addr: u64, tf: &TrapFrame) {
         crate::vm::PageTableEntry::WRITABLE | crate::vm::PageTableEntry::USER,
     ) {
         allocator.kfree(mem as usize);
+        drop(allocator);
+        drop(guard);
         crate::uart_println!("Map failed: pid={} name={:?}", p.pid, p.name);
         crate::proc::exit(-1);
     }
@@ -52,12 +52,16 @@ pub fn uartintr() {
 
 use crate::spinlock::Spinlock;
 
-pub static UART_TX: Spinlock<Uart> = Spinlock::new(Uart, "UART_TX");
+pub static UART_TX: Spinlock<Uart> =
+    Spinlock::ranked(Uart, "UART_TX", crate::lockorder::RANK_UART_TX);
 
+// To hvc0 instead, once it is the console (see virtio_console).
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    UART_TX.lock().write_fmt(args).unwrap();
+    if crate::virtio_console::print(args).is_err() {
+        UART_TX.lock().write_fmt(args).unwrap();
+    }
 }
 
 #[macro_export]
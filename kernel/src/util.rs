@@ -23,6 +23,8 @@ pub fn io2v(x: usize) -> usize {
 }
 
 // Interrupts
+pub const T_DEBUG: u32 = 1;
+pub const T_DEVICE: u32 = 7; // FPU not available (CR0.TS set)
 pub const T_PAGE_FAULT: u32 = 14;
 pub const T_SYSCALL: u32 = 64; // system call
 pub const T_IRQ0: u32 = 32;
@@ -30,12 +32,15 @@ pub const IRQ_TIMER: u32 = 0;
 pub const IRQ_UART: u32 = 4;
 pub const IRQ_VIRTIO: u32 = 11;
 pub const IRQ_ERROR: u32 = 19;
+pub const IRQ_TLB: u32 = 30; // TLB shootdown IPI, see vm::flush_tlb
+pub const IRQ_SPURIOUS: u32 = 31;
 
 // MSRs
 pub const MSR_EFER: u32 = 0xC0000080;
 pub const MSR_STAR: u32 = 0xC0000081;
 pub const MSR_LSTAR: u32 = 0xC0000082;
 pub const MSR_SFMASK: u32 = 0xC0000084;
+pub const MSR_GS_BASE: u32 = 0xC0000101;
 pub const MSR_KERNEL_GS_BASE: u32 = 0xC0000102;
 
 // EFER
@@ -52,6 +57,72 @@ pub unsafe fn stosq(addr: *mut u64, val: u64, count: usize) {
     }
 }
 
+// Does the CPU have Enhanced REP MOVSB/STOSB? Then the byte forms are as
+// fast as the qword ones for any length and alignment. Set by detect_cpu.
+static ERMS: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+// Read CPU feature flags. Called once at boot, before any fast_copy.
+pub fn detect_cpu() {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    let erms = __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 9) != 0;
+    ERMS.store(erms, core::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn has_erms() -> bool {
+    ERMS.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+// Copy len bytes with rep movsb (ERMS) or rep movsq plus a byte tail.
+// For page-sized copies; the ranges must not overlap.
+pub unsafe fn fast_copy(dst: *mut u8, src: *const u8, len: usize) {
+    unsafe {
+        if has_erms() {
+            core::arch::asm!(
+                "rep movsb",
+                inout("rdi") dst => _,
+                inout("rsi") src => _,
+                inout("rcx") len => _,
+            );
+        } else {
+            core::arch::asm!(
+                "rep movsq",
+                "mov rcx, {tail}",
+                "rep movsb",
+                tail = in(reg) len % 8,
+                inout("rdi") dst => _,
+                inout("rsi") src => _,
+                inout("rcx") len / 8 => _,
+            );
+        }
+    }
+}
+
+// Zero len bytes with rep stosb (ERMS) or rep stosq plus a byte tail.
+pub unsafe fn fast_zero(dst: *mut u8, len: usize) {
+    unsafe {
+        if has_erms() {
+            core::arch::asm!(
+                "rep stosb",
+                inout("rdi") dst => _,
+                inout("rcx") len => _,
+                in("al") 0u8,
+            );
+        } else {
+            stosq(dst as *mut u64, 0, len / 8);
+            core::arch::asm!(
+                "rep stosb",
+                inout("rdi") dst.add(len & !7) => _,
+                inout("rcx") len % 8 => _,
+                in("al") 0u8,
+            );
+        }
+    }
+}
+
+pub unsafe fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
 pub unsafe fn outb(port: u16, val: u8) {
     unsafe {
         core::arch::asm!("out dx, al", in("dx") port, in("al") val);
@@ -152,3 +223,73 @@ pub unsafe fn rcr2() -> u64 {
     }
     val
 }
+
+pub unsafe fn rcr0() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) val);
+    }
+    val
+}
+
+pub unsafe fn lcr0(val: u64) {
+    unsafe {
+        core::arch::asm!("mov cr0, {}", in(reg) val);
+    }
+}
+
+pub unsafe fn rcr4() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) val);
+    }
+    val
+}
+
+pub unsafe fn lcr4(val: u64) {
+    unsafe {
+        core::arch::asm!("mov cr4, {}", in(reg) val);
+    }
+}
+
+// Debug registers: DR0-DR3 hold breakpoint addresses, DR6 the status of
+// the last debug exception and DR7 the enable bits and breakpoint kinds.
+pub unsafe fn ldr(n: usize, val: u64) {
+    unsafe {
+        match n {
+            0 => core::arch::asm!("mov dr0, {}", in(reg) val),
+            1 => core::arch::asm!("mov dr1, {}", in(reg) val),
+            2 => core::arch::asm!("mov dr2, {}", in(reg) val),
+            3 => core::arch::asm!("mov dr3, {}", in(reg) val),
+            _ => panic!("ldr: no DR{}", n),
+        }
+    }
+}
+
+pub unsafe fn rdr6() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr6", out(reg) val);
+    }
+    val
+}
+
+pub unsafe fn ldr6(val: u64) {
+    unsafe {
+        core::arch::asm!("mov dr6, {}", in(reg) val);
+    }
+}
+
+pub unsafe fn rdr7() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr7", out(reg) val);
+    }
+    val
+}
+
+pub unsafe fn ldr7(val: u64) {
+    unsafe {
+        core::arch::asm!("mov dr7, {}", in(reg) val);
+    }
+}
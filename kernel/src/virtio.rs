@@ -1,17 +1,38 @@
 #![allow(unsafe_op_in_unsafe_fn)]
+// virtio-blk, over the modern PCI transport (see virtio_pci) if the device
+// has it, or else the legacy one, whose registers are IO ports from BAR0.
+// QEMU's virtio-blk-pci is transitional, with both, unless it is on a PCIe
+// bus or has disable-legacy=on; then it is modern only. Without PCI, the
+// device may be on virtio-mmio instead (see virtio_mmio).
+//
+// There may be up to NDISK of them, virtio0 first, each with a driver and
+// a lock of its own. Disks on PCI may share an IRQ line.
+//
+// Reads and writes sleep until they are done. Those started with
+// block_io_async, for bio's read-ahead and write-back, are not waited for:
+// the interrupt finishes them.
 use crate::allocator::Allocator;
 use crate::pci::PciDevice;
+use crate::virtio_mmio::Transport as MmioTransport;
+use crate::virtio_pci::{Transport, MODERN_DEVICE_ID};
 
 use crate::util::{inb, inl, inw, outb, outl, outw};
-use crate::util::{v2p, PG_SIZE};
+use crate::util::{v2p, IRQ_VIRTIO, PG_SIZE};
 use core::mem::size_of;
 use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 
 pub const VIRTIO_LEGACY_DEVICE_ID: u16 = 0x1001;
+pub const VIRTIO_MODERN_DEVICE_ID: u16 = MODERN_DEVICE_ID + 2;
+
+pub const NDISK: usize = 4;
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
 
+// Request status written back by the device
+const VIRTIO_BLK_S_OK: u8 = 0;
+
 // Offsets for Legacy Virtio Header (IO Space)
 const VIRTIO_REG_HOST_FEATURES: u16 = 0;
 const VIRTIO_REG_GUEST_FEATURES: u16 = 4;
@@ -67,59 +88,211 @@ struct VirtioBlkOutHeader {
     sector: u64,
 }
 
+// How the device's registers are reached
+enum Regs {
+    Legacy(u16),                 // IO ports from this base
+    Modern(Transport, *mut u16), // And where to notify queue 0
+    Mmio(MmioTransport),
+}
+
+impl Regs {
+    unsafe fn notify(&self) {
+        match self {
+            Regs::Legacy(io_base) => outw(io_base + VIRTIO_REG_QUEUE_NOTIFY, 0),
+            Regs::Modern(_, notify) => core::ptr::write_volatile(*notify, 0),
+            Regs::Mmio(transport) => transport.notify(0),
+        }
+    }
+
+    // Read, and so acknowledge, the ISR status
+    unsafe fn isr(&self) -> u8 {
+        match self {
+            Regs::Legacy(io_base) => inb(io_base + VIRTIO_REG_ISR_STATUS),
+            Regs::Modern(transport, _) => transport.isr(),
+            Regs::Mmio(transport) => transport.isr(),
+        }
+    }
+}
+
+// A request the device has, by the head of its descriptor chain: the data
+// buffer, or 0 if the head is not one, whether the device is done with it,
+// and its Async slot if no one waits for it. The owner of any other
+// sleeps on the entry until it is done.
+#[derive(Clone, Copy)]
+struct Inflight {
+    buf: usize,
+    done: bool,
+    slot: u16,
+}
+
+const NO_SLOT: u16 = u16::MAX;
+
+// Where block_io_async is at: IO_PENDING from when it starts, then
+// IO_OK or IO_FAILED.
+pub const IO_PENDING: u8 = 1;
+pub const IO_OK: u8 = 2;
+pub const IO_FAILED: u8 = 3;
+
+// A request no one waits for: its header and status byte, which must outlive
+// the call that started it, and the state to set and channel to wake when
+// it is done. state is 0 if the slot is free.
+#[repr(C)]
+struct Async {
+    req: VirtioBlkReq,
+    status: u8,
+    state: usize, // &AtomicU8
+    chan: usize,
+}
+
+const NASYNC: usize = PG_SIZE / size_of::<Async>();
+
 pub struct VirtioDriver {
-    io_base: u16,
+    regs: Regs,
     queue_desc: *mut VRingDesc,
     queue_avail: *mut VRingAvail,
     queue_used: *mut VRingUsed,
+    inflight: *mut Inflight, // [Inflight; QUEUE_SIZE], a page
+    asyncs: *mut Async,      // [Async; NASYNC], a page
+    disk: usize,
     free_head: u16,
+    nfree: usize,
     used_idx: u16,
     avail_idx: u16,
 }
 
+const _: () = assert!(size_of::<Inflight>() * QUEUE_SIZE <= PG_SIZE);
+
 use crate::spinlock::Spinlock;
 
-pub static VIRTIO_BLK_DRIVER: Spinlock<Option<VirtioDriver>> =
-    Spinlock::new(None, "VIRTIO_BLK_DRIVER");
-
-pub unsafe fn intr() {
-    let guard = VIRTIO_BLK_DRIVER.lock();
-    if let Some(driver) = guard.as_ref() {
-        let status = unsafe { inb(driver.io_base + VIRTIO_REG_ISR_STATUS) };
-        if status & 1 != 0 || status & 3 != 0 {
-            // Wakeup waiting process
-            // We wake up the VIRTIO_BLK_DRIVER address (global static address)
-            crate::proc::wakeup(addr_of!(VIRTIO_BLK_DRIVER) as usize);
+// The driver of each disk. Never two held at once.
+static DISKS: [Spinlock<Option<VirtioDriver>>; NDISK] =
+    [const { Spinlock::ranked(None, "VIRTIO_BLK_DRIVER", crate::lockorder::RANK_VIRTIO) }; NDISK];
+
+const NO_IRQ: u32 = u32::MAX;
+
+// The IRQ each disk interrupts on, once it is set up: its PCI line, 11 for
+// slot 3 under QEMU, or the one a virtio-mmio device is found with.
+static IRQS: [AtomicU32; NDISK] = [const { AtomicU32::new(NO_IRQ) }; NDISK];
+
+// Requests in flight on each disk, and the most there have been, for
+// sysinfo.
+static DEPTH: [AtomicU32; NDISK] = [const { AtomicU32::new(0) }; NDISK];
+static DEPTH_MAX: [AtomicU32; NDISK] = [const { AtomicU32::new(0) }; NDISK];
+
+pub fn queue_depth(disk: usize) -> (u32, u32) {
+    (
+        DEPTH[disk].load(Ordering::Relaxed),
+        DEPTH_MAX[disk].load(Ordering::Relaxed),
+    )
+}
+
+// What processes waiting for free descriptors on disk sleep on
+fn desc_chan(disk: usize) -> usize {
+    addr_of!(DISKS[disk]) as usize
+}
+
+// Whether disk is there and set up.
+pub fn present(disk: usize) -> bool {
+    disk < NDISK && IRQS[disk].load(Ordering::Relaxed) != NO_IRQ
+}
+
+// The IRQs of the disks, once per disk.
+pub fn irqs() -> impl Iterator<Item = u32> {
+    IRQS.iter()
+        .map(|irq| irq.load(Ordering::Relaxed))
+        .filter(|&irq| irq != NO_IRQ)
+}
+
+pub fn has_irq(irq: u32) -> bool {
+    irqs().any(|i| i == irq)
+}
+
+// Complete what the disks on irq have finished.
+pub unsafe fn intr(irq: u32) {
+    for (disk, i) in IRQS.iter().enumerate() {
+        if i.load(Ordering::Relaxed) != irq {
+            continue;
+        }
+        let mut guard = DISKS[disk].lock();
+        if let Some(driver) = guard.as_mut() {
+            let status = unsafe { driver.regs.isr() };
+            if status & 1 != 0 {
+                driver.complete();
+            }
         }
     }
 }
 
-pub unsafe fn init(dev: &PciDevice, allocator: &mut Allocator) {
-    let mut guard = VIRTIO_BLK_DRIVER.lock();
+// Set up the virtio-blk dev as disk.
+pub unsafe fn init(disk: usize, dev: &PciDevice, allocator: &mut Allocator) {
+    let regs = match crate::virtio_pci::probe(dev) {
+        Some(transport) => Regs::Modern(transport, core::ptr::null_mut()),
+        None if dev.device_id == VIRTIO_LEGACY_DEVICE_ID => Regs::Legacy(dev.base_addr as u16),
+        None => {
+            crate::error!("Virtio: no usable virtio registers");
+            return;
+        }
+    };
+    // The line the firmware routed the slot to
+    let irq = match dev.irq_line {
+        0 | 0xFF => IRQ_VIRTIO,
+        line => line as u32,
+    };
+    start(disk, regs, irq, allocator);
+}
+
+// Set up a virtio-blk found on virtio-mmio (see virtio_mmio::find) as disk.
+pub unsafe fn init_mmio(disk: usize, transport: MmioTransport, allocator: &mut Allocator) {
+    let irq = transport.irq;
+    start(disk, Regs::Mmio(transport), irq, allocator);
+}
+
+unsafe fn start(disk: usize, mut regs: Regs, irq: u32, allocator: &mut Allocator) {
+    let mut guard = DISKS[disk].lock();
     if guard.is_some() {
         return;
     }
 
-    let io_base = dev.base_addr as u16;
-    crate::info!("Virtio: io_base={:x}", io_base);
+    // No features but VIRTIO_F_VERSION_1 from a modern device: requests
+    // are plain reads and writes.
+    let q_size = match &regs {
+        Regs::Modern(transport, _) => transport.negotiate(0).map(|_| transport.queue_size(0)),
+        Regs::Mmio(transport) => transport.negotiate(0).map(|_| transport.queue_size(0)),
+        &Regs::Legacy(io_base) => {
+            crate::info!("Virtio: io_base={:x}", io_base);
 
-    // 1. Reset device
-    unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, 0) };
+            // 1. Reset device
+            unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, 0) };
 
-    // 2. Set ACKNOWLEDGE and DRIVER
-    let mut status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
-    unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, status) };
+            // 2. Set ACKNOWLEDGE and DRIVER
+            let status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+            unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, status) };
 
-    // 3. Negotiate Features
-    let features = unsafe { inl(io_base + VIRTIO_REG_HOST_FEATURES) };
-    unsafe { outl(io_base + VIRTIO_REG_GUEST_FEATURES, features) };
+            // 3. Negotiate Features
+            let features = unsafe { inl(io_base + VIRTIO_REG_HOST_FEATURES) };
+            unsafe { outl(io_base + VIRTIO_REG_GUEST_FEATURES, features) };
 
-    // 4. Setup Virtqueues
-    unsafe { outw(io_base + VIRTIO_REG_QUEUE_SELECT, 0) };
-
-    let q_size = unsafe { inw(io_base + VIRTIO_REG_QUEUE_SIZE) } as usize;
+            // 4. Setup Virtqueues
+            unsafe { outw(io_base + VIRTIO_REG_QUEUE_SELECT, 0) };
+            Ok(unsafe { inw(io_base + VIRTIO_REG_QUEUE_SIZE) })
+        }
+    };
+    let Ok(q_size) = q_size.map(|size| size as usize) else {
+        crate::error!("Virtio: feature negotiation failed");
+        return;
+    };
     crate::info!("Virtio: Device Queue 0 size {}", q_size);
 
+    // Other devices take a smaller queue than their own, but a legacy PCI
+    // one's is what it is.
+    if !matches!(regs, Regs::Legacy(_)) && q_size < QUEUE_SIZE {
+        crate::error!(
+            "Virtio: device queue size {} < compiled {}",
+            q_size,
+            QUEUE_SIZE
+        );
+        return;
+    }
     if q_size < QUEUE_SIZE {
         crate::error!(
             "Virtio: Warning device queue size {} < compiled {}",
@@ -128,12 +301,15 @@ pub unsafe fn init(dev: &PciDevice, allocator: &mut Allocator) {
         );
     }
 
-    // Allocate 3 contiguous pages manually
+    // Allocate 3 contiguous pages manually, and one each for the requests
+    // in flight and the ones no one waits for
     let p1 = allocator.kalloc();
     let p2 = allocator.kalloc();
     let p3 = allocator.kalloc();
+    let inflight = allocator.kalloc();
+    let asyncs = allocator.kalloc();
 
-    if p1.is_null() || p2.is_null() || p3.is_null() {
+    if p1.is_null() || p2.is_null() || p3.is_null() || inflight.is_null() || asyncs.is_null() {
         crate::error!("Virtio: Failed to allocate pages");
         return;
     }
@@ -150,6 +326,8 @@ pub unsafe fn init(dev: &PciDevice, allocator: &mut Allocator) {
 
     unsafe {
         crate::util::stosq(base_addr as *mut u64, 0, PG_SIZE * 3 / 8);
+        crate::util::stosq(inflight as *mut u64, 0, PG_SIZE / 8);
+        crate::util::stosq(asyncs as *mut u64, 0, PG_SIZE / 8);
     }
 
     let paddr_pages = v2p(base_addr as usize);
@@ -158,7 +336,6 @@ pub unsafe fn init(dev: &PciDevice, allocator: &mut Allocator) {
         base_addr,
         paddr_pages
     );
-    unsafe { outl(io_base + VIRTIO_REG_QUEUE_ADDR, (paddr_pages as u32) >> 12) };
 
     let desc_ptr = base_addr as *mut VRingDesc;
     let avail_ptr = unsafe { base_addr.add(4096) } as *mut VRingAvail;
@@ -168,22 +345,57 @@ pub unsafe fn init(dev: &PciDevice, allocator: &mut Allocator) {
         unsafe { (*desc_ptr.add(i)).next = (i + 1) as u16 };
     }
 
+    // 5. Give the device the queue, and Driver OK
+    let paddr = paddr_pages as u64;
+    let (desc, avail, used) = (paddr, paddr + PG_SIZE as u64, paddr + 2 * PG_SIZE as u64);
+    let size = QUEUE_SIZE as u16;
+    match &mut regs {
+        Regs::Modern(transport, notify) => {
+            *notify = transport.enable_queue(0, size, desc, avail, used);
+            transport.driver_ok();
+        }
+        Regs::Mmio(transport) => {
+            if transport.enable_queue(0, size, desc, avail, used).is_err() {
+                crate::error!("Virtio: device can't take the queue's layout");
+                return;
+            }
+            transport.driver_ok();
+        }
+        &mut Regs::Legacy(io_base) => {
+            unsafe { outl(io_base + VIRTIO_REG_QUEUE_ADDR, (paddr_pages as u32) >> 12) };
+            let status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER | VIRTIO_STATUS_DRIVER_OK;
+            unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, status) };
+        }
+    }
+    let kind = match regs {
+        Regs::Legacy(_) => "Legacy",
+        Regs::Modern(..) => "Modern",
+        Regs::Mmio(_) => "MMIO",
+    };
+
     let driver = VirtioDriver {
-        io_base,
+        regs,
         queue_desc: desc_ptr,
         queue_avail: avail_ptr,
         queue_used: used_ptr,
+        inflight: inflight as *mut Inflight,
+        asyncs: asyncs as *mut Async,
+        disk,
         free_head: 0,
+        nfree: QUEUE_SIZE,
         used_idx: 0,
         avail_idx: 0,
     };
 
-    // 5. Driver OK
-    status |= VIRTIO_STATUS_DRIVER_OK;
-    unsafe { outb(io_base + VIRTIO_REG_DEVICE_STATUS, status) };
-
     *guard = Some(driver);
-    crate::info!("Virtio-blk initialized (Legacy) QSize={}", QUEUE_SIZE);
+    IRQS[disk].store(irq, Ordering::Relaxed);
+    crate::info!(
+        "virtio{}: Virtio-blk initialized ({}) QSize={} IRQ={}",
+        disk,
+        kind,
+        QUEUE_SIZE,
+        irq
+    );
 }
 
 #[repr(C)]
@@ -193,18 +405,69 @@ struct VirtioBlkReq {
     sector: u64,
 }
 
-pub fn read_block(sector: u64, buf: &mut [u8]) {
-    do_block_io(sector, buf, false);
+// Returns Err if there is no such disk or the device reports an IO error.
+pub fn read_block(disk: usize, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+    do_block_io(disk, sector, buf, false)
 }
 
-pub fn write_block(sector: u64, buf: &[u8]) {
+pub fn write_block(disk: usize, sector: u64, buf: &[u8]) -> Result<(), ()> {
     // cast const buf to mut for common helper, but we won't write to it if write=true
     let mut_buf = unsafe { core::slice::from_raw_parts_mut(buf.as_ptr() as *mut u8, buf.len()) };
-    do_block_io(sector, mut_buf, true);
+    do_block_io(disk, sector, mut_buf, true)
 }
 
-fn do_block_io(sector: u64, buf: &mut [u8], write: bool) {
-    let mut guard = VIRTIO_BLK_DRIVER.lock();
+// Start reading buf from sector, or writing it there, and return without
+// waiting: state is set to IO_PENDING, and the interrupt sets it to IO_OK
+// or IO_FAILED and wakes chan. Err, with nothing started, if there is no
+// such disk or no room in its queue. The caller keeps buf and state where
+// they are until it is done.
+pub unsafe fn block_io_async(
+    disk: usize,
+    sector: u64,
+    buf: &mut [u8],
+    write: bool,
+    state: &AtomicU8,
+    chan: usize,
+) -> Result<(), ()> {
+    let mut guard = DISKS.get(disk).ok_or(())?.lock();
+    let driver = guard.as_mut().ok_or(())?;
+    if driver.nfree < 3 {
+        return Err(());
+    }
+    let slot = (0..NASYNC)
+        .find(|&i| (*driver.asyncs.add(i)).state == 0)
+        .ok_or(())?;
+    let a = &mut *driver.asyncs.add(slot);
+    *a = Async {
+        req: VirtioBlkReq {
+            type_: if write {
+                VIRTIO_BLK_T_OUT
+            } else {
+                VIRTIO_BLK_T_IN
+            },
+            reserved: 0,
+            sector,
+        },
+        status: 111,
+        state: state as *const AtomicU8 as usize,
+        chan,
+    };
+    state.store(IO_PENDING, Ordering::Relaxed);
+    let (req, status) = (addr_of!(a.req) as usize, addr_of!(a.status) as usize);
+    driver.submit(v2p(req), buf, write, v2p(status), slot as u16);
+    Ok(())
+}
+
+// Submit a request and sleep until the interrupt handler finds it done.
+// Any number are in flight at once, as many as there are descriptors for:
+// the driver's lock is only held to submit and to complete them, and is
+// let go of while waiting.
+fn do_block_io(disk: usize, sector: u64, buf: &mut [u8], write: bool) -> Result<(), ()> {
+    let lock = DISKS.get(disk).ok_or(())?;
+    let mut guard = lock.lock();
+    if guard.is_none() {
+        return Err(());
+    }
     let mut status_val: u8 = 111;
     let req = VirtioBlkReq {
         type_: if write {
@@ -216,143 +479,226 @@ fn do_block_io(sector: u64, buf: &mut [u8], write: bool) {
         sector,
     };
 
-    // 1. Submit Request
-    let head_idx = {
-        let driver = match guard.as_mut() {
-            Some(d) => d,
-            None => return,
-        };
+    // 1. Wait for descriptors: a request takes three
+    while guard.as_ref().unwrap().nfree < 3 {
+        if crate::proc::myproc().is_none() {
+            panic!("virtio: no descriptors");
+        }
+        crate::proc::sleep(desc_chan(disk), Some(guard));
+        guard = lock.lock();
+    }
 
-        let head_idx = driver.alloc_desc();
-        let data_idx = driver.alloc_desc();
-        let status_idx = driver.alloc_desc();
+    // 2. Submit Request
+    let head_idx = unsafe {
+        guard.as_mut().unwrap().submit(
+            v2p(&req as *const _ as usize),
+            buf,
+            write,
+            v2p(addr_of_mut!(status_val) as usize),
+            NO_SLOT,
+        )
+    };
 
-        let req_paddr = v2p(&req as *const _ as usize);
-        let buf_paddr = v2p(buf.as_ptr() as usize);
-        let status_paddr = v2p(&status_val as *const _ as usize);
+    // 3. Wait for completion. Looking at the used ring here too covers the
+    // boot, before there are processes or interrupts, and an interrupt
+    // that came before this request was submitted.
+    loop {
+        let driver = guard.as_mut().unwrap();
+        driver.complete();
+        let entry = unsafe { driver.inflight.add(head_idx as usize) };
+        if unsafe { (*entry).done } {
+            break;
+        }
+        if crate::proc::myproc().is_some() {
+            crate::proc::sleep(entry as usize, Some(guard));
+            guard = lock.lock();
+        } else {
+            drop(guard);
+            unsafe { core::arch::asm!("pause") };
+            guard = lock.lock();
+        }
+    }
 
-        let desc_ptr = driver.queue_desc;
+    // 4. Cleanup
+    guard.as_mut().unwrap().free_chain(head_idx);
+    drop(guard);
 
-        unsafe {
-            // Desc 1: Header
-            (*desc_ptr.add(head_idx as usize)).addr = req_paddr as u64;
-            (*desc_ptr.add(head_idx as usize)).len = size_of::<VirtioBlkReq>() as u32;
-            (*desc_ptr.add(head_idx as usize)).flags = 1; // NEXT
-            (*desc_ptr.add(head_idx as usize)).next = data_idx;
-
-            // Desc 2: Data
-            (*desc_ptr.add(data_idx as usize)).addr = buf_paddr as u64;
-            (*desc_ptr.add(data_idx as usize)).len = buf.len() as u32;
-            (*desc_ptr.add(data_idx as usize)).flags = 1; // NEXT
-            if !write {
-                (*desc_ptr.add(data_idx as usize)).flags |= 2; // WRITE
-            }
-            (*desc_ptr.add(data_idx as usize)).next = status_idx;
+    // The device writes the status byte behind the compiler's back.
+    let status = unsafe { core::ptr::read_volatile(addr_of!(status_val)) };
+    if status != VIRTIO_BLK_S_OK {
+        crate::error!(
+            "virtio{}: IO error sector={} status={}",
+            disk,
+            sector,
+            status
+        );
+        return Err(());
+    }
+    Ok(())
+}
 
-            // Desc 3: Status
-            (*desc_ptr.add(status_idx as usize)).addr = status_paddr as u64;
-            (*desc_ptr.add(status_idx as usize)).len = 1;
-            (*desc_ptr.add(status_idx as usize)).flags = 2; // WRITE
-            (*desc_ptr.add(status_idx as usize)).next = 0;
+impl VirtioDriver {
+    fn alloc_desc(&mut self) -> u16 {
+        let idx = self.free_head;
+        unsafe {
+            self.free_head = (*self.queue_desc.add(idx as usize)).next;
+        }
+        self.nfree -= 1;
+        idx
+    }
 
-            let avail = driver.queue_avail;
-            let idx = driver.avail_idx;
+    fn free_desc(&mut self, idx: u16) {
+        unsafe {
+            (*self.queue_desc.add(idx as usize)).next = self.free_head;
+            self.free_head = idx;
+        }
+        self.nfree += 1;
+    }
 
-            // 2. Update Avail Ring
-            // Use volatile write to ensure it happens before idx update
-            core::ptr::write_volatile(&mut (*avail).ring[idx as usize % QUEUE_SIZE], head_idx);
+    // Give the device a request: the header at paddr req, buf, and the
+    // status byte at paddr status. Three descriptors must be free. Returns
+    // the head of its chain.
+    unsafe fn submit(
+        &mut self,
+        req: usize,
+        buf: &[u8],
+        write: bool,
+        status: usize,
+        slot: u16,
+    ) -> u16 {
+        let head_idx = self.alloc_desc();
+        let data_idx = self.alloc_desc();
+        let status_idx = self.alloc_desc();
+        let depth = ((QUEUE_SIZE - self.nfree) / 3) as u32;
+        DEPTH[self.disk].store(depth, Ordering::Relaxed);
+        DEPTH_MAX[self.disk].fetch_max(depth, Ordering::Relaxed);
 
-            // Barrier to ensure ring update is visible before idx update
-            // (Processor barrier shouldn't be needed for TSO x86, but compiler barrier is essential)
-            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        let buf_paddr = v2p(buf.as_ptr() as usize);
 
-            // 3. Update Avail Idx
-            driver.avail_idx = idx.wrapping_add(1);
-            core::ptr::write_volatile(&mut (*avail).idx, driver.avail_idx);
+        let desc_ptr = self.queue_desc;
 
-            // Barrier to ensure idx update is visible before notify
-            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        // Desc 1: Header
+        (*desc_ptr.add(head_idx as usize)).addr = req as u64;
+        (*desc_ptr.add(head_idx as usize)).len = size_of::<VirtioBlkReq>() as u32;
+        (*desc_ptr.add(head_idx as usize)).flags = 1; // NEXT
+        (*desc_ptr.add(head_idx as usize)).next = data_idx;
 
-            outw(driver.io_base + VIRTIO_REG_QUEUE_NOTIFY, 0);
+        // Desc 2: Data
+        (*desc_ptr.add(data_idx as usize)).addr = buf_paddr as u64;
+        (*desc_ptr.add(data_idx as usize)).len = buf.len() as u32;
+        (*desc_ptr.add(data_idx as usize)).flags = 1; // NEXT
+        if !write {
+            (*desc_ptr.add(data_idx as usize)).flags |= 2; // WRITE
         }
+        (*desc_ptr.add(data_idx as usize)).next = status_idx;
+
+        // Desc 3: Status
+        (*desc_ptr.add(status_idx as usize)).addr = status as u64;
+        (*desc_ptr.add(status_idx as usize)).len = 1;
+        (*desc_ptr.add(status_idx as usize)).flags = 2; // WRITE
+        (*desc_ptr.add(status_idx as usize)).next = 0;
+
+        *self.inflight.add(head_idx as usize) = Inflight {
+            buf: buf.as_ptr() as usize,
+            done: false,
+            slot,
+        };
 
-        // crate::uart_println!("Virtio: submit sector={} head={}", sector, head_idx);
-
-        head_idx
-    };
-
-    // 2. Wait for completion
-    loop {
-        let driver = guard.as_mut().unwrap(); // Safe unwrap as checked above
+        let avail = self.queue_avail;
+        let idx = self.avail_idx;
 
-        let used = driver.queue_used;
-        let used_idx = unsafe { core::ptr::read_volatile(&(*used).idx) };
+        // Update Avail Ring
+        // Use volatile write to ensure it happens before idx update
+        core::ptr::write_volatile(&mut (*avail).ring[idx as usize % QUEUE_SIZE], head_idx);
 
-        // Ensure we read the index before reading the ring entry (load-load barrier)
+        // Barrier to ensure ring update is visible before idx update
+        // (Processor barrier shouldn't be needed for TSO x86, but compiler barrier is essential)
         core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
 
-        if driver.used_idx != used_idx {
-            let entry_idx = driver.used_idx as usize % QUEUE_SIZE;
-            let id = unsafe { (*used).ring[entry_idx].id };
+        // Update Avail Idx
+        self.avail_idx = idx.wrapping_add(1);
+        core::ptr::write_volatile(&mut (*avail).idx, self.avail_idx);
 
-            // crate::uart_println!(
-            //     "Virtio: check used_idx={} driver_used={} id={} head={}",
-            //     used_idx,
-            //     driver.used_idx,
-            //     id,
-            //     head_idx
-            // );
+        // Barrier to ensure idx update is visible before notify
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
 
-            if id as u16 == head_idx {
-                break;
-            }
-        }
+        self.regs.notify();
 
-        // Use yield to avoid lost wakeup race conditions
-        if crate::proc::mycpu().process.is_some() {
-            crate::proc::sleep(addr_of!(VIRTIO_BLK_DRIVER) as usize, Some(guard));
-            guard = VIRTIO_BLK_DRIVER.lock();
-        } else {
-            drop(guard);
-            unsafe { core::arch::asm!("pause") };
-            guard = VIRTIO_BLK_DRIVER.lock();
-        }
+        head_idx
     }
 
-    // 3. Cleanup
-    {
-        let driver = guard.as_mut().unwrap();
-        driver.used_idx = driver.used_idx.wrapping_add(1);
-
-        // Wake up others because used_idx changed, so the next pending request (if any)
-        // is now at the head of the driver's process queue.
-        crate::proc::wakeup(addr_of!(VIRTIO_BLK_DRIVER) as usize);
-
+    // Free the descriptors of the request at head, and wake whoever waits
+    // for them.
+    fn free_chain(&mut self, head: u16) {
         unsafe {
-            let desc_ptr = driver.queue_desc;
-            let data_idx = (*desc_ptr.add(head_idx as usize)).next;
+            (*self.inflight.add(head as usize)).buf = 0;
+
+            let desc_ptr = self.queue_desc;
+            let data_idx = (*desc_ptr.add(head as usize)).next;
             let status_idx = (*desc_ptr.add(data_idx as usize)).next;
 
-            driver.free_desc(head_idx);
-            driver.free_desc(data_idx);
-            driver.free_desc(status_idx);
+            self.free_desc(head);
+            self.free_desc(data_idx);
+            self.free_desc(status_idx);
         }
+        let depth = ((QUEUE_SIZE - self.nfree) / 3) as u32;
+        DEPTH[self.disk].store(depth, Ordering::Relaxed);
+        crate::proc::wakeup(desc_chan(self.disk));
     }
-}
 
-impl VirtioDriver {
-    fn alloc_desc(&mut self) -> u16 {
-        let idx = self.free_head;
-        unsafe {
-            self.free_head = (*self.queue_desc.add(idx as usize)).next;
+    // Finish a block_io_async whose chain starts at head.
+    fn finish_async(&mut self, head: u16, slot: u16) {
+        let a = unsafe { &mut *self.asyncs.add(slot as usize) };
+        // The device writes the status byte behind the compiler's back.
+        let status = unsafe { core::ptr::read_volatile(addr_of!(a.status)) };
+        if status != VIRTIO_BLK_S_OK {
+            crate::error!(
+                "virtio{}: IO error sector={} status={}",
+                self.disk,
+                a.req.sector,
+                status
+            );
         }
-        idx
+        let state = unsafe { &*(a.state as *const AtomicU8) };
+        let done = if status == VIRTIO_BLK_S_OK {
+            IO_OK
+        } else {
+            IO_FAILED
+        };
+        state.store(done, Ordering::Release);
+        crate::proc::wakeup(a.chan);
+        a.state = 0;
+        self.free_chain(head);
     }
 
-    fn free_desc(&mut self, idx: u16) {
-        unsafe {
-            (*self.queue_desc.add(idx as usize)).next = self.free_head;
-            self.free_head = idx;
+    // Mark the requests the device has finished since the last call done,
+    // and wake their owners. Their descriptors are freed by the owners, but
+    // for requests no one waits for, which are finished here.
+    fn complete(&mut self) {
+        let used = self.queue_used;
+        loop {
+            let used_idx = unsafe { core::ptr::read_volatile(&(*used).idx) };
+            if self.used_idx == used_idx {
+                break;
+            }
+            // Ensure we read the index before reading the ring entry (load-load barrier)
+            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+            let entry_idx = self.used_idx as usize % QUEUE_SIZE;
+            let head = unsafe { core::ptr::read_volatile(&(*used).ring[entry_idx].id) } as usize;
+            self.used_idx = self.used_idx.wrapping_add(1);
+
+            let entry = unsafe { &mut *self.inflight.add(head % QUEUE_SIZE) };
+            if head >= QUEUE_SIZE || entry.buf == 0 || entry.done {
+                crate::error!("Virtio: device finished unknown request {}", head);
+                continue;
+            }
+            if entry.slot != NO_SLOT {
+                let slot = entry.slot;
+                self.finish_async(head as u16, slot);
+                continue;
+            }
+            entry.done = true;
+            crate::proc::wakeup(entry as *mut Inflight as usize);
         }
     }
 }
@@ -0,0 +1,169 @@
+// virtio-console: hvc0, a console line that takes output a page at a time
+// where the 16550 takes it a byte at a time. Booted with console=hvc0, the
+// kernel log and the virtual consoles (see console::put) go out on it in
+// place of the serial line; input is taken from both.
+//
+// Port 0 only: VIRTIO_CONSOLE_F_MULTIPORT is not negotiated, so queue 0
+// receives and queue 1 transmits. Polled, like the other modern virtio
+// devices: what is written is copied to a page and waited for with run(),
+// and poll() passes what has been typed to console::consoleintr on every
+// timer tick.
+
+use crate::allocator::Allocator;
+use crate::pci::PciDevice;
+use crate::spinlock::Spinlock;
+use crate::util::{v2p, PG_SIZE};
+use crate::virtio_pci::{Queue, MODERN_DEVICE_ID, QUEUE_SIZE};
+use core::fmt;
+
+pub const DEVICE_ID: u16 = MODERN_DEVICE_ID + 3;
+// QEMU's virtio-serial-pci answers to the legacy ID too.
+pub const TRANSITIONAL_DEVICE_ID: u16 = 0x1003;
+
+// The receive buffers split a page between them.
+const RX_BUF: usize = PG_SIZE / QUEUE_SIZE as usize;
+
+struct Rx {
+    queue: Queue,
+    bufs: [usize; QUEUE_SIZE as usize], // Buffer posted at each descriptor
+    cur: Option<(usize, usize, usize)>, // Buffer being read: address, next byte, length
+}
+
+struct Tx {
+    queue: Queue,
+    buf: *mut u8, // A page the output is gathered in
+    len: usize,
+}
+
+static RX: Spinlock<Option<Rx>> = Spinlock::ranked(None, "HVC_RX", crate::lockorder::RANK_VIRTIO);
+static TX: Spinlock<Option<Tx>> = Spinlock::ranked(None, "HVC_TX", crate::lockorder::RANK_HVC_TX);
+
+impl Tx {
+    // Send what has been gathered.
+    fn flush(&mut self) {
+        if self.len > 0 {
+            let _ = self
+                .queue
+                .run(&[(v2p(self.buf as usize) as u64, self.len as u32, false)]);
+            self.len = 0;
+        }
+    }
+
+    fn put(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let m = bytes.len().min(PG_SIZE - self.len);
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.buf.add(self.len), m) };
+            self.len += m;
+            bytes = &bytes[m..];
+            if self.len == PG_SIZE {
+                self.flush();
+            }
+        }
+    }
+}
+
+impl fmt::Write for Tx {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes());
+        Ok(())
+    }
+}
+
+// Set up the device if it is to be the console, and switch to it.
+pub fn init(dev: &PciDevice, allocator: &mut Allocator) {
+    if crate::cmdline::get("console") != Some("hvc0") {
+        crate::info!("virtio-console: found; boot with console=hvc0 to use it");
+        return;
+    }
+    let Some(transport) = crate::virtio_pci::probe(dev) else {
+        crate::error!("virtio-console: no usable virtio registers");
+        return;
+    };
+    dev.disable_intx();
+    if transport.negotiate(0).is_err() {
+        crate::error!("virtio-console: feature negotiation failed");
+        return;
+    }
+    let (Some(mut rx), Some(tx)) = (
+        transport.setup_queue(0, allocator),
+        transport.setup_queue(1, allocator),
+    ) else {
+        crate::error!("virtio-console: no receive or transmit queue");
+        return;
+    };
+    let (rxbuf, txbuf) = (allocator.kalloc(), allocator.kalloc());
+    if rxbuf.is_null() || txbuf.is_null() {
+        crate::error!("virtio-console: out of memory");
+        return;
+    }
+    transport.driver_ok();
+
+    // The queue may be shorter than QUEUE_SIZE.
+    let mut bufs = [0; QUEUE_SIZE as usize];
+    for i in 0..QUEUE_SIZE as usize {
+        let buf = rxbuf as usize + i * RX_BUF;
+        if let Ok(head) = rx.post(&[(v2p(buf) as u64, RX_BUF as u32, true)]) {
+            bufs[head as usize] = buf;
+        }
+    }
+    *RX.lock() = Some(Rx {
+        queue: rx,
+        bufs,
+        cur: None,
+    });
+    // Said on the serial line, for whoever is still watching it.
+    crate::info!("virtio-console: switching the console to hvc0");
+    *TX.lock() = Some(Tx {
+        queue: tx,
+        buf: txbuf,
+        len: 0,
+    });
+}
+
+// Send bytes on hvc0. Err if it is not the console.
+pub fn write(bytes: &[u8]) -> Result<(), ()> {
+    let mut guard = TX.lock();
+    let tx = guard.as_mut().ok_or(())?;
+    tx.put(bytes);
+    tx.flush();
+    Ok(())
+}
+
+// Format args and send them on hvc0 all at once. Err if it is not the
+// console.
+pub fn print(args: fmt::Arguments) -> Result<(), ()> {
+    use core::fmt::Write;
+    let mut guard = TX.lock();
+    let tx = guard.as_mut().ok_or(())?;
+    let _ = tx.write_fmt(args);
+    tx.flush();
+    Ok(())
+}
+
+// The next byte typed on hvc0, giving each buffer back to the device once
+// it has been read.
+fn getc() -> Option<u8> {
+    let mut guard = RX.lock();
+    let rx = guard.as_mut()?;
+    loop {
+        if let Some((buf, pos, len)) = rx.cur {
+            if pos < len {
+                rx.cur = Some((buf, pos + 1, len));
+                return Some(unsafe { *((buf + pos) as *const u8) });
+            }
+            rx.cur = None;
+            if let Ok(head) = rx.queue.post(&[(v2p(buf) as u64, RX_BUF as u32, true)]) {
+                rx.bufs[head as usize] = buf;
+            }
+        }
+        let (head, len) = rx.queue.take()?;
+        rx.cur = Some((rx.bufs[head as usize], 0, (len as usize).min(RX_BUF)));
+    }
+}
+
+// Take in what has been typed, from the timer interrupt.
+pub fn poll() {
+    if RX.lock().is_some() {
+        crate::console::consoleintr(getc);
+    }
+}
@@ -0,0 +1,389 @@
+// virtio-gpu: a 2D framebuffer behind /dev/fb (see abi::fb).
+//
+// At boot the driver creates one host resource of WIDTH x HEIGHT pixels,
+// backs it with pages of its own and shows it on scanout 0. A write to
+// /dev/fb copies pixels into those pages, then has the host copy the rows
+// it touched into the resource (TRANSFER_TO_HOST_2D) and redraw them
+// (RESOURCE_FLUSH).
+
+use crate::allocator::Allocator;
+use crate::pci::PciDevice;
+use crate::spinlock::Spinlock;
+use crate::util::{v2p, PG_SIZE};
+use crate::virtio_pci::{Queue, MODERN_DEVICE_ID};
+use abi::fb::{FbInfo, FBIOGET_VSCREENINFO};
+use core::mem::size_of;
+
+pub const DEVICE_ID: u16 = MODERN_DEVICE_ID + 16;
+
+pub const WIDTH: usize = 640;
+pub const HEIGHT: usize = 480;
+const STRIDE: usize = WIDTH * 4;
+pub const FB_SIZE: usize = STRIDE * HEIGHT;
+const FB_PAGES: usize = FB_SIZE.div_ceil(PG_SIZE);
+
+// Control queue commands and responses
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const FORMAT_B8G8R8X8_UNORM: u32 = 2; // 0x00RRGGBB in a little-endian u32
+const RESOURCE_ID: u32 = 1;
+const MAX_SCANOUTS: usize = 16;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CtrlHdr {
+    typ: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    r: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct RespDisplayInfo {
+    hdr: CtrlHdr,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// Followed by nr_entries MemEntry
+#[repr(C)]
+struct ResourceAttachBacking {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+#[repr(C)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    hdr: CtrlHdr,
+    r: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    hdr: CtrlHdr,
+    r: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    hdr: CtrlHdr,
+    r: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+struct Gpu {
+    queue: Queue,
+    req: *mut u8,  // A page for the request being run
+    resp: *mut u8, // And one for its response
+}
+
+static GPU: Spinlock<Option<Gpu>> = Spinlock::ranked(None, "GPU", crate::lockorder::RANK_VIRTIO);
+
+// The framebuffer pages, in ascending address order. Set once by init,
+// before GPU, and never changed.
+static mut FB: [usize; FB_PAGES] = [0; FB_PAGES];
+
+fn hdr(typ: u32) -> CtrlHdr {
+    CtrlHdr {
+        typ,
+        ..Default::default()
+    }
+}
+
+fn whole() -> Rect {
+    Rect {
+        x: 0,
+        y: 0,
+        width: WIDTH as u32,
+        height: HEIGHT as u32,
+    }
+}
+
+impl Gpu {
+    // Run the request of len bytes in self.req; returns the response type.
+    fn run(&mut self, len: usize) -> Result<u32, ()> {
+        self.queue.run(&[
+            (v2p(self.req as usize) as u64, len as u32, false),
+            (v2p(self.resp as usize) as u64, PG_SIZE as u32, true),
+        ])?;
+        Ok(unsafe { (*(self.resp as *const CtrlHdr)).typ })
+    }
+
+    // Run req, which must get RESP_OK_NODATA back.
+    fn command<T>(&mut self, req: T) -> Result<(), ()> {
+        unsafe { (self.req as *mut T).write(req) };
+        match self.run(size_of::<T>())? {
+            RESP_OK_NODATA => Ok(()),
+            resp => {
+                crate::error!("virtio-gpu: command failed: {:x}", resp);
+                Err(())
+            }
+        }
+    }
+
+    // Show rows [y0, y1) of the framebuffer.
+    fn flush(&mut self, y0: usize, y1: usize) -> Result<(), ()> {
+        let r = Rect {
+            x: 0,
+            y: y0 as u32,
+            width: WIDTH as u32,
+            height: (y1 - y0) as u32,
+        };
+        self.command(TransferToHost2d {
+            hdr: hdr(CMD_TRANSFER_TO_HOST_2D),
+            r,
+            offset: (y0 * STRIDE) as u64,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })?;
+        self.command(ResourceFlush {
+            hdr: hdr(CMD_RESOURCE_FLUSH),
+            r,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })
+    }
+
+    // Back the resource with the FB pages: one entry per run of contiguous
+    // pages, which must all fit in the request page.
+    fn attach_backing(&mut self) -> Result<(), ()> {
+        #[allow(static_mut_refs)]
+        let fb = unsafe { &FB };
+        let max = (PG_SIZE - size_of::<ResourceAttachBacking>()) / size_of::<MemEntry>();
+        let entries = unsafe { self.req.add(size_of::<ResourceAttachBacking>()) } as *mut MemEntry;
+        let mut n = 0;
+        for (i, &page) in fb.iter().enumerate() {
+            if i > 0 && page == fb[i - 1] + PG_SIZE {
+                unsafe { (*entries.add(n - 1)).length += PG_SIZE as u32 };
+                continue;
+            }
+            if n == max {
+                crate::error!("virtio-gpu: framebuffer too scattered");
+                return Err(());
+            }
+            unsafe {
+                entries.add(n).write(MemEntry {
+                    addr: v2p(page) as u64,
+                    length: PG_SIZE as u32,
+                    padding: 0,
+                })
+            };
+            n += 1;
+        }
+        unsafe {
+            (self.req as *mut ResourceAttachBacking).write(ResourceAttachBacking {
+                hdr: hdr(CMD_RESOURCE_ATTACH_BACKING),
+                resource_id: RESOURCE_ID,
+                nr_entries: n as u32,
+            })
+        };
+        match self.run(size_of::<ResourceAttachBacking>() + n * size_of::<MemEntry>())? {
+            RESP_OK_NODATA => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    // Log the size of scanout 0, which is what the host window starts at.
+    fn display_info(&mut self) -> Result<(), ()> {
+        unsafe { (self.req as *mut CtrlHdr).write(hdr(CMD_GET_DISPLAY_INFO)) };
+        if self.run(size_of::<CtrlHdr>())? != RESP_OK_DISPLAY_INFO {
+            return Err(());
+        }
+        let info = unsafe { &*(self.resp as *const RespDisplayInfo) };
+        let mode = info.pmodes[0];
+        crate::info!(
+            "virtio-gpu: scanout 0 is {}x{}{}",
+            mode.r.width,
+            mode.r.height,
+            if mode.enabled != 0 { "" } else { " (disabled)" }
+        );
+        Ok(())
+    }
+}
+
+pub fn init(dev: &PciDevice, allocator: &mut Allocator) {
+    let Some(transport) = crate::virtio_pci::probe(dev) else {
+        crate::error!("virtio-gpu: no usable virtio registers");
+        return;
+    };
+    dev.disable_intx();
+    if transport.negotiate(0).is_err() {
+        crate::error!("virtio-gpu: feature negotiation failed");
+        return;
+    }
+    let Some(queue) = transport.setup_queue(0, allocator) else {
+        crate::error!("virtio-gpu: no control queue");
+        return;
+    };
+    transport.driver_ok();
+
+    let (req, resp) = (allocator.kalloc(), allocator.kalloc());
+    #[allow(static_mut_refs)]
+    let fb = unsafe { &mut FB };
+    for page in fb.iter_mut() {
+        *page = allocator.kalloc() as usize;
+    }
+    if req.is_null() || resp.is_null() || fb.contains(&0) {
+        crate::error!("virtio-gpu: out of memory");
+        return;
+    }
+    // kalloc hands out pages high to low: sorted, they make few runs.
+    fb.sort_unstable();
+
+    let mut gpu = Gpu { queue, req, resp };
+    let r = gpu
+        .display_info()
+        .and_then(|()| {
+            gpu.command(ResourceCreate2d {
+                hdr: hdr(CMD_RESOURCE_CREATE_2D),
+                resource_id: RESOURCE_ID,
+                format: FORMAT_B8G8R8X8_UNORM,
+                width: WIDTH as u32,
+                height: HEIGHT as u32,
+            })
+        })
+        .and_then(|()| gpu.attach_backing())
+        .and_then(|()| {
+            gpu.command(SetScanout {
+                hdr: hdr(CMD_SET_SCANOUT),
+                r: whole(),
+                scanout_id: 0,
+                resource_id: RESOURCE_ID,
+            })
+        })
+        .and_then(|()| gpu.flush(0, HEIGHT));
+    if r.is_err() {
+        crate::error!("virtio-gpu: setting up the framebuffer failed");
+        return;
+    }
+    *GPU.lock() = Some(gpu);
+    crate::info!("virtio-gpu: {}x{} framebuffer", WIDTH, HEIGHT);
+}
+
+pub fn present() -> bool {
+    GPU.lock().is_some()
+}
+
+// Copy n bytes between the framebuffer at off and user memory at addr, a
+// page at a time; returns how many. The user side is touched directly, so
+// that the kernel's fault handler pages it in, with no lock held.
+fn copy(off: u32, addr: u64, n: usize, to_fb: bool) -> usize {
+    #[allow(static_mut_refs)]
+    let fb = unsafe { &FB };
+    let off = off as usize;
+    let n = n.min(FB_SIZE.saturating_sub(off));
+    let mut done = 0;
+    while done < n {
+        let at = off + done;
+        let m = (n - done).min(PG_SIZE - at % PG_SIZE);
+        let page = (fb[at / PG_SIZE] + at % PG_SIZE) as *mut u8;
+        let user = (addr as usize + done) as *mut u8;
+        unsafe {
+            if to_fb {
+                core::ptr::copy_nonoverlapping(user, page, m);
+            } else {
+                core::ptr::copy_nonoverlapping(page, user, m);
+            }
+        }
+        done += m;
+    }
+    n
+}
+
+// write() on /dev/fb at off: draw, and show the rows drawn on.
+pub fn write(off: u32, src: u64, n: usize) -> isize {
+    if !present() {
+        return -1;
+    }
+    let n = copy(off, src, n, true);
+    if n == 0 {
+        return 0;
+    }
+    let y0 = off as usize / STRIDE;
+    let y1 = (off as usize + n - 1) / STRIDE + 1;
+    match GPU.lock().as_mut().unwrap().flush(y0, y1) {
+        Ok(()) => n as isize,
+        Err(()) => -1,
+    }
+}
+
+// read() on /dev/fb at off
+pub fn read(off: u32, dst: u64, n: usize) -> isize {
+    if !present() {
+        return -1;
+    }
+    copy(off, dst, n, false) as isize
+}
+
+// ioctl on /dev/fb: see abi::fb.
+pub fn ioctl(request: usize, arg: u64) -> isize {
+    if request != FBIOGET_VSCREENINFO {
+        return -abi::syscall::ENOTTY;
+    }
+    let info = FbInfo {
+        width: WIDTH as u32,
+        height: HEIGHT as u32,
+        stride: STRIDE as u32,
+        bpp: 32,
+    };
+    let pgdir = unsafe { (*crate::proc::myproc().unwrap()).pgdir };
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    if !crate::vm::copyout(
+        pgdir,
+        &mut allocator,
+        arg,
+        &info as *const FbInfo as *const u8,
+        size_of::<FbInfo>(),
+    ) {
+        return -1;
+    }
+    0
+}
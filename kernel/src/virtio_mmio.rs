@@ -0,0 +1,249 @@
+// virtio-mmio transport: a device's registers are a window of MMIO with no
+// bus to find it on, as on QEMU's microvm machine, which has no PCI. A
+// device is named on the kernel command line the way Linux takes it,
+// virtio_mmio.device=<size>@<base>:<irq> (e.g.
+// virtio_mmio.device=512@0xfeb00000:5), or else, on a machine without
+// PCI, looked for in the slots microvm puts them in.
+//
+// Both register layouts are driven: the legacy one (version 1), which
+// QEMU gives unless -global virtio-mmio.force-legacy=false, and virtio
+// 1.0's (version 2). Queues are laid out by the driver and handed over
+// with enable_queue, as over PCI. Only registers inside the device window
+// mapped at DEVBASE can be used.
+
+use crate::ioapic::MAX_IRQS;
+use crate::pci::DEVICE_WINDOW;
+use crate::util::{io2v, DEVSPACE, PG_SIZE};
+use crate::virtio_pci::{
+    STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED, STATUS_FEATURES_OK,
+    VIRTIO_F_VERSION_1,
+};
+use core::ptr::{read_volatile, write_volatile};
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+
+// Device IDs
+pub const DEVICE_BLK: u32 = 2;
+
+// Registers
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028; // Legacy only
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03C; // Legacy only
+const REG_QUEUE_PFN: usize = 0x040; // Legacy only
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080; // 64-bit addresses, low half first
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0A0;
+
+const LEGACY: u32 = 1;
+const MODERN: u32 = 2;
+
+// Where microvm puts its transports, and the IRQ of the first: each slot
+// after it has the next one.
+const MICROVM_BASE: u64 = 0xFEB0_0000;
+const MICROVM_SLOT: u64 = 0x200;
+const MICROVM_SLOTS: u64 = 8;
+const MICROVM_IRQ: u32 = 5;
+
+pub struct Transport {
+    base: usize, // Kernel address of the registers
+    version: u32,
+    pub irq: u32,
+}
+
+// The device of type device_id at base, if there is one.
+fn probe(base: u64, irq: u32, device_id: u32) -> Option<Transport> {
+    let window = DEVSPACE as u64..DEVSPACE as u64 + DEVICE_WINDOW;
+    if !window.contains(&base) || !window.contains(&(base + PG_SIZE as u64 - 1)) {
+        crate::error!("virtio-mmio: {:x} is outside the device window", base);
+        return None;
+    }
+    let t = Transport {
+        base: io2v(base as usize),
+        version: 0,
+        irq,
+    };
+    if t.read(REG_MAGIC) != MAGIC || t.read(REG_DEVICE_ID) != device_id {
+        return None;
+    }
+    let version = t.read(REG_VERSION);
+    if version != LEGACY && version != MODERN {
+        crate::error!("virtio-mmio: {:x} has version {}", base, version);
+        return None;
+    }
+    crate::info!(
+        "virtio-mmio: device {} at {:x} IRQ={} version {}",
+        device_id,
+        base,
+        irq,
+        version
+    );
+    Some(Transport { version, ..t })
+}
+
+// A number as the command line gives it: decimal, or hex after 0x.
+fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Find a device of type device_id.
+pub fn find(device_id: u32) -> Option<Transport> {
+    if let Some(spec) = crate::cmdline::get("virtio_mmio.device") {
+        // <size>@<base>:<irq>; the size is the page the registers are in.
+        let place = spec
+            .split_once('@')
+            .and_then(|(_, place)| place.split_once(':'));
+        let irq = |i: &str| {
+            i.parse()
+                .ok()
+                .filter(|&irq: &u32| (irq as usize) < MAX_IRQS)
+        };
+        let Some((base, Some(irq))) = place.map(|(b, i)| (parse_num(b), irq(i))) else {
+            crate::error!("virtio-mmio: bad virtio_mmio.device={}", spec);
+            return None;
+        };
+        return base.and_then(|base| probe(base, irq, device_id));
+    }
+    if unsafe { crate::pci::check_device(0, 0) }.is_some() {
+        return None;
+    }
+    (0..MICROVM_SLOTS).find_map(|i| {
+        probe(
+            MICROVM_BASE + i * MICROVM_SLOT,
+            MICROVM_IRQ + i as u32,
+            device_id,
+        )
+    })
+}
+
+impl Transport {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, v: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, v) }
+    }
+
+    fn write_addr(&self, reg: usize, addr: u64) {
+        self.write(reg, addr as u32);
+        self.write(reg + 4, (addr >> 32) as u32);
+    }
+
+    // Reset the device and agree on the features in want that it offers.
+    // A modern device must offer VIRTIO_F_VERSION_1, which is taken too.
+    // Returns those agreed on.
+    pub fn negotiate(&self, want: u64) -> Result<u64, ()> {
+        self.write(REG_STATUS, 0);
+        while self.read(REG_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+        let mut status = (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u32;
+        self.write(REG_STATUS, status);
+
+        self.write(REG_DEVICE_FEATURES_SEL, 0);
+        let lo = self.read(REG_DEVICE_FEATURES);
+        self.write(REG_DEVICE_FEATURES_SEL, 1);
+        let hi = self.read(REG_DEVICE_FEATURES);
+        let offered = (hi as u64) << 32 | lo as u64;
+        let features = if self.version == LEGACY {
+            offered & want & 0xFFFF_FFFF
+        } else {
+            offered & (want | VIRTIO_F_VERSION_1)
+        };
+        if self.version == MODERN && features & VIRTIO_F_VERSION_1 == 0 {
+            self.write(REG_STATUS, STATUS_FAILED as u32);
+            return Err(());
+        }
+        self.write(REG_DRIVER_FEATURES_SEL, 0);
+        self.write(REG_DRIVER_FEATURES, features as u32);
+        self.write(REG_DRIVER_FEATURES_SEL, 1);
+        self.write(REG_DRIVER_FEATURES, (features >> 32) as u32);
+
+        if self.version == LEGACY {
+            self.write(REG_GUEST_PAGE_SIZE, PG_SIZE as u32);
+            return Ok(features);
+        }
+        status |= STATUS_FEATURES_OK as u32;
+        self.write(REG_STATUS, status);
+        if self.read(REG_STATUS) & STATUS_FEATURES_OK as u32 == 0 {
+            self.write(REG_STATUS, STATUS_FAILED as u32);
+            return Err(());
+        }
+        Ok(features)
+    }
+
+    // The most descriptors queue index can have; 0 if there is no such
+    // queue.
+    pub fn queue_size(&self, index: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, index as u32);
+        self.read(REG_QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
+
+    // Give queue index size descriptors and the rings at the physical
+    // addresses desc, driver (available) and device (used), and turn it
+    // on. A legacy device takes the three as one: the available ring
+    // right after the descriptors, and the used ring at the next page.
+    pub fn enable_queue(
+        &self,
+        index: u16,
+        size: u16,
+        desc: u64,
+        driver: u64,
+        device: u64,
+    ) -> Result<(), ()> {
+        self.write(REG_QUEUE_SEL, index as u32);
+        self.write(REG_QUEUE_NUM, size as u32);
+        if self.version == LEGACY {
+            let page = PG_SIZE as u64;
+            let avail_end = driver + 6 + 2 * size as u64;
+            if !desc.is_multiple_of(page)
+                || driver != desc + 16 * size as u64
+                || device != avail_end.next_multiple_of(page)
+            {
+                return Err(());
+            }
+            self.write(REG_QUEUE_ALIGN, PG_SIZE as u32);
+            self.write(REG_QUEUE_PFN, (desc / page) as u32);
+            return Ok(());
+        }
+        self.write_addr(REG_QUEUE_DESC, desc);
+        self.write_addr(REG_QUEUE_DRIVER, driver);
+        self.write_addr(REG_QUEUE_DEVICE, device);
+        self.write(REG_QUEUE_READY, 1);
+        Ok(())
+    }
+
+    pub fn notify(&self, index: u16) {
+        self.write(REG_QUEUE_NOTIFY, index as u32);
+    }
+
+    // Read and acknowledge the interrupt status: bit 0 is set for used
+    // buffers, bit 1 for a configuration change.
+    pub fn isr(&self) -> u8 {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        self.write(REG_INTERRUPT_ACK, status);
+        status as u8
+    }
+
+    pub fn driver_ok(&self) {
+        let status = self.read(REG_STATUS);
+        self.write(REG_STATUS, status | STATUS_DRIVER_OK as u32);
+    }
+}
@@ -0,0 +1,123 @@
+// virtio-net: the network interface under net/. Polled, like the other
+// modern virtio devices: net::poll takes what has been received on every
+// timer tick.
+//
+// Every frame is preceded by a virtio_net_hdr, all zeros going out: no
+// checksum or segmentation offload is negotiated. The receive and transmit
+// sides have a lock each, so that a frame being received can be answered.
+
+use crate::net::eth::FRAME_MAX;
+use crate::pci::PciDevice;
+use crate::spinlock::Spinlock;
+use crate::util::{v2p, PG_SIZE};
+use crate::virtio_pci::{Queue, MODERN_DEVICE_ID, QUEUE_SIZE};
+
+pub const DEVICE_ID: u16 = MODERN_DEVICE_ID + 1;
+// QEMU's virtio-net-pci answers to the legacy ID too, and has the modern
+// registers as well.
+pub const TRANSITIONAL_DEVICE_ID: u16 = 0x1000;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const HDR_LEN: usize = 12; // struct virtio_net_hdr, with num_buffers
+
+struct Rx {
+    queue: Queue,
+    bufs: [usize; QUEUE_SIZE as usize], // Page posted at each descriptor
+}
+
+struct Tx {
+    queue: Queue,
+    buf: *mut u8, // A page the frame being sent is built in
+}
+
+static RX: Spinlock<Option<Rx>> = Spinlock::ranked(None, "NIC_RX", crate::lockorder::RANK_NIC_RX);
+static TX: Spinlock<Option<Tx>> = Spinlock::ranked(None, "NIC_TX", crate::lockorder::RANK_NIC_TX);
+
+// Set up the device and fill its receive queue. Returns its MAC address.
+pub fn init(dev: &PciDevice) -> Option<[u8; 6]> {
+    let Some(transport) = crate::virtio_pci::probe(dev) else {
+        crate::error!("virtio-net: no usable virtio registers");
+        return None;
+    };
+    dev.disable_intx();
+    let Ok(features) = transport.negotiate(VIRTIO_NET_F_MAC) else {
+        crate::error!("virtio-net: feature negotiation failed");
+        return None;
+    };
+    let mac = if features & VIRTIO_NET_F_MAC != 0 && transport.device != 0 {
+        core::array::from_fn(|i| unsafe {
+            core::ptr::read_volatile((transport.device + i) as *const u8)
+        })
+    } else {
+        [0x52, 0x54, 0x00, 0x12, 0x34, 0x56] // QEMU's default
+    };
+
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    let (Some(mut rx), Some(tx)) = (
+        transport.setup_queue(0, &mut allocator),
+        transport.setup_queue(1, &mut allocator),
+    ) else {
+        crate::error!("virtio-net: no receive or transmit queue");
+        return None;
+    };
+    let txbuf = allocator.kalloc();
+    let pages: [*mut u8; QUEUE_SIZE as usize] = core::array::from_fn(|_| allocator.kalloc());
+    drop(allocator);
+    if txbuf.is_null() || pages.iter().any(|p| p.is_null()) {
+        crate::error!("virtio-net: out of memory");
+        return None;
+    }
+    transport.driver_ok();
+
+    // The queue may be shorter than QUEUE_SIZE.
+    let mut bufs = [0; QUEUE_SIZE as usize];
+    for page in pages {
+        match rx.post(&[(v2p(page as usize) as u64, PG_SIZE as u32, true)]) {
+            Ok(head) => bufs[head as usize] = page as usize,
+            Err(()) => crate::allocator::ALLOCATOR.lock().kfree(page as usize),
+        }
+    }
+    *RX.lock() = Some(Rx { queue: rx, bufs });
+    *TX.lock() = Some(Tx {
+        queue: tx,
+        buf: txbuf,
+    });
+    Some(mac)
+}
+
+// Send a frame built by fill, which gets room for FRAME_MAX bytes and
+// returns the length of the frame.
+pub fn transmit(fill: impl FnOnce(&mut [u8]) -> usize) -> Result<(), ()> {
+    let mut guard = TX.lock();
+    let tx = guard.as_mut().ok_or(())?;
+    let buf = unsafe { core::slice::from_raw_parts_mut(tx.buf, HDR_LEN + FRAME_MAX) };
+    buf[..HDR_LEN].fill(0);
+    let len = fill(&mut buf[HDR_LEN..]);
+    tx.queue
+        .run(&[(v2p(tx.buf as usize) as u64, (HDR_LEN + len) as u32, false)])
+        .map(|_| ())
+}
+
+// Pass each frame received since the last call to f, and give its buffer
+// back to the device.
+pub fn receive(mut f: impl FnMut(&[u8])) {
+    let mut guard = RX.lock();
+    let Some(rx) = guard.as_mut() else {
+        return;
+    };
+    while let Some((head, len)) = rx.queue.take() {
+        let page = rx.bufs[head as usize];
+        let len = (len as usize).min(PG_SIZE);
+        if len > HDR_LEN {
+            f(
+                unsafe {
+                    core::slice::from_raw_parts((page + HDR_LEN) as *const u8, len - HDR_LEN)
+                },
+            );
+        }
+        if let Ok(head) = rx.queue.post(&[(v2p(page) as u64, PG_SIZE as u32, true)]) {
+            rx.bufs[head as usize] = page;
+        }
+    }
+}
@@ -0,0 +1,351 @@
+// Modern (virtio 1.0) PCI transport. The device's registers are in memory
+// BARs, found through vendor capabilities in its PCI config space: common
+// configuration (features, status, queue setup), notification, ISR status
+// and device-specific configuration.
+//
+// Queues made by setup_queue are small and polled, with the device's
+// interrupts turned off (see PciDevice::disable_intx): drivers check for
+// finished requests with take(), or wait for the one request they submit
+// with run(). A driver that takes interrupts, virtio-blk's, lays out its
+// own rings and hands them to the device with enable_queue. Only BARs
+// inside the device window mapped at DEVBASE can be used (see
+// PciDevice::map_bar).
+
+use crate::allocator::Allocator;
+use crate::pci::PciDevice;
+use crate::util::v2p;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// The PCI device ID of virtio device type t is MODERN_DEVICE_ID + t.
+pub const MODERN_DEVICE_ID: u16 = 0x1040;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const PCI_STATUS_CAP_LIST: u32 = 1 << 20; // In the dword at 0x04
+const PCI_CAP_PTR: u8 = 0x34;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// virtio_pci_cap.cfg_type
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Status Bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+// Descriptors per queue, or fewer if the device has fewer
+pub const QUEUE_SIZE: u16 = 16;
+
+#[repr(C)]
+struct CommonCfg {
+    device_feature_select: u32,
+    device_feature: u32,
+    driver_feature_select: u32,
+    driver_feature: u32,
+    msix_config: u16,
+    num_queues: u16,
+    device_status: u8,
+    config_generation: u8,
+    queue_select: u16,
+    queue_size: u16,
+    queue_msix_vector: u16,
+    queue_enable: u16,
+    queue_notify_off: u16,
+    queue_desc: [u32; 2], // 64-bit addresses, written a half at a time
+    queue_driver: [u32; 2],
+    queue_device: [u32; 2],
+}
+
+const _: () = assert!(core::mem::offset_of!(CommonCfg, queue_desc) == 32);
+
+#[repr(C)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+pub struct Transport {
+    common: *mut CommonCfg,
+    notify: usize,   // Notification area
+    notify_mul: u32, // Bytes between the notification addresses of queues
+    isr: *const u8,
+    pub device: usize, // Device-specific configuration, or 0 if none
+}
+
+pub struct Queue {
+    desc: *mut Desc,
+    avail: *mut u16, // flags, idx, ring[size]
+    used: *mut u8,   // flags: u16, idx: u16, ring[size] of (id: u32, len: u32)
+    size: u16,
+    free_head: u16, // Free descriptors, linked through next
+    nfree: u16,
+    avail_idx: u16,
+    used_idx: u16,
+    index: u16,
+    notify: *mut u16,
+    isr: *const u8,
+}
+
+// Read and write fields of the common configuration
+macro_rules! rd {
+    ($t:expr, $f:ident) => {
+        unsafe { read_volatile(addr_of!((*$t.common).$f)) }
+    };
+}
+macro_rules! wr {
+    ($t:expr, $f:ident, $v:expr) => {
+        unsafe { write_volatile(addr_of_mut!((*$t.common).$f), $v) }
+    };
+}
+
+unsafe fn write_addr(field: *mut [u32; 2], addr: u64) {
+    let field = field as *mut u32;
+    unsafe {
+        write_volatile(field, addr as u32);
+        write_volatile(field.add(1), (addr >> 32) as u32);
+    }
+}
+
+// Find the registers of a modern virtio device, or of the modern half of
+// a transitional one.
+pub fn probe(dev: &PciDevice) -> Option<Transport> {
+    if dev.read(0x04) & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_mul = 0;
+    let mut cap = dev.read(PCI_CAP_PTR) as u8 & 0xFC;
+    while cap != 0 {
+        let head = dev.read(cap);
+        if head as u8 == PCI_CAP_ID_VNDR {
+            let bar = dev.read(cap + 4) as u8;
+            let off = dev.read(cap + 8);
+            // The first capability of each type is the one to use
+            match (head >> 24) as u8 {
+                CAP_COMMON_CFG if common.is_none() => common = dev.map_bar(bar, off),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = dev.map_bar(bar, off);
+                    notify_mul = dev.read(cap + 16);
+                }
+                CAP_ISR_CFG if isr.is_none() => isr = dev.map_bar(bar, off),
+                CAP_DEVICE_CFG if device.is_none() => device = dev.map_bar(bar, off),
+                _ => {}
+            }
+        }
+        cap = (head >> 8) as u8 & 0xFC;
+    }
+    Some(Transport {
+        common: common? as *mut CommonCfg,
+        notify: notify?,
+        notify_mul,
+        isr: isr? as *const u8,
+        device: device.unwrap_or(0),
+    })
+}
+
+impl Transport {
+    // Reset the device and agree on the features in want that it offers,
+    // plus VIRTIO_F_VERSION_1, which it must. Returns those agreed on.
+    pub fn negotiate(&self, want: u64) -> Result<u64, ()> {
+        wr!(self, device_status, 0);
+        while rd!(self, device_status) != 0 {
+            core::hint::spin_loop();
+        }
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        wr!(self, device_status, status);
+
+        wr!(self, device_feature_select, 0);
+        let lo = rd!(self, device_feature);
+        wr!(self, device_feature_select, 1);
+        let hi = rd!(self, device_feature);
+        let features = ((hi as u64) << 32 | lo as u64) & (want | VIRTIO_F_VERSION_1);
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            wr!(self, device_status, STATUS_FAILED);
+            return Err(());
+        }
+        wr!(self, driver_feature_select, 0);
+        wr!(self, driver_feature, features as u32);
+        wr!(self, driver_feature_select, 1);
+        wr!(self, driver_feature, (features >> 32) as u32);
+
+        status |= STATUS_FEATURES_OK;
+        wr!(self, device_status, status);
+        if rd!(self, device_status) & STATUS_FEATURES_OK == 0 {
+            wr!(self, device_status, STATUS_FAILED);
+            return Err(());
+        }
+        Ok(features)
+    }
+
+    // The most descriptors queue index can have; 0 if there is no such
+    // queue.
+    pub fn queue_size(&self, index: u16) -> u16 {
+        wr!(self, queue_select, index);
+        rd!(self, queue_size)
+    }
+
+    // Give queue index size descriptors and the rings at the physical
+    // addresses desc, driver (available) and device (used), and turn it
+    // on. Returns where to write index to notify the device of new
+    // requests.
+    pub fn enable_queue(
+        &self,
+        index: u16,
+        size: u16,
+        desc: u64,
+        driver: u64,
+        device: u64,
+    ) -> *mut u16 {
+        wr!(self, queue_select, index);
+        wr!(self, queue_size, size);
+        unsafe {
+            write_addr(addr_of_mut!((*self.common).queue_desc), desc);
+            write_addr(addr_of_mut!((*self.common).queue_driver), driver);
+            write_addr(addr_of_mut!((*self.common).queue_device), device);
+        }
+        let notify_off = rd!(self, queue_notify_off) as usize;
+        wr!(self, queue_enable, 1);
+        (self.notify + notify_off * self.notify_mul as usize) as *mut u16
+    }
+
+    // Read the ISR status, which acknowledges the interrupt: bit 0 is set
+    // for used buffers, bit 1 for a configuration change.
+    pub fn isr(&self) -> u8 {
+        unsafe { read_volatile(self.isr) }
+    }
+
+    // Set up queue index, between negotiate() and driver_ok().
+    pub fn setup_queue(&self, index: u16, allocator: &mut Allocator) -> Option<Queue> {
+        let size = self.queue_size(index).min(QUEUE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        // A page each for the descriptors, the available and the used ring
+        let pages = [allocator.kalloc(), allocator.kalloc(), allocator.kalloc()];
+        if pages.iter().any(|p| p.is_null()) {
+            for p in pages.into_iter().filter(|p| !p.is_null()) {
+                allocator.kfree(p as usize);
+            }
+            return None;
+        }
+        let addrs = pages.map(|p| v2p(p as usize) as u64);
+        let notify = self.enable_queue(index, size, addrs[0], addrs[1], addrs[2]);
+
+        let desc = pages[0] as *mut Desc;
+        for i in 0..size {
+            unsafe { (*desc.add(i as usize)).next = i + 1 };
+        }
+        let avail = pages[1] as *mut u16;
+        unsafe { write_volatile(avail, AVAIL_F_NO_INTERRUPT) };
+        Some(Queue {
+            desc,
+            avail,
+            used: pages[2],
+            size,
+            free_head: 0,
+            nfree: size,
+            avail_idx: 0,
+            used_idx: 0,
+            index,
+            notify,
+            isr: self.isr,
+        })
+    }
+
+    pub fn driver_ok(&self) {
+        let status = rd!(self, device_status);
+        wr!(self, device_status, status | STATUS_DRIVER_OK);
+    }
+}
+
+impl Queue {
+    // Hand the device a request made of bufs, (physical address, length,
+    // written by the device) each, chained in order. Returns the head of the
+    // chain, which take() gives back once the device is done with it.
+    pub fn post(&mut self, bufs: &[(u64, u32, bool)]) -> Result<u16, ()> {
+        if bufs.is_empty() || bufs.len() > self.nfree as usize {
+            return Err(());
+        }
+        let head = self.free_head;
+        let mut i = head;
+        for (n, &(addr, len, write)) in bufs.iter().enumerate() {
+            let d = unsafe { &mut *self.desc.add(i as usize) };
+            d.addr = addr;
+            d.len = len;
+            d.flags = if write { DESC_F_WRITE } else { 0 };
+            if n + 1 < bufs.len() {
+                d.flags |= DESC_F_NEXT;
+            }
+            self.free_head = d.next;
+            i = d.next;
+        }
+        self.nfree -= bufs.len() as u16;
+        unsafe {
+            let slot = 2 + (self.avail_idx % self.size) as usize;
+            write_volatile(self.avail.add(slot), head);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail.add(1), self.avail_idx);
+            fence(Ordering::SeqCst);
+            write_volatile(self.notify, self.index);
+        }
+        Ok(head)
+    }
+
+    // The next request the device is done with: the head of its chain and
+    // the number of bytes written. Its descriptors are free again.
+    pub fn take(&mut self) -> Option<(u16, u32)> {
+        let used_idx = self.used.wrapping_add(2) as *const u16;
+        if unsafe { read_volatile(used_idx) } == self.used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = 4 + 8 * (self.used_idx % self.size) as usize;
+        let (head, written) = unsafe {
+            (
+                read_volatile(self.used.add(elem) as *const u32) as u16,
+                read_volatile(self.used.add(elem + 4) as *const u32),
+            )
+        };
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // Reading the ISR status acknowledges whatever the device signalled.
+        let _ = unsafe { read_volatile(self.isr) };
+
+        let mut i = head;
+        loop {
+            let d = unsafe { &mut *self.desc.add(i as usize) };
+            self.nfree += 1;
+            if d.flags & DESC_F_NEXT == 0 {
+                d.next = self.free_head;
+                break;
+            }
+            i = d.next;
+        }
+        self.free_head = head;
+        Some((head, written))
+    }
+
+    // Run one request and wait for the device to finish it; returns the
+    // number of bytes it wrote. Nothing else may be in flight.
+    pub fn run(&mut self, bufs: &[(u64, u32, bool)]) -> Result<u32, ()> {
+        self.post(bufs)?;
+        loop {
+            if let Some((_, written)) = self.take() {
+                return Ok(written);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use crate::allocator::Allocator;
 
-use crate::util::{p2v, v2p, PG_SIZE};
+use crate::proc::NCPU;
+use crate::util::{p2v, v2p, IRQ_TLB, PG_SIZE, T_IRQ0};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 static mut KPGDIR: *mut PageTable = core::ptr::null_mut();
 
@@ -82,18 +84,134 @@ pub fn uvm_create(allocator: &mut Allocator) -> Option<*mut PageTable> {
 
     // Only map high memory
     if !map_highmem(pgdir, allocator) {
+        uvm_free(pgdir, allocator);
         return None;
     }
 
     Some(pgdir)
 }
 
+// Free a page table made by uvm_create: the user pages mapped in it, then
+// every table under it and the PML4 itself. The kernel's mappings above are
+// shared, so only the tables holding them go. pgdir must not be in use.
+pub fn uvm_free(pgdir: *mut PageTable, allocator: &mut Allocator) {
+    free_table(pgdir, 3, allocator);
+}
+
+fn free_table(table: *mut PageTable, level: u8, allocator: &mut Allocator) {
+    for i in 0..512 {
+        let pte = unsafe { (*table).entries[i] };
+        if !pte.is_present() {
+            continue;
+        }
+        let pa = p2v(pte.addr() as usize);
+        if level > 0 && pte.flags() & PageTableEntry::HUGE_PAGE == 0 {
+            free_table(pa as *mut PageTable, level - 1, allocator);
+        } else if level == 0 && pte.flags() & PageTableEntry::USER != 0 {
+            allocator.kfree(pa);
+        }
+    }
+    allocator.kfree(table as usize);
+}
+
 pub fn switch(pgdir: *mut PageTable) {
     unsafe {
         core::arch::asm!("mov cr3, {}", in(reg) v2p(pgdir as usize));
     }
 }
 
+// TLB shootdown. A CPU keeps cached translations of an address space while
+// it runs it, so removing or narrowing a user mapping must flush the TLB of
+// every CPU running pgdir, not just this one. flush_tlb sets the TLB_FLUSH
+// flag of each other such CPU, indexed by LAPIC ID, and sends it
+// T_IRQ0 + IRQ_TLB; the handler clears the flag and then reloads cr3, and
+// flush_tlb returns once all the flags are clear.
+//
+// The wait happens with interrupts off, as syscalls run, so a CPU waiting
+// also does what its own flag asks: two CPUs flushing at once would
+// otherwise each wait for the other. No spinlock may be held, since a target
+// may be spinning for it with interrupts off.
+static TLB_FLUSH: [AtomicBool; NCPU] = [const { AtomicBool::new(false) }; NCPU];
+
+pub fn flush_tlb(pgdir: *mut PageTable) {
+    crate::spinlock::push_cli();
+    let cpu = crate::proc::mycpu();
+    if cpu.ncli != 1 {
+        panic!("flush_tlb: holding a spinlock");
+    }
+    let me = cpu.lapicid;
+    if crate::proc::myproc().is_some_and(|p| unsafe { (*p).pgdir } == pgdir) {
+        switch(pgdir);
+    }
+    let mut sent = [false; NCPU];
+    unsafe {
+        #[allow(static_mut_refs)]
+        for other in crate::proc::CPUS.iter() {
+            let id = other.lapicid as usize;
+            let running = core::ptr::read_volatile(&other.process);
+            if other.lapicid == me
+                || !core::ptr::read_volatile(&other.started)
+                || running.is_null()
+                || core::ptr::read_volatile(&(*running).pgdir) != pgdir
+            {
+                continue;
+            }
+            TLB_FLUSH[id].store(true, Ordering::SeqCst);
+            crate::lapic::send_ipi(other.lapicid, T_IRQ0 + IRQ_TLB);
+            sent[id] = true;
+        }
+    }
+    for (id, _) in sent.iter().enumerate().filter(|(_, &s)| s) {
+        while TLB_FLUSH[id].load(Ordering::SeqCst) {
+            tlb_interrupt();
+            core::hint::spin_loop();
+        }
+    }
+    crate::spinlock::pop_cli();
+}
+
+// Unmap the user pages in [lo, hi) of pgdir, which may be live on other
+// CPUs: they keep their frames, marked UNMAPPED, until flush_unmapped.
+pub fn uvm_unmap(pgdir: *mut PageTable, allocator: &mut Allocator, lo: u64, hi: u64) {
+    let mut va = pgrounddown(lo);
+    while va < hi {
+        if let Some(pte) = walk(pgdir, allocator, va, false, 0).filter(|pte| pte.is_present()) {
+            *pte = PageTableEntry::new(pte.addr(), PageTableEntry::UNMAPPED);
+        }
+        va += PG_SIZE as u64;
+    }
+}
+
+// Flush the TLBs running pgdir, then free the frames uvm_unmap left in
+// [lo, hi): no CPU can reach them any more.
+pub fn flush_unmapped(pgdir: *mut PageTable, lo: u64, hi: u64) {
+    flush_tlb(pgdir);
+    let mut allocator = crate::allocator::ALLOCATOR.lock();
+    let mut va = pgrounddown(lo);
+    while va < hi {
+        if let Some(pte) = walk(pgdir, &mut allocator, va, false, 0)
+            .filter(|pte| pte.flags() & PageTableEntry::UNMAPPED != 0)
+        {
+            allocator.kfree(p2v(pte.addr() as usize));
+            *pte = PageTableEntry::new(0, 0);
+        }
+        va += PG_SIZE as u64;
+    }
+}
+
+// T_IRQ0 + IRQ_TLB: flush this CPU's TLB if another CPU asked.
+pub fn tlb_interrupt() {
+    let id = crate::lapic::id() as usize;
+    if TLB_FLUSH[id].swap(false, Ordering::SeqCst) {
+        // Reloading cr3 drops all the non-global entries: every user one.
+        unsafe {
+            let cr3: u64;
+            core::arch::asm!("mov {}, cr3", out(reg) cr3);
+            core::arch::asm!("mov cr3, {}", in(reg) cr3);
+        }
+    }
+}
+
 pub fn map_pages(
     pgdir: *mut PageTable,
     allocator: &mut Allocator,
@@ -183,6 +301,11 @@ pub fn walk(
     unsafe { Some(&mut (*table).entries[idx as usize]) }
 }
 
+// Whether the page holding va is mapped.
+pub fn is_mapped(pgdir: *mut PageTable, allocator: &mut Allocator, va: u64) -> bool {
+    walk(pgdir, allocator, va, false, 0).is_some_and(|pte| pte.is_present())
+}
+
 #[repr(C, align(4096))]
 pub struct PageTable {
     pub entries: [PageTableEntry; 512],
@@ -206,6 +329,8 @@ impl PageTableEntry {
     pub const DIRTY: u64 = 1 << 6;
     pub const HUGE_PAGE: u64 = 1 << 7;
     pub const GLOBAL: u64 = 1 << 8;
+    pub const SHARED: u64 = 1 << 9; // Ignored by the MMU: fork shares the page
+    pub const UNMAPPED: u64 = 1 << 10; // Not present: free after a TLB flush
     pub const NO_EXECUTE: u64 = 1 << 63;
 
     pub fn new(addr: u64, flags: u64) -> Self {
@@ -225,41 +350,91 @@ impl PageTableEntry {
     }
 }
 
+// Copy every present user page in the lower half of old_pgdir into
+// new_pgdir, wherever it lies: text, heap, stack or anything else mapped.
+// A SHARED page is mapped in both instead, with another reference.
 pub fn uvm_copy(
     old_pgdir: *mut PageTable,
     new_pgdir: *mut PageTable,
-    sz: u64,
     allocator: &mut Allocator,
 ) -> bool {
-    let mut i = 0;
-    while i < sz {
-        let pte = walk(old_pgdir, allocator, i, false, 0);
-        if let Some(pte) = pte {
-            if pte.is_present() {
-                let pa = pte.addr();
-                let flags = pte.flags();
+    for_each_user_page(old_pgdir, &mut |va, pte| {
+        if pte.flags() & PageTableEntry::SHARED != 0 {
+            let mapped = map_pages(
+                new_pgdir,
+                allocator,
+                va,
+                pte.addr(),
+                PG_SIZE as u64,
+                pte.flags(),
+            );
+            if mapped {
+                allocator.incref(p2v(pte.addr() as usize));
+            }
+            return mapped;
+        }
+        let mem = allocator.kalloc();
+        if mem.is_null() {
+            return false;
+        }
+        unsafe {
+            crate::util::fast_copy(mem, p2v(pte.addr() as usize) as *const u8, PG_SIZE);
+        }
+        let mapped = map_pages(
+            new_pgdir,
+            allocator,
+            va,
+            v2p(mem as usize) as u64,
+            PG_SIZE as u64,
+            pte.flags(),
+        );
+        if !mapped {
+            allocator.kfree(mem as usize);
+        }
+        mapped
+    })
+}
 
-                let mem = allocator.kalloc();
-                if mem.is_null() {
-                    return false;
-                }
-                unsafe {
-                    core::ptr::copy_nonoverlapping(p2v(pa as usize) as *const u8, mem, PG_SIZE);
-                }
+// Call f(va, pte) for every present user page in the lower half of pgdir,
+// in address order, until f returns false. Returns whether all calls
+// returned true.
+pub fn for_each_user_page(
+    pgdir: *const PageTable,
+    f: &mut dyn FnMut(u64, PageTableEntry) -> bool,
+) -> bool {
+    walk_table(pgdir, 3, 0, f)
+}
 
-                if !map_pages(
-                    new_pgdir,
-                    allocator,
-                    i,
-                    v2p(mem as usize) as u64,
-                    PG_SIZE as u64,
-                    flags,
-                ) {
-                    return false;
-                }
+// Visit the user pages under one page table of the given level (3: PML4,
+// 0: last level) that maps virtual addresses starting at base.
+fn walk_table(
+    table: *const PageTable,
+    level: u8,
+    base: u64,
+    f: &mut dyn FnMut(u64, PageTableEntry) -> bool,
+) -> bool {
+    // The upper half of the PML4 is the kernel's.
+    let n = if level == 3 { 256 } else { 512 };
+    for i in 0..n {
+        let pte = unsafe { (*table).entries[i] };
+        if !pte.is_present() || pte.flags() & PageTableEntry::USER == 0 {
+            continue;
+        }
+        let va = base + ((i as u64) << (12 + 9 * level));
+        if level > 0 {
+            // User memory is mapped with 4K pages only.
+            if pte.flags() & PageTableEntry::HUGE_PAGE != 0 {
+                continue;
             }
+            let next = p2v(pte.addr() as usize) as *const PageTable;
+            if !walk_table(next, level - 1, va, f) {
+                return false;
+            }
+            continue;
+        }
+        if !f(va, pte) {
+            return false;
         }
-        i += PG_SIZE as u64;
     }
     true
 }
@@ -268,7 +443,7 @@ pub fn pgrounddown(x: u64) -> u64 {
     x & !(PG_SIZE as u64 - 1)
 }
 
-fn pgroundup(x: u64) -> u64 {
+pub fn pgroundup(x: u64) -> u64 {
     (x + PG_SIZE as u64 - 1) & !(PG_SIZE as u64 - 1)
 }
 
@@ -289,7 +464,7 @@ pub fn uvm_alloc(
             return None;
         }
         unsafe {
-            core::ptr::write_bytes(mem, 0, PG_SIZE);
+            crate::util::fast_zero(mem, PG_SIZE);
         }
         if !map_pages(
             pgdir,
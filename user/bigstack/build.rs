@@ -0,0 +1,4 @@
+fn main() {
+    // Ask exec for a 256K initial stack (PT_GNU_STACK).
+    println!("cargo:rustc-link-arg=-zstack-size=262144");
+}
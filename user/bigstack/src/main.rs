@@ -0,0 +1,18 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, println};
+
+entry!(main);
+
+// Uses a 128K local array right away, more than the default user stack.
+// Run by selftest.
+fn main(_argc: usize, _argv: *const *const u8) {
+    let mut buf = [0u8; 128 * 1024];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let buf = core::hint::black_box(&buf);
+    let ok = buf.iter().enumerate().all(|(i, &b)| b == i as u8);
+    println!("{}", if ok { "bigstack ok" } else { "bigstack bad" });
+}
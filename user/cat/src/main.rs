@@ -29,9 +29,14 @@ fn cat(fd: i32) {
     let mut buf = [0u8; 512];
     loop {
         let n = syscall::read(fd, &mut buf);
+        if n < 0 {
+            ulib::print!("cat: read error {}\n", n);
+        }
         if n <= 0 {
             break;
         }
-        syscall::write(1, &buf[0..n as usize]);
+        if ulib::io::write_all(1, &buf[0..n as usize]).is_err() {
+            break;
+        }
     }
 }
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use alloc::vec::Vec;
+use ulib::ptrace::{
+    Breakpoint, Regs, BP_EXEC, PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH, PTRACE_SINGLESTEP,
+    PTRACE_TRACEME,
+};
+use ulib::{entry, env, println, syscall};
+
+entry!(main);
+
+const NSTEPS: usize = 16;
+
+// Run a program under ptrace, printing RIP for each of its first NSTEPS
+// instructions (or, with -b, the first NSTEPS from the hex address addr on),
+// then let it run to completion.
+// Usage: debug [-b addr] prog [args...]
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let (bp, args) = match args.get(1).map(|a| a.to_bytes()) {
+        Some(b"-b") if args.len() > 2 => {
+            let addr = args[2]
+                .to_str()
+                .ok()
+                .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+                .unwrap_or_else(|| usage());
+            (Some(addr), &args[2..])
+        }
+        _ => (None, &args[..]),
+    };
+    if args.len() < 2 {
+        usage();
+    }
+
+    // The child reports through the pipe once it is traced, so our attach
+    // only waits for the stop at exec instead of stopping it earlier.
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        println!("debug: pipe failed");
+        syscall::exit(1);
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        syscall::ptrace(PTRACE_TRACEME, 0, 0);
+        syscall::write(fds[1], b"t");
+        syscall::close(fds[1]);
+        let mut argv: Vec<*const u8> = args[1..].iter().map(|a| a.as_ptr() as *const u8).collect();
+        argv.push(core::ptr::null());
+        syscall::exec(argv[0], &argv);
+        println!("debug: cannot exec {}", args[1].to_str().unwrap_or("?"));
+        syscall::exit(1);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 1];
+    syscall::read(fds[0], &mut buf);
+    syscall::close(fds[0]);
+
+    if syscall::ptrace(PTRACE_ATTACH, pid, 0) < 0 {
+        syscall::wait(None);
+        syscall::exit(1);
+    }
+    if let Some(addr) = bp {
+        let bp = Breakpoint {
+            addr,
+            kind: BP_EXEC,
+            ..Default::default()
+        };
+        if syscall::ptrace_setbp(pid, &bp) < 0 {
+            println!("debug: bad breakpoint {:x}", addr);
+        } else if syscall::ptrace(PTRACE_CONT, pid, 0) < 0 {
+            println!("debug: exited before {:x}", addr);
+            syscall::wait(None);
+            return;
+        }
+    }
+    let mut regs = Regs::default();
+    for i in 0..NSTEPS {
+        if syscall::ptrace_getregs(pid, &mut regs) < 0 {
+            break;
+        }
+        println!("debug: {} rip={:x} rsp={:x}", i, regs.rip, regs.rsp);
+        if syscall::ptrace(PTRACE_SINGLESTEP, pid, 0) < 0 {
+            break; // Exited
+        }
+    }
+    syscall::ptrace(PTRACE_DETACH, pid, 0);
+    syscall::wait(None);
+}
+
+fn usage() -> ! {
+    println!("usage: debug [-b addr] prog [args...]");
+    syscall::exit(1);
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use alloc::vec;
+use ulib::{entry, fb, fs, println, syscall};
+
+entry!(main);
+
+// Draw a test pattern on /dev/fb: eight colour bars over a grey ramp.
+// Usage: fbdemo
+fn main(_argc: usize, _argv: *const *const u8) {
+    let fd = syscall::open("/dev/fb", fs::O_RDWR);
+    if fd < 0 {
+        println!("fbdemo: cannot open /dev/fb");
+        syscall::exit(1);
+    }
+    let mut info = fb::FbInfo::default();
+    if syscall::ioctl(
+        fd,
+        fb::FBIOGET_VSCREENINFO,
+        &mut info as *mut fb::FbInfo as usize,
+    ) < 0
+    {
+        println!("fbdemo: not a framebuffer");
+        syscall::exit(1);
+    }
+    let (w, h) = (info.width as usize, info.height as usize);
+    let mut frame = vec![0u8; info.stride as usize * h];
+    for y in 0..h {
+        for x in 0..w {
+            let px = if y < h * 3 / 4 {
+                // Bars of white, yellow, cyan, green, magenta, red, blue, black
+                let bar = 7 - x * 8 / w;
+                let on = |bit: usize| if bar & bit != 0 { 0xff } else { 0 };
+                on(4) << 16 | on(2) << 8 | on(1)
+            } else {
+                let v = (x * 255 / (w - 1)) as u32;
+                v << 16 | v << 8 | v
+            };
+            let at = y * info.stride as usize + x * 4;
+            frame[at..at + 4].copy_from_slice(&px.to_le_bytes());
+        }
+    }
+    if syscall::write(fd, &frame) != frame.len() as isize {
+        println!("fbdemo: write failed");
+        syscall::exit(1);
+    }
+    println!("fbdemo: drew {}x{}", w, h);
+    syscall::exit(0);
+}
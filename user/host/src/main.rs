@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use ulib::{dns, entry, env, println, syscall};
+
+entry!(main);
+
+// Look up the IPv4 addresses of each name with the DNS resolver.
+// Usage: host name...
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let names = args.get(1..).unwrap_or(&[]);
+    if names.is_empty() {
+        println!("usage: host name...");
+        syscall::exit(1);
+    }
+    let mut status = 0;
+    for arg in names {
+        let name = arg.to_str().unwrap_or("");
+        match dns::lookup(name) {
+            Ok(addrs) => {
+                for [a, b, c, d] in addrs {
+                    println!("{} has address {}.{}.{}.{}", name, a, b, c, d);
+                }
+            }
+            Err(e) => {
+                println!("host: {}: {}", name, e);
+                status = 1;
+            }
+        }
+    }
+    syscall::exit(status);
+}
@@ -1,39 +1,80 @@
 #![no_std]
 #![no_main]
 
-use ulib::{entry, println, syscall};
+use ulib::{entry, fs, println, syscall, tty};
 
 entry!(main);
 
+// A shell on each virtual console. tty0 is the console init was started on;
+// the others are opened from /dev, and left out if the image has no node.
 fn main(_argc: usize, _argv: *const *const u8) {
     println!("init: starting");
 
+    let mut shells = [-1i32; tty::NTTY];
     loop {
-        let pid = syscall::fork();
-        if pid < 0 {
-            println!("init: fork failed");
+        for (n, pid) in shells.iter_mut().enumerate() {
+            if *pid < 0 && (n == 0 || has_tty(n)) {
+                *pid = spawn_sh(n);
+            }
+        }
+
+        // Orphaned processes are re-parented to init, so other pids may be
+        // reaped here too. None left means no shell could be started: try
+        // again.
+        let wpid = syscall::wait(None);
+        if wpid < 0 {
             continue;
         }
+        if let Some(n) = shells.iter().position(|&pid| pid == wpid) {
+            // Shell exited (or crashed), restart it
+            println!("init: sh on tty{} exited, restarting", n);
+            shells[n] = -1;
+        }
+    }
+}
+
+fn tty_path(n: usize) -> [u8; 9] {
+    let mut path = *b"/dev/tty0";
+    path[8] = b'0' + n as u8;
+    path
+}
+
+fn has_tty(n: usize) -> bool {
+    let path = tty_path(n);
+    let fd = syscall::open(core::str::from_utf8(&path).unwrap(), fs::O_RDWR);
+    if fd < 0 {
+        return false;
+    }
+    syscall::close(fd);
+    true
+}
 
-        if pid == 0 {
-            // Child
-            let sh = "sh\0";
-            let argv = [sh.as_ptr(), core::ptr::null()];
-            syscall::exec(sh.as_ptr(), &argv);
-            println!("init: exec sh failed");
-            syscall::exit(1);
-        } else {
-            // Parent
-            loop {
-                // Wait for shell to exit
-                let wpid = syscall::wait(None);
-                if wpid == pid {
-                    // Shell exited, restart it
-                    break;
-                } else if wpid < 0 {
-                    // Wait failed?
-                }
+// Start a shell on ttyn; returns its pid, or -1.
+fn spawn_sh(n: usize) -> i32 {
+    let pid = syscall::fork();
+    if pid < 0 {
+        println!("init: fork failed");
+        return -1;
+    }
+    if pid == 0 {
+        if n != 0 {
+            let path = tty_path(n);
+            let fd = syscall::open(core::str::from_utf8(&path).unwrap(), fs::O_RDWR);
+            if fd < 0 {
+                syscall::exit(1);
+            }
+            for std in 0..3 {
+                syscall::dup2(fd, std);
+            }
+            if fd > 2 {
+                syscall::close(fd);
             }
         }
+        let sh = "sh\0";
+        let argv = [sh.as_ptr(), core::ptr::null()];
+        syscall::exec(sh.as_ptr(), &argv);
+        println!("init: exec sh failed");
+        syscall::exit(1);
     }
+    pid
 }
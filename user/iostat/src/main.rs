@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use ulib::syscall::{SysInfo, SYSINFO_NDISK};
+use ulib::{entry, println, syscall};
+
+entry!(main);
+
+// Print the buffer cache and disk counters since boot, and the requests in
+// flight on each virtio disk that has had any.
+// Usage: iostat
+fn main(_argc: usize, _argv: *const *const u8) {
+    let mut info = SysInfo::default();
+    if syscall::sysinfo(&mut info) < 0 {
+        println!("iostat: failed");
+        syscall::exit(1);
+    }
+    println!(
+        "bcache: {} hits, {} misses, {} read ahead",
+        info.bcache_hits, info.bcache_misses, info.readaheads
+    );
+    println!(
+        "blocks: {} read, {} written, {} waits for a clean buffer",
+        info.blocks_read, info.blocks_written, info.dirty_waits
+    );
+    for disk in 0..SYSINFO_NDISK {
+        if info.disk_queue_max[disk] > 0 {
+            println!(
+                "virtio{}: {} in flight, at most {}",
+                disk, info.disk_queue[disk], info.disk_queue_max[disk]
+            );
+        }
+    }
+    syscall::exit(0);
+}
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use ulib::syscall::{IRQSTAT_NCPU, IRQSTAT_NIRQ};
+use ulib::{entry, print, println, syscall};
+
+entry!(main);
+
+// Print the interrupt counters of every IRQ that fired, one column per CPU.
+fn main(_argc: usize, _argv: *const *const u8) {
+    let mut counts = [0u64; IRQSTAT_NCPU * IRQSTAT_NIRQ];
+    if syscall::irqstat(&mut counts) < 0 {
+        println!("irqstat: failed");
+        syscall::exit(1);
+    }
+
+    let ncpu = (0..IRQSTAT_NCPU)
+        .filter(|c| {
+            counts[c * IRQSTAT_NIRQ..(c + 1) * IRQSTAT_NIRQ]
+                .iter()
+                .any(|n| *n > 0)
+        })
+        .max()
+        .map_or(1, |c| c + 1);
+
+    print!("IRQ ");
+    for c in 0..ncpu {
+        print!("      CPU{}", c);
+    }
+    println!();
+    for irq in 0..IRQSTAT_NIRQ {
+        if (0..ncpu).all(|c| counts[c * IRQSTAT_NIRQ + irq] == 0) {
+            continue;
+        }
+        print!("{:>3} ", irq);
+        for c in 0..ncpu {
+            print!("{:>10}", counts[c * IRQSTAT_NIRQ + irq]);
+        }
+        println!();
+    }
+    syscall::exit(0);
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, env, println, signal, syscall};
+
+entry!(main);
+
+// Usage: kill [-sig] pid...
+// The signal is SIGTERM unless given by number.
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let mut pids = args.get(1..).unwrap_or(&[]);
+    let mut sig = signal::SIGTERM;
+    if let Some(n) = pids
+        .first()
+        .and_then(|a| a.to_str().ok()?.strip_prefix('-'))
+    {
+        match n.parse::<usize>() {
+            Ok(n) => sig = n,
+            Err(_) => pids = &[],
+        }
+        pids = pids.get(1..).unwrap_or(&[]);
+    }
+    if pids.is_empty() {
+        println!("usage: kill [-sig] pid...");
+        syscall::exit(1);
+    }
+    let mut status = 0;
+    for arg in pids {
+        let arg = arg.to_str().unwrap_or("");
+        let ok = match arg.parse::<i32>() {
+            Ok(pid) => syscall::kill(pid, sig) == 0,
+            Err(_) => false,
+        };
+        if !ok {
+            println!("kill: cannot kill {}", arg);
+            status = 1;
+        }
+    }
+    syscall::exit(status);
+}
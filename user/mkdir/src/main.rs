@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, env, println, syscall};
+
+entry!(main);
+
+// Usage: mkdir dir...
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    if args.len() < 2 {
+        println!("usage: mkdir dir...");
+        syscall::exit(1);
+    }
+    let mut status = 0;
+    for arg in args.iter().skip(1) {
+        let path = arg.to_str().unwrap_or("");
+        if syscall::mkdir(path) < 0 {
+            println!("mkdir: cannot create directory {}", path);
+            status = 1;
+        }
+    }
+    syscall::exit(status);
+}
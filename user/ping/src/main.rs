@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+
+use ulib::net::{self, SockAddrIn};
+use ulib::{dns, entry, env, println, syscall};
+
+entry!(main);
+
+const HLEN: usize = 8; // ICMP header
+const DATA: usize = 56;
+const INTERVAL: u64 = 100; // Ticks between requests, and to wait for a reply
+const MS_PER_TICK: u64 = 10;
+
+fn usage() -> ! {
+    println!("usage: ping [-c count] host");
+    syscall::exit(1);
+}
+
+// Send count ICMP echo requests to host, default 4, one a second, and
+// print the round trip time of each reply, to the 10 ms of a tick.
+// Usage: ping [-c count] host
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let (mut count, mut host) = (4u16, None);
+    let mut words = args.iter().skip(1).map(|a| a.to_str().unwrap_or(""));
+    while let Some(w) = words.next() {
+        match w {
+            "-c" => match words.next().and_then(|n| n.parse().ok()) {
+                Some(n) => count = n,
+                None => usage(),
+            },
+            _ if host.is_none() => host = Some(w),
+            _ => usage(),
+        }
+    }
+    let Some(host) = host else { usage() };
+    let addr = match dns::resolve(host) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("ping: {}: {}", host, e);
+            syscall::exit(1);
+        }
+    };
+    let fd = syscall::socket(net::AF_INET, net::SOCK_DGRAM, net::IPPROTO_ICMP);
+    if fd < 0 {
+        println!("ping: socket: error {}", fd);
+        syscall::exit(1);
+    }
+    let [a, b, c, d] = addr;
+    println!(
+        "PING {} ({}.{}.{}.{}): {} data bytes",
+        host, a, b, c, d, DATA
+    );
+    let dst = SockAddrIn::new(addr, 0);
+    let mut received = 0;
+    for seq in 1..=count {
+        let mut req = [0u8; HLEN + DATA];
+        req[0] = net::ICMP_ECHO;
+        req[6..8].copy_from_slice(&seq.to_be_bytes());
+        for (i, byte) in req[HLEN..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let start = syscall::uptime();
+        let sent = syscall::sendto(fd, &req, &dst);
+        if sent < 0 {
+            println!("ping: sendto: error {}", sent);
+            syscall::exit(1);
+        }
+        // The identifier is the kernel's; a reply is told by its sequence
+        // number.
+        let mut reply = [0u8; HLEN + DATA];
+        while syscall::uptime() - start < INTERVAL {
+            let mut from = SockAddrIn::default();
+            let n = syscall::recvfrom(fd, &mut reply, net::MSG_DONTWAIT, Some(&mut from));
+            if n == -syscall::EAGAIN {
+                syscall::sleep(1);
+                continue;
+            }
+            if n < 0 {
+                println!("ping: recvfrom: error {}", n);
+                syscall::exit(1);
+            }
+            if from.addr == addr && n as usize >= HLEN && reply[6..8] == seq.to_be_bytes() {
+                let ms = (syscall::uptime() - start) * MS_PER_TICK;
+                let n = n as usize - HLEN;
+                println!(
+                    "{} bytes from {}.{}.{}.{}: icmp_seq={} time={} ms",
+                    n, a, b, c, d, seq, ms
+                );
+                received += 1;
+                break;
+            }
+        }
+        let elapsed = syscall::uptime() - start;
+        if seq < count && elapsed < INTERVAL {
+            syscall::sleep(INTERVAL - elapsed);
+        }
+    }
+    println!("--- {} ping statistics ---", host);
+    println!(
+        "{} packets transmitted, {} received, {}% packet loss",
+        count,
+        received,
+        (count - received) as u32 * 100 / count.max(1) as u32
+    );
+    syscall::exit(if received > 0 { 0 } else { 1 });
+}
@@ -0,0 +1,3423 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use ulib::{dns, entry, env, fb, fs, io, mman, net, println, signal, syscall, tty};
+
+entry!(main);
+
+// Smoke test of core kernel functionality, run from the shell inside the booted system.
+// `make test` boots the kernel, runs this program and checks the summary line.
+
+struct Results {
+    passed: usize,
+    failed: usize,
+}
+
+impl Results {
+    fn check(&mut self, name: &str, ok: bool) {
+        if ok {
+            self.passed += 1;
+            println!("selftest: {} ... ok", name);
+        } else {
+            self.failed += 1;
+            println!("selftest: {} ... FAILED", name);
+        }
+    }
+}
+
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    if args.len() > 1 && args[1].to_bytes() == b"argcheck" {
+        // Re-exec'd by test_exec_args and exec_under_pressure: verify the
+        // arguments and report on stdout.
+        let ok = args.len() == EXEC_NARGS + 2
+            && args[2..]
+                .iter()
+                .enumerate()
+                .all(|(i, a)| a.to_bytes() == exec_arg(i).as_bytes());
+        println!("{}", if ok { "argcheck ok" } else { "argcheck bad" });
+        syscall::exit(0);
+    }
+
+    let mut r = Results {
+        passed: 0,
+        failed: 0,
+    };
+
+    test_fork_wait(&mut r);
+    test_fork_stack(&mut r);
+    test_fork_heap(&mut r);
+    test_heap_bounds(&mut r);
+    test_unmap_flush(&mut r);
+    test_exec(&mut r);
+    test_exec_args(&mut r);
+    test_exec_args_pressure(&mut r);
+    test_exec_stack_size(&mut r);
+    test_stack_growth(&mut r);
+    test_pipe(&mut r);
+    test_dup2(&mut r);
+    test_file_read(&mut r);
+    test_concurrent_fs(&mut r);
+    test_concurrent_disk(&mut r);
+    test_readahead(&mut r);
+    test_fsync(&mut r);
+    test_writeback(&mut r);
+    test_inode_exhaustion(&mut r);
+    test_inode_recycle(&mut r);
+    test_dcache(&mut r);
+    test_bcache_stats(&mut r);
+    test_alloc(&mut r);
+    test_alloc_stress(&mut r);
+    test_arena(&mut r);
+    test_interrupts_enabled(&mut r);
+    test_irqstat(&mut r);
+    test_syscall_numbers(&mut r);
+    test_fstat(&mut r);
+    test_open_directory(&mut r);
+    test_getdents_large(&mut r);
+    test_sparse_read(&mut r);
+    test_block_balance(&mut r);
+    test_truncate(&mut r);
+    test_indirect(&mut r);
+    test_double_indirect(&mut r);
+    test_name_max(&mut r);
+    test_long_names(&mut r);
+    test_unlink(&mut r);
+    test_mkdir(&mut r);
+    test_mknod(&mut r);
+    test_chdir(&mut r);
+    test_lseek(&mut r);
+    test_open_flags(&mut r);
+    test_kill(&mut r);
+    test_wait_status(&mut r);
+    test_nice(&mut r);
+    test_fork_storm(&mut r);
+    test_threads(&mut r);
+    test_pgid(&mut r);
+    test_termios(&mut r);
+    test_vt(&mut r);
+    test_fb(&mut r);
+    test_udp(&mut r);
+    test_udp_connect(&mut r);
+    test_tcp(&mut r);
+    test_ping(&mut r);
+    test_dns(&mut r);
+    test_reclaim(&mut r);
+    test_mmap_anon(&mut r);
+    test_mmap_file(&mut r);
+    test_mmap_shared(&mut r);
+    test_signals(&mut r);
+    test_read_to_string(&mut r);
+    test_write_all(&mut r);
+    test_stdout_flush_on_exit(&mut r);
+    test_ptrace(&mut r);
+    test_ptrace_breakpoint(&mut r);
+    test_coredump(&mut r);
+    test_fpu_switch(&mut r);
+    test_fpu_math(&mut r);
+    test_fpu_lazy(&mut r);
+    test_rusage(&mut r);
+    test_time(&mut r);
+    test_sleep_ticks(&mut r);
+    test_uptime(&mut r);
+    test_runaway(&mut r);
+
+    println!("selftest: {} passed, {} failed", r.passed, r.failed);
+    if r.failed == 0 {
+        println!("selftest: all tests passed");
+    }
+    syscall::exit(if r.failed == 0 { 0 } else { 1 });
+}
+
+fn test_fork_wait(r: &mut Results) {
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::exit(0);
+    }
+    r.check("fork", pid > 0);
+    r.check("wait returns child pid", syscall::wait(None) == pid);
+    r.check("wait without children fails", syscall::wait(None) < 0);
+}
+
+// The stack lives above the heap (sz), so fork must copy it on its own.
+fn test_fork_stack(r: &mut Results) {
+    let mut local = [0u64; 256];
+    for (i, v) in local.iter_mut().enumerate() {
+        *v = i as u64 * 3 + 1;
+    }
+    let local = core::hint::black_box(&local);
+    let above_heap = local.as_ptr() as isize > syscall::sbrk(0);
+
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("fork copies the stack", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        let ok = local
+            .iter()
+            .enumerate()
+            .all(|(i, &v)| v == i as u64 * 3 + 1);
+        syscall::write(fds[1], if ok { b"y" } else { b"n" });
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 1];
+    let n = syscall::read(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("stack is above the heap", above_heap);
+    r.check("fork copies the stack", n == 1 && buf[0] == b'y');
+}
+
+// fork copies whatever is mapped: heap pages written before the fork, and
+// nothing extra for sbrk'd pages never touched (they fault in as zeroes).
+// Only the heap is paged in on demand: a null pointer is not in it, and
+// sbrk cannot give back the loaded image below it.
+fn test_heap_bounds(r: &mut Results) {
+    // Near null rather than null, which Rust's own checks may catch first.
+    r.check(
+        "a null pointer dereference kills",
+        access_kills(8, false) && access_kills(16, true),
+    );
+    let brk = syscall::sbrk(0);
+    r.check(
+        "sbrk cannot shrink below the heap",
+        syscall::sbrk(-brk) < 0 && syscall::sbrk(isize::MIN) < 0 && syscall::sbrk(0) == brk,
+    );
+}
+
+// A page just used and then unmapped must fault, not be reached through a
+// stale TLB entry, and its frame must be freed.
+fn test_unmap_flush(r: &mut Results) {
+    let before = free_pages();
+    let pid = syscall::fork();
+    if pid == 0 {
+        let brk = syscall::sbrk(4096);
+        unsafe { (brk as *mut u8).write_volatile(1) };
+        syscall::sbrk(-4096);
+        unsafe { (brk as *mut u8).write_volatile(2) };
+        syscall::exit(0);
+    }
+    let mut heap = 0;
+    syscall::waitpid(pid, Some(&mut heap));
+    let pid = syscall::fork();
+    if pid == 0 {
+        let m = syscall::mmap(4096, RW, mman::MAP_PRIVATE | mman::MAP_ANONYMOUS, -1, 0);
+        unsafe { (m as *mut u8).write_volatile(1) };
+        syscall::munmap(m as usize, 4096);
+        unsafe { (m as *mut u8).write_volatile(2) };
+        syscall::exit(0);
+    }
+    let mut mapping = 0;
+    syscall::waitpid(pid, Some(&mut mapping));
+    let after = free_pages();
+    r.check("sbrk shrinking flushes the TLB", heap == -1);
+    r.check("munmap flushes the TLB", mapping == -1);
+    r.check("unmapped pages are freed", after == before);
+}
+
+fn test_fork_heap(r: &mut Results) {
+    let heap: Vec<u32> = (0..16 * 1024).map(|i| i * 7).collect();
+    let untouched = syscall::sbrk(4096);
+    let mut fds = [0i32; 2];
+    if untouched < 0 || syscall::pipe(&mut fds) < 0 {
+        r.check("fork copies the heap", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        let ok = heap.iter().enumerate().all(|(i, &v)| v == i as u32 * 7)
+            && unsafe { *(untouched as *const u64) } == 0;
+        syscall::write(fds[1], if ok { b"y" } else { b"n" });
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 1];
+    let n = syscall::read(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("fork copies the heap", n == 1 && buf[0] == b'y');
+}
+
+fn test_exec(r: &mut Results) {
+    let pid = syscall::fork();
+    if pid == 0 {
+        let echo = "echo\0";
+        let arg = "selftest-exec\0";
+        let argv = [echo.as_ptr(), arg.as_ptr(), core::ptr::null()];
+        syscall::exec(echo.as_ptr(), &argv);
+        syscall::exit(1);
+    }
+    r.check("exec", pid > 0 && syscall::wait(None) == pid);
+
+    let missing = "/no-such-program\0";
+    let argv = [missing.as_ptr(), core::ptr::null()];
+    r.check(
+        "exec missing file fails",
+        syscall::exec(missing.as_ptr(), &argv) < 0,
+    );
+
+    // Arguments are copied in through the page table, so a wild pointer is an error.
+    let echo = "echo\0";
+    let argv = [echo.as_ptr(), 0x7fff_0000 as *const u8, core::ptr::null()];
+    r.check(
+        "exec bad argv fails",
+        syscall::exec(echo.as_ptr(), &argv) < 0,
+    );
+}
+
+// Read from fd until EOF or buf is full.
+fn read_all(fd: i32, buf: &mut [u8]) -> usize {
+    let mut total = 0;
+    while total < buf.len() {
+        let n = syscall::read(fd, &mut buf[total..]);
+        if n <= 0 {
+            break;
+        }
+        total += n as usize;
+    }
+    total
+}
+
+const EXEC_NARGS: usize = 40;
+
+fn exec_arg(i: usize) -> String {
+    let mut s = String::new();
+    for j in 0..60 {
+        s.push((b'a' + ((i + j) % 26) as u8) as char);
+    }
+    s
+}
+
+// The arguments "selftest argcheck" checks for, NUL-terminated.
+fn argcheck_strs() -> Vec<String> {
+    let mut strs: Vec<String> = Vec::new();
+    strs.push(String::from("selftest\0"));
+    strs.push(String::from("argcheck\0"));
+    for i in 0..EXEC_NARGS {
+        let mut s = exec_arg(i);
+        s.push('\0');
+        strs.push(s);
+    }
+    strs
+}
+
+// More (and longer) arguments than fit the old fixed argv arrays must survive
+// the switch to the new address space.
+fn test_exec_args(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("exec many args", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(1);
+        syscall::dup(fds[1]);
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+
+        let strs = argcheck_strs();
+        let mut argv: Vec<*const u8> = strs.iter().map(|s| s.as_ptr()).collect();
+        argv.push(core::ptr::null());
+        syscall::exec(argv[0], &argv);
+        syscall::exit(1);
+    }
+    syscall::close(fds[1]);
+
+    let mut buf = [0u8; 64];
+    let total = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("exec many args", &buf[..total] == b"argcheck ok\n");
+}
+
+// The same exec, while another process holds all but a few of the free
+// pages, fewer each round: the arguments must arrive intact, or exec fail
+// and return, never anything in between.
+fn test_exec_args_pressure(r: &mut Results) {
+    let mut ok = true;
+    for reserve in [256, 64, 16, 0] {
+        ok &= exec_under_pressure(reserve);
+    }
+    r.check("exec many args under memory pressure", ok);
+}
+
+// Run "selftest argcheck" from a child while a second child holds all but
+// about reserve free pages. True if it reported its arguments intact, or
+// its exec failed cleanly.
+fn exec_under_pressure(reserve: u64) -> bool {
+    const CHUNK: usize = 16; // Pages the hog takes at a time
+    let mut out = [0i32; 2];
+    let mut go = [0i32; 2];
+    let mut ready = [0i32; 2];
+    if syscall::pipe(&mut out) < 0 || syscall::pipe(&mut go) < 0 || syscall::pipe(&mut ready) < 0 {
+        return false;
+    }
+    let execer = syscall::fork();
+    if execer == 0 {
+        syscall::close(1);
+        syscall::dup(out[1]);
+        for fd in [out[0], out[1], go[1], ready[0], ready[1]] {
+            syscall::close(fd);
+        }
+        // Everything is in place before memory runs short.
+        let strs = argcheck_strs();
+        let mut argv: Vec<*const u8> = strs.iter().map(|s| s.as_ptr()).collect();
+        argv.push(core::ptr::null());
+        let mut b = [0u8; 1];
+        syscall::read(go[0], &mut b);
+        syscall::exec(argv[0], &argv);
+        syscall::exit(2);
+    }
+    let hog = syscall::fork();
+    if hog == 0 {
+        for fd in [out[0], out[1], go[0], go[1], ready[0]] {
+            syscall::close(fd);
+        }
+        while free_pages() > reserve + 2 * CHUNK as u64 {
+            let p = syscall::sbrk((CHUNK * 4096) as isize);
+            if p < 0 {
+                break;
+            }
+            for i in 0..CHUNK {
+                unsafe { ((p as usize + i * 4096) as *mut u8).write_volatile(1) };
+            }
+        }
+        syscall::write(ready[1], b"r");
+        loop {
+            syscall::sleep(100);
+        }
+    }
+    syscall::close(out[1]);
+    syscall::close(go[0]);
+    syscall::close(ready[1]);
+    let mut b = [0u8; 1];
+    let held = hog > 0 && read_all(ready[0], &mut b) == 1;
+    syscall::write(go[1], b"g");
+    let mut buf = [0u8; 64];
+    let total = read_all(out[0], &mut buf);
+    let mut status = -1;
+    let waited = execer > 0 && syscall::waitpid(execer, Some(&mut status)) == execer;
+    if hog > 0 {
+        syscall::kill(hog, signal::SIGKILL);
+        syscall::waitpid(hog, None);
+    }
+    for fd in [out[0], go[1], ready[0]] {
+        syscall::close(fd);
+    }
+    let intact = status == 0 && &buf[..total] == b"argcheck ok\n";
+    let refused = status == 2 && total == 0;
+    held && waited && (intact || refused)
+}
+
+// /bigstack asks for a 256K stack in its ELF and puts 128K on it at once.
+fn test_exec_stack_size(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("exec stack size from ELF", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(1);
+        syscall::dup(fds[1]);
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+        let path = "/bigstack\0";
+        let argv = [path.as_ptr(), core::ptr::null()];
+        syscall::exec(path.as_ptr(), &argv);
+        syscall::exit(1);
+    }
+    syscall::close(fds[1]);
+
+    let mut buf = [0u8; 64];
+    let total = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check(
+        "exec stack size from ELF",
+        &buf[..total] == b"bigstack ok\n",
+    );
+}
+
+// Each call puts 1K on the stack; depth 0 means forever.
+fn recurse(n: u64, depth: u64) -> u64 {
+    if n + 1 == depth {
+        return n;
+    }
+    let buf = core::hint::black_box([n as u8; 1024]);
+    recurse(n + 1, depth) + buf[1023] as u64 - n as u8 as u64
+}
+
+// The stack grows past its initial size on demand, up to a limit where the
+// guard page below it kills the process.
+fn test_stack_growth(r: &mut Results) {
+    let grows = syscall::fork();
+    if grows == 0 {
+        // Well past the default 32K stack.
+        syscall::exit((recurse(0, 200) == 199) as i32);
+    }
+    let mut status = 0;
+    syscall::waitpid(grows, Some(&mut status));
+    r.check("the stack grows on demand", status == 1);
+
+    let overflows = syscall::fork();
+    if overflows == 0 {
+        recurse(0, 0);
+        syscall::exit(0);
+    }
+    syscall::waitpid(overflows, Some(&mut status));
+    r.check("a stack overflow kills the process", status == -1);
+}
+
+fn test_pipe(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("pipe", false);
+        return;
+    }
+    let msg = b"hello through a pipe";
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        syscall::write(fds[1], msg);
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+
+    let mut buf = [0u8; 64];
+    let total = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("pipe read/write", &buf[..total] == msg);
+}
+
+fn test_dup2(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("dup2", false);
+        return;
+    }
+    let fd = 9;
+    let msg = b"dup2";
+    let dup = syscall::dup2(fds[1], fd) == fd;
+    syscall::close(fds[1]);
+    let wrote = syscall::write(fd, msg) == msg.len() as isize;
+    let mut buf = [0u8; 8];
+    let n = syscall::read(fds[0], &mut buf);
+    r.check(
+        "dup2 installs the file at newfd",
+        dup && wrote && n == msg.len() as isize && &buf[..msg.len()] == msg,
+    );
+    r.check(
+        "dup2 onto itself is a no-op",
+        syscall::dup2(fds[0], fds[0]) == fds[0],
+    );
+    // Replacing the last write end closes it: the read end sees EOF.
+    r.check(
+        "dup2 closes an open newfd",
+        syscall::dup2(fds[0], fd) == fd && syscall::read(fds[0], &mut buf) == 0,
+    );
+    r.check(
+        "dup2 of a closed fd fails",
+        syscall::dup2(fds[1], fd) < 0 && syscall::dup2(fds[0], 100_000) < 0,
+    );
+    syscall::close(fd);
+    syscall::close(fds[0]);
+}
+
+fn test_file_read(r: &mut Results) {
+    let fd = syscall::open("/hello.txt", 0);
+    r.check("open", fd >= 0);
+    if fd >= 0 {
+        let mut buf = [0u8; 64];
+        let n = syscall::read(fd, &mut buf);
+        r.check("read", n > 0 && &buf[..n as usize] == b"Hello Ext2\n");
+        r.check("close", syscall::close(fd) == 0);
+    }
+    r.check(
+        "open missing file fails",
+        syscall::open("/no-such-file", 0) < 0,
+    );
+}
+
+// Processes on different CPUs looking up, locking and reading inodes at the
+// same time, so iget/ilock/bread interleave. With PROFILE=debug the kernel
+// also checks the file system lock order on every acquisition.
+fn test_concurrent_fs(r: &mut Results) {
+    const NCHILD: usize = 4;
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("concurrent open/read", false);
+        return;
+    }
+    for _ in 0..NCHILD {
+        if syscall::fork() == 0 {
+            syscall::close(fds[0]);
+            let mut ok = true;
+            for _ in 0..50 {
+                for path in ["/hello.txt", "/irqstat"] {
+                    let fd = syscall::open(path, 0);
+                    let mut buf = [0u8; 64];
+                    ok &= fd >= 0 && syscall::read(fd, &mut buf) > 0;
+                    if path == "/hello.txt" {
+                        ok &= &buf[..11] == b"Hello Ext2\n";
+                    }
+                    syscall::close(fd);
+                }
+            }
+            syscall::write(fds[1], if ok { b"y" } else { b"n" });
+            syscall::exit(0);
+        }
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; NCHILD];
+    let n = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    for _ in 0..NCHILD {
+        syscall::wait(None);
+    }
+    r.check(
+        "concurrent open/read",
+        n == NCHILD && buf.iter().all(|b| *b == b'y'),
+    );
+}
+
+// Create one file per in-memory inode slot that is free now, each holding its
+// own path, so that opening them all takes every slot. Empty if that fails.
+fn make_pin_files() -> Vec<String> {
+    let mut info = syscall::SysInfo::default();
+    if syscall::sysinfo(&mut info) < 0 {
+        return Vec::new();
+    }
+    let paths: Vec<String> = (0..info.inodes_free)
+        .map(|i| alloc::format!("/pin{}", i))
+        .collect();
+    for path in &paths {
+        let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+        let ok = fd >= 0 && io::write_all(fd, path.as_bytes()).is_ok();
+        syscall::close(fd);
+        if !ok {
+            remove_pin_files(&paths);
+            return Vec::new();
+        }
+    }
+    paths
+}
+
+fn remove_pin_files(paths: &[String]) {
+    for path in paths {
+        syscall::unlink(path);
+    }
+}
+
+// Children each write a file of their own and read it back at the same
+// time. Only a correctness test: each must get its own data back. It does
+// not show that their requests were on the disk at once, as the virtio
+// queue-depth maximum counts since boot.
+fn test_concurrent_disk(r: &mut Results) {
+    const NCHILD: usize = 4;
+    const BLOCKS: usize = 64;
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("concurrent disk writes and reads", false);
+        return;
+    }
+    let paths = ["/cdisk0.dat", "/cdisk1.dat", "/cdisk2.dat", "/cdisk3.dat"];
+    for (i, path) in paths.iter().enumerate().take(NCHILD) {
+        if syscall::fork() == 0 {
+            syscall::close(fds[0]);
+            let mut ok = true;
+            let mut buf = [0u8; 1024];
+            let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+            for b in 0..BLOCKS {
+                buf.fill((i * BLOCKS + b) as u8);
+                ok &= fd >= 0 && io::write_all(fd, &buf).is_ok();
+            }
+            syscall::close(fd);
+            let fd = syscall::open(path, fs::O_RDONLY);
+            for b in 0..BLOCKS {
+                ok &= fd >= 0 && read_all(fd, &mut buf) == buf.len();
+                ok &= buf.iter().all(|&x| x == (i * BLOCKS + b) as u8);
+            }
+            syscall::close(fd);
+            syscall::unlink(path);
+            syscall::write(fds[1], if ok { b"y" } else { b"n" });
+            syscall::exit(0);
+        }
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; NCHILD];
+    let n = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    for _ in 0..NCHILD {
+        syscall::wait(None);
+    }
+    r.check(
+        "concurrent disk writes and reads",
+        n == NCHILD && buf.iter().all(|b| *b == b'y'),
+    );
+}
+
+// Read a file sequentially in pieces smaller than a block, so read-ahead
+// starts, then seek back into what it read and past where it got to.
+fn test_readahead(r: &mut Results) {
+    const BLOCKS: usize = 48;
+    let path = "/readahead.dat";
+    let mut buf = [0u8; 1024];
+    let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+    let mut ok = fd >= 0;
+    for b in 0..BLOCKS {
+        buf.fill(b as u8);
+        ok &= io::write_all(fd, &buf).is_ok();
+    }
+    syscall::close(fd);
+    let fd = syscall::open(path, fs::O_RDONLY);
+    ok &= fd >= 0;
+    let mut half = [0u8; 512];
+    for i in 0..BLOCKS {
+        ok &= read_all(fd, &mut half) == half.len() && half.iter().all(|&x| x as usize == i / 2);
+    }
+    for b in [3, 40, 20, 47] {
+        syscall::lseek(fd, (b * buf.len()) as i64, fs::SEEK_SET);
+        ok &= read_all(fd, &mut buf) == buf.len() && buf.iter().all(|&x| x as usize == b);
+    }
+    syscall::close(fd);
+    syscall::unlink(path);
+    r.check("sequential reads with read-ahead, then seeks", ok);
+}
+
+// fsync writes a file through to the disk, and then has nothing left to
+// write; so does sync. The data reads back, and a pipe has nothing to sync.
+fn test_fsync(r: &mut Results) {
+    let path = "/fsync.dat";
+    let written = || {
+        let mut info = syscall::SysInfo::default();
+        syscall::sysinfo(&mut info);
+        info.blocks_written
+    };
+    let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+    let mut ok = fd >= 0 && io::write_all(fd, &[7u8; 3000]).is_ok();
+    let before = written();
+    ok &= syscall::fsync(fd) == 0;
+    let after = written();
+    ok &= after > before && syscall::fsync(fd) == 0 && written() == after;
+    ok &= io::write_all(fd, &[8u8; 1000]).is_ok();
+    let before = written();
+    syscall::sync();
+    let after = written();
+    syscall::sync();
+    ok &= after > before && written() == after;
+    syscall::close(fd);
+    let mut buf = [0u8; 4000];
+    let fd = syscall::open(path, fs::O_RDONLY);
+    ok &= fd >= 0 && read_all(fd, &mut buf) == buf.len();
+    ok &= buf[..3000].iter().all(|&b| b == 7) && buf[3000..].iter().all(|&b| b == 8);
+    syscall::close(fd);
+    syscall::unlink(path);
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) == 0 {
+        ok &= syscall::fsync(fds[0]) == -(syscall::EINVAL as i32);
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+    } else {
+        ok = false;
+    }
+    r.check("fsync and sync write what is pending, and only that", ok);
+}
+
+// Write twice as many blocks as the buffer cache holds by default: flushd
+// is woken as buffers turn dirty, so bget never waits for a clean one,
+// and after sync everything reads back.
+fn test_writeback(r: &mut Results) {
+    const CHUNKS: usize = 512; // Of 4 KiB, 2048 blocks
+    let path = "/writeback.dat";
+    let info = || {
+        let mut info = syscall::SysInfo::default();
+        syscall::sysinfo(&mut info);
+        info
+    };
+    let before = info();
+    let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+    let mut buf = [0u8; 4096];
+    let mut ok = fd >= 0;
+    for c in 0..CHUNKS {
+        buf.fill(c as u8);
+        ok &= io::write_all(fd, &buf).is_ok();
+    }
+    syscall::close(fd);
+    syscall::sync();
+    let after = info();
+    ok &= after.dirty_waits == before.dirty_waits
+        && after.blocks_written - before.blocks_written >= (CHUNKS * 4) as u64;
+    let fd = syscall::open(path, fs::O_RDONLY);
+    ok &= fd >= 0;
+    for c in 0..CHUNKS {
+        ok &= read_all(fd, &mut buf) == buf.len() && buf.iter().all(|&b| b == c as u8);
+    }
+    syscall::close(fd);
+    syscall::unlink(path);
+    r.check(
+        "heavy writes never wait for a clean buffer, and read back after sync",
+        ok,
+    );
+}
+
+// One process holds every inode slot; another process's open must wait for
+// them to be released instead of panicking the kernel. A process killed while
+// it waits gives up.
+fn test_inode_exhaustion(r: &mut Results) {
+    let pins = make_pin_files();
+    let mut fds = [0i32; 2];
+    let mut go = [0i32; 2];
+    if pins.is_empty() || syscall::pipe(&mut fds) < 0 || syscall::pipe(&mut go) < 0 {
+        remove_pin_files(&pins);
+        r.check("iget waits for a free inode", false);
+        return;
+    }
+    if syscall::fork() == 0 {
+        syscall::close(fds[0]);
+        syscall::close(go[1]);
+        let pinned: Vec<i32> = pins.iter().map(|path| syscall::open(path, 0)).collect();
+        let ok = pinned.iter().all(|fd| *fd >= 0);
+        syscall::write(fds[1], if ok { b"p" } else { b"x" });
+        read_all(go[0], &mut [0u8; 1]);
+        syscall::write(fds[1], b"r");
+        for fd in pinned {
+            syscall::close(fd);
+        }
+        syscall::exit(0);
+    }
+
+    syscall::close(go[0]);
+    let mut buf = [0u8; 3];
+    let mut n = read_all(fds[0], &mut buf[..1]);
+    let opener = |fds: &[i32; 2]| {
+        let pid = syscall::fork();
+        if pid == 0 {
+            syscall::close(fds[0]);
+            let fd = syscall::open("/hello.txt", 0);
+            syscall::write(fds[1], if fd >= 0 { b"b" } else { b"x" });
+            syscall::close(fd);
+            syscall::exit(0);
+        }
+        // Give it time to block in iget.
+        for _ in 0..200000 {
+            syscall::sbrk(0);
+        }
+        pid
+    };
+    let killed = opener(&fds);
+    syscall::kill(killed, signal::SIGKILL);
+    let gave_up = syscall::wait(None) == killed;
+    opener(&fds);
+    syscall::write(go[1], b"g");
+    syscall::close(go[1]);
+    syscall::close(fds[1]);
+    n += read_all(fds[0], &mut buf[1..]);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    syscall::wait(None);
+    remove_pin_files(&pins);
+    // The second open can only complete after the pinned inodes are released.
+    r.check("iget waits for a free inode", n == 3 && &buf == b"prb");
+    r.check("a killed process stops waiting for an inode", gave_up);
+}
+
+// Closing a file drops its inode to no references, and the next open of a
+// different file recycles the slot. Every open must see its own inode, not
+// cached state left over from the slot's previous one.
+fn test_inode_recycle(r: &mut Results) {
+    let pins = make_pin_files();
+    let mut expect = alloc::vec![fs::Stat::default(); pins.len()];
+    let mut fds = alloc::vec![-1i32; pins.len()];
+    let mut ok = !pins.is_empty();
+    for ((fd, st), path) in fds.iter_mut().zip(expect.iter_mut()).zip(&pins) {
+        *fd = syscall::open(path, fs::O_RDONLY);
+        ok &= *fd >= 0 && syscall::fstat(*fd, st) == 0;
+    }
+    for fd in fds {
+        syscall::close(fd);
+    }
+    for _ in 0..3 {
+        for (path, want) in pins.iter().zip(&expect).rev() {
+            let mut st = fs::Stat::default();
+            let mut buf = [0u8; 16];
+            let fd = syscall::open(path, fs::O_RDONLY);
+            ok &= fd >= 0 && syscall::fstat(fd, &mut st) == 0 && st == *want;
+            let n = syscall::read(fd, &mut buf);
+            ok &= n == path.len() as isize && &buf[..path.len()] == path.as_bytes();
+            syscall::close(fd);
+        }
+    }
+    remove_pin_files(&pins);
+    r.check("recycled inode slots hold no stale data", ok);
+}
+
+// Opening a deep path (created by `make fs`) a second time should be answered
+// by the name cache: no directory scans, so fewer breads.
+fn test_dcache(r: &mut Results) {
+    const PATH: &str = "/d1/d2/d3/deep.txt";
+    let open_cost = || {
+        let mut before = syscall::SysInfo::default();
+        let mut after = syscall::SysInfo::default();
+        syscall::sysinfo(&mut before);
+        let fd = syscall::open(PATH, 0);
+        syscall::sysinfo(&mut after);
+        syscall::close(fd);
+        (
+            fd >= 0,
+            after.breads - before.breads,
+            after.dcache_hits - before.dcache_hits,
+        )
+    };
+
+    let (ok1, breads1, _) = open_cost();
+    let mut ok = ok1;
+    let mut hits_ok = true;
+    let mut fewer_breads = true;
+    for _ in 0..20 {
+        let (ok2, breads2, hits2) = open_cost();
+        ok &= ok2;
+        hits_ok &= hits2 >= 4; // d1, d2, d3, deep.txt
+        fewer_breads &= breads2 < breads1;
+    }
+    r.check("open deep path", ok);
+    r.check("dcache hits", hits_ok);
+    r.check("dcache saves breads", fewer_breads);
+
+    // A child keeps looking the name up while it is created and unlinked:
+    // a lookup that scanned the directory before an unlink must not cache
+    // the name after it, or the unlinked file would open again.
+    const RACE: &str = "/dcache-race.dat";
+    let child = syscall::fork();
+    if child == 0 {
+        loop {
+            syscall::close(syscall::open(RACE, fs::O_RDONLY));
+        }
+    }
+    let mut gone = child > 0;
+    for _ in 0..200 {
+        syscall::close(syscall::open(RACE, fs::O_CREATE | fs::O_RDWR));
+        gone &= syscall::unlink(RACE) == 0;
+        let fd = syscall::open(RACE, fs::O_RDONLY);
+        gone &= fd < 0;
+        syscall::close(fd);
+    }
+    if child > 0 {
+        syscall::kill(child, signal::SIGKILL);
+        syscall::waitpid(child, None);
+    }
+    syscall::unlink(RACE);
+    r.check("an unlinked name does not open again from the dcache", gone);
+}
+
+// Reading a file again is answered from the buffer cache, and each bread
+// counts as a hit or a miss. The root disk has had requests in flight.
+fn test_bcache_stats(r: &mut Results) {
+    let read_file = || {
+        let fd = syscall::open("/lines.txt", 0);
+        let mut buf = [0u8; 512];
+        while fd >= 0 && syscall::read(fd, &mut buf) > 0 {}
+        syscall::close(fd);
+        fd >= 0
+    };
+    let mut ok = read_file();
+    let mut before = syscall::SysInfo::default();
+    let mut after = syscall::SysInfo::default();
+    syscall::sysinfo(&mut before);
+    ok &= read_file();
+    syscall::sysinfo(&mut after);
+    let breads = after.breads - before.breads;
+    ok &= breads > 0
+        && after.bcache_hits - before.bcache_hits == breads
+        && after.bcache_misses == before.bcache_misses
+        && after.blocks_read >= after.bcache_misses
+        && after.disk_queue_max.iter().any(|&n| n > 0);
+    r.check("buffer cache hits, misses and disk queue counted", ok);
+}
+
+fn test_alloc(r: &mut Results) {
+    let mut v: Vec<usize> = Vec::new();
+    for i in 0..10000 {
+        v.push(i);
+    }
+    r.check("alloc", v.iter().enumerate().all(|(i, x)| i == *x));
+    drop(v);
+
+    // Freed memory should be reused instead of growing the heap again.
+    let before = syscall::sbrk(0);
+    let v: Vec<u8> = alloc::vec![0u8; 4096];
+    drop(v);
+    let v: Vec<u8> = alloc::vec![1u8; 4096];
+    r.check(
+        "free",
+        syscall::sbrk(0) == before && v.iter().all(|b| *b == 1),
+    );
+}
+
+// Several processes page-faulting in fresh heap pages at once, so the fault
+// handler and kalloc run concurrently on all CPUs.
+fn test_alloc_stress(r: &mut Results) {
+    const NCHILD: usize = 4;
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("alloc stress", false);
+        return;
+    }
+    for c in 0..NCHILD {
+        if syscall::fork() == 0 {
+            syscall::close(fds[0]);
+            let mut ok = true;
+            for round in 0..8 {
+                let n = 64 * 1024;
+                let v: Vec<u8> = (0..n).map(|i| (i + c + round) as u8).collect();
+                ok &= v
+                    .iter()
+                    .enumerate()
+                    .all(|(i, b)| *b == (i + c + round) as u8);
+            }
+            syscall::write(fds[1], if ok { b"y" } else { b"n" });
+            syscall::exit(0);
+        }
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; NCHILD];
+    let n = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    for _ in 0..NCHILD {
+        syscall::wait(None);
+    }
+    r.check(
+        "alloc stress",
+        n == NCHILD && buf.iter().all(|b| *b == b'y'),
+    );
+}
+
+// Allocations after reset() reuse the arena's memory instead of growing
+// the heap.
+fn test_arena(r: &mut Results) {
+    let Some(mut arena) = ulib::alloc::Arena::new(16 * 1024) else {
+        r.check("arena", false);
+        return;
+    };
+    let brk = syscall::sbrk(0);
+    let mut first = [core::ptr::null::<u64>(); 2];
+    let mut ok = true;
+    for slot in first.iter_mut() {
+        for i in 0..1000u64 {
+            match arena.alloc(i) {
+                Some(v) if *v == i => {
+                    if i == 0 {
+                        *slot = v as *const u64;
+                    }
+                }
+                _ => ok = false,
+            }
+        }
+        ok &= arena.used() == 1000 * 8;
+        arena.reset();
+    }
+    r.check("arena allocations", ok);
+    r.check(
+        "arena reuses memory after reset",
+        first[0] == first[1] && syscall::sbrk(0) == brk,
+    );
+    r.check("arena full", {
+        let a = ulib::alloc::Arena::new(64).unwrap();
+        a.alloc([0u8; 48]).is_some() && a.alloc([0u8; 48]).is_none()
+    });
+}
+
+fn rflags() -> u64 {
+    let flags: u64;
+    unsafe { core::arch::asm!("pushfq; pop {}", out(reg) flags) };
+    flags
+}
+
+// Returning from syscalls and timer interrupts (possibly after switching to
+// another process) must always leave IF set in user mode.
+fn test_interrupts_enabled(r: &mut Results) {
+    let mut ok = true;
+    for i in 0..200000 {
+        if i % 16 == 0 {
+            syscall::sbrk(0);
+        }
+        ok &= rflags() & 0x200 != 0;
+    }
+    r.check("interrupts enabled in user mode", ok);
+}
+
+const IRQ_TIMER: usize = 0;
+const IRQ_UART: usize = 4;
+const IRQ_VIRTIO: usize = 11;
+
+fn irq_counts() -> [u64; syscall::IRQSTAT_NCPU * syscall::IRQSTAT_NIRQ] {
+    let mut counts = [0u64; syscall::IRQSTAT_NCPU * syscall::IRQSTAT_NIRQ];
+    syscall::irqstat(&mut counts);
+    counts
+}
+
+fn irq_total(counts: &[u64], irq: usize) -> u64 {
+    counts.iter().skip(irq).step_by(syscall::IRQSTAT_NIRQ).sum()
+}
+
+fn irq_cpus(counts: &[u64], irq: usize) -> impl Iterator<Item = usize> + '_ {
+    (0..syscall::IRQSTAT_NCPU).filter(move |c| counts[c * syscall::IRQSTAT_NIRQ + irq] > 0)
+}
+
+// Disk reads and timer ticks must show up in the per-CPU counters, and with
+// more than one CPU the device IRQs must not all land on the same core.
+fn test_irqstat(r: &mut Results) {
+    let before = irq_counts();
+
+    // Read a program image nobody has run yet, so it is not in the buffer cache.
+    let fd = syscall::open("/irqstat", 0);
+    let mut nread = 0;
+    if fd >= 0 {
+        let mut buf = [0u8; 512];
+        loop {
+            let n = syscall::read(fd, &mut buf);
+            if n <= 0 {
+                break;
+            }
+            nread += n as usize;
+        }
+        syscall::close(fd);
+    }
+    let after = irq_counts();
+
+    let disk = irq_total(&after, IRQ_VIRTIO) - irq_total(&before, IRQ_VIRTIO);
+    // At most one interrupt per 1K block read, plus a few inode/indirect blocks.
+    r.check(
+        "irqstat disk interrupts",
+        nread > 0 && disk >= 1 && disk <= (nread as u64).div_ceil(1024) + 4,
+    );
+    r.check(
+        "irqstat timer interrupts",
+        irq_total(&after, IRQ_TIMER) > irq_total(&before, IRQ_TIMER),
+    );
+
+    // The shell read our command line from the UART, so console IRQs were counted.
+    let ncpu = irq_cpus(&after, IRQ_TIMER).count();
+    if ncpu > 1 {
+        let uart: Vec<usize> = irq_cpus(&after, IRQ_UART).collect();
+        let virtio: Vec<usize> = irq_cpus(&after, IRQ_VIRTIO).collect();
+        r.check(
+            "irq balancing",
+            !uart.is_empty()
+                && !virtio.is_empty()
+                && uart.iter().chain(&virtio).any(|c| *c != uart[0]),
+        );
+    }
+}
+
+// Invoke every syscall number from abi with arguments that make it fail or
+// do nothing harmful; the kernel must recognize each one.
+fn test_syscall_numbers(r: &mut Results) {
+    let missing = b"/no-such-file\0";
+    let missing_dir = b"/no-such-dir/d\0";
+    let mut fds = [0i32; 2];
+    let mut counts = [0u64; 1];
+    let mut info = syscall::SysInfo::default();
+    let mut st = fs::Stat::default();
+    let bad_fd = usize::MAX; // -1
+    let mut all_known = true;
+    for &num in syscall::SYSCALLS {
+        let (a1, a2, a3) = match num {
+            syscall::SYS_OPEN | syscall::SYS_EXEC | syscall::SYS_STATFS | syscall::SYS_UNLINK => {
+                (missing.as_ptr() as usize, 0, 0)
+            }
+            syscall::SYS_MKDIR => (missing_dir.as_ptr() as usize, 0, 0),
+            syscall::SYS_SBRK | syscall::SYS_SLEEP => (0, 0, 0),
+            syscall::SYS_PIPE => (fds.as_mut_ptr() as usize, 0, 0),
+            syscall::SYS_FSTAT => (bad_fd, &mut st as *mut _ as usize, 0),
+            syscall::SYS_STAT => (missing.as_ptr() as usize, &mut st as *mut _ as usize, 0),
+            syscall::SYS_IRQSTAT => (counts.as_mut_ptr() as usize, counts.len(), 0),
+            syscall::SYS_SYSINFO => (&mut info as *mut _ as usize, 0, 0),
+            // exit and wait are covered by the fork child below.
+            syscall::SYS_FORK | syscall::SYS_EXIT | syscall::SYS_WAIT => continue,
+            syscall::SYS_SIGRETURN | syscall::SYS_CLONE => {
+                if !recognized_in_child(num) {
+                    println!("selftest: syscall {} not recognized", num);
+                    all_known = false;
+                }
+                continue;
+            }
+            _ => (bad_fd, 0, 0),
+        };
+        let ret = unsafe { syscall::syscall3(num, a1, a2, a3) } as isize;
+        if ret == -syscall::ENOSYS {
+            println!("selftest: syscall {} not recognized", num);
+            all_known = false;
+        }
+        if num == syscall::SYS_PIPE && ret == 0 {
+            syscall::close(fds[0]);
+            syscall::close(fds[1]);
+        }
+    }
+    let pid = unsafe { syscall::syscall0(syscall::SYS_FORK) } as isize;
+    if pid == 0 {
+        unsafe { syscall::syscall1(syscall::SYS_EXIT, 0) };
+    }
+    let wpid = unsafe { syscall::syscall1(syscall::SYS_WAIT, 0) } as isize;
+    r.check(
+        "every syscall number is recognized",
+        all_known && pid > 0 && wpid == pid,
+    );
+    r.check(
+        "unknown syscall returns -ENOSYS",
+        unsafe { syscall::syscall0(9999) } as isize == -syscall::ENOSYS,
+    );
+}
+
+// Make syscall num with bad arguments in a child, for a call that disturbs
+// its caller even then: sigreturn loads whatever frame it finds on the
+// stack, and a clone that went wrong would leave a thread for wait to reap.
+// The exit status says whether the kernel knew the number.
+fn recognized_in_child(num: usize) -> bool {
+    let pid = syscall::fork();
+    if pid == 0 {
+        let ret = unsafe { syscall::syscall3(num, usize::MAX, 0, 0) } as isize;
+        syscall::exit((ret == -syscall::ENOSYS) as i32);
+    }
+    let mut status = 0;
+    pid > 0 && syscall::waitpid(pid, Some(&mut status)) == pid && status != 1
+}
+
+// The Stat layout is shared with the kernel through abi; check that each
+// field arrives where userland expects it.
+fn test_fstat(r: &mut Results) {
+    let mut st = fs::Stat::default();
+    let fd = syscall::open("/hello.txt", fs::O_RDONLY);
+    let ok = fd >= 0 && syscall::fstat(fd, &mut st) == 0;
+    if fd >= 0 {
+        syscall::close(fd);
+    }
+    let mut root = fs::Stat::default();
+    let fd = syscall::open("/", fs::O_DIRECTORY);
+    let root_ok = fd >= 0 && syscall::fstat(fd, &mut root) == 0;
+    if fd >= 0 {
+        syscall::close(fd);
+    }
+    r.check("fstat", ok && root_ok);
+    // "Hello Ext2\n", one link, on the root device; ext2's root is inode 2.
+    r.check(
+        "fstat fields",
+        st.typ == fs::T_FILE
+            && st.size == 11
+            && st.nlink == 1
+            && st.ino > 2
+            && st.dev == root.dev
+            && root
+                == fs::Stat {
+                    dev: root.dev,
+                    ino: 2,
+                    typ: fs::T_DIR,
+                    nlink: root.nlink,
+                    size: 1024,
+                },
+    );
+    let mut by_path = fs::Stat::default();
+    r.check(
+        "stat of a path matches fstat",
+        syscall::stat("/hello.txt", &mut by_path) == 0
+            && by_path == st
+            && syscall::stat("/no-such-file", &mut by_path) < 0,
+    );
+    r.check("fstat on a pipe fails", {
+        let mut fds = [0i32; 2];
+        let ok = syscall::pipe(&mut fds) == 0 && syscall::fstat(fds[0], &mut st) < 0;
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+        ok
+    });
+}
+
+// /lines.txt is `seq 1 1000`, which spans several 1K blocks.
+// Directories open only with O_DIRECTORY, and then only for getdents.
+fn test_open_directory(r: &mut Results) {
+    let fd = syscall::open("/", fs::O_DIRECTORY);
+    let mut buf = [0u8; 1024];
+    let mut found = false;
+    let mut read_fails = false;
+    if fd >= 0 {
+        read_fails = syscall::read(fd, &mut buf) < 0;
+        loop {
+            let n = syscall::getdents(fd, &mut buf);
+            if n <= 0 {
+                break;
+            }
+            found |= fs::dirents(&buf[..n as usize]).any(|(_, name)| name == "hello.txt");
+        }
+        syscall::close(fd);
+    }
+    r.check("open directory with O_DIRECTORY", fd >= 0 && found);
+    r.check("read on a directory fails", read_fails);
+    r.check(
+        "O_DIRECTORY on a file fails ENOTDIR",
+        syscall::open("/hello.txt", fs::O_DIRECTORY) == -(syscall::ENOTDIR as i32),
+    );
+    r.check(
+        "plain open of a directory fails EISDIR",
+        syscall::open("/", fs::O_RDONLY) == -(syscall::EISDIR as i32),
+    );
+}
+
+// Files in /many (created by `make fs`): f1 .. f3000.
+const MANY_FILES: usize = 3000;
+
+// List a directory of thousands of entries through a small buffer, so it
+// takes hundreds of getdents calls: every entry must appear exactly once.
+fn test_getdents_large(r: &mut Results) {
+    let fd = syscall::open("/many", fs::O_DIRECTORY);
+    let mut seen = alloc::vec![0u32; MANY_FILES + 1];
+    let mut buf = [0u8; 128];
+    let mut calls = 0;
+    let mut other = 0;
+    let mut ok = fd >= 0;
+    while ok {
+        let n = syscall::getdents(fd, &mut buf);
+        if n <= 0 {
+            ok = n == 0;
+            break;
+        }
+        calls += 1;
+        for (_, name) in fs::dirents(&buf[..n as usize]) {
+            match name.strip_prefix('f').and_then(|i| i.parse::<usize>().ok()) {
+                Some(i) if (1..=MANY_FILES).contains(&i) => seen[i] += 1,
+                _ => other += 1, // ".", ".."
+            }
+        }
+    }
+    syscall::close(fd);
+    r.check(
+        "getdents lists every entry once",
+        ok && calls > 100 && other == 2 && seen[1..].iter().all(|c| *c == 1),
+    );
+}
+
+// /sparse.dat (created by `make fs`) has "sparse" at offset 10000 and only
+// holes before it, which must read as zeros rather than end the file.
+fn test_sparse_read(r: &mut Results) {
+    let fd = syscall::open("/sparse.dat", fs::O_RDONLY);
+    let mut data = Vec::new();
+    let read = fs::read_to_end(fd, &mut data) == Ok(10006);
+    syscall::close(fd);
+    r.check(
+        "holes in a sparse file read as zeros",
+        read && data[..10000].iter().all(|b| *b == 0) && &data[10000..] == b"sparse",
+    );
+}
+
+// Growing a file by 10 blocks takes exactly 10 from the bitmap, and
+// truncating it to 0 gives them all back.
+fn test_block_balance(r: &mut Results) {
+    let fd = syscall::open("/balance.dat", fs::O_CREATE | fs::O_WRONLY);
+    syscall::ftruncate(fd, 0);
+    let before = free_blocks();
+    let wrote = fd >= 0 && io::write_all(fd, &[0x5a; 10 * 1024]).is_ok();
+    let grown = free_blocks();
+    syscall::ftruncate(fd, 0);
+    syscall::close(fd);
+    r.check(
+        "balloc and bfree keep the free count balanced",
+        wrote && before - grown == 10 && free_blocks() == before,
+    );
+}
+
+const TRUNC_SIZE: usize = 100 * 1024;
+
+fn trunc_pattern(i: usize) -> u8 {
+    (i * 7 + i / 1024) as u8
+}
+
+fn free_blocks() -> u32 {
+    let mut st = fs::StatFs::default();
+    syscall::statfs("/", &mut st);
+    st.free_blocks
+}
+
+// Write 100KB, cut it to 1KB and check that its 100 blocks (99 data blocks
+// and the indirect block) are free again. Cutting inside a block and then
+// writing at the old end must read back zeros in between, not the old data.
+fn test_truncate(r: &mut Results) {
+    let fd = syscall::open("/trunc.dat", fs::O_CREATE | fs::O_RDWR);
+    if fd < 0 {
+        r.check("create a file for truncation", false);
+        return;
+    }
+    // Left over from an earlier boot of the same image.
+    syscall::ftruncate(fd, 0);
+    let data: Vec<u8> = (0..TRUNC_SIZE).map(trunc_pattern).collect();
+    let wrote = io::write_all(fd, &data).is_ok();
+    let before = free_blocks();
+    let cut = syscall::ftruncate(fd, 1024) == 0;
+    let after = free_blocks();
+    r.check(
+        "ftruncate frees the blocks past the new end",
+        wrote && cut && after == before + 100,
+    );
+
+    syscall::ftruncate(fd, 1000);
+    // The offset is still at the old end of file.
+    let wrote = io::write_all(fd, b"after").is_ok();
+    syscall::close(fd);
+    let fd = syscall::open("/trunc.dat", fs::O_RDONLY);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    syscall::close(fd);
+    r.check(
+        "writing past a truncated end reads back zeros before it",
+        wrote
+            && read
+            && back.len() == TRUNC_SIZE + 5
+            && back[..1000] == data[..1000]
+            && back[1000..TRUNC_SIZE].iter().all(|b| *b == 0)
+            && &back[TRUNC_SIZE..] == b"after",
+    );
+
+    let fd = syscall::open("/trunc.dat", fs::O_WRONLY);
+    syscall::ftruncate(fd, 0);
+    syscall::close(fd);
+}
+
+// 12 direct blocks and a full indirect block.
+const INDIRECT_FILE: usize = (12 + 256) * 1024;
+
+// Fill every block the direct and single-indirect pointers can address and
+// read it back.
+fn test_indirect(r: &mut Results) {
+    let data: Vec<u8> = (0..INDIRECT_FILE).map(|i| (i / 1024 + i) as u8).collect();
+    let fd = syscall::open("/indirect.dat", fs::O_CREATE | fs::O_WRONLY);
+    syscall::ftruncate(fd, 0);
+    let wrote = fd >= 0 && io::write_all(fd, &data).is_ok();
+    syscall::close(fd);
+    let fd = syscall::open("/indirect.dat", fs::O_RDONLY);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    syscall::close(fd);
+    r.check(
+        "a file using every indirect block reads back intact",
+        wrote && read && back == data,
+    );
+    let fd = syscall::open("/indirect.dat", fs::O_WRONLY);
+    syscall::ftruncate(fd, 0);
+    syscall::close(fd);
+}
+
+const DIND_FILE: usize = 1024 * 1024;
+
+// Write 1MB, which reaches 756 blocks into the double indirect tree, then
+// cut it to 300KB: 724 data blocks and two of the three indirect blocks under
+// the double indirect one are freed. /big.txt is written by mkfs and is also
+// past the single indirect blocks.
+fn test_double_indirect(r: &mut Results) {
+    let data: Vec<u8> = (0..DIND_FILE).map(|i| (i / 1024 * 3 + i) as u8).collect();
+    let fd = syscall::open("/dind.dat", fs::O_CREATE | fs::O_RDWR);
+    syscall::ftruncate(fd, 0);
+    let wrote = fd >= 0 && io::write_all(fd, &data).is_ok();
+    let before = free_blocks();
+    let cut = syscall::ftruncate(fd, 300 * 1024) == 0;
+    let freed = free_blocks() - before;
+    syscall::close(fd);
+    let fd = syscall::open("/dind.dat", fs::O_RDONLY);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    syscall::close(fd);
+    r.check(
+        "a 1MB file uses the double indirect block",
+        wrote && read && back == data[..300 * 1024],
+    );
+    r.check(
+        "truncation frees double indirect blocks",
+        cut && freed == 726,
+    );
+    let fd = syscall::open("/dind.dat", fs::O_WRONLY);
+    syscall::ftruncate(fd, 0);
+    syscall::close(fd);
+
+    let fd = syscall::open("/big.txt", fs::O_RDONLY);
+    let text = fs::read_to_string(fd).unwrap_or_default();
+    syscall::close(fd);
+    r.check(
+        "a large file from mkfs reads back intact",
+        text.lines().count() == 100000
+            && text
+                .lines()
+                .enumerate()
+                .all(|(i, l)| l.parse() == Ok(i + 1)),
+    );
+}
+
+// A name one byte over NAME_MAX must fail ENAMETOOLONG rather than be cut
+// down to NAME_MAX bytes, which would collide with the longest valid name.
+fn test_name_max(r: &mut Results) {
+    let mut longest = String::from("/");
+    longest.extend(core::iter::repeat_n('n', fs::NAME_MAX));
+    let fd = syscall::open(&longest, fs::O_CREATE | fs::O_WRONLY);
+    let wrote = fd >= 0 && io::write_all(fd, b"longest").is_ok();
+    syscall::close(fd);
+    r.check("a NAME_MAX-byte name can be created", wrote);
+
+    let too_long = longest.clone() + "n";
+    r.check(
+        "a name over NAME_MAX fails ENAMETOOLONG",
+        syscall::open(&too_long, fs::O_CREATE | fs::O_WRONLY) == -(syscall::ENAMETOOLONG as i32)
+            && syscall::open(&too_long, fs::O_RDONLY) == -(syscall::ENAMETOOLONG as i32),
+    );
+    let fd = syscall::open(&longest, fs::O_RDONLY);
+    let mut data = Vec::new();
+    let read = fs::read_to_end(fd, &mut data).is_ok();
+    syscall::close(fd);
+    r.check(
+        "the longest name is left untouched",
+        read && data == b"longest",
+    );
+}
+
+// Directories use ext2's variable-length entries. Create names of several
+// lengths in /d1, next to one written by mkfs, and list them back.
+fn test_long_names(r: &mut Results) {
+    let mut names: Vec<String> = [15, 60, 120, fs::NAME_MAX]
+        .iter()
+        .map(|&n| (0..n).map(|i| (b'a' + (i % 26) as u8) as char).collect())
+        .collect();
+    let mut created = true;
+    for name in &names {
+        let fd = syscall::open(&(String::from("/d1/") + name), fs::O_CREATE | fs::O_WRONLY);
+        created &= fd >= 0;
+        syscall::close(fd);
+    }
+    names.push(String::from("a-name-well-past-fourteen-bytes.txt"));
+
+    let mut seen = alloc::vec![0; names.len()];
+    let fd = syscall::open("/d1", fs::O_DIRECTORY);
+    let mut buf = [0u8; 512];
+    loop {
+        let n = syscall::getdents(fd, &mut buf);
+        if n <= 0 {
+            break;
+        }
+        for (_, name) in fs::dirents(&buf[..n as usize]) {
+            if let Some(i) = names.iter().position(|x| x == name) {
+                seen[i] += 1;
+            }
+        }
+    }
+    syscall::close(fd);
+    r.check(
+        "long names are listed back intact",
+        created && fd >= 0 && seen.iter().all(|c| *c == 1),
+    );
+}
+
+fn free_inodes() -> u32 {
+    let mut st = fs::StatFs::default();
+    syscall::statfs("/", &mut st);
+    st.free_inodes
+}
+
+// Unlinking a 10KB file that is still open removes its name at once but
+// keeps the data readable; its blocks and inode come back on the last close.
+fn test_unlink(r: &mut Results) {
+    let data = [0x3c; 10 * 1024];
+    let blocks = free_blocks();
+    let inodes = free_inodes();
+    let fd = syscall::open("/unlink.dat", fs::O_CREATE | fs::O_RDWR);
+    let wrote = fd >= 0 && io::write_all(fd, &data).is_ok();
+    syscall::close(fd);
+
+    let fd = syscall::open("/unlink.dat", fs::O_RDONLY);
+    let unlinked = syscall::unlink("/unlink.dat") == 0;
+    let gone = syscall::open("/unlink.dat", fs::O_RDONLY) < 0;
+    r.check(
+        "unlink removes the name",
+        wrote && fd >= 0 && unlinked && gone,
+    );
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    r.check(
+        "an unlinked file stays readable while open",
+        read && back[..] == data[..],
+    );
+    let held = free_blocks() < blocks;
+    syscall::close(fd);
+    r.check(
+        "the last close frees an unlinked file's blocks and inode",
+        held && free_blocks() == blocks && free_inodes() == inodes,
+    );
+
+    r.check(
+        "unlink refuses directories",
+        syscall::unlink("/d1") < 0 && syscall::unlink("/") < 0 && syscall::unlink("/d1/..") < 0,
+    );
+
+    // The journal lives in /.log's blocks: it can be read, but not
+    // removed, truncated or written.
+    let eperm = -(syscall::EPERM as i32);
+    let fd = syscall::open("/.log", fs::O_RDONLY);
+    let readable = fd >= 0;
+    syscall::close(fd);
+    r.check(
+        "/.log cannot be unlinked, truncated or written",
+        readable
+            && syscall::unlink("/.log") == eperm
+            && syscall::open("/.log", fs::O_WRONLY) == eperm
+            && syscall::open("/.log", fs::O_RDWR | fs::O_TRUNC) == eperm,
+    );
+}
+
+// A new directory lists "." and "..", can hold files, and adds a link to
+// its parent for its "..". Making it again fails.
+// mkfs puts /console in the image; a node made at run time works the same.
+fn test_mknod(r: &mut Results) {
+    let is_console = |path: &str| {
+        let fd = syscall::open(path, fs::O_RDWR);
+        let mut st = fs::Stat::default();
+        let ok = fd >= 0
+            && syscall::fstat(fd, &mut st) == 0
+            && st.typ == fs::T_DEV
+            && syscall::write(fd, b"\n") == 1;
+        syscall::close(fd);
+        ok
+    };
+    r.check("/console is the console device", is_console("/console"));
+
+    let path = "/d1/cons";
+    syscall::unlink(path); // Left over from an earlier boot
+    let inodes = free_inodes();
+    let made = syscall::mknod(path, fs::CONSOLE_MAJOR, 0) == 0;
+    r.check("mknod creates a device node", made && is_console(path));
+    r.check(
+        "mknod of an existing name fails",
+        syscall::mknod(path, fs::CONSOLE_MAJOR, 0) < 0,
+    );
+    r.check(
+        "unlink of a device node frees its inode",
+        syscall::unlink(path) == 0 && free_inodes() == inodes,
+    );
+}
+
+fn test_mkdir(r: &mut Results) {
+    let parent_links = || {
+        let fd = syscall::open("/d1", fs::O_DIRECTORY);
+        let mut st = fs::Stat::default();
+        syscall::fstat(fd, &mut st);
+        syscall::close(fd);
+        st.nlink
+    };
+    let links = parent_links();
+    // Left over from an earlier boot of the same image, the directory
+    // cannot be removed; start from a name nobody used yet.
+    let mut path = String::new();
+    for i in 0.. {
+        path = alloc::format!("/d1/new{}", i);
+        let fd = syscall::open(&path, fs::O_DIRECTORY);
+        if fd < 0 {
+            break;
+        }
+        syscall::close(fd);
+    }
+    let made = syscall::mkdir(&path) == 0;
+    r.check("mkdir creates a directory", made);
+    r.check("mkdir of an existing name fails", syscall::mkdir(&path) < 0);
+    r.check(
+        "mkdir adds a link to the parent",
+        parent_links() == links + 1,
+    );
+
+    let fd = syscall::open(&(path.clone() + "/f"), fs::O_CREATE | fs::O_WRONLY);
+    syscall::close(fd);
+    let mut names = Vec::new();
+    let dfd = syscall::open(&path, fs::O_DIRECTORY);
+    let mut buf = [0u8; 512];
+    let n = syscall::getdents(dfd, &mut buf);
+    if n > 0 {
+        for (_, name) in fs::dirents(&buf[..n as usize]) {
+            names.push(String::from(name));
+        }
+    }
+    syscall::close(dfd);
+    r.check(
+        "a new directory holds ., .. and what is created in it",
+        fd >= 0 && names == [".", "..", "f"],
+    );
+    let fd = syscall::open(
+        &(path + "/../a-name-well-past-fourteen-bytes.txt"),
+        fs::O_RDONLY,
+    );
+    syscall::close(fd);
+    r.check("a new directory's .. is its parent", fd >= 0);
+}
+
+fn opens(path: &str) -> bool {
+    let fd = syscall::open(path, fs::O_RDONLY);
+    syscall::close(fd);
+    fd >= 0
+}
+
+// Relative paths start from the working directory, which fork passes on.
+fn test_chdir(r: &mut Results) {
+    let name = "a-name-well-past-fourteen-bytes.txt";
+    r.check("a relative path starts at / by default", opens("hello.txt"));
+    let changed = syscall::chdir("/d1") == 0;
+    r.check(
+        "chdir makes relative paths start there",
+        changed && opens(name) && !opens("hello.txt") && opens("/hello.txt"),
+    );
+    let mut fds = [0i32; 2];
+    syscall::pipe(&mut fds);
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::write(fds[1], if opens(name) { b"y" } else { b"n" });
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 1];
+    let n = syscall::read(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("fork keeps the working directory", n == 1 && buf[0] == b'y');
+    r.check(
+        "chdir follows .. and refuses files",
+        syscall::chdir(name) < 0 && syscall::chdir("..") == 0 && opens("hello.txt"),
+    );
+    syscall::chdir("/");
+}
+
+// Seek around /lines.txt (`seq 1 1000`), and past the end of a new file,
+// which leaves a hole that reads back as zeros.
+fn test_lseek(r: &mut Results) {
+    let fd = syscall::open("/lines.txt", fs::O_RDONLY);
+    let mut buf = [0u8; 4];
+    let end = syscall::lseek(fd, 0, fs::SEEK_END);
+    let set = syscall::lseek(fd, 10, fs::SEEK_SET);
+    let n = syscall::read(fd, &mut buf);
+    r.check(
+        "lseek SEEK_SET and SEEK_END",
+        fd >= 0 && end == 3893 && set == 10 && n == 4 && &buf == b"6\n7\n",
+    );
+    let cur = syscall::lseek(fd, -4, fs::SEEK_CUR);
+    let n = syscall::read(fd, &mut buf);
+    r.check("lseek SEEK_CUR", cur == 10 && n == 4 && &buf == b"6\n7\n");
+    r.check(
+        "lseek refuses a negative offset or unknown whence",
+        syscall::lseek(fd, -1, fs::SEEK_SET) == -(syscall::EINVAL as i64)
+            && syscall::lseek(fd, 0, 7) == -(syscall::EINVAL as i64)
+            && syscall::lseek(fd, 0, fs::SEEK_CUR) == 14,
+    );
+    syscall::close(fd);
+
+    let mut fds = [0i32; 2];
+    syscall::pipe(&mut fds);
+    r.check(
+        "lseek on a pipe fails",
+        syscall::lseek(fds[0], 0, fs::SEEK_SET) == -(syscall::ESPIPE as i64),
+    );
+    syscall::close(fds[0]);
+    syscall::close(fds[1]);
+
+    let fd = syscall::open("/seek.dat", fs::O_CREATE | fs::O_RDWR);
+    syscall::ftruncate(fd, 0);
+    syscall::lseek(fd, 5000, fs::SEEK_SET);
+    syscall::write(fd, b"end");
+    syscall::lseek(fd, 0, fs::SEEK_SET);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back) == Ok(5003);
+    syscall::close(fd);
+    syscall::unlink("/seek.dat");
+    r.check(
+        "a write past the end after lseek leaves a hole of zeros",
+        read && back[..5000].iter().all(|b| *b == 0) && &back[5000..] == b"end",
+    );
+}
+
+// O_TRUNC empties the file, O_APPEND writes at its end wherever the offset
+// is, and the access mode decides which of read and write is allowed.
+fn test_open_flags(r: &mut Results) {
+    let path = "/flags.dat";
+    let fd = syscall::open(path, fs::O_CREATE | fs::O_WRONLY | fs::O_TRUNC);
+    syscall::write(fd, b"0123456789");
+    syscall::close(fd);
+    let fd = syscall::open(path, fs::O_WRONLY | fs::O_TRUNC);
+    syscall::write(fd, b"ab");
+    syscall::close(fd);
+    let fd = syscall::open(path, fs::O_RDONLY);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    syscall::close(fd);
+    r.check("O_TRUNC empties the file", read && back == b"ab");
+
+    let fd = syscall::open(path, fs::O_RDWR | fs::O_APPEND);
+    syscall::lseek(fd, 0, fs::SEEK_SET);
+    syscall::write(fd, b"cd");
+    syscall::lseek(fd, 0, fs::SEEK_SET);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    syscall::close(fd);
+    r.check("O_APPEND writes at the end", read && back == b"abcd");
+
+    let mut buf = [0u8; 1];
+    let rd = syscall::open(path, fs::O_RDONLY);
+    let wr = syscall::open(path, fs::O_WRONLY);
+    r.check(
+        "the access mode limits read and write",
+        syscall::write(rd, b"x") < 0 && syscall::read(wr, &mut buf) < 0,
+    );
+    syscall::close(rd);
+    syscall::close(wr);
+    // O_TRUNC needs write access.
+    let fd = syscall::open(path, fs::O_RDONLY | fs::O_TRUNC);
+    syscall::close(fd);
+    let mut st = fs::Stat::default();
+    syscall::stat(path, &mut st);
+    r.check("O_TRUNC without write access keeps the data", st.size == 4);
+    syscall::unlink(path);
+}
+
+// kill ends a child whether it is asleep in the kernel or running in user
+// mode, and fails for a pid that is not there.
+fn test_kill(r: &mut Results) {
+    let sleeper = syscall::fork();
+    if sleeper == 0 {
+        syscall::sleep(1_000_000);
+        syscall::exit(0);
+    }
+    let spinner = syscall::fork();
+    if spinner == 0 {
+        loop {
+            spin(100_000);
+        }
+    }
+    syscall::sleep(2); // Let both get going
+    let killed = syscall::kill(sleeper, signal::SIGKILL) == 0
+        && syscall::kill(spinner, signal::SIGKILL) == 0;
+    let a = syscall::wait(None);
+    let b = syscall::wait(None);
+    r.check(
+        "kill ends a sleeping and a running child",
+        killed && ((a, b) == (sleeper, spinner) || (a, b) == (spinner, sleeper)),
+    );
+    r.check(
+        "kill of a pid not in use fails",
+        syscall::kill(sleeper, signal::SIGKILL) < 0,
+    );
+}
+
+// Several processes forking and reaping at once, on however many CPUs,
+// each get back exactly their own children's statuses.
+fn test_fork_storm(r: &mut Results) {
+    let mut parents = [0; 4];
+    for (i, pid) in parents.iter_mut().enumerate() {
+        *pid = syscall::fork();
+        if *pid == 0 {
+            let mut sum = 0;
+            for j in 0..20 {
+                let child = syscall::fork();
+                if child == 0 {
+                    syscall::exit((i * 20 + j) as i32 % 64);
+                }
+                let mut status = -1;
+                if syscall::waitpid(child, Some(&mut status)) != child {
+                    syscall::exit(-2);
+                }
+                sum += status;
+            }
+            syscall::exit(sum);
+        }
+    }
+    let ok = parents.iter().enumerate().all(|(i, &pid)| {
+        let expect: i32 = (0..20).map(|j| (i * 20 + j) as i32 % 64).sum();
+        let mut status = 0;
+        pid > 0 && syscall::waitpid(pid, Some(&mut status)) == pid && status == expect
+    });
+    r.check("concurrent fork and wait", ok);
+}
+
+fn test_threads(r: &mut Results) {
+    use core::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+    static GO: AtomicBool = AtomicBool::new(false);
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static FD: AtomicI32 = AtomicI32::new(-1);
+
+    // Threads see each other's writes, and the heap is safe to share.
+    let threads: Vec<_> = (0..4)
+        .filter_map(|_| {
+            ulib::thread::spawn(|| {
+                while !GO.load(Ordering::Acquire) {
+                    core::hint::spin_loop();
+                }
+                for i in 0..500 {
+                    let v = alloc::vec![1; i % 50 + 1];
+                    COUNT.fetch_add(v[i % 50], Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    GO.store(true, Ordering::Release);
+    let spawned = threads.len();
+    let joined = threads
+        .into_iter()
+        .map(|t| t.join())
+        .filter(|&s| s == Some(0))
+        .count();
+    r.check("threads spawn and join", spawned == 4 && joined == 4);
+    r.check(
+        "threads share memory",
+        COUNT.load(Ordering::Relaxed) == 4 * 500,
+    );
+
+    // An fd opened by a thread stays open for the others.
+    let t = ulib::thread::spawn(|| FD.store(syscall::dup(0), Ordering::Relaxed));
+    let joined = t.and_then(|t| t.join()) == Some(0);
+    let fd = FD.load(Ordering::Relaxed);
+    let shared = joined && fd >= 0 && syscall::close(fd) == 0;
+    r.check("threads share open files", shared);
+
+    // The entry and stack become the thread's rip and rsp, so both must be
+    // user addresses.
+    let kernel = 0xffff_8000_0000_0000usize;
+    let stack = 0x1000_0000usize;
+    let bad = |entry, stack| {
+        let ret = unsafe { syscall::syscall3(syscall::SYS_CLONE, entry, stack, 0) };
+        ret as isize == -syscall::EINVAL
+    };
+    r.check(
+        "clone refuses an entry or stack outside user space",
+        bad(kernel, stack) && bad(handler(), kernel) && bad(handler(), 0),
+    );
+}
+
+fn test_pgid(r: &mut Results) {
+    let me = syscall::getpgid(0);
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::setpgid(0, 0);
+        syscall::sleep(5);
+        syscall::exit(syscall::getpgid(0));
+    }
+    let set = syscall::setpgid(pid, pid) == 0 && syscall::getpgid(pid) == pid;
+    let mut status = 0;
+    syscall::waitpid(pid, Some(&mut status));
+    r.check(
+        "setpgid moves a child to a group of its own",
+        set && status == pid,
+    );
+    r.check("fork passes on the process group", me > 0 && me != pid);
+    r.check(
+        "setpgid only moves the caller and its children",
+        syscall::setpgid(1, 0) == -syscall::ESRCH as i32,
+    );
+
+    let mut fds = [0i32; 2];
+    syscall::pipe(&mut fds);
+    let notty = syscall::tcsetpgrp(fds[0], me) == -syscall::ENOTTY as i32;
+    syscall::close(fds[0]);
+    syscall::close(fds[1]);
+    r.check("ioctl on a pipe fails with ENOTTY", notty);
+}
+
+fn test_termios(r: &mut Results) {
+    let mut saved = tty::Termios::default();
+    let got = syscall::tcgetattr(0, &mut saved) == 0;
+    r.check(
+        "the console starts in line mode with echo",
+        got && saved.lflag & (tty::ICANON | tty::ECHO) == tty::ICANON | tty::ECHO,
+    );
+
+    let raw = tty::Termios {
+        lflag: saved.lflag & !(tty::ICANON | tty::ECHO),
+    };
+    let set = syscall::tcsetattr(0, &raw) == 0;
+    let mut now = tty::Termios::default();
+    syscall::tcgetattr(0, &mut now);
+    syscall::tcsetattr(0, &saved);
+    let mut back = tty::Termios::default();
+    syscall::tcgetattr(0, &mut back);
+    r.check(
+        "TCSETS turns off echo and line mode, and back on",
+        set && now.lflag == raw.lflag && back.lflag == saved.lflag,
+    );
+}
+
+// Each virtual console has its own settings and foreground group: init
+// runs a shell on tty1, which made its own group the foreground there.
+fn test_vt(r: &mut Results) {
+    let tty1 = syscall::open("/dev/tty1", fs::O_RDWR);
+    let mut raw = tty::Termios::default();
+    syscall::tcgetattr(tty1, &mut raw);
+    let saved = raw;
+    raw.lflag &= !(tty::ICANON | tty::ECHO);
+    syscall::tcsetattr(tty1, &raw);
+    let mut here = tty::Termios::default();
+    syscall::tcgetattr(0, &mut here);
+    syscall::tcsetattr(tty1, &saved);
+    r.check(
+        "a console's settings are its own",
+        tty1 >= 0 && here.lflag & tty::ICANON != 0,
+    );
+
+    let fg = syscall::tcgetpgrp(tty1);
+    r.check(
+        "a console's foreground group is its own",
+        fg > 0 && fg != syscall::tcgetpgrp(0),
+    );
+    syscall::close(tty1);
+
+    let path = "/d1/tty9";
+    syscall::unlink(path); // Left over from an earlier boot
+    syscall::mknod(path, fs::CONSOLE_MAJOR, 9);
+    r.check(
+        "open of a console past NTTY fails with ENXIO",
+        syscall::open(path, fs::O_RDWR) == -syscall::ENXIO as i32,
+    );
+    syscall::unlink(path);
+}
+
+// /dev/fb fails to open without a GPU, which `make test` has none of; with
+// one, a pixel written reads back.
+fn test_fb(r: &mut Results) {
+    let fd = syscall::open("/dev/fb", fs::O_RDWR);
+    if fd < 0 {
+        r.check(
+            "/dev/fb without a GPU fails with ENXIO",
+            fd == -syscall::ENXIO as i32,
+        );
+        return;
+    }
+    let mut info = fb::FbInfo::default();
+    let got = syscall::ioctl(
+        fd,
+        fb::FBIOGET_VSCREENINFO,
+        &mut info as *mut fb::FbInfo as usize,
+    ) == 0;
+    r.check(
+        "FBIOGET_VSCREENINFO describes the framebuffer",
+        got && info.bpp == 32 && info.stride >= info.width * 4 && info.height > 0,
+    );
+    let last = (info.stride * info.height - 4) as i64;
+    let px = 0x00ff8000u32.to_le_bytes();
+    let mut back = [0u8; 4];
+    let ok = syscall::lseek(fd, last, fs::SEEK_SET) == last
+        && syscall::write(fd, &px) == 4
+        && syscall::write(fd, &px) == 0
+        && syscall::lseek(fd, last, fs::SEEK_SET) == last
+        && syscall::read(fd, &mut back) == 4;
+    syscall::close(fd);
+    r.check("a pixel written to /dev/fb reads back", ok && back == px);
+}
+
+// A datagram to the host's own address comes back through the stack; one
+// to the gateway needs an ARP answer from QEMU first. Without a network
+// device, socket() works but sendto fails with ENETDOWN.
+fn test_udp(r: &mut Results) {
+    let a = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    let b = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    r.check("socket makes UDP sockets", a >= 0 && b >= 0);
+    if a < 0 || b < 0 {
+        return;
+    }
+    r.check(
+        "socket refuses other families",
+        syscall::socket(10, net::SOCK_DGRAM, 0) == -syscall::EAFNOSUPPORT as i32,
+    );
+    let here = net::SockAddrIn::new([10, 0, 2, 15], 7777);
+    r.check(
+        "bind takes a free port and refuses a taken one",
+        syscall::bind(a, &here) == 0 && syscall::bind(b, &here) == -syscall::EADDRINUSE as i32,
+    );
+
+    let sent = syscall::sendto(b, b"ping", &here);
+    if sent == -syscall::ENETDOWN {
+        r.check("sendto without a network device fails with ENETDOWN", true);
+    } else {
+        let mut buf = [0u8; 16];
+        let mut from = net::SockAddrIn::default();
+        let n = syscall::recvfrom(a, &mut buf, 0, Some(&mut from));
+        r.check(
+            "a datagram to ourselves is received with its sender",
+            sent == 4
+                && n == 4
+                && &buf[..4] == b"ping"
+                && from.addr == [10, 0, 2, 15]
+                && from.port() >= net::EPHEMERAL_PORT,
+        );
+        let gw = net::SockAddrIn::new([10, 0, 2, 2], 9);
+        r.check(
+            "sendto the gateway resolves it with ARP",
+            syscall::sendto(b, b"discard", &gw) == 7,
+        );
+    }
+    r.check(
+        "socket calls on other fds fail with ENOTSOCK",
+        syscall::bind(0, &here) == -syscall::ENOTSOCK as i32,
+    );
+    syscall::close(a);
+    syscall::close(b);
+
+    let c = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    r.check(
+        "a closed socket's port is free again",
+        syscall::bind(c, &here) == 0,
+    );
+    syscall::close(c);
+}
+
+// A connected UDP socket is an fd like a pipe's: write sends to the peer,
+// read takes what the peer sends, and nobody else's datagrams.
+fn test_udp_connect(r: &mut Results) {
+    let a = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    let b = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    let other = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    if a < 0 || b < 0 || other < 0 {
+        r.check("socket makes UDP sockets to connect", false);
+        return;
+    }
+    r.check(
+        "write on an unconnected UDP socket fails with EDESTADDRREQ",
+        syscall::write(a, b"x") == -syscall::EDESTADDRREQ,
+    );
+    let addr_a = net::SockAddrIn::new([10, 0, 2, 15], 7780);
+    let addr_b = net::SockAddrIn::new([10, 0, 2, 15], 7781);
+    syscall::bind(a, &addr_a);
+    syscall::bind(b, &addr_b);
+    let err = syscall::connect(a, &addr_b);
+    if err == -syscall::ENETDOWN as i32 {
+        r.check("connect without a network device fails with ENETDOWN", true);
+    } else {
+        syscall::connect(b, &addr_a);
+        let (mut name, mut peer) = (net::SockAddrIn::default(), net::SockAddrIn::default());
+        r.check(
+            "getsockname and getpeername of a connected UDP socket",
+            err == 0
+                && syscall::getsockname(a, &mut name) == 0
+                && syscall::getpeername(a, &mut peer) == 0
+                && name == addr_a
+                && peer == addr_b,
+        );
+        // Sent first, so that it would be read first if it were taken.
+        syscall::sendto(other, b"stray", &addr_b);
+        let mut buf = [0u8; 16];
+        let n = if syscall::write(a, b"hello") == 5 {
+            syscall::read(b, &mut buf)
+        } else {
+            -1
+        };
+        r.check(
+            "write and read on connected UDP sockets, ignoring others",
+            n == 5 && &buf[..5] == b"hello",
+        );
+        let n = if syscall::send(b, b"back", 0) == 4 {
+            syscall::recv(a, &mut buf, 0)
+        } else {
+            -1
+        };
+        r.check(
+            "send and recv on connected UDP sockets",
+            n == 4 && &buf[..4] == b"back",
+        );
+    }
+    let mut peer = net::SockAddrIn::default();
+    r.check(
+        "getpeername of an unconnected socket fails with ENOTCONN",
+        syscall::getpeername(other, &mut peer) == -(syscall::ENOTCONN as i32),
+    );
+    syscall::close(a);
+    syscall::close(b);
+    syscall::close(other);
+}
+
+// A connection to the host's own address: what a child writes to one end,
+// more than the buffers hold, is read from the other in order, and its
+// close reads as end of file. Nobody listening refuses a connect.
+fn test_tcp(r: &mut Results) {
+    let l = syscall::socket(net::AF_INET, net::SOCK_STREAM, 0);
+    let here = net::SockAddrIn::new([10, 0, 2, 15], 7778);
+    let ok = l >= 0 && syscall::bind(l, &here) == 0 && syscall::listen(l, 1) == 0;
+    r.check("a TCP socket listens", ok);
+    if !ok {
+        syscall::close(l);
+        return;
+    }
+    let c = syscall::socket(net::AF_INET, net::SOCK_STREAM, 0);
+    let err = syscall::connect(c, &here);
+    if err == -syscall::ENETDOWN as i32 {
+        r.check("connect without a network device fails with ENETDOWN", true);
+        syscall::close(c);
+        syscall::close(l);
+        return;
+    }
+    let mut from = net::SockAddrIn::default();
+    let a = syscall::accept(l, Some(&mut from));
+    r.check(
+        "connect and accept over the loopback",
+        err == 0 && a >= 0 && from.addr == [10, 0, 2, 15],
+    );
+    if err != 0 || a < 0 {
+        syscall::close(c);
+        syscall::close(l);
+        return;
+    }
+    let (mut name, mut peer) = (net::SockAddrIn::default(), net::SockAddrIn::default());
+    r.check(
+        "the accepted socket's peer is the connecting one",
+        syscall::getsockname(c, &mut name) == 0
+            && syscall::getpeername(a, &mut peer) == 0
+            && name == peer
+            && syscall::getpeername(c, &mut peer) == 0
+            && peer == here,
+    );
+
+    let pid = syscall::fork();
+    if pid == 0 {
+        let buf = [7u8; 1000];
+        for _ in 0..20 {
+            if io::write_all(c, &buf).is_err() {
+                syscall::exit(1);
+            }
+        }
+        syscall::exit(0);
+    }
+    syscall::close(c);
+    let (mut total, mut same) = (0, true);
+    let mut buf = [0u8; 512];
+    loop {
+        let n = syscall::read(a, &mut buf);
+        if n <= 0 {
+            break;
+        }
+        same &= buf[..n as usize].iter().all(|&b| b == 7);
+        total += n as usize;
+    }
+    let mut status = 0;
+    syscall::waitpid(pid, Some(&mut status));
+    r.check(
+        "20000 bytes arrive in order, then end of file",
+        total == 20000 && same && status == 0,
+    );
+    syscall::close(a);
+
+    let s = syscall::socket(net::AF_INET, net::SOCK_STREAM, 0);
+    let nobody = net::SockAddrIn::new([10, 0, 2, 15], 7779);
+    r.check(
+        "connect to a closed port is refused",
+        syscall::connect(s, &nobody) == -syscall::ECONNREFUSED as i32,
+    );
+    syscall::close(s);
+    syscall::close(l);
+}
+
+// An echo request to the host's own address, on a ping socket, is
+// answered with its sequence number and data; the kernel gives it the
+// socket's identifier.
+fn test_ping(r: &mut Results) {
+    let fd = syscall::socket(net::AF_INET, net::SOCK_DGRAM, net::IPPROTO_ICMP);
+    r.check("socket makes ping sockets", fd >= 0);
+    if fd < 0 {
+        return;
+    }
+    let here = net::SockAddrIn::new([10, 0, 2, 15], 0);
+    r.check(
+        "a ping socket sends echo requests only",
+        syscall::sendto(fd, &[net::ICMP_ECHO_REPLY; 8], &here) == -syscall::EINVAL,
+    );
+    let mut req = [0u8; 8 + 32];
+    req[0] = net::ICMP_ECHO;
+    req[6..8].copy_from_slice(&7u16.to_be_bytes());
+    req[8..].fill(0x5A);
+    let sent = syscall::sendto(fd, &req, &here);
+    if sent == -syscall::ENETDOWN {
+        r.check("ping without a network device fails with ENETDOWN", true);
+        syscall::close(fd);
+        return;
+    }
+    let mut reply = [0u8; 64];
+    let mut from = net::SockAddrIn::default();
+    let n = syscall::recvfrom(fd, &mut reply, 0, Some(&mut from));
+    let mut name = net::SockAddrIn::default();
+    syscall::getsockname(fd, &mut name);
+    r.check(
+        "an echo request to ourselves is answered",
+        sent == req.len() as isize
+            && n == req.len() as isize
+            && from.addr == [10, 0, 2, 15]
+            && reply[0] == net::ICMP_ECHO_REPLY
+            && reply[4..6] == name.port.to_ne_bytes()
+            && reply[6..8] == 7u16.to_be_bytes()
+            && reply[8..n as usize] == req[8..],
+    );
+    syscall::close(fd);
+}
+
+// The resolver's query and the parsing of an answer, on a message made up
+// here, and the interface configuration it takes its server from. Needs no
+// DNS server.
+fn test_dns(r: &mut Results) {
+    let mut msg = [0u8; dns::MAX_MSG];
+    let len = dns::query(0x1234, "www.example.com", &mut msg).unwrap_or(0);
+    r.check(
+        "a DNS query asks for the A records of the name",
+        len == 12 + 17 + 4 && &msg[12..29] == b"\x03www\x07example\x03com\x00",
+    );
+    r.check(
+        "a DNS query refuses an empty label",
+        dns::query(1, "a..b", &mut msg).is_none(),
+    );
+
+    // A CNAME, then an A record for the name it gives, both pointing back
+    // at the question's name.
+    let mut answer = msg;
+    answer[2..4].copy_from_slice(&[0x81, 0x80]); // Response, no error
+    answer[7] = 2;
+    let records: &[u8] = &[
+        0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 12, // CNAME
+        0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34, // A
+    ];
+    answer[len..len + records.len()].copy_from_slice(records);
+    let answer = &answer[..len + records.len()];
+    r.check(
+        "the A records of a DNS answer are found past a CNAME",
+        dns::answers(0x1234, answer).as_deref() == Ok(&[[93, 184, 216, 34]][..]),
+    );
+    r.check(
+        "a DNS answer to another query is refused",
+        dns::answers(0x4321, answer) == Err(dns::Error::Failed),
+    );
+    let mut nxdomain = msg;
+    nxdomain[2..4].copy_from_slice(&[0x81, 0x83]);
+    r.check(
+        "NXDOMAIN is not found",
+        dns::answers(0x1234, &nxdomain[..len]) == Err(dns::Error::NotFound),
+    );
+    r.check(
+        "addresses need no lookup",
+        dns::resolve("10.0.2.2") == Ok([10, 0, 2, 2])
+            && dns::resolve("localhost") == Ok([127, 0, 0, 1]),
+    );
+
+    let s = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    let mut conf = net::NetConf::default();
+    let ok = syscall::netconf(s, &mut conf) == 0;
+    r.check(
+        "the interface has a DNS server once it has an address",
+        ok && (conf.up == 0 || conf.addr == [0; 4] || conf.dns != [0; 4]),
+    );
+    let mut buf = [0u8; 16];
+    r.check(
+        "recvfrom with MSG_DONTWAIT on an empty socket fails with EAGAIN",
+        syscall::recvfrom(s, &mut buf, net::MSG_DONTWAIT, None) == -syscall::EAGAIN,
+    );
+    syscall::close(s);
+}
+
+fn test_nice(r: &mut Results) {
+    let base = syscall::nice(0);
+    let niced = syscall::nice(5);
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::exit(syscall::nice(0));
+    }
+    let mut inherited = 0;
+    syscall::waitpid(pid, Some(&mut inherited));
+    let max = syscall::nice(100);
+    let min = syscall::nice(-100);
+    syscall::nice(base - min);
+    r.check("nice adds to the nice value", niced == base + 5);
+    r.check("fork passes on the nice value", inherited == niced);
+    r.check(
+        "nice stays within its range",
+        max == syscall::NICE_MAX as i32 && min == syscall::NICE_MIN as i32,
+    );
+
+    // A process that mostly sleeps keeps running promptly beside CPU hogs.
+    let mut hogs = [0; 4];
+    for pid in hogs.iter_mut() {
+        *pid = syscall::fork();
+        if *pid == 0 {
+            loop {
+                spin(100_000);
+            }
+        }
+    }
+    let start = syscall::uptime();
+    for _ in 0..10 {
+        syscall::sleep(1);
+    }
+    let elapsed = syscall::uptime() - start;
+    for &pid in hogs.iter() {
+        syscall::kill(pid, signal::SIGKILL);
+        syscall::waitpid(pid, None);
+    }
+    r.check("a sleeper is not starved by CPU hogs", elapsed < 50);
+}
+
+fn test_wait_status(r: &mut Results) {
+    let first = syscall::fork();
+    if first == 0 {
+        syscall::sleep(2);
+        syscall::exit(7);
+    }
+    let second = syscall::fork();
+    if second == 0 {
+        syscall::exit(42);
+    }
+    let mut status = 0;
+    let pid = syscall::waitpid(first, Some(&mut status));
+    r.check(
+        "waitpid waits for the given child",
+        pid == first && status == 7,
+    );
+    let pid = syscall::wait(Some(&mut status));
+    r.check(
+        "wait returns the exit status",
+        pid == second && status == 42,
+    );
+    r.check(
+        "waitpid of a pid that is not a child fails",
+        syscall::waitpid(second, None) < 0 && syscall::waitpid(1, None) < 0,
+    );
+
+    let child = syscall::fork();
+    if child == 0 {
+        loop {
+            spin(100_000);
+        }
+    }
+    syscall::kill(child, signal::SIGKILL);
+    r.check(
+        "a killed child exits with status -1",
+        syscall::waitpid(child, Some(&mut status)) == child && status == -1,
+    );
+}
+
+fn free_pages() -> u64 {
+    let mut info = syscall::SysInfo::default();
+    syscall::sysinfo(&mut info);
+    info.free_pages
+}
+
+// A child that grows its heap, then execs and exits, gives back every page
+// it had once it is waited for: its kernel stack, page tables, old image
+// and new one.
+fn test_reclaim(r: &mut Results) {
+    let path = b"/sleep\0";
+    let argv = [path.as_ptr(), b"0\0".as_ptr(), core::ptr::null()];
+    let child = || {
+        if syscall::fork() == 0 {
+            syscall::sbrk(16 * 4096);
+            syscall::exec(path.as_ptr(), &argv);
+            syscall::exit(1);
+        }
+        let mut status = -1;
+        syscall::wait(Some(&mut status));
+        status == 0
+    };
+    child(); // Anything allocated once and kept stays out of the count
+    let before = free_pages();
+    let mut ok = true;
+    for _ in 0..10 {
+        ok &= child();
+    }
+    let after = free_pages();
+    r.check(
+        "fork, exec and exit give back their memory",
+        ok && after == before,
+    );
+}
+
+const RW: u32 = mman::PROT_READ | mman::PROT_WRITE;
+
+// Whether touching addr, writing if write, kills a child.
+fn access_kills(addr: usize, write: bool) -> bool {
+    let pid = syscall::fork();
+    if pid == 0 {
+        let p = addr as *mut u8;
+        unsafe {
+            if write {
+                p.write_volatile(1);
+            } else {
+                p.read_volatile();
+            }
+        }
+        syscall::exit(0);
+    }
+    let mut status = 0;
+    syscall::waitpid(pid, Some(&mut status));
+    status == -1
+}
+
+fn test_mmap_anon(r: &mut Results) {
+    let len = 3 * 4096;
+    let map = || {
+        let a = syscall::mmap(len, RW, mman::MAP_PRIVATE | mman::MAP_ANONYMOUS, -1, 0);
+        if a < 0 {
+            return None;
+        }
+        let mem = unsafe { core::slice::from_raw_parts_mut(a as *mut u8, len) };
+        let zeroed = mem.iter().all(|&b| b == 0);
+        mem.fill(0x5a);
+        Some((a as usize, zeroed && mem.iter().all(|&b| b == 0x5a)))
+    };
+    // The first mapping may add page tables, which stay.
+    if let Some((a, _)) = map() {
+        syscall::munmap(a, len);
+    }
+    let before = free_pages();
+    let Some((a, ok)) = map() else {
+        r.check("mmap anonymous memory", false);
+        return;
+    };
+    // A hole in the middle leaves the pages on both sides.
+    let punched = syscall::munmap(a + 4096, 4096) == 0
+        && access_kills(a + 4096, false)
+        && !access_kills(a + 2 * 4096, true);
+    syscall::munmap(a, len);
+    let after = free_pages();
+    r.check("mmap anonymous memory", ok);
+    r.check("munmap of part of a mapping", punched);
+    r.check("munmap frees the pages", after == before);
+    r.check("an unmapped page cannot be used", access_kills(a, false));
+
+    r.check(
+        "mmap rejects bad arguments",
+        syscall::mmap(0, RW, mman::MAP_PRIVATE | mman::MAP_ANONYMOUS, -1, 0) == -syscall::EINVAL
+            && syscall::mmap(len, RW, mman::MAP_ANONYMOUS, -1, 0) == -syscall::EINVAL
+            && syscall::munmap(a + 1, 4096) as isize == -syscall::EINVAL,
+    );
+}
+
+fn test_mmap_file(r: &mut Results) {
+    let a = syscall::open("/hello.txt", fs::O_RDONLY);
+    let m = syscall::mmap(4096, mman::PROT_READ, mman::MAP_PRIVATE, a, 0);
+    syscall::close(a); // The mapping keeps the file
+    let page = unsafe { core::slice::from_raw_parts(m as *const u8, 4096) };
+    r.check(
+        "mmap of a file reads it in",
+        m > 0 && &page[..11] == b"Hello Ext2\n" && page[11..].iter().all(|&b| b == 0),
+    );
+    r.check(
+        "writing a read-only mapping kills",
+        access_kills(m as usize, true),
+    );
+    syscall::munmap(m as usize, 4096);
+
+    let path = "/mmap.dat";
+    let fd = syscall::open(path, fs::O_CREATE | fs::O_RDWR | fs::O_TRUNC);
+    syscall::write(fd, &[b'a'; 6000]);
+    let shared = syscall::mmap(8192, RW, mman::MAP_SHARED, fd, 0) as usize;
+    let private = syscall::mmap(8192, RW, mman::MAP_PRIVATE, fd, 0) as usize;
+    unsafe {
+        *((shared + 100) as *mut u8) = b'b';
+        *((shared + 5000) as *mut u8) = b'c';
+        *((private + 200) as *mut u8) = b'd';
+    }
+    syscall::munmap(shared, 8192);
+    syscall::munmap(private, 8192);
+    // A child's shared mapping is written back when it exits.
+    if syscall::fork() == 0 {
+        let m = syscall::mmap(4096, RW, mman::MAP_SHARED, fd, 4096) as usize;
+        unsafe { *((m + 10) as *mut u8) = b'e' };
+        syscall::exit(0);
+    }
+    syscall::wait(None);
+    syscall::lseek(fd, 0, fs::SEEK_SET);
+    let mut back = Vec::new();
+    let read = fs::read_to_end(fd, &mut back).is_ok();
+    let mut want = [b'a'; 6000];
+    want[100] = b'b';
+    want[5000] = b'c';
+    want[4096 + 10] = b'e';
+    r.check(
+        "MAP_SHARED writes reach the file, MAP_PRIVATE ones do not",
+        read && back == want,
+    );
+
+    let ro = syscall::open(path, fs::O_RDONLY);
+    let mut fds = [0i32; 2];
+    syscall::pipe(&mut fds);
+    r.check(
+        "mmap checks the file",
+        syscall::mmap(4096, RW, mman::MAP_SHARED, ro, 0) == -syscall::EACCES
+            && syscall::mmap(4096, mman::PROT_READ, mman::MAP_SHARED, fds[0], 0)
+                == -syscall::ENODEV,
+    );
+    syscall::close(fds[0]);
+    syscall::close(fds[1]);
+    syscall::close(ro);
+    syscall::close(fd);
+    syscall::unlink(path);
+}
+
+// A MAP_SHARED anonymous mapping is the same memory in parent and child;
+// a MAP_PRIVATE one is copied at fork.
+fn test_mmap_shared(r: &mut Results) {
+    let before = free_pages();
+    let shared = syscall::mmap(4096, RW, mman::MAP_SHARED | mman::MAP_ANONYMOUS, -1, 0);
+    let private = syscall::mmap(4096, RW, mman::MAP_PRIVATE | mman::MAP_ANONYMOUS, -1, 0);
+    if shared < 0 || private < 0 {
+        r.check("shared memory", false);
+        return;
+    }
+    let (s, p) = (shared as *mut u64, private as *mut u64);
+    unsafe {
+        s.write_volatile(1);
+        p.write_volatile(1);
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        unsafe {
+            s.write_volatile(s.read_volatile() + 41);
+            p.write_volatile(p.read_volatile() + 41);
+        }
+        syscall::exit(0);
+    }
+    syscall::waitpid(pid, None);
+    let (sv, pv) = unsafe { (s.read_volatile(), p.read_volatile()) };
+    syscall::munmap(shared as usize, 4096);
+    syscall::munmap(private as usize, 4096);
+    let after = free_pages();
+    r.check("MAP_SHARED memory is shared with a child", sv == 42);
+    r.check("MAP_PRIVATE memory is copied for a child", pv == 1);
+    r.check("shared pages go with the last mapping", after == before);
+}
+
+static CAUGHT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+extern "C" fn on_signal(sig: usize) {
+    // Use the FPU registers the interrupted code may be using.
+    let x = core::hint::black_box(7.25f64);
+    if core::hint::black_box(x * x) == 52.5625 {
+        CAUGHT.store(sig, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+fn handler() -> usize {
+    on_signal as *const () as usize
+}
+
+fn caught() -> usize {
+    CAUGHT.load(core::sync::atomic::Ordering::SeqCst)
+}
+
+// Fork a child that runs setup, and then f, whose result byte it passes
+// back. The parent sends sig in between.
+fn signal_child(sig: usize, setup: fn(), f: fn() -> u8) -> u8 {
+    let mut fds = [0i32; 2];
+    syscall::pipe(&mut fds);
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        setup();
+        syscall::write(fds[1], b"r");
+        let b = f();
+        syscall::write(fds[1], &[b]);
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+    let mut b = [0u8; 1];
+    syscall::read(fds[0], &mut b);
+    syscall::kill(pid, sig);
+    b[0] = 0;
+    syscall::read(fds[0], &mut b);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    b[0]
+}
+
+// A handler runs with the signal's number, interrupting a sleep or a loop
+// without changing its registers; blocked signals wait, ignored ones and
+// SIGCHLD by default vanish, and other signals by default terminate.
+fn test_signals(r: &mut Results) {
+    let asleep = signal_child(
+        signal::SIGUSR1,
+        || {
+            syscall::signal(signal::SIGUSR1, handler());
+        },
+        || {
+            let r = syscall::sleep(1_000_000);
+            (r == -syscall::EINTR && caught() == signal::SIGUSR1) as u8
+        },
+    );
+    r.check("a handler interrupts sleep with EINTR", asleep == 1);
+
+    let spinning = signal_child(
+        signal::SIGUSR2,
+        || {
+            syscall::signal(signal::SIGUSR2, handler());
+        },
+        || {
+            let a = core::hint::black_box(1.5f64);
+            let mut n = core::hint::black_box(0u64);
+            while caught() == 0 {
+                n += 1;
+            }
+            (a * 2.0 == 3.0 && n > 0) as u8
+        },
+    );
+    r.check(
+        "a handler in a running loop keeps its registers",
+        spinning == 1,
+    );
+
+    let blocked = signal_child(
+        signal::SIGUSR1,
+        || {
+            syscall::signal(signal::SIGUSR1, handler());
+            syscall::sigprocmask(signal::SIG_BLOCK, signal::sigbit(signal::SIGUSR1));
+        },
+        || {
+            syscall::sleep(10);
+            let held = caught() == 0;
+            syscall::sigprocmask(signal::SIG_UNBLOCK, signal::sigbit(signal::SIGUSR1));
+            (held && caught() == signal::SIGUSR1) as u8
+        },
+    );
+    r.check("a blocked signal waits until unblocked", blocked == 1);
+
+    let ignored = signal_child(
+        signal::SIGTERM,
+        || {
+            syscall::signal(signal::SIGTERM, signal::SIG_IGN);
+        },
+        || (syscall::sleep(10) == 0) as u8,
+    );
+    r.check("an ignored signal is dropped", ignored == 1);
+
+    let terminated = signal_child(
+        signal::SIGTERM,
+        || {},
+        || {
+            syscall::sleep(1_000_000);
+            1
+        },
+    );
+    r.check("SIGTERM terminates by default", terminated == 0);
+
+    syscall::signal(signal::SIGCHLD, handler());
+    if syscall::fork() == 0 {
+        syscall::exit(0);
+    }
+    syscall::wait(None);
+    r.check("a child's exit sends SIGCHLD", caught() == signal::SIGCHLD);
+    syscall::signal(signal::SIGCHLD, signal::SIG_DFL);
+    CAUGHT.store(0, core::sync::atomic::Ordering::SeqCst);
+
+    r.check(
+        "SIGKILL cannot be caught",
+        syscall::signal(signal::SIGKILL, handler()).is_none(),
+    );
+
+    // Nothing may send the kernel's iretq a rip outside user space: sigaction
+    // refuses such a handler, and sigreturn kills a caller whose frame has one.
+    let refused = [0xffff_8000_0000_0000usize, 0x8000_0000_0000_0000]
+        .iter()
+        .all(|&h| syscall::signal(signal::SIGUSR1, h).is_none());
+    syscall::signal(signal::SIGUSR1, signal::SIG_DFL);
+    r.check("sigaction refuses a handler outside user space", refused);
+    let pid = syscall::fork();
+    if pid == 0 {
+        let mut frame = [0u64; 256]; // SigFrame and FPU state
+        let regs = ulib::ptrace::Regs {
+            rip: 0x8000_0000_0000_0000,
+            ..Default::default()
+        };
+        unsafe {
+            (frame.as_mut_ptr() as *mut ulib::ptrace::Regs).write(regs);
+            core::arch::asm!(
+                "mov rsp, {sp}",
+                "syscall",
+                sp = in(reg) frame.as_ptr(),
+                in("rax") syscall::SYS_SIGRETURN,
+                options(noreturn)
+            );
+        }
+    }
+    let mut status = 0;
+    syscall::waitpid(pid, Some(&mut status));
+    r.check(
+        "sigreturn to a non-canonical rip kills the caller",
+        status == -1,
+    );
+}
+
+fn test_read_to_string(r: &mut Results) {
+    use core::fmt::Write;
+    let mut expected = String::new();
+    for i in 1..=1000 {
+        writeln!(expected, "{}", i).unwrap();
+    }
+    let fd = syscall::open("/lines.txt", fs::O_RDONLY);
+    let contents = if fd >= 0 {
+        let s = fs::read_to_string(fd);
+        syscall::close(fd);
+        s.ok()
+    } else {
+        None
+    };
+    r.check(
+        "read_to_string multi-block file",
+        expected.len() > 2 * 1024 && contents.as_deref() == Some(expected.as_str()),
+    );
+}
+
+// Push several pipe buffers' worth through write_all while the reader takes
+// small bites, so the writer keeps finding the pipe full.
+fn test_write_all(r: &mut Results) {
+    const LEN: usize = 4096;
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("write_all through a pipe", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        let _ = io::write_all(fds[1], &data);
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+
+    let mut got = Vec::new();
+    let mut buf = [0u8; 37];
+    loop {
+        let n = syscall::read(fds[0], &mut buf);
+        if n <= 0 {
+            break;
+        }
+        got.extend_from_slice(&buf[..n as usize]);
+    }
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("write_all through a pipe", got == data);
+}
+
+// A child writes through buffered stdout (more than one buffer's worth, so
+// some is still buffered) into a pipe and exits without flushing.
+fn test_stdout_flush_on_exit(r: &mut Results) {
+    use core::fmt::Write;
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("stdout flushed on exit", false);
+        return;
+    }
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::close(fds[0]);
+        syscall::close(1);
+        syscall::dup(fds[1]); // Lowest free fd: 1
+        syscall::close(fds[1]);
+        let mut out = io::stdout();
+        for i in 0..300 {
+            let _ = writeln!(out, "line {}", i);
+        }
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+
+    let mut expected = String::new();
+    for i in 0..300 {
+        let _ = writeln!(expected, "line {}", i);
+    }
+    let mut got = Vec::new();
+    let read = fs::read_to_end(fds[0], &mut got).is_ok();
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("stdout flushed on exit", read && got == expected.as_bytes());
+}
+
+// Eight one-byte nops at ptrace_spin+0..8, then a jump back to the start.
+core::arch::global_asm!(
+    ".global ptrace_spin",
+    "ptrace_spin:",
+    "nop",
+    "nop",
+    "nop",
+    "nop",
+    "nop",
+    "nop",
+    "nop",
+    "nop",
+    "jmp ptrace_spin",
+);
+
+extern "C" {
+    fn ptrace_spin() -> !;
+}
+
+// Attach to a spinning child and single-step it: every step must execute
+// exactly one instruction.
+fn test_ptrace(r: &mut Results) {
+    use ulib::ptrace::{Regs, PTRACE_ATTACH, PTRACE_KILL, PTRACE_SINGLESTEP};
+    let pid = syscall::fork();
+    if pid == 0 {
+        unsafe { ptrace_spin() }
+    }
+    let start = ptrace_spin as *const () as u64;
+    let in_loop = |rip: u64| (start..=start + 8).contains(&rip);
+    let step = |regs: &mut Regs| {
+        syscall::ptrace(PTRACE_SINGLESTEP, pid, 0) == 0 && syscall::ptrace_getregs(pid, regs) == 0
+    };
+    r.check("ptrace attach", syscall::ptrace(PTRACE_ATTACH, pid, 0) == 0);
+
+    let mut regs = Regs::default();
+    let mut ok = syscall::ptrace_getregs(pid, &mut regs) == 0;
+    // The child may have stopped on its way back from fork.
+    for _ in 0..100 {
+        if !ok || in_loop(regs.rip) {
+            break;
+        }
+        ok = step(&mut regs);
+    }
+    ok &= in_loop(regs.rip);
+    for _ in 0..20 {
+        if !ok {
+            break;
+        }
+        let next = if regs.rip == start + 8 {
+            start
+        } else {
+            regs.rip + 1
+        };
+        ok = step(&mut regs) && regs.rip == next;
+    }
+    r.check("ptrace single-step", ok);
+
+    syscall::ptrace(PTRACE_KILL, pid, 0);
+    r.check("ptrace kill", syscall::wait(None) == pid);
+}
+
+#[inline(never)]
+extern "C" fn ptrace_bp_target(i: u64) {
+    core::hint::black_box(i);
+}
+
+// Break at a function in a child calling it in a loop: the child must stop
+// at the function's first instruction, and continuing must run to the next
+// call rather than hit the same breakpoint again.
+fn test_ptrace_breakpoint(r: &mut Results) {
+    use ulib::ptrace::{
+        Breakpoint, Regs, BP_CLEAR, BP_EXEC, PTRACE_ATTACH, PTRACE_CONT, PTRACE_KILL,
+    };
+    let pid = syscall::fork();
+    if pid == 0 {
+        let mut i = 0;
+        loop {
+            ptrace_bp_target(i);
+            i += 1;
+        }
+    }
+    let target = ptrace_bp_target as *const () as u64;
+    let mut bp = Breakpoint {
+        addr: target,
+        kind: BP_EXEC,
+        ..Default::default()
+    };
+    let mut regs = Regs::default();
+    let mut ok =
+        syscall::ptrace(PTRACE_ATTACH, pid, 0) == 0 && syscall::ptrace_setbp(pid, &bp) == 0;
+    let mut calls = [0u64; 2];
+    for call in calls.iter_mut() {
+        ok = ok
+            && syscall::ptrace(PTRACE_CONT, pid, 0) == 0
+            && syscall::ptrace_getregs(pid, &mut regs) == 0
+            && regs.rip == target;
+        *call = regs.rdi; // The argument i
+    }
+    r.check("ptrace breakpoint stops at rip", ok);
+    r.check(
+        "ptrace breakpoint resumes past it",
+        ok && calls[1] == calls[0] + 1,
+    );
+
+    bp.kind = BP_CLEAR;
+    r.check(
+        "ptrace clear breakpoint",
+        syscall::ptrace_setbp(pid, &bp) == 0,
+    );
+    bp.kind = BP_EXEC;
+    bp.addr = 0xffff_8000_0000_0000;
+    r.check(
+        "ptrace kernel breakpoint rejected",
+        syscall::ptrace_setbp(pid, &bp) < 0,
+    );
+
+    syscall::ptrace(PTRACE_KILL, pid, 0);
+    syscall::wait(None);
+}
+
+// Filled in by the crashing child of test_coredump; zero in the parent.
+static mut CORE_SCRATCH: [u64; 4] = [0; 4];
+const CORE_R12: u64 = 0x1234_5678_9abc_def0;
+const CORE_BAD_ADDR: u64 = 0x4000_0000; // Above the heap, below the stack
+
+// Fork a child that faults, and return its pid once it has died.
+fn crash_child(dumpable: bool) -> i32 {
+    let pid = syscall::fork();
+    if pid == 0 {
+        syscall::prctl(ulib::coredump::PR_SET_DUMPABLE, dumpable as usize);
+        selftest_crash();
+    }
+    syscall::wait(None);
+    pid
+}
+
+// The kernel names this function in its fault message, which `make test`
+// looks for.
+#[no_mangle]
+#[inline(never)]
+extern "C" fn selftest_crash() -> ! {
+    unsafe {
+        let scratch = &mut *core::ptr::addr_of_mut!(CORE_SCRATCH);
+        for (i, v) in scratch.iter_mut().enumerate() {
+            *v = 0xc0de_0000 + i as u64;
+        }
+        core::arch::asm!(
+            "mov byte ptr [{bad}], 0",
+            bad = in(reg) CORE_BAD_ADDR,
+            in("r12") CORE_R12,
+            options(noreturn)
+        );
+    }
+}
+
+// A dumpable process that segfaults leaves /core.<pid> with its registers
+// and memory; one that is not dumpable leaves nothing.
+fn test_coredump(r: &mut Results) {
+    use ulib::coredump::{CoreHeader, CoreRegion, CORE_MAGIC};
+    let pid = crash_child(true);
+    let path = alloc::format!("/core.{}", pid);
+    let fd = syscall::open(&path, fs::O_RDONLY);
+    let mut data = Vec::new();
+    let read = fs::read_to_end(fd, &mut data).is_ok();
+    syscall::close(fd);
+    r.check(
+        "core file written",
+        read && data.len() > core::mem::size_of::<CoreHeader>(),
+    );
+    if data.len() <= core::mem::size_of::<CoreHeader>() {
+        return;
+    }
+
+    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const CoreHeader) };
+    let crash = selftest_crash as *const () as u64;
+    r.check(
+        "core header and registers",
+        header.magic == CORE_MAGIC
+            && header.pid == pid as u32
+            && header.fault_addr == CORE_BAD_ADDR
+            && header.regs.r12 == CORE_R12
+            && (crash..crash + 0x1000).contains(&header.regs.rip),
+    );
+
+    // Walk the regions, looking for the child's scratch values.
+    let scratch = core::ptr::addr_of!(CORE_SCRATCH) as u64;
+    let mut off = core::mem::size_of::<CoreHeader>();
+    let mut found = false;
+    let mut well_formed = true;
+    for _ in 0..header.nregions {
+        if off + core::mem::size_of::<CoreRegion>() > data.len() {
+            well_formed = false;
+            break;
+        }
+        let region =
+            unsafe { core::ptr::read_unaligned(data.as_ptr().add(off) as *const CoreRegion) };
+        off += core::mem::size_of::<CoreRegion>();
+        if off + region.len as usize > data.len() {
+            well_formed = false;
+            break;
+        }
+        if (region.start..region.start + region.len).contains(&scratch) {
+            let at = off + (scratch - region.start) as usize;
+            found = (0..4).all(|i| {
+                let v = unsafe {
+                    core::ptr::read_unaligned(data.as_ptr().add(at + 8 * i) as *const u64)
+                };
+                v == 0xc0de_0000 + i as u64
+            });
+        }
+        off += region.len as usize;
+    }
+    r.check("core regions", well_formed && off == data.len());
+    r.check("core memory contents", found);
+
+    let pid = crash_child(false);
+    let path = alloc::format!("/core.{}", pid);
+    r.check(
+        "no core unless dumpable",
+        syscall::open(&path, fs::O_RDONLY) < 0,
+    );
+}
+
+const FPU_ROUNDS: u64 = 5_000_000;
+
+// Put distinct values in xmm1-xmm15, add xmm1 (1.0) to xmm0 FPU_ROUNDS
+// times and read all sixteen back. The crate is built soft-float, so the
+// compiler itself never uses the XMM registers around this.
+fn fpu_work(seed: u64) -> bool {
+    let mut regs = [0u64; 16];
+    for (i, v) in regs.iter_mut().enumerate() {
+        *v = seed << 32 | (i as u64 * 0x0101_0101);
+    }
+    regs[0] = 0f64.to_bits();
+    regs[1] = 1f64.to_bits();
+    let mut expect = regs;
+    expect[0] = (FPU_ROUNDS as f64).to_bits();
+    unsafe {
+        core::arch::asm!(
+            "movq xmm0, qword ptr [{r}]",
+            "movq xmm1, qword ptr [{r} + 8]",
+            "movq xmm2, qword ptr [{r} + 16]",
+            "movq xmm3, qword ptr [{r} + 24]",
+            "movq xmm4, qword ptr [{r} + 32]",
+            "movq xmm5, qword ptr [{r} + 40]",
+            "movq xmm6, qword ptr [{r} + 48]",
+            "movq xmm7, qword ptr [{r} + 56]",
+            "movq xmm8, qword ptr [{r} + 64]",
+            "movq xmm9, qword ptr [{r} + 72]",
+            "movq xmm10, qword ptr [{r} + 80]",
+            "movq xmm11, qword ptr [{r} + 88]",
+            "movq xmm12, qword ptr [{r} + 96]",
+            "movq xmm13, qword ptr [{r} + 104]",
+            "movq xmm14, qword ptr [{r} + 112]",
+            "movq xmm15, qword ptr [{r} + 120]",
+            "2:",
+            "addsd xmm0, xmm1",
+            "dec {n}",
+            "jnz 2b",
+            "movq qword ptr [{r}], xmm0",
+            "movq qword ptr [{r} + 8], xmm1",
+            "movq qword ptr [{r} + 16], xmm2",
+            "movq qword ptr [{r} + 24], xmm3",
+            "movq qword ptr [{r} + 32], xmm4",
+            "movq qword ptr [{r} + 40], xmm5",
+            "movq qword ptr [{r} + 48], xmm6",
+            "movq qword ptr [{r} + 56], xmm7",
+            "movq qword ptr [{r} + 64], xmm8",
+            "movq qword ptr [{r} + 72], xmm9",
+            "movq qword ptr [{r} + 80], xmm10",
+            "movq qword ptr [{r} + 88], xmm11",
+            "movq qword ptr [{r} + 96], xmm12",
+            "movq qword ptr [{r} + 104], xmm13",
+            "movq qword ptr [{r} + 112], xmm14",
+            "movq qword ptr [{r} + 120], xmm15",
+            r = in(reg) regs.as_mut_ptr(),
+            n = inout(reg) FPU_ROUNDS => _,
+        );
+    }
+    regs == expect
+}
+
+// More float-crunching children than CPUs, so each is switched out and back
+// in many times; unsaved FPU state would leak from one into another.
+fn test_fpu_switch(r: &mut Results) {
+    const NCHILD: usize = 4;
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("fpu state across context switches", false);
+        return;
+    }
+    for i in 0..NCHILD {
+        if syscall::fork() == 0 {
+            syscall::close(fds[0]);
+            let ok = fpu_work(i as u64 + 1);
+            syscall::write(fds[1], if ok { b"y" } else { b"n" });
+            syscall::exit(0);
+        }
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; NCHILD];
+    let n = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    for _ in 0..NCHILD {
+        syscall::wait(None);
+    }
+    r.check(
+        "fpu state across context switches",
+        n == NCHILD && buf.iter().all(|b| *b == b'y'),
+    );
+
+    // Threads share an address space, but each has registers of its own.
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static OK: AtomicUsize = AtomicUsize::new(0);
+    let threads: Vec<_> = (0..NCHILD)
+        .filter_map(|i| {
+            ulib::thread::spawn(move || {
+                if fpu_work(i as u64 + 1) {
+                    OK.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    let joined = threads.into_iter().filter_map(|t| t.join()).count();
+    r.check(
+        "fpu state across thread switches",
+        joined == NCHILD && OK.load(Ordering::Relaxed) == NCHILD,
+    );
+}
+
+// x87 and SSE arithmetic with exactly known results, as IEEE bit patterns.
+// Returns false instead of faulting only if the kernel has enabled SSE.
+fn fpu_math() -> bool {
+    let mut out = [0u64; 5];
+    let packed = [
+        1.5f64.to_bits(),
+        2.5f64.to_bits(),
+        4f64.to_bits(),
+        (-2f64).to_bits(),
+    ];
+    unsafe {
+        core::arch::asm!(
+            "mov {t}, 2",
+            "cvtsi2sd xmm0, {t}",
+            "sqrtsd xmm1, xmm0",
+            "movq qword ptr [{o}], xmm1",
+            "mov {t}, 1",
+            "cvtsi2sd xmm0, {t}",
+            "mov {t}, 3",
+            "cvtsi2sd xmm1, {t}",
+            "divsd xmm0, xmm1",
+            "movq qword ptr [{o} + 8], xmm0",
+            "movupd xmm0, [{p}]",
+            "movupd xmm1, [{p} + 16]",
+            "mulpd xmm0, xmm1",
+            "movupd [{o} + 16], xmm0",
+            "fldpi",
+            "fstp qword ptr [{o} + 32]",
+            o = in(reg) out.as_mut_ptr(),
+            p = in(reg) packed.as_ptr(),
+            t = out(reg) _,
+        );
+    }
+    out == [
+        0x3ff6_a09e_667f_3bcd, // sqrt(2)
+        0x3fd5_5555_5555_5555, // 1/3
+        6f64.to_bits(),
+        (-5f64).to_bits(),
+        0x4009_21fb_5444_2d18, // pi
+    ]
+}
+
+// A child doing floating-point math gets the right answers; if SSE were off
+// it would be killed by #UD (or #NM) and never report.
+fn test_fpu_math(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("floating-point math", false);
+        return;
+    }
+    if syscall::fork() == 0 {
+        syscall::close(fds[0]);
+        let ok = fpu_math();
+        syscall::write(fds[1], if ok { b"y" } else { b"n" });
+        syscall::exit(0);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 1];
+    let n = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    r.check("floating-point math", n == 1 && buf[0] == b'y');
+}
+
+// Run f in a child and return the FPU switching counters it caused.
+fn fpu_counts(f: fn()) -> (u64, u64) {
+    let mut before = syscall::SysInfo::default();
+    let mut after = syscall::SysInfo::default();
+    syscall::sysinfo(&mut before);
+    if syscall::fork() == 0 {
+        f();
+        syscall::exit(0);
+    }
+    syscall::wait(None);
+    syscall::sysinfo(&mut after);
+    (
+        after.fpu_restores - before.fpu_restores,
+        after.fpu_saves - before.fpu_saves,
+    )
+}
+
+// FPU state is switched lazily: a child that only does integer work (long
+// enough to be preempted) never has its FPU state loaded or saved, while
+// one doing float work gets it loaded on its first FPU instruction.
+fn test_fpu_lazy(r: &mut Results) {
+    let (restores, saves) = fpu_counts(|| spin(FPU_ROUNDS));
+    r.check(
+        "no FPU switching for integer code",
+        restores == 0 && saves == 0,
+    );
+    let (restores, saves) = fpu_counts(|| {
+        fpu_work(7);
+    });
+    r.check("FPU state loaded on demand", restores >= 1 && saves >= 1);
+}
+
+// CPU-bound integer work: n rounds of a volatile add.
+fn spin(n: u64) {
+    let mut x = 0u64;
+    for i in 0..n {
+        x = unsafe { core::ptr::read_volatile(&x) }.wrapping_add(i);
+    }
+}
+
+// Run spin(n) in a child and return the user ticks it was charged.
+fn child_utime(n: u64) -> u64 {
+    let mut before = syscall::Rusage::default();
+    let mut after = syscall::Rusage::default();
+    syscall::getrusage(syscall::RUSAGE_CHILDREN, &mut before);
+    if syscall::fork() == 0 {
+        spin(n);
+        syscall::exit(0);
+    }
+    syscall::wait(None);
+    syscall::getrusage(syscall::RUSAGE_CHILDREN, &mut after);
+    after.utime - before.utime
+}
+
+// A CPU-bound child is charged user time, and twice the work is charged
+// about twice the ticks.
+fn test_rusage(r: &mut Results) {
+    // Grow the work until it spans enough ticks to compare.
+    let mut n = 1_000_000;
+    let mut t1 = child_utime(n);
+    while t1 < 10 && n < 1 << 32 {
+        n *= 2;
+        t1 = child_utime(n);
+    }
+    let t2 = child_utime(2 * n);
+    r.check("CPU-bound child is charged user time", t1 > 0);
+    r.check(
+        "CPU time proportional to work",
+        t2 * 10 >= t1 * 14 && t2 * 10 <= t1 * 30,
+    );
+}
+
+// sleep(n) lasts at least n ticks of the global counter, and sleep(0) none.
+fn test_sleep_ticks(r: &mut Results) {
+    let start = syscall::uptime();
+    let slept = syscall::sleep(5) == 0;
+    let mid = syscall::uptime();
+    syscall::sleep(0);
+    r.check(
+        "sleep waits for the ticks asked for",
+        slept && mid - start >= 5 && syscall::uptime() - mid < 2,
+    );
+}
+
+// /uptime prints "up N ticks", with N between our readings around it.
+fn test_uptime(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("uptime prints the tick counter", false);
+        return;
+    }
+    let before = syscall::uptime();
+    if syscall::fork() == 0 {
+        syscall::close(1);
+        syscall::dup(fds[1]);
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+        let argv = ["/uptime\0".as_ptr(), core::ptr::null()];
+        syscall::exec(argv[0], &argv);
+        syscall::exit(1);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 64];
+    let total = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+    let after = syscall::uptime();
+    let out = core::str::from_utf8(&buf[..total]).unwrap_or("");
+    let n = out
+        .strip_prefix("up ")
+        .and_then(|s| s.strip_suffix(" ticks\n"))
+        .and_then(|s| s.parse::<u64>().ok());
+    r.check(
+        "uptime prints the tick counter",
+        n.is_some_and(|n| (before..=after).contains(&n)),
+    );
+}
+
+const TIME_SLEEP: u64 = 50;
+
+// `time sleep 50` reports about 50 ticks of real time and little CPU time.
+fn test_time(r: &mut Results) {
+    let mut fds = [0i32; 2];
+    if syscall::pipe(&mut fds) < 0 {
+        r.check("time reports real time", false);
+        return;
+    }
+    if syscall::fork() == 0 {
+        syscall::close(1);
+        syscall::dup(fds[1]);
+        syscall::close(fds[0]);
+        syscall::close(fds[1]);
+        let n = alloc::format!("{}\0", TIME_SLEEP);
+        let argv = [
+            "/time\0".as_ptr(),
+            "/sleep\0".as_ptr(),
+            n.as_ptr(),
+            core::ptr::null(),
+        ];
+        syscall::exec(argv[0], &argv);
+        syscall::exit(1);
+    }
+    syscall::close(fds[1]);
+    let mut buf = [0u8; 128];
+    let total = read_all(fds[0], &mut buf);
+    syscall::close(fds[0]);
+    syscall::wait(None);
+
+    // "real R ticks, user U ticks, sys S ticks"
+    let out = core::str::from_utf8(&buf[..total]).unwrap_or("");
+    let nums: Vec<u64> = out
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|s| s.parse().ok())
+        .collect();
+    r.check(
+        "time reports real time",
+        nums.len() == 3 && (TIME_SLEEP..TIME_SLEEP + 25).contains(&nums[0]),
+    );
+    r.check(
+        "sleeping uses little CPU time",
+        nums.len() == 3 && nums[1] + nums[2] < TIME_SLEEP / 5,
+    );
+}
+
+// RUNAWAY_TICKS in kernel/src/proc.rs.
+const RUNAWAY_TICKS: u64 = 200;
+
+// A child that spins past the runaway threshold without sleeping. The kernel
+// only logs a "high CPU" warning, which `make test` looks for; here we check
+// that the child is left running to completion.
+fn test_runaway(r: &mut Results) {
+    let pid = syscall::fork();
+    if pid == 0 {
+        let mut ru = syscall::Rusage::default();
+        loop {
+            spin(100_000);
+            syscall::getrusage(syscall::RUSAGE_SELF, &mut ru);
+            if ru.utime + ru.stime > RUNAWAY_TICKS + 10 {
+                syscall::exit(0);
+            }
+        }
+    }
+    let mut before = syscall::Rusage::default();
+    let mut after = syscall::Rusage::default();
+    syscall::getrusage(syscall::RUSAGE_CHILDREN, &mut before);
+    let waited = syscall::wait(None);
+    syscall::getrusage(syscall::RUSAGE_CHILDREN, &mut after);
+    r.check(
+        "runaway process keeps running",
+        waited == pid && after.utime + after.stime - before.utime - before.stime > RUNAWAY_TICKS,
+    );
+}
@@ -4,18 +4,27 @@
 extern crate alloc;
 use alloc::string::String;
 use alloc::vec::Vec;
-use ulib::{entry, print, println, syscall};
+use ulib::{dns, entry, fs, net, print, println, signal, syscall};
 
 entry!(main);
 
 fn main(_argc: usize, _argv: *const *const u8) {
+    // Ctrl-C interrupts the command running, not the shell: each command
+    // runs in a process group of its own, made the console's foreground.
+    syscall::setpgid(0, 0);
+    syscall::signal(signal::SIGINT, signal::SIG_IGN);
+    syscall::tcsetpgrp(0, syscall::getpgid(0));
+
     loop {
         print!("$ ");
 
         let mut line = String::new();
+        let mut eof = false;
         loop {
             let mut c = [0u8; 1];
-            if syscall::read(0, &mut c) < 1 {
+            let n = syscall::read(0, &mut c);
+            if n < 1 {
+                eof = n == 0;
                 break;
             }
             if c[0] == b'\n' || c[0] == b'\r' {
@@ -25,6 +34,11 @@ fn main(_argc: usize, _argv: *const *const u8) {
         }
 
         if line.is_empty() {
+            // End of input, with nothing left to run: the console's Ctrl-D,
+            // or a telnetd connection closed.
+            if eof {
+                syscall::exit(0);
+            }
             continue;
         }
 
@@ -56,9 +70,15 @@ fn main(_argc: usize, _argv: *const *const u8) {
             continue;
         }
 
-        if pipe_cmd_strs.len() == 1 {
+        if pipe_cmd_strs[0][0] == "cd" {
+            // Built in: a child's working directory would go with it.
+            let dir = pipe_cmd_strs[0].get(1).copied().unwrap_or("/");
+            if syscall::chdir(dir) < 0 {
+                println!("cd: cannot change to {}", dir);
+            }
+        } else if pipe_cmd_strs.len() == 1 {
             // Normal command
-            run_cmd_strs(&pipe_cmd_strs[0]);
+            run_cmd_strs(&pipe_cmd_strs[0], 0);
         } else if pipe_cmd_strs.len() == 2 {
             // Pipe command
             let fds: &mut [i32; 2] = &mut [0, 0];
@@ -72,43 +92,142 @@ fn main(_argc: usize, _argv: *const *const u8) {
                 println!("fork failed");
             } else if pid1 == 0 {
                 // Left child
-                syscall::close(1);
-                syscall::dup(fds[1]);
+                job_child(0);
+                syscall::dup2(fds[1], 1);
                 syscall::close(fds[0]);
                 syscall::close(fds[1]);
 
-                run_cmd_strs(&pipe_cmd_strs[0]);
+                run_cmd_strs(&pipe_cmd_strs[0], syscall::getpgid(0));
                 syscall::exit(0);
             }
+            // Both sides go in the left one's group.
+            syscall::setpgid(pid1, pid1);
 
             let pid2 = syscall::fork();
             if pid2 < 0 {
                 println!("fork failed");
             } else if pid2 == 0 {
                 // Right child
-                syscall::close(0);
-                syscall::dup(fds[0]);
+                job_child(pid1);
+                syscall::dup2(fds[0], 0);
                 syscall::close(fds[0]);
                 syscall::close(fds[1]);
 
-                run_cmd_strs(&pipe_cmd_strs[1]);
+                run_cmd_strs(&pipe_cmd_strs[1], pid1);
                 syscall::exit(0);
             }
+            syscall::setpgid(pid2, pid1);
 
             syscall::close(fds[0]);
             syscall::close(fds[1]);
+            syscall::tcsetpgrp(0, pid1);
             syscall::wait(None);
             syscall::wait(None);
+            syscall::tcsetpgrp(0, syscall::getpgid(0));
         } else {
             println!("Only single pipe supported");
         }
     }
 }
 
-fn run_cmd_strs(args_strs: &Vec<&str>) {
+// In a child about to run a command: join process group pgid (0: a new
+// one) and take Ctrl-C again.
+fn job_child(pgid: i32) {
+    syscall::setpgid(0, pgid);
+    syscall::signal(signal::SIGINT, signal::SIG_DFL);
+}
+
+// A redirection of fd to path, opened with mode
+struct Redirect<'a> {
+    fd: i32,
+    path: &'a str,
+    mode: i32,
+}
+
+// Take the redirections out of a command's words: < file, > file and
+// >> file, with or without a space before the file. Err is the operator
+// missing its file.
+fn redirects<'a>(words: &[&'a str]) -> Result<(Vec<&'a str>, Vec<Redirect<'a>>), &'a str> {
+    let (mut rest, mut redirs) = (Vec::new(), Vec::new());
+    let mut words = words.iter();
+    while let Some(&w) = words.next() {
+        let (op, fd, mode) = if w.starts_with(">>") {
+            (">>", 1, fs::O_WRONLY | fs::O_CREATE | fs::O_APPEND)
+        } else if w.starts_with('>') {
+            (">", 1, fs::O_WRONLY | fs::O_CREATE | fs::O_TRUNC)
+        } else if w.starts_with('<') {
+            ("<", 0, fs::O_RDONLY)
+        } else {
+            rest.push(w);
+            continue;
+        };
+        let path = match &w[op.len()..] {
+            "" => *words.next().ok_or(op)?,
+            path => path,
+        };
+        redirs.push(Redirect { fd, path, mode });
+    }
+    Ok((rest, redirs))
+}
+
+// Open the file of a redirection. As in bash, /dev/tcp/host/port and
+// /dev/udp/host/port are a connection to port on host instead.
+fn open_redirect(r: &Redirect) -> i32 {
+    for (dir, ty) in [
+        ("/dev/tcp/", net::SOCK_STREAM),
+        ("/dev/udp/", net::SOCK_DGRAM),
+    ] {
+        if let Some(spec) = r.path.strip_prefix(dir) {
+            return connect(spec, ty);
+        }
+    }
+    syscall::open(r.path, r.mode)
+}
+
+// A socket of type ty connected to spec, host/port.
+fn connect(spec: &str, ty: usize) -> i32 {
+    let Some((host, Ok(port))) = spec.split_once('/').map(|(h, p)| (h, p.parse())) else {
+        return -1;
+    };
+    let addr = match dns::resolve(host) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("sh: {}: {}", host, e);
+            return -1;
+        }
+    };
+    let fd = syscall::socket(net::AF_INET, ty, 0);
+    if fd < 0 {
+        return fd;
+    }
+    let err = syscall::connect(fd, &net::SockAddrIn::new(addr, port));
+    if err < 0 {
+        syscall::close(fd);
+        return err;
+    }
+    fd
+}
+
+// Run a command in process group pgid (0: a new one), in the foreground, and
+// wait for it. Both sides set the group, so that it is in place whichever
+// runs first.
+fn run_cmd_strs(args_strs: &Vec<&str>, pgid: i32) {
+    let (words, redirs) = match redirects(args_strs) {
+        Ok(r) => r,
+        Err(op) => {
+            println!("sh: no file after {}", op);
+            return;
+        }
+    };
     let mut args: Vec<String> = Vec::new();
-    for p in args_strs {
-        let mut s = String::from(*p);
+    for (i, p) in words.iter().enumerate() {
+        // Programs live in /, whatever the working directory.
+        let mut s = if i == 0 && !p.contains('/') {
+            String::from("/")
+        } else {
+            String::new()
+        };
+        s.push_str(p);
         s.push('\0');
         args.push(s);
     }
@@ -125,6 +244,19 @@ fn run_cmd_strs(args_strs: &Vec<&str>) {
         println!("fork failed");
     } else if pid == 0 {
         // Child
+        job_child(pgid);
+        for r in &redirs {
+            let fd = open_redirect(r);
+            if fd < 0 {
+                println!("sh: cannot open {}", r.path);
+                syscall::exit(1);
+            }
+            syscall::dup2(fd, r.fd);
+            syscall::close(fd);
+        }
+        if words.is_empty() {
+            syscall::exit(0);
+        }
         let ret = syscall::exec(argv[0], &argv);
         if ret == -1 {
             println!("exec failed");
@@ -132,6 +264,10 @@ fn run_cmd_strs(args_strs: &Vec<&str>) {
         syscall::exit(1);
     } else {
         // Parent
+        let pgid = if pgid == 0 { pid } else { pgid };
+        syscall::setpgid(pid, pgid);
+        syscall::tcsetpgrp(0, pgid);
         syscall::wait(None);
+        syscall::tcsetpgrp(0, syscall::getpgid(0));
     }
 }
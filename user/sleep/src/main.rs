@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, env, println, syscall};
+
+entry!(main);
+
+// Usage: sleep ticks
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let n = args
+        .get(1)
+        .and_then(|a| a.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok());
+    match n {
+        Some(n) if args.len() == 2 => {
+            syscall::sleep(n);
+            syscall::exit(0);
+        }
+        _ => {
+            println!("usage: sleep ticks");
+            syscall::exit(1);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, syscall};
+
+entry!(main);
+
+// Write every file system through to its disk.
+// Usage: sync
+fn main(_argc: usize, _argv: *const *const u8) {
+    syscall::sync();
+    syscall::exit(0);
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, env, net, println, syscall};
+
+entry!(main);
+
+// A shell for every TCP connection to port (23 if not given), with the
+// connection as its standard input, output and error. There is no telnet
+// option negotiation: `nc` makes a fine client.
+// Usage: telnetd [port]
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    let port = match args
+        .get(1)
+        .map(|a| a.to_str().ok().and_then(|s| s.parse().ok()))
+    {
+        None => 23,
+        Some(Some(port)) => port,
+        Some(None) => {
+            println!("usage: telnetd [port]");
+            syscall::exit(1);
+        }
+    };
+
+    let fd = syscall::socket(net::AF_INET, net::SOCK_STREAM, 0);
+    if fd < 0 {
+        println!("telnetd: socket failed: {}", fd);
+        syscall::exit(1);
+    }
+    let err = syscall::bind(fd, &net::SockAddrIn::new([0; 4], port));
+    if err < 0 {
+        println!("telnetd: cannot bind port {}: {}", port, err);
+        syscall::exit(1);
+    }
+    syscall::listen(fd, 4);
+    println!("telnetd: listening on port {}", port);
+
+    loop {
+        let mut peer = net::SockAddrIn::default();
+        let conn = syscall::accept(fd, Some(&mut peer));
+        if conn < 0 {
+            println!("telnetd: accept failed: {}", conn);
+            syscall::exit(1);
+        }
+        let [a, b, c, d] = peer.addr;
+        println!(
+            "telnetd: connection from {}.{}.{}.{}:{}",
+            a,
+            b,
+            c,
+            d,
+            peer.port()
+        );
+        serve(fd, conn);
+        syscall::close(conn);
+    }
+}
+
+// Start a shell on conn. It runs in a grandchild, which init reaps, so
+// that telnetd need not wait for it.
+fn serve(listener: i32, conn: i32) {
+    let pid = syscall::fork();
+    if pid < 0 {
+        println!("telnetd: fork failed");
+        return;
+    }
+    if pid > 0 {
+        syscall::wait(None);
+        return;
+    }
+    if syscall::fork() != 0 {
+        syscall::exit(0);
+    }
+    for std in 0..3 {
+        syscall::dup2(conn, std);
+    }
+    syscall::close(conn);
+    syscall::close(listener);
+    let sh = "sh\0";
+    let argv = [sh.as_ptr(), core::ptr::null()];
+    syscall::exec(sh.as_ptr(), &argv);
+    println!("telnetd: exec sh failed");
+    syscall::exit(1);
+}
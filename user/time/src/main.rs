@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use alloc::vec::Vec;
+use ulib::syscall::{Rusage, RUSAGE_CHILDREN};
+use ulib::{entry, env, println, syscall};
+
+entry!(main);
+
+// Run a command and report the elapsed (real) time and the CPU time it
+// used, in timer ticks.
+// Usage: time prog [args...]
+fn main(argc: usize, argv: *const *const u8) {
+    let args = unsafe { env::args(argc, argv) };
+    if args.len() < 2 {
+        println!("usage: time prog [args...]");
+        syscall::exit(1);
+    }
+
+    let start = syscall::uptime();
+    let pid = syscall::fork();
+    if pid == 0 {
+        let mut argv: Vec<*const u8> = args[1..].iter().map(|a| a.as_ptr() as *const u8).collect();
+        argv.push(core::ptr::null());
+        syscall::exec(argv[0], &argv);
+        println!("time: cannot exec {}", args[1].to_str().unwrap_or("?"));
+        syscall::exit(1);
+    }
+    if pid < 0 {
+        println!("time: fork failed");
+        syscall::exit(1);
+    }
+    syscall::wait(None);
+    let real = syscall::uptime() - start;
+
+    let mut ru = Rusage::default();
+    syscall::getrusage(RUSAGE_CHILDREN, &mut ru);
+    println!(
+        "real {} ticks, user {} ticks, sys {} ticks",
+        real, ru.utime, ru.stime
+    );
+    syscall::exit(0);
+}
@@ -1,5 +1,6 @@
 use crate::syscall;
 use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 #[repr(C)]
 // 16 bytes header for each block
@@ -31,6 +32,27 @@ static mut FIRST_BLOCK: Header = Header {
 // Large free block list (circular, sorted by address).
 static mut LARGE_LIST: *mut Header = core::ptr::null_mut();
 
+// Held by malloc and free, since threads (see thread.rs) share the heap.
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+struct HeapGuard;
+
+fn lock() -> HeapGuard {
+    while LOCKED
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    HeapGuard
+}
+
+impl Drop for HeapGuard {
+    fn drop(&mut self) {
+        LOCKED.store(false, Ordering::Release);
+    }
+}
+
 pub struct TinyAllocator;
 
 #[global_allocator]
@@ -47,6 +69,7 @@ unsafe impl GlobalAlloc for TinyAllocator {
 }
 
 pub unsafe fn malloc(nbytes: usize) -> *mut u8 {
+    let _guard = lock();
     // Init LARGE_LIST if it is not initialized.
     if LARGE_LIST.is_null() {
         FIRST_BLOCK.next = &mut FIRST_BLOCK as *mut Header;
@@ -124,6 +147,7 @@ unsafe fn sbrk(nunits: usize) -> bool {
 }
 
 pub unsafe fn free(targetp: *mut u8) {
+    let _guard = lock();
     let p = (targetp as *mut Header).offset(-1);
     let nunits = (*p).nunits;
 
@@ -170,6 +194,65 @@ unsafe fn merge(p: *mut Header, q: *mut Header) {
     (*p).next = (*q).next;
 }
 
+// Bump allocator over a region of its own, taken with sbrk and never given
+// back to the global allocator. Everything is freed at once by reset(), so
+// it suits scratch memory for one file or one request.
+pub struct Arena {
+    base: usize,
+    cap: usize,
+    used: core::cell::Cell<usize>,
+}
+
+impl Arena {
+    // Reserve cap bytes, or None if the heap cannot grow.
+    pub fn new(cap: usize) -> Option<Self> {
+        let base = syscall::sbrk(cap as isize);
+        if base == -1 {
+            return None;
+        }
+        Some(Self {
+            base: base as usize,
+            cap,
+            used: core::cell::Cell::new(0),
+        })
+    }
+
+    // Memory for layout, or null if the arena is full.
+    pub fn alloc_layout(&self, layout: Layout) -> *mut u8 {
+        let start = (self.base + self.used.get()).next_multiple_of(layout.align());
+        let end = start + layout.size();
+        if end > self.base + self.cap {
+            return core::ptr::null_mut();
+        }
+        self.used.set(end - self.base);
+        start as *mut u8
+    }
+
+    // Move val into the arena. The reference lives until reset(), which
+    // needs &mut self, so it cannot outlive the memory.
+    #[allow(clippy::mut_from_ref)] // Each call returns fresh memory
+    pub fn alloc<T>(&self, val: T) -> Option<&mut T> {
+        let p = self.alloc_layout(Layout::new::<T>()) as *mut T;
+        if p.is_null() {
+            return None;
+        }
+        unsafe {
+            p.write(val);
+            Some(&mut *p)
+        }
+    }
+
+    // Free everything allocated so far. Destructors are not run.
+    pub fn reset(&mut self) {
+        self.used.set(0);
+    }
+
+    // Bytes handed out since the last reset.
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+}
+
 #[alloc_error_handler]
 fn alloc_error(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout)
@@ -0,0 +1,189 @@
+// A DNS stub resolver: the IPv4 addresses of a name, from the A records in
+// the answer of the DNS server DHCP gave the interface (see
+// syscall::netconf). The query goes over UDP and is sent TRIES times, WAIT
+// ticks apart, before giving up; the answer is taken as it comes, without
+// caching. Addresses written as a.b.c.d, and localhost, need no server.
+
+use crate::net::{self, SockAddrIn};
+use crate::syscall;
+use core::fmt;
+use rust_alloc::vec::Vec;
+
+const PORT: u16 = 53;
+const TRIES: usize = 3;
+const WAIT: u64 = 100; // Ticks
+
+const HLEN: usize = 12;
+const MAX_NAME: usize = 255;
+pub const MAX_MSG: usize = 512; // Over UDP, without EDNS
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RD: u16 = 0x0100; // Recursion desired
+const RCODE_NXDOMAIN: u16 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    BadName,  // Not something to look up
+    NoServer, // The interface has no DNS server
+    TimedOut, // Nothing came from it
+    NotFound, // No such name, or no address for it
+    Failed,   // The server answered with an error, or garbage
+    Sys(isize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadName => write!(f, "bad name"),
+            Error::NoServer => write!(f, "no DNS server"),
+            Error::TimedOut => write!(f, "no answer from the DNS server"),
+            Error::NotFound => write!(f, "not found"),
+            Error::Failed => write!(f, "DNS server failure"),
+            Error::Sys(e) => write!(f, "error {}", e),
+        }
+    }
+}
+
+fn be16(b: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([b[off], b[off + 1]])
+}
+
+// a.b.c.d, if s is one
+pub fn parse_addr(s: &str) -> Option<[u8; 4]> {
+    let mut addr = [0; 4];
+    let mut parts = s.split('.');
+    for byte in &mut addr {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(addr)
+}
+
+// The first address of name.
+pub fn resolve(name: &str) -> Result<[u8; 4], Error> {
+    lookup(name).map(|addrs| addrs[0])
+}
+
+// All the addresses of name, at least one.
+pub fn lookup(name: &str) -> Result<Vec<[u8; 4]>, Error> {
+    if let Some(addr) = parse_addr(name) {
+        return Ok(Vec::from([addr]));
+    }
+    if name == "localhost" {
+        return Ok(Vec::from([[127, 0, 0, 1]]));
+    }
+    let fd = syscall::socket(net::AF_INET, net::SOCK_DGRAM, 0);
+    if fd < 0 {
+        return Err(Error::Sys(fd as isize));
+    }
+    let r = ask(fd, name);
+    syscall::close(fd);
+    r
+}
+
+fn ask(fd: i32, name: &str) -> Result<Vec<[u8; 4]>, Error> {
+    let mut conf = net::NetConf::default();
+    if syscall::netconf(fd, &mut conf) < 0 || conf.dns == [0; 4] {
+        return Err(Error::NoServer);
+    }
+    let server = SockAddrIn::new(conf.dns, PORT);
+    let id = syscall::uptime() as u16;
+    let mut q = [0u8; MAX_MSG];
+    let len = query(id, name, &mut q).ok_or(Error::BadName)?;
+    let mut msg = [0u8; MAX_MSG];
+    for _ in 0..TRIES {
+        let sent = syscall::sendto(fd, &q[..len], &server);
+        if sent < 0 {
+            return Err(Error::Sys(sent));
+        }
+        let start = syscall::uptime();
+        while syscall::uptime() - start < WAIT {
+            let mut from = SockAddrIn::default();
+            let n = syscall::recvfrom(fd, &mut msg, net::MSG_DONTWAIT, Some(&mut from));
+            if n == -syscall::EAGAIN {
+                syscall::sleep(1);
+                continue;
+            }
+            if n < 0 {
+                return Err(Error::Sys(n));
+            }
+            // Anything else is someone else's, or an old answer.
+            if from == server && n as usize >= HLEN && be16(&msg, 0) == id {
+                return answers(id, &msg[..n as usize]);
+            }
+        }
+    }
+    Err(Error::TimedOut)
+}
+
+// Build a query with ID id for the A records of name in buf. Returns its
+// length, or None if name is not a valid one.
+pub fn query(id: u16, name: &str, buf: &mut [u8]) -> Option<usize> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() + 2 > MAX_NAME || buf.len() < HLEN + name.len() + 6 {
+        return None;
+    }
+    buf[..HLEN].fill(0);
+    buf[0..2].copy_from_slice(&id.to_be_bytes());
+    buf[2..4].copy_from_slice(&FLAG_RD.to_be_bytes());
+    buf[4..6].copy_from_slice(&1u16.to_be_bytes()); // One question
+    let mut off = HLEN;
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        buf[off] = label.len() as u8;
+        buf[off + 1..off + 1 + label.len()].copy_from_slice(label.as_bytes());
+        off += 1 + label.len();
+    }
+    buf[off] = 0;
+    buf[off + 1..off + 3].copy_from_slice(&TYPE_A.to_be_bytes());
+    buf[off + 3..off + 5].copy_from_slice(&CLASS_IN.to_be_bytes());
+    Some(off + 5)
+}
+
+// The offset just past the name at off, which may end in a pointer.
+fn skip_name(msg: &[u8], mut off: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(off)? as usize;
+        match len {
+            0 => return Some(off + 1),
+            l if l & 0xC0 == 0xC0 => return Some(off + 2),
+            l => off += 1 + l,
+        }
+    }
+}
+
+// The addresses in the A records of msg, the answer to query id.
+pub fn answers(id: u16, msg: &[u8]) -> Result<Vec<[u8; 4]>, Error> {
+    if msg.len() < HLEN || be16(msg, 0) != id || be16(msg, 2) & FLAG_RESPONSE == 0 {
+        return Err(Error::Failed);
+    }
+    match be16(msg, 2) & 0xF {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(Error::NotFound),
+        _ => return Err(Error::Failed),
+    }
+    let (questions, records) = (be16(msg, 4), be16(msg, 6));
+    let mut off = HLEN;
+    for _ in 0..questions {
+        off = skip_name(msg, off).ok_or(Error::Failed)? + 4;
+    }
+    // Those for a CNAME come before the A records of the name it gives.
+    let mut addrs = Vec::new();
+    for _ in 0..records {
+        off = skip_name(msg, off).ok_or(Error::Failed)?;
+        let rr = msg.get(off..off + 10).ok_or(Error::Failed)?;
+        let len = be16(rr, 8) as usize;
+        let data = msg.get(off + 10..off + 10 + len).ok_or(Error::Failed)?;
+        if be16(rr, 0) == TYPE_A && be16(rr, 2) == CLASS_IN && len == 4 {
+            addrs.push([data[0], data[1], data[2], data[3]]);
+        }
+        off += 10 + len;
+    }
+    if addrs.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(addrs)
+}
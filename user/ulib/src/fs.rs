@@ -1,8 +1,47 @@
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct DirEntry {
-    pub inode: u32,
-    pub rec_len: u16,
-    pub name_len: u8,
-    pub file_type: u8,
+use crate::syscall;
+use rust_alloc::string::String;
+use rust_alloc::vec::Vec;
+
+pub use abi::fs::*;
+
+// Read from fd until EOF, appending to buf. Returns the bytes read, or the
+// negated errno of a read that failed, with what came before it left in buf.
+pub fn read_to_end(fd: i32, buf: &mut Vec<u8>) -> Result<usize, isize> {
+    let start = buf.len();
+    let mut chunk = [0u8; 512];
+    loop {
+        let n = syscall::read(fd, &mut chunk);
+        if n < 0 {
+            return Err(n);
+        }
+        if n == 0 {
+            return Ok(buf.len() - start);
+        }
+        buf.extend_from_slice(&chunk[..n as usize]);
+    }
+}
+
+// read_to_end, checking that the contents are UTF-8: -EINVAL if not.
+pub fn read_to_string(fd: i32) -> Result<String, isize> {
+    let mut buf = Vec::new();
+    read_to_end(fd, &mut buf)?;
+    String::from_utf8(buf).map_err(|_| -syscall::EINVAL)
+}
+
+// The (inode, name) pairs in a buffer filled by getdents.
+pub fn dirents(buf: &[u8]) -> impl Iterator<Item = (u32, &str)> + '_ {
+    let hdr = core::mem::size_of::<DirEntry>();
+    let mut off = 0;
+    core::iter::from_fn(move || {
+        if off + hdr > buf.len() {
+            return None;
+        }
+        let de = unsafe { core::ptr::read_unaligned(buf.as_ptr().add(off) as *const DirEntry) };
+        let name = buf.get(off + hdr..off + hdr + de.name_len as usize)?;
+        if de.rec_len == 0 {
+            return None;
+        }
+        off += de.rec_len as usize;
+        Some((de.inode, core::str::from_utf8(name).unwrap_or("???")))
+    })
 }
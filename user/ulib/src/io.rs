@@ -5,14 +5,78 @@ pub struct Stdout;
 
 impl fmt::Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        syscall::write(1, s.as_bytes());
+        write_all(1, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+// Write all of buf, continuing after short writes and retrying on EAGAIN.
+// Fails on an error or if the fd stops accepting bytes.
+pub fn write_all(fd: i32, mut buf: &[u8]) -> Result<(), ()> {
+    while !buf.is_empty() {
+        let n = syscall::write(fd, buf);
+        if n == -syscall::EAGAIN {
+            continue;
+        }
+        if n <= 0 {
+            return Err(());
+        }
+        buf = &buf[n as usize..];
+    }
+    Ok(())
+}
+
+// Buffered standard output: bytes are held until the buffer fills, flush()
+// is called or the program exits (syscall::exit flushes).
+const STDOUT_BUFSIZE: usize = 1024;
+static mut STDOUT_BUF: [u8; STDOUT_BUFSIZE] = [0; STDOUT_BUFSIZE];
+static mut STDOUT_LEN: usize = 0;
+
+pub struct BufStdout;
+
+pub fn stdout() -> BufStdout {
+    BufStdout
+}
+
+impl BufStdout {
+    pub fn write(&mut self, mut bytes: &[u8]) -> Result<(), ()> {
+        while !bytes.is_empty() {
+            let len = unsafe { STDOUT_LEN };
+            if len == STDOUT_BUFSIZE {
+                self.flush()?;
+                continue;
+            }
+            let n = core::cmp::min(bytes.len(), STDOUT_BUFSIZE - len);
+            unsafe {
+                let buf = &mut *core::ptr::addr_of_mut!(STDOUT_BUF);
+                buf[len..len + n].copy_from_slice(&bytes[..n]);
+                STDOUT_LEN = len + n;
+            }
+            bytes = &bytes[n..];
+        }
         Ok(())
     }
+
+    pub fn flush(&mut self) -> Result<(), ()> {
+        let len = unsafe { STDOUT_LEN };
+        // Empty the buffer even on failure, so exit does not retry forever.
+        unsafe { STDOUT_LEN = 0 };
+        let buf = unsafe { &*core::ptr::addr_of!(STDOUT_BUF) };
+        write_all(1, &buf[..len])
+    }
+}
+
+impl fmt::Write for BufStdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
 }
 
 pub fn print(args: fmt::Arguments) {
     use fmt::Write;
-    Stdout.write_fmt(args).unwrap();
+    // Keep output in order with anything buffered through stdout().
+    let _ = stdout().flush();
+    // Nowhere to report a failed stdout write (the panic handler prints too).
+    let _ = Stdout.write_fmt(args);
 }
 
 #[macro_export]
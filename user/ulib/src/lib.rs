@@ -5,10 +5,20 @@ extern crate alloc as rust_alloc;
 use core::panic::PanicInfo;
 
 pub mod alloc;
+pub mod dns;
 pub mod env;
 pub mod fs;
 pub mod io;
 pub mod syscall;
+pub mod thread;
+
+pub use abi::coredump;
+pub use abi::fb;
+pub use abi::mman;
+pub use abi::net;
+pub use abi::ptrace;
+pub use abi::signal;
+pub use abi::tty;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -1,16 +1,10 @@
 use core::arch::asm;
 
-pub const SYS_READ: usize = 0;
-pub const SYS_WRITE: usize = 1;
-pub const SYS_OPEN: u64 = 2;
-pub const SYS_CLOSE: u64 = 3;
-pub const SYS_SBRK: u64 = 12;
-pub const SYS_FORK: usize = 57;
-pub const SYS_EXEC: usize = 59;
-pub const SYS_EXIT: usize = 60;
-pub const SYS_WAIT: usize = 61;
-pub const SYS_PIPE: usize = 22;
-pub const SYS_DUP: usize = 32;
+pub use abi::syscall::*;
+
+// Shape of the irqstat table: counts[cpu * IRQSTAT_NIRQ + irq].
+pub const IRQSTAT_NCPU: usize = 8;
+pub const IRQSTAT_NIRQ: usize = 32;
 
 #[inline(always)]
 pub unsafe fn syscall0(num: usize) -> usize {
@@ -70,7 +64,35 @@ pub unsafe fn syscall3(num: usize, a1: usize, a2: usize, a3: usize) -> usize {
     ret
 }
 
+#[inline(always)]
+pub unsafe fn syscall6(
+    num: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> usize {
+    let ret: usize;
+    asm!(
+        "syscall",
+        inout("rax") num => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        in("r8") a5,
+        in("r9") a6,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack, preserves_flags)
+    );
+    ret
+}
+
 pub fn exit(status: i32) -> ! {
+    let _ = crate::io::stdout().flush();
     unsafe {
         syscall1(SYS_EXIT, status as usize);
     }
@@ -89,6 +111,7 @@ pub fn fork() -> i32 {
     unsafe { syscall0(SYS_FORK) as i32 }
 }
 
+// Wait for any child to exit; returns its pid, with its exit status in status.
 pub fn wait(status: Option<&mut i32>) -> i32 {
     unsafe {
         let ptr = status.map(|s| s as *mut i32 as usize).unwrap_or(0);
@@ -96,6 +119,164 @@ pub fn wait(status: Option<&mut i32>) -> i32 {
     }
 }
 
+// Wait for the child pid to exit; returns its pid, or -1 if pid is not a child.
+pub fn waitpid(pid: i32, status: Option<&mut i32>) -> i32 {
+    unsafe {
+        let ptr = status.map(|s| s as *mut i32 as usize).unwrap_or(0);
+        syscall2(SYS_WAITPID, pid as usize, ptr) as i32
+    }
+}
+
+// Start a thread running entry(arg) on the stack ending at stack, sharing
+// this process's memory and open files; see ulib::thread. Returns its pid.
+pub fn clone(entry: extern "C" fn(usize) -> !, stack: usize, arg: usize) -> i32 {
+    unsafe { syscall3(SYS_CLONE, entry as usize, stack, arg) as i32 }
+}
+
+// Add inc to this process's nice value; returns the new one, which fork
+// passes on to children.
+pub fn nice(inc: i32) -> i32 {
+    unsafe { syscall1(SYS_NICE, inc as isize as usize) as i32 }
+}
+
+// Send signal sig (see ulib::signal) to process pid.
+pub fn kill(pid: i32, sig: usize) -> i32 {
+    unsafe { syscall2(SYS_KILL, pid as usize, sig) as i32 }
+}
+
+// Move pid (0: this process), which must be this process or a child, into
+// process group pgid (0: one of its own).
+pub fn setpgid(pid: i32, pgid: i32) -> i32 {
+    unsafe { syscall2(SYS_SETPGID, pid as usize, pgid as usize) as i32 }
+}
+
+pub fn getpgid(pid: i32) -> i32 {
+    unsafe { syscall1(SYS_GETPGID, pid as usize) as i32 }
+}
+
+// Device control; see ulib::tty for the requests.
+pub fn ioctl(fd: i32, request: usize, arg: usize) -> isize {
+    unsafe { syscall3(SYS_IOCTL, fd as usize, request, arg) as isize }
+}
+
+// Make pgid the foreground process group of the terminal fd, which Ctrl-C
+// interrupts.
+pub fn tcsetpgrp(fd: i32, pgid: i32) -> i32 {
+    ioctl(fd, crate::tty::TIOCSPGRP, &pgid as *const i32 as usize) as i32
+}
+
+pub fn tcgetpgrp(fd: i32) -> i32 {
+    let mut pgid = 0i32;
+    match ioctl(fd, crate::tty::TIOCGPGRP, &mut pgid as *mut i32 as usize) {
+        0 => pgid,
+        err => err as i32,
+    }
+}
+
+pub fn tcgetattr(fd: i32, t: &mut crate::tty::Termios) -> i32 {
+    ioctl(
+        fd,
+        crate::tty::TCGETS,
+        t as *mut crate::tty::Termios as usize,
+    ) as i32
+}
+
+pub fn tcsetattr(fd: i32, t: &crate::tty::Termios) -> i32 {
+    ioctl(
+        fd,
+        crate::tty::TCSETS,
+        t as *const crate::tty::Termios as usize,
+    ) as i32
+}
+
+// Restorer for every handler set here: handlers return into it.
+core::arch::global_asm!(
+    ".global __sigreturn",
+    "__sigreturn:",
+    "mov eax, {n}",
+    "syscall",
+    "ud2",
+    n = const SYS_SIGRETURN,
+);
+
+unsafe extern "C" {
+    fn __sigreturn();
+}
+
+// Set the action for sig, returning the old one. The restorer is filled in.
+pub fn sigaction(sig: usize, act: &crate::signal::SigAction) -> Option<crate::signal::SigAction> {
+    let mut act = *act;
+    act.restorer = __sigreturn as *const () as usize;
+    let mut old = crate::signal::SigAction::default();
+    let r = unsafe {
+        syscall3(
+            SYS_SIGACTION,
+            sig,
+            &act as *const _ as usize,
+            &mut old as *mut _ as usize,
+        ) as isize
+    };
+    (r == 0).then_some(old)
+}
+
+// Run handler(sig) when sig arrives, or pass signal::SIG_DFL or SIG_IGN as
+// handler. Returns the old handler, or None for a bad sig.
+pub fn signal(sig: usize, handler: usize) -> Option<usize> {
+    let act = crate::signal::SigAction {
+        handler,
+        ..Default::default()
+    };
+    sigaction(sig, &act).map(|old| old.handler)
+}
+
+// Change the blocked mask as how (signal::SIG_BLOCK, SIG_UNBLOCK or
+// SIG_SETMASK) says, returning the old mask.
+pub fn sigprocmask(how: usize, set: u32) -> u32 {
+    unsafe { syscall2(SYS_SIGPROCMASK, how, set as usize) as u32 }
+}
+
+// Timer ticks since boot.
+pub fn uptime() -> u64 {
+    unsafe { syscall0(SYS_UPTIME) as u64 }
+}
+
+// Sleep for n timer ticks.
+pub fn sleep(n: u64) -> isize {
+    unsafe { syscall1(SYS_SLEEP, n as usize) as isize }
+}
+
+// CPU time of this process (RUSAGE_SELF) or of its waited-for children
+// (RUSAGE_CHILDREN), in timer ticks.
+pub fn getrusage(who: isize, ru: &mut Rusage) -> isize {
+    unsafe { syscall2(SYS_GETRUSAGE, who as usize, ru as *mut Rusage as usize) as isize }
+}
+
+// ptrace(request, pid, addr); see ulib::ptrace for the requests.
+pub fn ptrace(req: usize, pid: i32, addr: usize) -> isize {
+    unsafe { syscall3(SYS_PTRACE, req, pid as usize, addr) as isize }
+}
+
+pub fn ptrace_getregs(pid: i32, regs: &mut crate::ptrace::Regs) -> isize {
+    ptrace(
+        crate::ptrace::PTRACE_GETREGS,
+        pid,
+        regs as *mut crate::ptrace::Regs as usize,
+    )
+}
+
+pub fn ptrace_setbp(pid: i32, bp: &crate::ptrace::Breakpoint) -> isize {
+    ptrace(
+        crate::ptrace::PTRACE_SETBP,
+        pid,
+        bp as *const crate::ptrace::Breakpoint as usize,
+    )
+}
+
+// prctl(option, arg); see ulib::coredump for the options.
+pub fn prctl(option: usize, arg: usize) -> isize {
+    unsafe { syscall2(SYS_PRCTL, option, arg) as isize }
+}
+
 pub fn exec(path: *const u8, argv: &[*const u8]) -> i32 {
     // We need to convert &[&str] to null-terminated C-style array of pointers
     // This is tricky without allocation. User has to provide the buffer or we use variable stack.
@@ -119,38 +300,288 @@ pub fn exec(path: *const u8, argv: &[*const u8]) -> i32 {
 
 // Safer exec is hard without alloc.
 
-pub fn open(path: &str, mode: i32) -> i32 {
-    // We need null-terminated path.
-    // Ideally we assume path is null-terminated or we copy to stack buffer.
-    // For now, let's just pass pointer and hope user provided null-terminated or use a small buffer.
-    // Since we can't allocate, we can't easily append null.
-    // BUT we can check if it ends with null?
-    // Rust strings are not null terminated.
-    // Let's implement a small stack buffer copy for open since paths are usually short.
-    let mut buf = [0u8; 128];
-    if path.len() >= 128 {
-        return -1;
+// Call f with path as a NUL-terminated string. Paths the kernel would refuse
+// as too long fail with -ENAMETOOLONG.
+fn with_cstr(path: &str, f: impl FnOnce(usize) -> usize) -> isize {
+    let mut buf = [0u8; abi::fs::PATH_MAX];
+    if path.len() >= buf.len() {
+        return -ENAMETOOLONG;
     }
-    for (i, b) in path.bytes().enumerate() {
-        buf[i] = b;
-    }
-    buf[path.len()] = 0;
+    buf[..path.len()].copy_from_slice(path.as_bytes());
+    f(buf.as_ptr() as usize) as isize
+}
 
-    unsafe { syscall2(SYS_OPEN as usize, buf.as_ptr() as usize, mode as usize) as i32 }
+pub fn open(path: &str, mode: i32) -> i32 {
+    with_cstr(path, |p| unsafe { syscall2(SYS_OPEN, p, mode as usize) }) as i32
+}
+
+// Fill buf with entries of a directory opened with O_DIRECTORY (see
+// fs::dirents). Returns the bytes filled, 0 at the end of the directory.
+pub fn getdents(fd: i32, buf: &mut [u8]) -> isize {
+    unsafe {
+        syscall3(
+            SYS_GETDENTS,
+            fd as usize,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+        ) as isize
+    }
 }
 
 pub fn close(fd: i32) -> i32 {
-    unsafe { syscall1(SYS_CLOSE as usize, fd as usize) as i32 }
+    unsafe { syscall1(SYS_CLOSE, fd as usize) as i32 }
+}
+
+pub fn stat(path: &str, st: &mut crate::fs::Stat) -> i32 {
+    let st = st as *mut crate::fs::Stat as usize;
+    with_cstr(path, |p| unsafe { syscall2(SYS_STAT, p, st) }) as i32
+}
+
+pub fn fstat(fd: i32, st: &mut crate::fs::Stat) -> i32 {
+    unsafe { syscall2(SYS_FSTAT, fd as usize, st as *mut crate::fs::Stat as usize) as i32 }
+}
+
+// Move the offset of fd to off from whence (fs::SEEK_SET, SEEK_CUR or
+// SEEK_END). Returns the new offset.
+pub fn lseek(fd: i32, off: i64, whence: i32) -> i64 {
+    unsafe { syscall3(SYS_LSEEK, fd as usize, off as usize, whence as usize) as i64 }
+}
+
+// Set the size of a file open for writing, freeing blocks past a new end.
+pub fn ftruncate(fd: i32, len: usize) -> i32 {
+    unsafe { syscall2(SYS_FTRUNCATE, fd as usize, len) as i32 }
+}
+
+// Write what has been written to the file system fd is on through to the
+// disk.
+pub fn fsync(fd: i32) -> i32 {
+    unsafe { syscall1(SYS_FSYNC, fd as usize) as i32 }
+}
+
+// Write every file system through to its disk.
+pub fn sync() {
+    unsafe { syscall0(SYS_SYNC) };
+}
+
+// Make path the directory that relative paths start from.
+pub fn chdir(path: &str) -> i32 {
+    with_cstr(path, |p| unsafe { syscall1(SYS_CHDIR, p) }) as i32
+}
+
+// Create an empty directory at path.
+pub fn mkdir(path: &str) -> i32 {
+    with_cstr(path, |p| unsafe { syscall1(SYS_MKDIR, p) }) as i32
+}
+
+// Create a character device node at path for device (major, minor).
+pub fn mknod(path: &str, major: u16, minor: u16) -> i32 {
+    with_cstr(path, |p| unsafe {
+        syscall3(SYS_MKNOD, p, major as usize, minor as usize)
+    }) as i32
+}
+
+// Remove the directory entry path; the file goes once it is no longer open.
+pub fn unlink(path: &str) -> i32 {
+    with_cstr(path, |p| unsafe { syscall1(SYS_UNLINK, p) }) as i32
+}
+
+// Block and inode counts of the filesystem holding path.
+pub fn statfs(path: &str, st: &mut crate::fs::StatFs) -> i32 {
+    let st = st as *mut crate::fs::StatFs as usize;
+    with_cstr(path, |p| unsafe { syscall2(SYS_STATFS, p, st) }) as i32
+}
+
+// Map len bytes of fd from offset off, or zeroed memory with MAP_ANONYMOUS
+// (see ulib::mman). Returns the address, or a negated errno.
+pub fn mmap(len: usize, prot: u32, flags: u32, fd: i32, off: usize) -> isize {
+    unsafe {
+        syscall6(
+            SYS_MMAP,
+            0,
+            len,
+            prot as usize,
+            flags as usize,
+            fd as usize,
+            off,
+        ) as isize
+    }
+}
+
+// Remove the mappings in [addr, addr + len).
+pub fn munmap(addr: usize, len: usize) -> i32 {
+    unsafe { syscall2(SYS_MUNMAP, addr, len) as i32 }
 }
 
 pub fn sbrk(n: isize) -> isize {
-    unsafe { syscall1(SYS_SBRK as usize, n as usize) as isize }
+    unsafe { syscall1(SYS_SBRK, n as usize) as isize }
 }
 
 pub fn dup(fd: i32) -> i32 {
-    unsafe { syscall1(SYS_DUP as usize, fd as usize) as i32 }
+    unsafe { syscall1(SYS_DUP, fd as usize) as i32 }
+}
+
+// Make newfd a copy of oldfd, closing newfd first if it is open.
+pub fn dup2(oldfd: i32, newfd: i32) -> i32 {
+    unsafe { syscall2(SYS_DUP2, oldfd as usize, newfd as usize) as i32 }
 }
 
 pub fn pipe(fds: &mut [i32; 2]) -> i32 {
-    unsafe { syscall1(SYS_PIPE as usize, fds.as_mut_ptr() as usize) as i32 }
+    unsafe { syscall1(SYS_PIPE, fds.as_mut_ptr() as usize) as i32 }
+}
+
+// A UDP socket, socket(net::AF_INET, net::SOCK_DGRAM, 0), or a TCP one,
+// with net::SOCK_STREAM.
+pub fn socket(domain: u16, ty: usize, protocol: usize) -> i32 {
+    unsafe { syscall3(SYS_SOCKET, domain as usize, ty, protocol) as i32 }
+}
+
+pub fn bind(fd: i32, addr: &crate::net::SockAddrIn) -> i32 {
+    let len = core::mem::size_of::<crate::net::SockAddrIn>();
+    unsafe {
+        syscall3(
+            SYS_BIND,
+            fd as usize,
+            addr as *const crate::net::SockAddrIn as usize,
+            len,
+        ) as i32
+    }
+}
+
+pub fn listen(fd: i32, backlog: usize) -> i32 {
+    unsafe { syscall2(SYS_LISTEN, fd as usize, backlog) as i32 }
+}
+
+// Wait for a connection to a listening socket; returns its fd, and the
+// peer's address in from if given.
+pub fn accept(fd: i32, from: Option<&mut crate::net::SockAddrIn>) -> i32 {
+    let mut len = core::mem::size_of::<crate::net::SockAddrIn>() as u32;
+    let (addr, addrlen) = match from {
+        Some(sa) => (
+            sa as *mut crate::net::SockAddrIn as usize,
+            &mut len as *mut u32 as usize,
+        ),
+        None => (0, 0),
+    };
+    unsafe { syscall3(SYS_ACCEPT, fd as usize, addr, addrlen) as i32 }
+}
+
+pub fn connect(fd: i32, addr: &crate::net::SockAddrIn) -> i32 {
+    let len = core::mem::size_of::<crate::net::SockAddrIn>();
+    unsafe {
+        syscall3(
+            SYS_CONNECT,
+            fd as usize,
+            addr as *const crate::net::SockAddrIn as usize,
+            len,
+        ) as i32
+    }
+}
+
+pub fn sendto(fd: i32, buf: &[u8], addr: &crate::net::SockAddrIn) -> isize {
+    let len = core::mem::size_of::<crate::net::SockAddrIn>();
+    unsafe {
+        syscall6(
+            SYS_SENDTO,
+            fd as usize,
+            buf.as_ptr() as usize,
+            buf.len(),
+            0,
+            addr as *const crate::net::SockAddrIn as usize,
+            len,
+        ) as isize
+    }
+}
+
+// Send to a connected socket's peer, as write does.
+pub fn send(fd: i32, buf: &[u8], flags: usize) -> isize {
+    unsafe {
+        syscall6(
+            SYS_SENDTO,
+            fd as usize,
+            buf.as_ptr() as usize,
+            buf.len(),
+            flags,
+            0,
+            0,
+        ) as isize
+    }
+}
+
+// recvfrom, without the sender.
+pub fn recv(fd: i32, buf: &mut [u8], flags: usize) -> isize {
+    recvfrom(fd, buf, flags, None)
+}
+
+// A socket's own address and port.
+pub fn getsockname(fd: i32, addr: &mut crate::net::SockAddrIn) -> i32 {
+    let mut len = core::mem::size_of::<crate::net::SockAddrIn>() as u32;
+    unsafe {
+        syscall3(
+            SYS_GETSOCKNAME,
+            fd as usize,
+            addr as *mut crate::net::SockAddrIn as usize,
+            &mut len as *mut u32 as usize,
+        ) as i32
+    }
+}
+
+// The address and port of a socket's peer.
+pub fn getpeername(fd: i32, addr: &mut crate::net::SockAddrIn) -> i32 {
+    let mut len = core::mem::size_of::<crate::net::SockAddrIn>() as u32;
+    unsafe {
+        syscall3(
+            SYS_GETPEERNAME,
+            fd as usize,
+            addr as *mut crate::net::SockAddrIn as usize,
+            &mut len as *mut u32 as usize,
+        ) as i32
+    }
+}
+
+// Wait for a datagram, unless flags has MSG_DONTWAIT; its sender is stored
+// in from if given.
+pub fn recvfrom(
+    fd: i32,
+    buf: &mut [u8],
+    flags: usize,
+    from: Option<&mut crate::net::SockAddrIn>,
+) -> isize {
+    let mut len = core::mem::size_of::<crate::net::SockAddrIn>() as u32;
+    let (addr, addrlen) = match from {
+        Some(sa) => (
+            sa as *mut crate::net::SockAddrIn as usize,
+            &mut len as *mut u32 as usize,
+        ),
+        None => (0, 0),
+    };
+    unsafe {
+        syscall6(
+            SYS_RECVFROM,
+            fd as usize,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+            flags,
+            addr,
+            addrlen,
+        ) as isize
+    }
+}
+
+// The network interface's configuration, through any socket fd.
+pub fn netconf(fd: i32, conf: &mut crate::net::NetConf) -> i32 {
+    ioctl(
+        fd,
+        crate::net::SIOCGNETCONF,
+        conf as *mut crate::net::NetConf as usize,
+    ) as i32
+}
+
+// Per-CPU interrupt counters. Fills `counts` (see IRQSTAT_NCPU/IRQSTAT_NIRQ)
+// and returns how many entries were written.
+pub fn irqstat(counts: &mut [u64]) -> isize {
+    unsafe { syscall2(SYS_IRQSTAT, counts.as_mut_ptr() as usize, counts.len()) as isize }
+}
+
+pub fn sysinfo(info: &mut SysInfo) -> isize {
+    unsafe { syscall1(SYS_SYSINFO, info as *mut SysInfo as usize) as isize }
 }
@@ -0,0 +1,65 @@
+// Threads: processes made by clone that share this one's memory and open
+// files. Each runs on a stack of its own, mapped here and unmapped by join.
+//
+// The heap is safe to use from several threads; buffered stdout is not, so a
+// thread should print with fs or io::write_all rather than println.
+
+use crate::mman::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use crate::syscall;
+use rust_alloc::boxed::Box;
+
+pub const STACK_SIZE: usize = 64 * 1024;
+
+pub struct JoinHandle {
+    pid: i32,
+    stack: usize,
+}
+
+// Run f in a new thread, or None if there is no memory or process for one.
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Option<JoinHandle> {
+    let stack = syscall::mmap(
+        STACK_SIZE,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if stack < 0 {
+        return None;
+    }
+    let stack = stack as usize;
+    let f: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+    let arg = Box::into_raw(f) as usize;
+    let pid = syscall::clone(start, stack + STACK_SIZE, arg);
+    if pid < 0 {
+        drop(unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce()>) });
+        syscall::munmap(stack, STACK_SIZE);
+        return None;
+    }
+    Some(JoinHandle { pid, stack })
+}
+
+extern "C" fn start(arg: usize) -> ! {
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce()>) };
+    f();
+    // Not syscall::exit, which flushes stdout: it belongs to the main thread.
+    unsafe { syscall::syscall1(syscall::SYS_EXIT, 0) };
+    unreachable!()
+}
+
+impl JoinHandle {
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    // Wait for the thread to finish; returns its exit status, or None if it
+    // could not be waited for.
+    pub fn join(self) -> Option<i32> {
+        let mut status = 0;
+        if syscall::waitpid(self.pid, Some(&mut status)) != self.pid {
+            return None;
+        }
+        syscall::munmap(self.stack, STACK_SIZE);
+        Some(status)
+    }
+}
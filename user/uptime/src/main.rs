@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use ulib::{entry, println, syscall};
+
+entry!(main);
+
+// Print the timer ticks since boot.
+// Usage: uptime
+fn main(_argc: usize, _argv: *const *const u8) {
+    println!("up {} ticks", syscall::uptime());
+    syscall::exit(0);
+}